@@ -1,34 +1,122 @@
-use std::sync::{Mutex};
-use types::{Action, ActionError};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use rustc_serialize::Encodable;
+use types::{Action, ActionContext};
+use error::Error;
+use metrics::{self, Metrics};
+use metadata::AuditEntry;
+use time;
 use bincode;
 
-struct ActionRunner {
-	replay: bool,
-	lock: Mutex
+/// Clones share the lock, so actions never run concurrently, like the
+/// replicator's replays and the mount's own actions
+#[derive(Clone)]
+pub struct ActionRunner {
+	lock: Arc<Mutex<()>>,
+	metrics: Metrics
 }
 
 impl ActionRunner {
-	pub fn new(replay: bool) -> ActionRunner {
+	pub fn new() -> ActionRunner {
 		ActionRunner {
-			replay,
-			lock: Mutex::new(0)
+			lock: Arc::new(Mutex::new(())),
+			metrics: Metrics::new()
 		}
 	}
 
-	pub fn run(&self, action: &Action) -> Result<(), ActionError> {
+	/// Record the latency of every action in these metrics, shared with the mount
+	pub fn set_metrics(&mut self, metrics: Metrics) {
+		self.metrics = metrics;
+	}
+
+	pub fn run<A: Action + Encodable>(&self, context: &ActionContext, action: &mut A) -> Result<(), Error> {
+		self.run_logged(context, action, false, false)
+	}
+
+	/// Run an action on an ignored path, logged but never replicated
+	pub fn run_local_only<A: Action + Encodable>(&self, context: &ActionContext, action: &mut A) -> Result<(), Error> {
+		self.run_logged(context, action, true, false)
+	}
+
+	/// Run an action received from a peer
+	///
+	/// It's logged and audited with the device and user of the context, the
+	/// peer's, and never handed out again since it wasn't made on this device.
+	pub fn replay<A: Action + Encodable>(&self, context: &ActionContext, action: &mut A) -> Result<(), Error> {
+		self.run_logged(context, action, false, true)
+	}
+
+	fn run_logged<A: Action + Encodable>(&self, context: &ActionContext, action: &mut A, local_only: bool, replay: bool) -> Result<(), Error> {
 		// Lock, so we cannot run actions concurrently when called from different threads
 		let mut _guard = self.lock.lock().unwrap();
 
-		let name = action.get_name();
-		let encoded: Vec<u8> = bincode::encode(&action, bincode::SizeLimit::Infinite).unwrap();
-		// TODO: save to log
+		// Save to log, so it can be replicated to peers
+		let encoded: Vec<u8> = match bincode::encode(action, bincode::SizeLimit::Infinite) {
+			Ok(encoded) => encoded,
+			Err(e)      => {
+				error!("Unable to encode {}: {}", action.get_name(), e);
+				return Err(Error::InvalidArgument);
+			}
+		};
+		let seq = context.metadata.log_action(action.get_name(), &encoded, context.device, context.uid, local_only)?;
+
+		// Run the action, its metadata changes are undone when a later step fails
+		let start = Instant::now();
+		let result = context.metadata.in_transaction(|| action.run(context, replay));
+		let duration = start.elapsed();
 
-		// Run the action
-		let result = action.run(self.replay);
+		let outcome = match result {
+			Ok(())     => "ok".to_string(),
+			Err(ref e) => format!("errno {}", e.errno())
+		};
+		debug!("action={} seq={} target={} device={} uid={} local_only={} replay={} duration_us={} result={}",
+		       action.get_name(), seq, action.get_target(), context.device, context.uid, local_only, replay, metrics::micros(duration), outcome);
+		self.metrics.record_action(action.get_name(), action.get_target(), duration, result.as_ref().err().map(|e| e.errno()));
 
-		// TODO: update log: finished with result (result)
+		// Update log: finished with result
+		let _ = context.metadata.finish_action(seq, result.is_ok());
+
+		// Replayed actions are audited too, to tell who changed a file on any device
+		if result.is_ok() {
+			self.audit(context, action);
+		}
 
 		// Return the result
 		result
 	}
-}
\ No newline at end of file
+
+	/// Record who ran the action, a failure to do so doesn't fail the action
+	fn audit<A: Action>(&self, context: &ActionContext, action: &A) {
+		let inodes = action.get_inodes();
+		let path = match action.get_dentry() {
+			Some((parent, name)) => dentry_path(context, parent, name),
+			None                 => inodes.first()
+				.and_then(|id| context.metadata.get_by_id(id).ok())
+				.and_then(|inode| context.metadata.get_volume_path(&inode).ok())
+				.map(|path| path.to_string_lossy().into_owned())
+		};
+		let new_path = action.get_new_dentry().and_then(|(parent, name)| dentry_path(context, parent, name));
+
+		let entry = AuditEntry {
+			seq: 0,
+			time: time::get_time(),
+			device: context.device.to_string(),
+			uid: context.uid,
+			name: action.get_name().to_string(),
+			inodes,
+			path: path.unwrap_or(String::new()),
+			new_path
+		};
+		if let Err(e) = context.metadata.add_audit_entry(&entry) {
+			error!("Unable to audit {} on {}: {}", action.get_name(), action.get_target(), e);
+		}
+	}
+}
+
+/// Volume path of a name in a directory
+fn dentry_path(context: &ActionContext, parent: &str, name: &str) -> Option<String> {
+	context.metadata.get_by_id(&parent.to_string())
+		.and_then(|parent| context.metadata.get_volume_path(&parent))
+		.map(|path| path.join(name).to_string_lossy().into_owned())
+		.ok()
+}
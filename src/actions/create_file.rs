@@ -0,0 +1,78 @@
+use std::path::PathBuf;
+use metadata::Ownership;
+use types::{Action, ActionContext};
+use super::check_name;
+use error::{Error, optional};
+
+/// A new empty regular file, its content follows as a `WriteVersion`
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct CreateFile {
+    pub id: String,
+    /// The empty first version
+    pub version: String,
+    pub parent: String,
+    pub name: String,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32
+}
+
+impl CreateFile {
+    pub const NAME: &'static str = "create_file";
+}
+
+impl Action for CreateFile {
+    fn get_name(&self) -> &str {
+        CreateFile::NAME
+    }
+
+    fn get_target(&self) -> &str {
+        &self.parent
+    }
+
+    fn get_inodes(&self) -> Vec<String> {
+        vec![self.id.clone(), self.parent.clone()]
+    }
+
+    fn get_dentry(&self) -> Option<(&str, &str)> {
+        Some((&self.parent, &self.name))
+    }
+
+    fn run(&mut self, context: &ActionContext, replay: bool) -> Result<(), Error> {
+        let metadata = context.metadata;
+
+        // Already applied
+        if replay && optional(metadata.get_by_id(&self.id))?.is_some() {
+            return Ok(());
+        }
+
+        let parent_inode = metadata.get_by_id(&self.parent)?;
+        if !parent_inode.kind.is_directory() {
+            return Err(Error::NotADirectory);
+        }
+        check_name(&parent_inode, &self.name, replay)?;
+
+        if optional(metadata.lookup(&parent_inode, &self.name))?.is_some() {
+            if replay {
+                return Err(Error::Conflict(self.name.clone()));
+            }
+            return Err(Error::Exists);
+        }
+
+        let ownership = Ownership {
+            mode: self.mode,
+            uid: self.uid,
+            gid: self.gid
+        };
+
+        // A file of another device is a placeholder until its content is needed here
+        let inode = if replay {
+            metadata.create_placeholder(&self.id, &self.version, &parent_inode, &self.name, &ownership, 0, &String::new())?
+        } else {
+            metadata.create_file(&self.id, &self.version, &parent_inode, &self.name, &ownership)?
+        };
+        let mut path_buf = PathBuf::new();
+        metadata.get_path(&inode, &mut path_buf)?;
+        context.storage.create(path_buf.as_path())
+    }
+}
@@ -0,0 +1,63 @@
+use metadata::Ownership;
+use types::{Action, ActionContext};
+use super::check_name;
+use error::{Error, optional};
+
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct CreateSymlink {
+    pub id: String,
+    pub parent: String,
+    pub name: String,
+    pub target: String,
+    pub uid: u32,
+    pub gid: u32
+}
+
+impl CreateSymlink {
+    pub const NAME: &'static str = "create_symlink";
+}
+
+impl Action for CreateSymlink {
+    fn get_name(&self) -> &str {
+        CreateSymlink::NAME
+    }
+
+    fn get_target(&self) -> &str {
+        &self.parent
+    }
+
+    fn get_inodes(&self) -> Vec<String> {
+        vec![self.id.clone(), self.parent.clone()]
+    }
+
+    fn run(&mut self, context: &ActionContext, replay: bool) -> Result<(), Error> {
+        let metadata = context.metadata;
+
+        // Already applied
+        if replay && optional(metadata.get_by_id(&self.id))?.is_some() {
+            return Ok(());
+        }
+
+        let parent_inode = metadata.get_by_id(&self.parent)?;
+        if !parent_inode.kind.is_directory() {
+            return Err(Error::NotADirectory);
+        }
+        check_name(&parent_inode, &self.name, replay)?;
+
+        if optional(metadata.lookup(&parent_inode, &self.name))?.is_some() {
+            if replay {
+                return Err(Error::Conflict(self.name.clone()));
+            }
+            return Err(Error::Exists);
+        }
+
+        let ownership = Ownership {
+            mode: 0o777,
+            uid: self.uid,
+            gid: self.gid
+        };
+
+        metadata.create_symlink(&self.id, &parent_inode, &self.name, &self.target, &ownership)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+use std::path::PathBuf;
+use types::{Action, ActionContext};
+use super::check_name;
+use error::{Error, optional};
+
+/// Another name for a file or symlink
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct Link {
+    pub id: String,
+    /// Directory of the new name
+    pub parent: String,
+    pub name: String
+}
+
+impl Link {
+    pub const NAME: &'static str = "link";
+}
+
+impl Action for Link {
+    fn get_name(&self) -> &str {
+        Link::NAME
+    }
+
+    fn get_target(&self) -> &str {
+        &self.id
+    }
+
+    fn get_inodes(&self) -> Vec<String> {
+        vec![self.id.clone(), self.parent.clone()]
+    }
+
+    fn get_dentry(&self) -> Option<(&str, &str)> {
+        Some((&self.parent, &self.name))
+    }
+
+    fn run(&mut self, context: &ActionContext, replay: bool) -> Result<(), Error> {
+        let metadata = context.metadata;
+
+        let inode = metadata.get_by_id(&self.id)?;
+        if inode.kind.is_directory() {
+            return Err(Error::PermissionDenied);
+        }
+        let parent_inode = metadata.get_by_id(&self.parent)?;
+        if !parent_inode.kind.is_directory() {
+            return Err(Error::NotADirectory);
+        }
+        check_name(&parent_inode, &self.name, replay)?;
+
+        match optional(metadata.lookup(&parent_inode, &self.name))? {
+            // Already applied
+            Some(ref existing) if replay && existing.id == self.id => return Ok(()),
+            Some(_) if replay => return Err(Error::Conflict(self.name.clone())),
+            Some(_)           => return Err(Error::Exists),
+            None              => ()
+        }
+
+        let mut path_buf = PathBuf::new();
+        metadata.get_path(&inode, &mut path_buf)?;
+        let new_inode = metadata.link(&inode, &parent_inode, &self.name)?;
+
+        // Mirror the link locally, so every name resolves to the same content
+        if new_inode.kind.is_regular_file() {
+            let mut new_path_buf = PathBuf::new();
+            metadata.get_path(&new_inode, &mut new_path_buf)?;
+            context.storage.link(path_buf.as_path(), new_path_buf.as_path())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+use bincode;
+use rustc_serialize::{Encodable, Decodable};
+use action_runner::ActionRunner;
+use types::{Action, ActionContext};
+use metadata::{Metadata, ActionEnvelope, INode};
+use storage::StorageBackend;
+use error::{Error, optional};
+use sync_rules::{SyncRules, ExcludedMode};
+use s3::CACHE_DIR;
+use control::SOCKET_FILE;
+use daemon::PID_FILE;
+
+mod create_dir;
+mod create_file;
+mod create_symlink;
+mod link;
+mod unlink;
+mod remove_dir;
+mod set_attr;
+mod set_xattr;
+mod remove_xattr;
+mod rename;
+mod write_version;
+
+pub use self::create_dir::CreateDir;
+pub use self::create_file::CreateFile;
+pub use self::create_symlink::CreateSymlink;
+pub use self::link::Link;
+pub use self::unlink::Unlink;
+pub use self::remove_dir::RemoveDir;
+pub use self::set_attr::SetAttr;
+pub use self::set_xattr::{SetXattr, check_xattr_name};
+pub use self::remove_xattr::RemoveXattr;
+pub use self::rename::Rename;
+pub use self::write_version::WriteVersion;
+
+/// Entries at the root of the storage that hold the state of the volume,
+/// with the state directory left at its default
+pub const RESERVED_NAMES: &'static [&'static str] = &["metadata.sqlite", "metadata.sqlite-journal", CACHE_DIR, SOCKET_FILE, PID_FILE];
+
+/// Refuse a new name that would collide with the state of the volume
+pub fn check_name(parent: &INode, name: &str, replay: bool) -> Result<(), Error> {
+    if parent.ino == 1 && RESERVED_NAMES.contains(&name) {
+        return Err(if replay { Error::Conflict(name.to_string()) } else { Error::PermissionDenied });
+    }
+    Ok(())
+}
+
+/// Decode an action from the log of a peer and run it, returns false when it
+/// didn't run because the selective sync rules hide its target
+///
+/// While paths are hidden, a target that doesn't exist counts as hidden too,
+/// its creation was held back. The caller keeps such actions until the rules change, see
+/// `Metadata::add_pending_action`. The action runs, and is logged and
+/// audited, as made by the device and user in the envelope.
+pub fn replay(runner: &ActionRunner, metadata: &Metadata, storage: &StorageBackend, sync_rules: &SyncRules, envelope: &ActionEnvelope) -> Result<bool, Error> {
+    let context = ActionContext {
+        metadata,
+        storage,
+        device: &envelope.device,
+        uid: envelope.uid
+    };
+    let data = &envelope.data;
+
+    match envelope.name.as_str() {
+        CreateDir::NAME     => replay_action::<CreateDir>(runner, &context, sync_rules, data),
+        CreateFile::NAME    => replay_action::<CreateFile>(runner, &context, sync_rules, data),
+        CreateSymlink::NAME => replay_action::<CreateSymlink>(runner, &context, sync_rules, data),
+        Link::NAME          => replay_action::<Link>(runner, &context, sync_rules, data),
+        Unlink::NAME        => replay_action::<Unlink>(runner, &context, sync_rules, data),
+        RemoveDir::NAME     => replay_action::<RemoveDir>(runner, &context, sync_rules, data),
+        SetAttr::NAME       => replay_action::<SetAttr>(runner, &context, sync_rules, data),
+        SetXattr::NAME      => replay_action::<SetXattr>(runner, &context, sync_rules, data),
+        RemoveXattr::NAME   => replay_action::<RemoveXattr>(runner, &context, sync_rules, data),
+        WriteVersion::NAME  => replay_action::<WriteVersion>(runner, &context, sync_rules, data),
+        Rename::NAME        => replay_action::<Rename>(runner, &context, sync_rules, data),
+        _                   => Err(Error::NotImplemented)
+    }
+}
+
+fn replay_action<A: Action + Encodable + Decodable>(runner: &ActionRunner, context: &ActionContext, sync_rules: &SyncRules, data: &Vec<u8>) -> Result<bool, Error> {
+    let mut action: A = match bincode::decode(data) {
+        Ok(action) => action,
+        Err(e)     => return Err(Error::Remote(format!("Unable to decode a replicated action: {}", e)))
+    };
+
+    match optional(context.metadata.get_by_id(&action.get_target().to_string()))? {
+        Some(ref inode) if !sync_rules.is_visible(&context.metadata.get_volume_path(inode)?) => return Ok(false),
+        None if sync_rules.excluded_mode == ExcludedMode::Hide && !sync_rules.is_empty() => return Ok(false),
+        _ => ()
+    }
+
+    runner.replay(context, &mut action)?;
+    Ok(true)
+}
@@ -0,0 +1,60 @@
+use std::path::PathBuf;
+use types::{Action, ActionContext};
+use error::{Error, optional};
+
+/// Remove an empty directory, with its ".." link to the parent
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct RemoveDir {
+    pub id: String,
+    pub parent: String,
+    pub name: String
+}
+
+impl RemoveDir {
+    pub const NAME: &'static str = "remove_dir";
+}
+
+impl Action for RemoveDir {
+    fn get_name(&self) -> &str {
+        RemoveDir::NAME
+    }
+
+    fn get_target(&self) -> &str {
+        &self.id
+    }
+
+    fn get_inodes(&self) -> Vec<String> {
+        vec![self.id.clone(), self.parent.clone()]
+    }
+
+    fn get_dentry(&self) -> Option<(&str, &str)> {
+        Some((&self.parent, &self.name))
+    }
+
+    fn run(&mut self, context: &ActionContext, replay: bool) -> Result<(), Error> {
+        let metadata = context.metadata;
+
+        let parent_inode = metadata.get_by_id(&self.parent)?;
+        let inode = match optional(metadata.lookup(&parent_inode, &self.name))? {
+            Some(ref inode) if inode.id == self.id => inode.clone(),
+            // The name was replaced here in the meantime
+            Some(_) if replay => return Err(Error::Conflict(self.name.clone())),
+            // Already applied
+            None if replay    => return Ok(()),
+            _                 => return Err(Error::NotFound)
+        };
+        if !inode.kind.is_directory() {
+            return Err(Error::NotADirectory);
+        }
+
+        let mut path_buf = PathBuf::new();
+        metadata.get_path(&inode, &mut path_buf)?;
+        match metadata.remove_dir(&inode) {
+            Ok(())                         => (),
+            // Entries were added here in the meantime
+            Err(Error::NotEmpty) if replay => return Err(Error::Conflict(self.name.clone())),
+            Err(e)                         => return Err(e)
+        }
+        context.storage.remove(path_buf.as_path())
+    }
+}
@@ -0,0 +1,35 @@
+use types::{Action, ActionContext};
+use error::Error;
+
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct RemoveXattr {
+    pub id: String,
+    pub name: String
+}
+
+impl RemoveXattr {
+    pub const NAME: &'static str = "remove_xattr";
+}
+
+impl Action for RemoveXattr {
+    fn get_name(&self) -> &str {
+        RemoveXattr::NAME
+    }
+
+    fn get_target(&self) -> &str {
+        &self.id
+    }
+
+    fn run(&mut self, context: &ActionContext, replay: bool) -> Result<(), Error> {
+        let metadata = context.metadata;
+
+        let inode = metadata.get_by_id(&self.id)?;
+
+        match metadata.remove_xattr(&inode, &self.name) {
+            Ok(true)  => Ok(()),
+            // Already removed
+            Ok(false) => if replay { Ok(()) } else { Err(Error::NoAttribute) },
+            Err(e)    => Err(e)
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use std::path::PathBuf;
+use types::{Action, ActionContext};
+use super::check_name;
+use error::{Error, optional};
+
+/// Move a name to a new parent and name, replacing a compatible entry there
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct Rename {
+    pub id: String,
+    pub parent: String,
+    pub name: String,
+    pub new_parent: String,
+    pub new_name: String
+}
+
+impl Rename {
+    pub const NAME: &'static str = "rename";
+}
+
+impl Action for Rename {
+    fn get_name(&self) -> &str {
+        Rename::NAME
+    }
+
+    fn get_target(&self) -> &str {
+        &self.id
+    }
+
+    fn get_inodes(&self) -> Vec<String> {
+        vec![self.id.clone(), self.parent.clone(), self.new_parent.clone()]
+    }
+
+    fn get_dentry(&self) -> Option<(&str, &str)> {
+        Some((&self.parent, &self.name))
+    }
+
+    fn get_new_dentry(&self) -> Option<(&str, &str)> {
+        Some((&self.new_parent, &self.new_name))
+    }
+
+    fn run(&mut self, context: &ActionContext, replay: bool) -> Result<(), Error> {
+        let metadata = context.metadata;
+
+        let parent_inode = metadata.get_by_id(&self.parent)?;
+        let new_parent_inode = metadata.get_by_id(&self.new_parent)?;
+        if !new_parent_inode.kind.is_directory() {
+            return Err(Error::NotADirectory);
+        }
+        check_name(&new_parent_inode, &self.new_name, replay)?;
+
+        let replaced = optional(metadata.lookup(&new_parent_inode, &self.new_name))?;
+        let inode = match optional(metadata.lookup(&parent_inode, &self.name))? {
+            Some(ref inode) if inode.id == self.id => inode.clone(),
+            // Already applied
+            _ if replay && replaced.as_ref().map_or(false, |replaced| replaced.id == self.id) => return Ok(()),
+            _ if replay => return Err(Error::Conflict(self.name.clone())),
+            _           => return Err(Error::NotFound)
+        };
+
+        // A directory can't become its own ancestor
+        if inode.kind.is_directory() && metadata.is_within(&new_parent_inode, &inode.id)? {
+            return Err(if replay { Error::Conflict(self.new_name.clone()) } else { Error::InvalidArgument });
+        }
+
+        if let Some(ref replaced) = replaced {
+            if replaced.id == inode.id {
+                return Ok(());
+            }
+
+            if replaced.kind.is_directory() && !inode.kind.is_directory() {
+                return Err(Error::IsADirectory);
+            } else if !replaced.kind.is_directory() && inode.kind.is_directory() {
+                return Err(Error::NotADirectory);
+            } else if replaced.kind.is_directory() && !metadata.get_children(replaced)?.is_empty() {
+                return Err(if replay { Error::Conflict(self.new_name.clone()) } else { Error::NotEmpty });
+            }
+        }
+
+        let mut old_path = PathBuf::new();
+        metadata.get_path(&inode, &mut old_path)?;
+        let new_inode = metadata.rename(&inode, &new_parent_inode, &self.new_name)?;
+        let mut new_path = PathBuf::new();
+        metadata.get_path(&new_inode, &mut new_path)?;
+
+        // Symlinks have no local content, but may replace a name that has
+        if inode.kind.is_symlink() {
+            match replaced {
+                Some(ref replaced) if !replaced.kind.is_symlink() => context.storage.remove(&new_path),
+                _                                                 => Ok(())
+            }
+        } else {
+            context.storage.rename(&old_path, &new_path)
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use time::Timespec;
+use metadata::Ownership;
+use permission::{self, Acl};
+use types::{Action, ActionContext};
+use error::Error;
+
+/// A chmod, chown or change of the access and modification times
+///
+/// A change of size is a write, which makes a `WriteVersion`.
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct SetAttr {
+    pub id: String,
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Seconds and nanoseconds
+    pub atime: Option<(i64, i32)>,
+    pub mtime: Option<(i64, i32)>
+}
+
+impl SetAttr {
+    pub const NAME: &'static str = "set_attr";
+}
+
+impl Action for SetAttr {
+    fn get_name(&self) -> &str {
+        SetAttr::NAME
+    }
+
+    fn get_target(&self) -> &str {
+        &self.id
+    }
+
+    fn run(&mut self, context: &ActionContext, _replay: bool) -> Result<(), Error> {
+        let metadata = context.metadata;
+
+        let mut inode = metadata.get_by_id(&self.id)?;
+
+        if self.mode.is_some() || self.uid.is_some() || self.gid.is_some() {
+            let ownership = Ownership {
+                mode: self.mode.map(|mode| mode & 0o7777).unwrap_or(inode.mode),
+                uid: self.uid.unwrap_or(inode.uid),
+                gid: self.gid.unwrap_or(inode.gid)
+            };
+
+            // A chmod is mirrored in the access ACL
+            if self.mode.is_some() {
+                let name = permission::ACL_ACCESS.to_string();
+                if let Some(acl) = metadata.get_xattr(&inode, &name)?.and_then(|data| Acl::parse(&data)) {
+                    metadata.set_xattr(&inode, &name, &acl.with_mode(ownership.mode).to_bytes())?;
+                }
+            }
+
+            inode = metadata.set_ownership(&inode, &ownership)?;
+        }
+
+        if self.atime.is_some() || self.mtime.is_some() {
+            let timespec = |time: Option<(i64, i32)>| time.map(|(sec, nsec)| Timespec::new(sec, nsec));
+            metadata.set_times(&inode, timespec(self.atime), timespec(self.mtime))?;
+        }
+        Ok(())
+    }
+}
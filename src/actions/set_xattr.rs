@@ -0,0 +1,107 @@
+use libc;
+use metadata::Ownership;
+use permission::{self, Acl};
+use types::{Action, ActionContext};
+use error::Error;
+
+/// Longest attribute name, like XATTR_NAME_MAX on Linux
+pub const XATTR_NAME_MAX: usize = 255;
+
+/// Largest attribute value, like XATTR_SIZE_MAX on Linux
+pub const XATTR_SIZE_MAX: usize = 64 * 1024;
+
+/// Largest total of names and values per inode
+pub const XATTR_TOTAL_MAX: usize = 256 * 1024;
+
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct SetXattr {
+    pub id: String,
+    pub name: String,
+    pub value: Vec<u8>,
+    pub flags: u32
+}
+
+impl SetXattr {
+    pub const NAME: &'static str = "set_xattr";
+}
+
+/// Only user attributes and POSIX ACLs are stored and replicated
+pub fn check_xattr_name(name: &String) -> Result<(), Error> {
+    if name.len() > XATTR_NAME_MAX {
+        return Err(Error::OutOfRange);
+    }
+    if permission::is_acl_name(name) {
+        return Ok(());
+    }
+    if !name.starts_with("user.") || name.len() == "user.".len() {
+        return Err(Error::NotSupported);
+    }
+    Ok(())
+}
+
+impl Action for SetXattr {
+    fn get_name(&self) -> &str {
+        SetXattr::NAME
+    }
+
+    fn get_target(&self) -> &str {
+        &self.id
+    }
+
+    fn run(&mut self, context: &ActionContext, replay: bool) -> Result<(), Error> {
+        let metadata = context.metadata;
+
+        let inode = metadata.get_by_id(&self.id)?;
+
+        check_xattr_name(&self.name)?;
+        if self.value.len() > XATTR_SIZE_MAX {
+            return Err(Error::ArgumentTooBig);
+        }
+
+        // ACLs must be well-formed, and the access ACL is mirrored in the mode
+        let acl_mode = if permission::is_acl_name(&self.name) {
+            let acl = match Acl::parse(&self.value) {
+                Some(acl) => acl,
+                None      => return Err(Error::InvalidArgument)
+            };
+            if self.name == permission::ACL_DEFAULT && !inode.kind.is_directory() {
+                return Err(Error::AccessDenied);
+            }
+            if self.name == permission::ACL_ACCESS { Some(acl.mode()) } else { None }
+        } else {
+            None
+        };
+
+        let existing = metadata.list_xattr(&inode)?;
+        let exists = existing.iter().any(|&(ref name, _)| *name == self.name);
+
+        // Last writer wins when replaying
+        if !replay {
+            if self.flags & (libc::XATTR_CREATE as u32) != 0 && exists {
+                return Err(Error::Exists);
+            }
+            if self.flags & (libc::XATTR_REPLACE as u32) != 0 && !exists {
+                return Err(Error::NoAttribute);
+            }
+        }
+
+        let total = existing.iter()
+            .filter(|&&(ref name, _)| *name != self.name)
+            .fold(self.name.len() + self.value.len(), |total, &(ref name, size)| total + name.len() + size);
+        if total > XATTR_TOTAL_MAX {
+            return Err(Error::NoSpace);
+        }
+
+        metadata.set_xattr(&inode, &self.name, &self.value)?;
+
+        if let Some(mode) = acl_mode {
+            let ownership = Ownership {
+                mode: (inode.mode & !0o777) | mode,
+                uid: inode.uid,
+                gid: inode.gid
+            };
+            metadata.set_ownership(&inode, &ownership)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+use std::path::PathBuf;
+use types::{Action, ActionContext};
+use error::{Error, optional};
+
+/// Remove a name of a file or symlink, the inode goes with its last name
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct Unlink {
+    pub id: String,
+    pub parent: String,
+    pub name: String
+}
+
+impl Unlink {
+    pub const NAME: &'static str = "unlink";
+}
+
+impl Action for Unlink {
+    fn get_name(&self) -> &str {
+        Unlink::NAME
+    }
+
+    fn get_target(&self) -> &str {
+        &self.id
+    }
+
+    fn get_inodes(&self) -> Vec<String> {
+        vec![self.id.clone(), self.parent.clone()]
+    }
+
+    fn get_dentry(&self) -> Option<(&str, &str)> {
+        Some((&self.parent, &self.name))
+    }
+
+    fn run(&mut self, context: &ActionContext, replay: bool) -> Result<(), Error> {
+        let metadata = context.metadata;
+
+        let parent_inode = metadata.get_by_id(&self.parent)?;
+        let inode = match optional(metadata.lookup(&parent_inode, &self.name))? {
+            Some(ref inode) if inode.id == self.id => inode.clone(),
+            // The name was replaced here in the meantime
+            Some(_) if replay => return Err(Error::Conflict(self.name.clone())),
+            // Already applied
+            None if replay    => return Ok(()),
+            _                 => return Err(Error::NotFound)
+        };
+        if inode.kind.is_directory() {
+            return Err(Error::IsADirectory);
+        }
+
+        let mut path_buf = PathBuf::new();
+        metadata.get_path(&inode, &mut path_buf)?;
+        metadata.unlink(&inode)?;
+
+        // Other local names keep the content alive
+        if inode.kind.is_regular_file() {
+            context.storage.remove(path_buf.as_path())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+use std::path::PathBuf;
+use libc::{O_WRONLY, O_TRUNC, O_CREAT};
+use uuid::Uuid;
+use metadata::FileVersion;
+use types::{Action, ActionContext};
+use error::Error;
+
+/// A finished version of a regular file, written or restored
+///
+/// Only the size and hash are replicated, a peer turns the file into a
+/// placeholder whose content is fetched from a remote on first access.
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct WriteVersion {
+    pub id: String,
+    pub version: String,
+    /// The version the writes started from, or the restored one
+    pub source_version: String,
+    pub size: u64,
+    pub hash: String
+}
+
+impl WriteVersion {
+    pub const NAME: &'static str = "write_version";
+
+    /// A new version with the content of an earlier one, put in place already
+    pub fn restoring(restored: &FileVersion) -> WriteVersion {
+        WriteVersion {
+            id: restored.id.clone(),
+            version: Uuid::new_v4().to_string(),
+            source_version: restored.version.clone(),
+            size: restored.size,
+            hash: restored.hash.clone()
+        }
+    }
+}
+
+impl Action for WriteVersion {
+    fn get_name(&self) -> &str {
+        WriteVersion::NAME
+    }
+
+    fn get_target(&self) -> &str {
+        &self.id
+    }
+
+    fn run(&mut self, context: &ActionContext, replay: bool) -> Result<(), Error> {
+        let metadata = context.metadata;
+
+        let inode = metadata.get_by_id(&self.id)?;
+        if !inode.kind.is_regular_file() {
+            return Err(Error::IsADirectory);
+        }
+
+        if !replay {
+            metadata.finish_version(&inode, &self.version, &self.source_version, self.size, &self.hash, true)?;
+            return Ok(());
+        }
+
+        // Already applied, unless the same version ended up with other content
+        let known = metadata.get_versions(&inode)?.into_iter()
+            .find(|file_version| file_version.version == self.version && !file_version.hash.is_empty());
+        if let Some(known) = known {
+            if known.hash != self.hash {
+                return Err(Error::Conflict(inode.name.clone()));
+            }
+            return Ok(());
+        }
+
+        // Still being written here, the last writer wins once both are finished.
+        // An unfinished version the write started from, like the one of a new
+        // file, isn't being written.
+        if inode.hash.is_empty() && inode.current_version != self.version && inode.current_version != self.source_version {
+            return Err(Error::Conflict(inode.name.clone()));
+        }
+
+        let hydrated = inode.hydrated && inode.hash == self.hash;
+        metadata.finish_version(&inode, &self.version, &self.source_version, self.size, &self.hash, hydrated)?;
+
+        // Drop the stale local content, it's fetched again when needed
+        if !hydrated {
+            let mut path_buf = PathBuf::new();
+            metadata.get_path(&inode, &mut path_buf)?;
+            context.storage.open(path_buf.as_path(), O_WRONLY | O_TRUNC | O_CREAT)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use std::ffi::OsString;
+use time::{self, Timespec, Duration};
+use super::{open_volume, usage_error, EXIT_OK, EXIT_FAILURE};
+
+pub const USAGE: &'static [&'static str] = &["audit <local_path> [--path <path>] [--since <YYYY-MM-DD[ HH:MM[:SS]]|30m|2h|7d>] [--limit <count>]"];
+
+const DEFAULT_LIMIT: u32 = 100;
+
+/// Characters of a device id that are shown, enough to tell devices apart
+const DEVICE_ID_CHARS: usize = 8;
+
+/// `markfs audit <local_path> [--path <path>] [--since <time>] [--limit <count>]`
+///
+/// Who changed what and when, on this device and on the peers whose actions
+/// were replayed here. A path shows its own changes and those below it, the
+/// time is local, or how long ago.
+pub fn audit(args: &[OsString]) -> i32 {
+    if args.is_empty() {
+        return usage_error(USAGE);
+    }
+
+    let mut path = None;
+    let mut since = None;
+    let mut limit = DEFAULT_LIMIT;
+
+    let mut options = args[1..].iter();
+    while let Some(arg) = options.next() {
+        let value = match options.next().and_then(|value| value.to_str()) {
+            Some(value) => value,
+            None        => return usage_error(USAGE)
+        };
+
+        if arg == "--path" {
+            // Volume paths always start at the root
+            path = Some(format!("/{}", value.trim_left_matches('/')));
+        } else if arg == "--since" {
+            match parse_time(value) {
+                Some(time) => since = Some(time),
+                None       => return usage_error(USAGE)
+            }
+        } else if arg == "--limit" {
+            match value.parse::<u32>() {
+                Ok(count) if count > 0 => limit = count,
+                _                      => return usage_error(USAGE)
+            }
+        } else {
+            return usage_error(USAGE);
+        }
+    }
+
+    let metadata = match open_volume(&args[0]) {
+        Ok(metadata) => metadata,
+        Err(code)    => return code
+    };
+    let this_device = metadata.device_id().ok();
+
+    let entries = match metadata.get_audit_log(path.as_ref().map(|path| path.as_str()), since, limit) {
+        Ok(entries) => entries,
+        Err(e)      => {
+            println!("Unable to read the audit log: {}", e);
+            return EXIT_FAILURE;
+        }
+    };
+
+    println!("{:<20} {:<8} {:>6} {:<14} {}", "TIME", "DEVICE", "UID", "ACTION", "PATH [INODES]");
+    for entry in entries {
+        let time = time::at(entry.time);
+        let device = if this_device.as_ref() == Some(&entry.device) {
+            "this".to_string()
+        } else {
+            entry.device.chars().take(DEVICE_ID_CHARS).collect()
+        };
+        let path = match entry.new_path {
+            Some(ref new_path) => format!("{} -> {}", entry.path, new_path),
+            None               => entry.path.clone()
+        };
+
+        println!("{:<20} {:<8} {:>6} {:<14} {} [{}]", time::strftime("%Y-%m-%d %H:%M:%S", &time).unwrap_or(String::new()),
+                 device, entry.uid, entry.name, path, entry.inodes.join(" "));
+    }
+    EXIT_OK
+}
+
+/// A local date and time, or a time ago like `30m`
+fn parse_time(value: &str) -> Option<Timespec> {
+    let ago = |count: &str, unit: fn(i64) -> Duration| count.parse::<i64>().ok().map(|count| time::get_time() - unit(count));
+    if value.ends_with('m') {
+        return ago(value.trim_right_matches('m'), Duration::minutes);
+    } else if value.ends_with('h') {
+        return ago(value.trim_right_matches('h'), Duration::hours);
+    } else if value.ends_with('d') {
+        return ago(value.trim_right_matches('d'), Duration::days);
+    }
+
+    ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%d"].iter()
+        .filter_map(|format| time::strptime(value, format).ok())
+        .next()
+        .map(|mut tm| {
+            tm.tm_utcoff = time::now().tm_utcoff;
+            tm.to_timespec()
+        })
+}
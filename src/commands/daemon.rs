@@ -0,0 +1,260 @@
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+use rustc_serialize::json::{Json, ToJson};
+use control::Response;
+use daemon::{self, Daemon, Fork};
+use super::{load_all_configs, request, request_daemon, absolute_path, usage_error, EXIT_OK, EXIT_FAILURE};
+
+pub const PROGRESS_USAGE: &'static [&'static str] = &["progress <local_path> [--cancel <ino>]"];
+pub const PAUSE_USAGE: &'static [&'static str] = &["pause <local_path>"];
+pub const RESUME_USAGE: &'static [&'static str] = &["resume <local_path>"];
+pub const DAEMON_USAGE: &'static [&'static str] = &[
+    "daemon [--background] [--pidfile <path>]",
+    "daemon --shutdown"
+];
+pub const VOLUMES_USAGE: &'static [&'static str] = &["volumes"];
+pub const START_USAGE: &'static [&'static str] = &["start <local_path> [<mountpoint>] [-o <option>[,<option>...]] [--read-only]"];
+pub const STOP_USAGE: &'static [&'static str] = &["stop <local_path>"];
+
+/// `markfs progress <local_path> [--cancel <ino>]`, downloads of placeholders by the mount
+pub fn progress(args: &[OsString]) -> i32 {
+    if args.len() == 3 && args[1] == "--cancel" {
+        let ino = match args[2].to_str().and_then(|ino| ino.parse::<u64>().ok()) {
+            Some(ino) => ino,
+            None      => return usage_error(PROGRESS_USAGE)
+        };
+
+        let mut arguments = Response::new();
+        arguments.insert("ino".to_string(), ino.to_json());
+        return match request(&args[0], "cancel", arguments) {
+            Ok(_)     => EXIT_OK,
+            Err(code) => code
+        };
+    }
+    if args.len() != 1 {
+        return usage_error(PROGRESS_USAGE);
+    }
+
+    let response = match request(&args[0], "progress", Response::new()) {
+        Ok(response) => response,
+        Err(code)    => return code
+    };
+
+    println!("{:>8} {:>12} {:>12} {}", "INO", "DONE", "TOTAL", "PATH");
+    if let Some(&Json::Array(ref hydrations)) = response.get("hydrations") {
+        for hydration in hydrations.iter() {
+            let field = |name: &str| hydration.find(name).and_then(|value| value.as_u64()).unwrap_or(0);
+            let path = hydration.find("path").and_then(|path| path.as_string()).unwrap_or("");
+            println!("{:>8} {:>12} {:>12} {}", field("ino"), field("done"), field("total"), path);
+        }
+    }
+    EXIT_OK
+}
+
+/// `markfs pause <local_path>`, queued uploads wait until resumed
+pub fn pause(args: &[OsString]) -> i32 {
+    if args.len() != 1 {
+        return usage_error(PAUSE_USAGE);
+    }
+    match request(&args[0], "pause", Response::new()) {
+        Ok(_)     => EXIT_OK,
+        Err(code) => code
+    }
+}
+
+/// `markfs resume <local_path>`
+pub fn resume(args: &[OsString]) -> i32 {
+    if args.len() != 1 {
+        return usage_error(RESUME_USAGE);
+    }
+    match request(&args[0], "resume", Response::new()) {
+        Ok(_)     => EXIT_OK,
+        Err(code) => code
+    }
+}
+
+/// `markfs daemon`, mounts every volume with a configured mountpoint and
+/// serves them until SIGTERM, SIGINT, SIGHUP or `markfs daemon --shutdown`
+///
+/// More volumes are mounted and unmounted with `markfs start` and `markfs stop`.
+pub fn daemon(args: &[OsString]) -> i32 {
+    let mut background = false;
+    let mut shutdown = false;
+    let mut pidfile = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--background" {
+            background = true;
+        } else if arg == "--shutdown" {
+            shutdown = true;
+        } else if arg == "--pidfile" {
+            match args.next() {
+                Some(path) => pidfile = Some(PathBuf::from(path)),
+                None       => return usage_error(DAEMON_USAGE)
+            }
+        } else {
+            return usage_error(DAEMON_USAGE);
+        }
+    }
+
+    if shutdown {
+        return match request_daemon("shutdown", Response::new()) {
+            Ok(_)     => EXIT_OK,
+            Err(code) => code
+        };
+    }
+
+    let mut pidfile = match pidfile.map_or_else(daemon::pidfile_path, Ok) {
+        Ok(pidfile) => pidfile,
+        Err(e)      => {
+            println!("{}", e);
+            return EXIT_FAILURE;
+        }
+    };
+    let configs = match load_all_configs() {
+        Ok(configs) => configs,
+        Err(code)   => return code
+    };
+    let listener = match daemon::bind() {
+        Ok(listener) => listener,
+        Err(e)       => {
+            println!("{}", e);
+            return EXIT_FAILURE;
+        }
+    };
+
+    // Before any thread exists, only this process continues in the child
+    let mut ready = None;
+    if background {
+        if let Ok(current_dir) = env::current_dir() {
+            pidfile = current_dir.join(pidfile);
+        }
+        match daemon::daemonize() {
+            Ok(Fork::Child(child)) => ready = Some(child),
+            Ok(Fork::Parent(code)) => return code.unwrap_or(EXIT_FAILURE),
+            Err(e)                 => {
+                println!("Unable to run in the background: {}", e);
+                return EXIT_FAILURE;
+            }
+        }
+    }
+
+    if let Err(message) = daemon::write_pidfile(&pidfile) {
+        println!("{}", message);
+        return EXIT_FAILURE;
+    }
+
+    // Every thread spawned from here on leaves the signals to the handler
+    let markfs_daemon = Daemon::new();
+    let signal_daemon = markfs_daemon.clone();
+    daemon::spawn_signal_handler(daemon::block_signals(), move || {
+        signal_daemon.stop();
+        true
+    });
+
+    for (local_path, config) in configs {
+        if config.mountpoint.is_some() {
+            if let Err(e) = markfs_daemon.mount(&local_path, None, Vec::new(), false) {
+                warn!("{}", e);
+            }
+        }
+    }
+
+    // The socket is listening and the configured volumes are mounted
+    if let Some(ready) = ready {
+        ready.notify(EXIT_OK);
+    }
+    markfs_daemon.serve(listener);
+
+    if let Ok(path) = daemon::socket_path() {
+        let _ = fs::remove_file(path);
+    }
+    let _ = fs::remove_file(&pidfile);
+    EXIT_OK
+}
+
+/// `markfs volumes`, the volumes served by the daemon
+pub fn volumes(args: &[OsString]) -> i32 {
+    if !args.is_empty() {
+        return usage_error(VOLUMES_USAGE);
+    }
+
+    let response = match request_daemon("volumes", Response::new()) {
+        Ok(response) => response,
+        Err(code)    => return code
+    };
+
+    println!("{:<8} {:<32} {}", "STATE", "LOCAL PATH", "MOUNTPOINT");
+    if let Some(&Json::Array(ref volumes)) = response.get("volumes") {
+        for volume in volumes.iter() {
+            let field = |name: &str| volume.find(name).and_then(|value| value.as_string()).unwrap_or("").to_string();
+            let mounted = volume.find("mounted").and_then(|mounted| mounted.as_boolean()).unwrap_or(false);
+            println!("{:<8} {:<32} {}", if mounted { "mounted" } else { "stopped" }, field("local_path"), field("mountpoint"));
+        }
+    }
+    EXIT_OK
+}
+
+/// `markfs start <local_path> [<mountpoint>]`, mount a volume in the daemon,
+/// at its configured mountpoint unless one is given
+pub fn start(args: &[OsString]) -> i32 {
+    let mut positional = Vec::new();
+    let mut read_only = false;
+    let mut mount_options = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--read-only" {
+            read_only = true;
+        } else if arg == "-o" {
+            match args.next().and_then(|options| options.to_str()) {
+                Some(options) => mount_options.extend(options.split(',').filter(|option| !option.is_empty()).map(|option| option.to_json())),
+                None          => return usage_error(START_USAGE)
+            }
+        } else {
+            positional.push(arg.clone());
+        }
+    }
+    if positional.is_empty() || positional.len() > 2 {
+        return usage_error(START_USAGE);
+    }
+
+    let mut arguments = Response::new();
+    match absolute_path(&positional[0]) {
+        Ok(local_path) => arguments.insert("local_path".to_string(), local_path.to_string_lossy().into_owned().to_json()),
+        Err(code)      => return code
+    };
+    if let Some(mountpoint) = positional.get(1) {
+        match absolute_path(mountpoint) {
+            Ok(mountpoint) => arguments.insert("mountpoint".to_string(), mountpoint.to_string_lossy().into_owned().to_json()),
+            Err(code)      => return code
+        };
+    }
+    arguments.insert("options".to_string(), Json::Array(mount_options));
+    arguments.insert("read_only".to_string(), read_only.to_json());
+
+    match request_daemon("mount", arguments) {
+        Ok(_)     => EXIT_OK,
+        Err(code) => code
+    }
+}
+
+/// `markfs stop <local_path>`, unmount a volume served by the daemon
+pub fn stop(args: &[OsString]) -> i32 {
+    if args.len() != 1 {
+        return usage_error(STOP_USAGE);
+    }
+
+    let mut arguments = Response::new();
+    match absolute_path(&args[0]) {
+        Ok(local_path) => arguments.insert("local_path".to_string(), local_path.to_string_lossy().into_owned().to_json()),
+        Err(code)      => return code
+    };
+    match request_daemon("unmount", arguments) {
+        Ok(_)     => EXIT_OK,
+        Err(code) => code
+    }
+}
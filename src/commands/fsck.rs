@@ -0,0 +1,73 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+use local::LocalFileOperations;
+use storage::{StorageBackend, StatKind};
+use super::{open_volume, usage_error, EXIT_OK, EXIT_FAILURE};
+
+pub const USAGE: &'static [&'static str] = &["fsck <local_path>"];
+
+/// `markfs fsck <local_path>`, fails when any problem is found
+pub fn fsck(args: &[OsString]) -> i32 {
+    if args.len() != 1 {
+        return usage_error(USAGE);
+    }
+
+    let metadata = match open_volume(&args[0]) {
+        Ok(metadata) => metadata,
+        Err(code)    => return code
+    };
+    let storage = LocalFileOperations::new(&args[0]);
+
+    let mut problems = metadata.check_integrity();
+
+    // Every inode needs its counterpart in the storage, symlinks only live in the metadata
+    let inodes = match metadata.get_all() {
+        Ok(inodes) => inodes,
+        Err(e)     => {
+            println!("Unable to read the metadata: {}", e);
+            return EXIT_FAILURE;
+        }
+    };
+    for inode in inodes {
+        if inode.kind.is_symlink() {
+            continue;
+        }
+
+        let mut path_buf = PathBuf::new();
+        let volume_path = match metadata.get_path(&inode, &mut path_buf).and_then(|_| metadata.get_volume_path(&inode)) {
+            Ok(volume_path) => volume_path,
+            Err(e)          => {
+                problems.push(format!("Unable to find inode {}: {}", inode.id, e));
+                continue;
+            }
+        };
+
+        match storage.stat(path_buf.as_path()) {
+            Ok(ref stat) if inode.kind.is_directory() && stat.kind != StatKind::Directory => {
+                problems.push(format!("Not a directory in the storage: {}", volume_path.display()));
+            },
+            Ok(ref stat) if inode.kind.is_regular_file() && stat.kind != StatKind::RegularFile => {
+                problems.push(format!("Not a file in the storage: {}", volume_path.display()));
+            },
+            Ok(ref stat) if inode.kind.is_regular_file() => {
+                let expected = if inode.hydrated { inode.size } else { 0 };
+                if stat.size != expected {
+                    problems.push(format!("Size {} instead of {}: {}", stat.size, expected, volume_path.display()));
+                }
+            },
+            Ok(_)  => (),
+            Err(_) => problems.push(format!("Missing in the storage: {}", volume_path.display()))
+        }
+    }
+
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    if problems.is_empty() {
+        println!("No problems found");
+        EXIT_OK
+    } else {
+        println!("{} problems found", problems.len());
+        EXIT_FAILURE
+    }
+}
@@ -0,0 +1,147 @@
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use metadata::Metadata;
+use error::{Error, optional};
+use local::LocalFileOperations;
+use storage::{StorageBackend, StatKind};
+use s3::{S3Config, S3Remote, CACHE_DIR};
+use peer::Remote;
+use actions::RESERVED_NAMES;
+use super::{load_config, open_volume, connect, usage_error, EXIT_OK, EXIT_FAILURE, EXIT_MOUNTED};
+
+pub const USAGE: &'static [&'static str] = &["gc <local_path> [--dry-run]"];
+
+/// `markfs gc <local_path> [--dry-run]`
+///
+/// Drops earlier versions beyond the configured retention, along with the
+/// blobs on the peers no version refers to anymore, removes stored files the
+/// metadata doesn't know, like leftovers of interrupted repairs, and empties
+/// the download cache.
+///
+/// A mount moves, repairs and downloads files while it runs, which would
+/// look like orphans here, so the volume has to be unmounted.
+pub fn gc(args: &[OsString]) -> i32 {
+    let dry_run = args.len() == 2 && args[1] == "--dry-run";
+    if args.len() != 1 && !dry_run {
+        return usage_error(USAGE);
+    }
+    if connect(&args[0]).is_some() {
+        println!("{} is mounted, unmount it first", args[0].to_string_lossy());
+        return EXIT_MOUNTED;
+    }
+
+    let config = match load_config(Some(&args[0])) {
+        Ok(config) => config,
+        Err(code)  => return code
+    };
+    let metadata = match open_volume(&args[0]) {
+        Ok(metadata) => metadata,
+        Err(code)    => return code
+    };
+    let storage = LocalFileOperations::new(&args[0]);
+
+    let mut failed = 0;
+    let retention = &config.retention;
+    if !dry_run && (retention.versions.is_some() || retention.max_age.is_some()) {
+        match metadata.prune_versions(retention.versions, retention.max_age) {
+            Ok((pruned, unused)) => {
+                println!("Pruned {} earlier versions", pruned);
+                failed += delete_blobs(&config.peers, &unused);
+            },
+            Err(_) => {
+                println!("Failed to prune earlier versions");
+                failed += 1;
+            }
+        }
+    }
+
+    // Without a complete view of the metadata nothing is an orphan
+    let mut orphans = Vec::new();
+    if let Err(e) = find_orphans(&metadata, &storage, &PathBuf::new(), &mut orphans) {
+        println!("Unable to find orphans: {}", e);
+        return EXIT_FAILURE;
+    }
+
+    for orphan in orphans.iter() {
+        println!("Orphan: {}", orphan.display());
+        if !dry_run && remove_all(&storage, orphan).is_err() {
+            failed += 1;
+        }
+    }
+
+    let cache_dir = Path::new(&args[0]).join(CACHE_DIR);
+    if let Ok(entries) = fs::read_dir(&cache_dir) {
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            println!("Cached: {}", entry.file_name().to_string_lossy());
+            if !dry_run && fs::remove_file(entry.path()).is_err() {
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        println!("{} entries could not be removed", failed);
+        return EXIT_FAILURE;
+    }
+    EXIT_OK
+}
+
+/// Remove the blobs from every peer, returns how many removals failed
+fn delete_blobs(peers: &[S3Config], hashes: &[String]) -> usize {
+    if hashes.is_empty() {
+        return 0;
+    }
+
+    let mut failed = 0;
+    for peer in peers.iter() {
+        let remote = match S3Remote::new(peer.clone()) {
+            Ok(remote) => remote,
+            Err(e)     => {
+                println!("Unable to reach bucket {}: {}", peer.bucket, e);
+                failed += hashes.len();
+                continue;
+            }
+        };
+        for hash in hashes.iter() {
+            println!("Unused blob: {}", hash);
+            if let Err(e) = remote.delete_blob(hash) {
+                println!("Unable to remove blob {} from bucket {}: {}", hash, peer.bucket, e);
+                failed += 1;
+            }
+        }
+    }
+    failed
+}
+
+fn find_orphans(metadata: &Metadata, storage: &StorageBackend, path: &PathBuf, orphans: &mut Vec<PathBuf>) -> Result<(), Error> {
+    let names = match storage.list(path.as_path()) {
+        Ok(names) => names,
+        Err(_)    => return Ok(())
+    };
+
+    for name in names {
+        if path.as_os_str().is_empty() && RESERVED_NAMES.iter().any(|reserved| name == **reserved) {
+            continue;
+        }
+
+        let child = path.join(&name);
+        match optional(metadata.resolve(child.as_path()))? {
+            Some(ref inode) if inode.kind.is_directory() => find_orphans(metadata, storage, &child, orphans)?,
+            Some(_) => (),
+            None    => orphans.push(child)
+        }
+    }
+    Ok(())
+}
+
+fn remove_all(storage: &StorageBackend, path: &Path) -> Result<(), ()> {
+    if let Ok(stat) = storage.stat(path) {
+        if stat.kind == StatKind::Directory {
+            for name in storage.list(path).unwrap_or(Vec::new()) {
+                remove_all(storage, &path.join(name))?;
+            }
+        }
+    }
+    storage.remove(path).map_err(|_| ())
+}
@@ -0,0 +1,49 @@
+use std::ffi::OsString;
+use std::path::Path;
+use time;
+use super::{open_volume, usage_error, EXIT_OK, EXIT_FAILURE};
+
+pub const USAGE: &'static [&'static str] = &["history <local_path> <path>"];
+
+/// `markfs history <local_path> <path>`, the versions of a file, oldest first
+pub fn history(args: &[OsString]) -> i32 {
+    if args.len() != 2 {
+        return usage_error(USAGE);
+    }
+
+    let metadata = match open_volume(&args[0]) {
+        Ok(metadata) => metadata,
+        Err(code)    => return code
+    };
+    let inode = match metadata.resolve(Path::new(&args[1])) {
+        Ok(ref inode) if inode.kind.is_regular_file() => inode.clone(),
+        Ok(_)  => {
+            println!("No such file: {}", args[1].to_string_lossy());
+            return EXIT_FAILURE;
+        },
+        Err(e) => {
+            println!("{}: {}", args[1].to_string_lossy(), e);
+            return EXIT_FAILURE;
+        }
+    };
+    let versions = match metadata.get_versions(&inode) {
+        Ok(versions) => versions,
+        Err(e)       => {
+            println!("Unable to read the history: {}", e);
+            return EXIT_FAILURE;
+        }
+    };
+
+    println!("  {:<36} {:<20} {:>12} {}", "VERSION", "CREATED", "SIZE", "HASH");
+    for file_version in versions {
+        let created = match file_version.created {
+            Some(created) => time::strftime("%Y-%m-%d %H:%M:%S", &time::at(created)).unwrap_or(String::new()),
+            None          => "-".to_string()
+        };
+
+        println!("{} {:<36} {:<20} {:>12} {}", if file_version.version == inode.current_version { "*" } else { " " },
+                 file_version.version, created, file_version.size,
+                 if file_version.hash.is_empty() { "-" } else { file_version.hash.as_str() });
+    }
+    EXIT_OK
+}
@@ -0,0 +1,41 @@
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+use metadata::Metadata;
+use super::{load_config, usage_error, EXIT_OK, EXIT_FAILURE};
+
+pub const USAGE: &'static [&'static str] = &["init <local_path>"];
+
+/// `markfs init <local_path>`, create the directory and the metadata of a new volume
+///
+/// The metadata goes to the state directory when the configuration sets one.
+pub fn init(args: &[OsString]) -> i32 {
+    if args.len() != 1 {
+        return usage_error(USAGE);
+    }
+
+    let config = match load_config(Some(&args[0])) {
+        Ok(config) => config,
+        Err(code)  => return code
+    };
+
+    let local_path = Path::new(&args[0]);
+    let state_dir = Path::new(&config.state_dir);
+    if state_dir.join("metadata.sqlite").exists() {
+        println!("{} already holds a volume", local_path.display());
+        return EXIT_FAILURE;
+    }
+    for dir in [local_path, state_dir].iter() {
+        if let Err(e) = fs::create_dir_all(dir) {
+            println!("Unable to create {}: {}", dir.display(), e);
+            return EXIT_FAILURE;
+        }
+    }
+
+    if let Err(e) = Metadata::new(&config.state_dir) {
+        println!("Unable to create the metadata in {}: {}", state_dir.display(), e);
+        return EXIT_FAILURE;
+    }
+    println!("Created a volume at {}", local_path.display());
+    EXIT_OK
+}
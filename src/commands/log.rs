@@ -0,0 +1,57 @@
+use std::ffi::OsString;
+use time;
+use super::{open_volume, usage_error, EXIT_OK, EXIT_FAILURE};
+
+pub const USAGE: &'static [&'static str] = &["log <local_path> [<since_seq> [<count>]]"];
+
+const DEFAULT_COUNT: u32 = 50;
+
+/// `markfs log <local_path> [<since_seq> [<count>]]`
+pub fn log(args: &[OsString]) -> i32 {
+    if args.is_empty() || args.len() > 3 {
+        return usage_error(USAGE);
+    }
+
+    let number = |i: usize| args.get(i).map(|arg| arg.to_string_lossy().parse::<u64>());
+    let since = match number(1) {
+        Some(Ok(since)) => since as i64,
+        Some(Err(_))    => return usage_error(USAGE),
+        None            => 0
+    };
+    let count = match number(2) {
+        Some(Ok(count)) => count as u32,
+        Some(Err(_))    => return usage_error(USAGE),
+        None            => DEFAULT_COUNT
+    };
+
+    let metadata = match open_volume(&args[0]) {
+        Ok(metadata) => metadata,
+        Err(code)    => return code
+    };
+
+    let entries = match metadata.get_action_log(since, count) {
+        Ok(entries) => entries,
+        Err(e)      => {
+            println!("Unable to read the action log: {}", e);
+            return EXIT_FAILURE;
+        }
+    };
+
+    // Actions replayed from peers name the device they came from
+    let device = metadata.device_id().unwrap_or(String::new());
+
+    println!("{:>8} {:<20} {:>6} {:<16} {}", "SEQ", "TIME", "UID", "ACTION", "RESULT");
+    for entry in entries {
+        let result = match (entry.finished, entry.success) {
+            (false, _)    => "unfinished",
+            (true, true)  => "ok",
+            (true, false) => "failed"
+        };
+        let time = time::at(entry.time);
+
+        println!("{:>8} {:<20} {:>6} {:<16} {}{}{}", entry.seq, time::strftime("%Y-%m-%d %H:%M:%S", &time).unwrap_or(String::new()),
+                 entry.uid, entry.name, result, if entry.local_only { ", local only" } else { "" },
+                 if !entry.device.is_empty() && entry.device != device { format!(", from {}", entry.device) } else { String::new() });
+    }
+    EXIT_OK
+}
@@ -0,0 +1,60 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use rustc_serialize::json::{Json, ToJson};
+use control::Response;
+use metrics::OpStats;
+use super::{request, usage_error, EXIT_OK};
+
+pub const USAGE: &'static [&'static str] = &["metrics <local_path> [--prometheus]"];
+
+/// `markfs metrics <local_path> [--prometheus]`, operation counts and latencies of the mount
+pub fn metrics(args: &[OsString]) -> i32 {
+    let prometheus = match args.len() {
+        1                              => false,
+        2 if args[1] == "--prometheus" => true,
+        _                              => return usage_error(USAGE)
+    };
+
+    let mut arguments = Response::new();
+    arguments.insert("format".to_string(), (if prometheus { "prometheus" } else { "json" }).to_json());
+    let response = match request(&args[0], "metrics", arguments) {
+        Ok(response) => response,
+        Err(code)    => return code
+    };
+
+    if prometheus {
+        print!("{}", response.get("text").and_then(|text| text.as_string()).unwrap_or(""));
+        return EXIT_OK;
+    }
+
+    println!("{:<24} {:>10} {:>8} {:>10} {:>10}", "OPERATION", "COUNT", "ERRORS", "AVG MS", "P99 MS");
+    print_stats("", &parse_stats(response.get("operations")));
+    print_stats("action ", &parse_stats(response.get("actions")));
+    EXIT_OK
+}
+
+/// Stats by name, as reported by the control socket
+pub fn parse_stats(json: Option<&Json>) -> BTreeMap<String, OpStats> {
+    match json {
+        Some(&Json::Object(ref stats)) => stats.iter()
+            .filter_map(|(name, stats)| OpStats::from_json(stats).map(|stats| (name.clone(), stats)))
+            .collect(),
+        _ => BTreeMap::new()
+    }
+}
+
+fn print_stats(prefix: &str, stats: &BTreeMap<String, OpStats>) {
+    for (name, op) in stats.iter() {
+        println!("{:<24} {:>10} {:>8} {:>10.3} {:>10}", format!("{}{}", prefix, name), op.count, op.errors,
+                 op.average_seconds() * 1000.0, milliseconds(op.quantile_seconds(0.99)));
+    }
+}
+
+/// Milliseconds of a bucket bound, `>5000` beyond the last one
+pub fn milliseconds(seconds: f64) -> String {
+    if seconds.is_infinite() {
+        ">5000".to_string()
+    } else {
+        format!("{}", seconds * 1000.0)
+    }
+}
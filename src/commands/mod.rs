@@ -0,0 +1,237 @@
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+use metadata::Metadata;
+use hydrate::Hydrator;
+use s3::S3Remote;
+use config::VolumeConfig;
+use control::{ControlClient, Response};
+
+mod init;
+mod mount;
+mod unmount;
+mod status;
+mod log;
+mod history;
+mod restore;
+mod fsck;
+mod gc;
+mod peers;
+mod snapshot;
+mod daemon;
+mod quota;
+mod pin;
+mod sync;
+mod metrics;
+mod top;
+mod audit;
+
+pub const EXIT_OK: i32 = 0;
+/// The command ran, but failed or found problems
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+/// The local path holds no volume
+pub const EXIT_NO_VOLUME: i32 = 3;
+/// The configuration file is invalid
+pub const EXIT_CONFIG: i32 = 4;
+/// The command needs the volume to be mounted
+pub const EXIT_NOT_MOUNTED: i32 = 5;
+/// The command can't run while the volume is mounted
+pub const EXIT_MOUNTED: i32 = 6;
+
+struct Command {
+    name: &'static str,
+    summary: &'static str,
+    usage: &'static [&'static str],
+    run: fn(&[OsString]) -> i32
+}
+
+const COMMANDS: &'static [Command] = &[
+    Command { name: "init",     summary: "Create a new volume",                         usage: init::USAGE,            run: init::init },
+    Command { name: "mount",    summary: "Mount a volume",                              usage: mount::USAGE,           run: mount::mount },
+    Command { name: "unmount",  summary: "Unmount a mounted volume",                    usage: unmount::USAGE,         run: unmount::unmount },
+    Command { name: "status",   summary: "Show what is stored and synced",              usage: status::USAGE,          run: status::status },
+    Command { name: "metrics",  summary: "Show operation counts and latencies",         usage: metrics::USAGE,         run: metrics::metrics },
+    Command { name: "top",      summary: "Watch the activity of a mounted volume",      usage: top::USAGE,             run: top::top },
+    Command { name: "log",      summary: "Show the action log",                         usage: log::USAGE,             run: log::log },
+    Command { name: "audit",    summary: "Show who changed what and when",              usage: audit::USAGE,           run: audit::audit },
+    Command { name: "history",  summary: "List the versions of a file",                 usage: history::USAGE,         run: history::history },
+    Command { name: "restore",  summary: "Make an earlier version of a file current",   usage: restore::USAGE,         run: restore::restore },
+    Command { name: "fsck",     summary: "Check the metadata against the stored files", usage: fsck::USAGE,            run: fsck::fsck },
+    Command { name: "gc",       summary: "Remove orphaned files and cached downloads",  usage: gc::USAGE,              run: gc::gc },
+    Command { name: "peers",    summary: "List the configured peers and remotes",       usage: peers::USAGE,           run: peers::peers },
+    Command { name: "progress", summary: "Show or cancel running downloads",            usage: daemon::PROGRESS_USAGE, run: daemon::progress },
+    Command { name: "pause",    summary: "Pause uploads of a mounted volume",           usage: daemon::PAUSE_USAGE,    run: daemon::pause },
+    Command { name: "resume",   summary: "Resume uploads of a mounted volume",          usage: daemon::RESUME_USAGE,   run: daemon::resume },
+    Command { name: "daemon",   summary: "Serve several volumes from one process",      usage: daemon::DAEMON_USAGE,   run: daemon::daemon },
+    Command { name: "volumes",  summary: "List the volumes served by the daemon",       usage: daemon::VOLUMES_USAGE,  run: daemon::volumes },
+    Command { name: "start",    summary: "Mount a volume in the daemon",                usage: daemon::START_USAGE,    run: daemon::start },
+    Command { name: "stop",     summary: "Unmount a volume served by the daemon",       usage: daemon::STOP_USAGE,     run: daemon::stop },
+    Command { name: "snapshot", summary: "Upload a copy of the metadata",               usage: snapshot::USAGE,        run: snapshot::snapshot },
+    Command { name: "quota",    summary: "Report and set quotas",                       usage: quota::USAGE,           run: quota::quota },
+    Command { name: "pin",      summary: "Keep a path on this device",                  usage: pin::PIN_USAGE,         run: pin::pin },
+    Command { name: "unpin",    summary: "Let a pinned path be evicted again",          usage: pin::UNPIN_USAGE,       run: pin::unpin },
+    Command { name: "sync",     summary: "Choose which paths sync to this device",      usage: sync::USAGE,            run: sync::sync }
+];
+
+/// Run the command named by the first argument, returns the exit code
+pub fn run(args: &[OsString]) -> i32 {
+    let name = match args.get(0).and_then(|name| name.to_str()) {
+        Some("--help") | Some("-h") | Some("help") => {
+            help();
+            return EXIT_OK;
+        },
+        Some(name) => name,
+        None       => {
+            help();
+            return EXIT_USAGE;
+        }
+    };
+
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => {
+            if args[1..].iter().any(|arg| arg == "--help" || arg == "-h") {
+                print_usage(command.usage);
+                EXIT_OK
+            } else {
+                (command.run)(&args[1..])
+            }
+        },
+        None => {
+            println!("Unknown command: {}", name);
+            help();
+            EXIT_USAGE
+        }
+    }
+}
+
+fn help() {
+    println!("Usage: markfs <command> [<args>]");
+    println!("");
+    for command in COMMANDS.iter() {
+        println!("    {:<10} {}", command.name, command.summary);
+    }
+    println!("");
+    println!("Run `markfs <command> --help` for the arguments of a command.");
+    println!("Exit codes: 0 success, 1 failure, 2 usage error, 3 no volume at the local path, 4 invalid configuration,");
+    println!("            5 the volume isn't mounted or the daemon isn't running");
+}
+
+fn print_usage(usage: &[&str]) {
+    for (i, line) in usage.iter().enumerate() {
+        println!("{} markfs {}", if i == 0 { "Usage:" } else { "      " }, line);
+    }
+}
+
+/// Print the usage of a command after wrong arguments
+fn usage_error(usage: &[&str]) -> i32 {
+    print_usage(usage);
+    EXIT_USAGE
+}
+
+/// Read the configuration, only the global settings without a local path
+fn load_config(local_path: Option<&OsString>) -> Result<VolumeConfig, i32> {
+    VolumeConfig::load(local_path).map_err(|e| {
+        println!("Invalid configuration: {}", e);
+        EXIT_CONFIG
+    })
+}
+
+/// Read the configuration of every volume in the configuration file
+fn load_all_configs() -> Result<Vec<(OsString, VolumeConfig)>, i32> {
+    VolumeConfig::load_all().map_err(|e| {
+        println!("Invalid configuration: {}", e);
+        EXIT_CONFIG
+    })
+}
+
+/// Open the metadata of an existing volume
+fn open_volume(local_path: &OsString) -> Result<Metadata, i32> {
+    let config = load_config(Some(local_path))?;
+    check_volume(local_path, &config)?;
+    Metadata::new(&config.state_dir).map_err(|e| {
+        println!("Unable to open the metadata of {}: {}", local_path.to_string_lossy(), e);
+        EXIT_FAILURE
+    })
+}
+
+/// Fail unless the local path holds a volume
+fn check_volume(local_path: &OsString, config: &VolumeConfig) -> Result<(), i32> {
+    if !Path::new(&config.state_dir).join("metadata.sqlite").is_file() {
+        println!("No volume at {}, create one with `markfs init`", local_path.to_string_lossy());
+        return Err(EXIT_NO_VOLUME);
+    }
+    Ok(())
+}
+
+/// Canonical form of a path given on the command line, for a process that
+/// doesn't share our working directory
+fn absolute_path(path: &OsString) -> Result<OsString, i32> {
+    match fs::canonicalize(path) {
+        Ok(path) => Ok(path.into_os_string()),
+        Err(e)   => {
+            println!("Unable to open {}: {}", path.to_string_lossy(), e);
+            Err(EXIT_NO_VOLUME)
+        }
+    }
+}
+
+/// Hydrator fetching from the peers configured for the volume
+///
+/// The configuration was validated when the volume was opened.
+fn remote_hydrator(local_path: &OsString) -> Hydrator {
+    let peers = VolumeConfig::load(Some(local_path)).map(|config| config.peers).unwrap_or(Vec::new());
+
+    let hydrator = Hydrator::new();
+    for peer in peers.iter() {
+        match S3Remote::new(peer.clone()) {
+            Ok(remote) => hydrator.add_peer(Box::new(remote)),
+            Err(e)     => println!("Unable to connect to the S3 bucket {}: {:?}", peer.bucket, e)
+        }
+    }
+    hydrator
+}
+
+/// Connection to the mount of the volume, None when it isn't mounted
+fn connect(local_path: &OsString) -> Option<ControlClient> {
+    VolumeConfig::load(Some(local_path)).ok().and_then(|config| ControlClient::connect(&config.state_dir))
+}
+
+/// Send a command to the mount of the volume, printing why it failed
+fn request(local_path: &OsString, command: &str, arguments: Response) -> Result<Response, i32> {
+    let mut client = match connect(local_path) {
+        Some(client) => client,
+        None         => {
+            println!("{} is not mounted", local_path.to_string_lossy());
+            return Err(EXIT_NOT_MOUNTED);
+        }
+    };
+
+    client.request(command, arguments).map_err(|error| {
+        println!("{}", error);
+        EXIT_FAILURE
+    })
+}
+
+/// Send a command to the daemon, printing why it failed
+fn request_daemon(command: &str, arguments: Response) -> Result<Response, i32> {
+    let path = match ::daemon::socket_path() {
+        Ok(path) => path,
+        Err(e)   => {
+            println!("{}", e);
+            return Err(EXIT_FAILURE);
+        }
+    };
+    let mut client = match ControlClient::connect_to(&path) {
+        Some(client) => client,
+        None         => {
+            println!("The daemon isn't running, start it with `markfs daemon`");
+            return Err(EXIT_NOT_MOUNTED);
+        }
+    };
+
+    client.request(command, arguments).map_err(|error| {
+        println!("{}", error);
+        EXIT_FAILURE
+    })
+}
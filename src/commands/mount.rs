@@ -0,0 +1,143 @@
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use markfs::MarkFS;
+use metadata::Metadata;
+use memory::MemoryStorage;
+use daemon::{daemonize, write_pidfile, block_signals, spawn_signal_handler, Fork, PID_FILE};
+use mounted_volume::{self, MountedVolume, unmount_path};
+use super::{load_config, check_volume, absolute_path, usage_error, EXIT_OK, EXIT_FAILURE};
+
+pub const USAGE: &'static [&'static str] = &[
+    "mount <local_path> <mountpoint> [-o <option>[,<option>...]] [--read-only] [--background] [--pidfile <path>]",
+    "mount --memory <mountpoint> [-o <option>[,<option>...]] [--read-only] [--background] [--pidfile <path>]"
+];
+
+/// Capacity of an in-memory volume
+const MEMORY_CAPACITY: u64 = 1 << 30;
+
+/// `markfs mount <local_path> <mountpoint>`, runs until the volume is unmounted
+///
+/// SIGTERM, SIGINT and SIGHUP unmount the volume, after which the pidfile
+/// and the control socket are removed.
+pub fn mount(args: &[OsString]) -> i32 {
+    let mut positional = Vec::new();
+    let mut background = false;
+    let mut pidfile = None;
+    let mut read_only = false;
+    let mut mount_options = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--background" {
+            background = true;
+        } else if arg == "--read-only" {
+            read_only = true;
+        } else if arg == "-o" {
+            match args.next().and_then(|options| options.to_str()) {
+                Some(options) => mount_options.extend(options.split(',').filter(|option| !option.is_empty()).map(|option| option.to_string())),
+                None          => return usage_error(USAGE)
+            }
+        } else if arg == "--pidfile" {
+            match args.next() {
+                Some(path) => pidfile = Some(PathBuf::from(path)),
+                None       => return usage_error(USAGE)
+            }
+        } else {
+            positional.push(arg.clone());
+        }
+    }
+    if positional.len() != 2 {
+        return usage_error(USAGE);
+    }
+
+    let memory = positional[0] == "--memory";
+    // The background process runs from the root directory
+    let local_path = if memory { Ok(positional[0].clone()) } else { absolute_path(&positional[0]) };
+    let (local_path, mountpoint) = match (local_path, absolute_path(&positional[1])) {
+        (Ok(local_path), Ok(mountpoint)) => (local_path, mountpoint),
+        (Err(code), _) | (_, Err(code))  => return code
+    };
+    let (local_path, mountpoint) = (&local_path, &mountpoint);
+
+    let mut config = match load_config(if memory { None } else { Some(local_path) }) {
+        Ok(config) => config,
+        Err(code)  => return code
+    };
+
+    config.add_mount_options(mount_options, read_only);
+    if !memory {
+        if let Err(code) = check_volume(local_path, &config) {
+            return code;
+        }
+        pidfile = pidfile.or(Some(Path::new(&config.state_dir).join(PID_FILE)));
+    }
+
+    // Before any thread or connection exists, only this process continues in the child
+    let mut ready = None;
+    if background {
+        if let (Some(path), Ok(current_dir)) = (pidfile.clone(), env::current_dir()) {
+            pidfile = Some(current_dir.join(path));
+        }
+        match daemonize() {
+            Ok(Fork::Child(child)) => ready = Some(child),
+            Ok(Fork::Parent(code)) => return code.unwrap_or(EXIT_FAILURE),
+            Err(e)                 => {
+                println!("Unable to run in the background: {}", e);
+                return EXIT_FAILURE;
+            }
+        }
+    }
+
+    if let Some(ref pidfile) = pidfile {
+        if let Err(message) = write_pidfile(pidfile) {
+            println!("{}", message);
+            return EXIT_FAILURE;
+        }
+    }
+
+    // Every thread spawned from here on leaves the signals to the handler
+    let signal_mountpoint = mountpoint.clone();
+    spawn_signal_handler(block_signals(), move || {
+        match unmount_path(&signal_mountpoint) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("{}", e);
+                false
+            }
+        }
+    });
+
+    let result = if memory {
+        Metadata::in_memory()
+            .map_err(|e| format!("Unable to create the metadata: {}", e))
+            .and_then(|metadata| {
+                let mut markfs = MarkFS::new(metadata, MemoryStorage::new(MEMORY_CAPACITY), mountpoint).map_err(|e| format!("Unable to read the sync rules: {}", e))?;
+                markfs.configure(&config);
+                mounted_volume::run(markfs, mountpoint, &config.mount_options, || {
+                    if let Some(ready) = ready.take() {
+                        ready.notify(EXIT_OK);
+                    }
+                })
+            })
+    } else {
+        MountedVolume::mount(local_path, mountpoint, config).and_then(|volume| {
+            if let Some(ready) = ready.take() {
+                ready.notify(EXIT_OK);
+            }
+            volume.wait()
+        })
+    };
+
+    if let Some(ref pidfile) = pidfile {
+        let _ = fs::remove_file(pidfile);
+    }
+    match result {
+        Ok(()) => EXIT_OK,
+        Err(e) => {
+            println!("{}", e);
+            EXIT_FAILURE
+        }
+    }
+}
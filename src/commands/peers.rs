@@ -0,0 +1,83 @@
+use std::ffi::OsString;
+use rustc_serialize::json::{Json, ToJson};
+use s3::S3Remote;
+use control::Response;
+use super::{load_config, connect, request, usage_error, EXIT_OK, EXIT_FAILURE, EXIT_USAGE};
+
+pub const USAGE: &'static [&'static str] = &[
+    "peers <local_path>",
+    "peers <local_path> add <peer_json>",
+    "peers <local_path> remove <bucket>"
+];
+
+/// `markfs peers <local_path>`, fails when a configured peer can't be reached
+///
+/// A mounted volume can take extra peers until it's unmounted, given as in
+/// the configuration file, like `{"type": "s3", "bucket": "photos"}`.
+pub fn peers(args: &[OsString]) -> i32 {
+    match (args.len(), args.get(1).and_then(|command| command.to_str())) {
+        (1, _)              => list(&args[0]),
+        (3, Some("add"))    => {
+            let peer = match Json::from_str(&args[2].to_string_lossy()) {
+                Ok(peer) => peer,
+                Err(e)   => {
+                    println!("Invalid peer: {}", e);
+                    return EXIT_USAGE;
+                }
+            };
+            let mut arguments = Response::new();
+            arguments.insert("peer".to_string(), peer);
+            match request(&args[0], "add_peer", arguments) {
+                Ok(_)     => EXIT_OK,
+                Err(code) => code
+            }
+        },
+        (3, Some("remove")) => {
+            let mut arguments = Response::new();
+            arguments.insert("name".to_string(), args[2].to_string_lossy().into_owned().to_json());
+            match request(&args[0], "remove_peer", arguments) {
+                Ok(_)     => EXIT_OK,
+                Err(code) => code
+            }
+        },
+        _ => usage_error(USAGE)
+    }
+}
+
+fn list(local_path: &OsString) -> i32 {
+    let config = match load_config(Some(local_path)) {
+        Ok(config) => config,
+        Err(code)  => return code
+    };
+    if config.peers.is_empty() {
+        println!("No peers configured");
+    }
+
+    let mut code = EXIT_OK;
+    for peer in config.peers.iter() {
+        let endpoint = peer.endpoint.clone().unwrap_or(format!("s3 {}", peer.region));
+        let name = format!("s3://{}/{} at {}", peer.bucket, peer.prefix, endpoint);
+        match S3Remote::new(peer.clone()) {
+            Ok(remote) => {
+                let reachable = remote.is_reachable();
+                println!("{:<60} {}", name, if reachable { "reachable" } else { "unreachable" });
+                if !reachable {
+                    code = EXIT_FAILURE;
+                }
+            },
+            Err(e) => {
+                println!("{:<60} {:?}", name, e);
+                code = EXIT_FAILURE;
+            }
+        }
+    }
+
+    // Including peers added while mounted
+    if let Some(Ok(response)) = connect(local_path).map(|mut client| client.request("peers", Response::new())) {
+        if let Some(&Json::Array(ref names)) = response.get("peers") {
+            let names: Vec<&str> = names.iter().filter_map(|name| name.as_string()).collect();
+            println!("Mounted, fetching from: {}", names.join(", "));
+        }
+    }
+    code
+}
@@ -0,0 +1,79 @@
+use std::ffi::OsString;
+use std::path::Path;
+use local::LocalFileOperations;
+use rustc_serialize::json::ToJson;
+use control::Response;
+use super::{open_volume, connect, usage_error, remote_hydrator, EXIT_OK, EXIT_FAILURE};
+
+pub const PIN_USAGE: &'static [&'static str] = &["pin <local_path> <path>"];
+pub const UNPIN_USAGE: &'static [&'static str] = &["unpin <local_path> <path>"];
+
+/// `markfs pin <local_path> <path>`, keep the path on this device and download it now
+pub fn pin(args: &[OsString]) -> i32 {
+    if args.len() != 2 {
+        return usage_error(PIN_USAGE);
+    }
+    set_pinned(args, true)
+}
+
+/// `markfs unpin <local_path> <path>`, let the cache evict the path again
+pub fn unpin(args: &[OsString]) -> i32 {
+    if args.len() != 2 {
+        return usage_error(UNPIN_USAGE);
+    }
+    set_pinned(args, false)
+}
+
+fn set_pinned(args: &[OsString], pinned: bool) -> i32 {
+    // The mount downloads with its own peers and reports the progress
+    if let Some(mut client) = connect(&args[0]) {
+        let mut arguments = Response::new();
+        arguments.insert("path".to_string(), args[1].to_string_lossy().into_owned().to_json());
+        return match client.request(if pinned { "pin" } else { "unpin" }, arguments) {
+            Ok(response) => report_failed(response.get("failed").and_then(|failed| failed.as_u64()).unwrap_or(0)),
+            Err(error)   => {
+                println!("{}", error);
+                EXIT_FAILURE
+            }
+        };
+    }
+
+    let metadata = match open_volume(&args[0]) {
+        Ok(metadata) => metadata,
+        Err(code)    => return code
+    };
+    let inode = match metadata.resolve(Path::new(&args[1])) {
+        Ok(inode) => inode,
+        Err(e)    => {
+            println!("{}: {}", args[1].to_string_lossy(), e);
+            return EXIT_FAILURE;
+        }
+    };
+
+    let inode = match metadata.set_pinned(&inode, pinned) {
+        Ok(inode) => inode,
+        Err(_)    => {
+            println!("Failed to {} {}", if pinned { "pin" } else { "unpin" }, args[1].to_string_lossy());
+            return EXIT_FAILURE;
+        }
+    };
+    if !pinned {
+        return EXIT_OK;
+    }
+
+    match remote_hydrator(&args[0]).hydrate_subtree(&LocalFileOperations::new(&args[0]), &metadata, &inode) {
+        Ok(failed) => report_failed(failed as u64),
+        Err(e)     => {
+            println!("Unable to download {}: {}", args[1].to_string_lossy(), e);
+            EXIT_FAILURE
+        }
+    }
+}
+
+fn report_failed(failed: u64) -> i32 {
+    if failed > 0 {
+        println!("Pinned, but {} files could not be downloaded yet", failed);
+        return EXIT_FAILURE;
+    }
+    EXIT_OK
+}
@@ -0,0 +1,140 @@
+use std::ffi::OsString;
+use std::path::Path;
+use metadata::{Metadata, QuotaKind};
+use error::{Error, optional};
+use super::{open_volume, usage_error, EXIT_OK, EXIT_FAILURE};
+
+pub const USAGE: &'static [&'static str] = &[
+    "quota <local_path>",
+    "quota <local_path> set <path|uid:N> <bytes|-> [<inodes|->]",
+    "quota <local_path> remove <path|uid:N>"
+];
+
+/// `markfs quota <local_path> [set <path|uid:N> <bytes|-> [<inodes|->] | remove <path|uid:N>]`
+pub fn quota(args: &[OsString]) -> i32 {
+    if args.is_empty() {
+        return usage_error(USAGE);
+    }
+
+    let metadata = match open_volume(&args[0]) {
+        Ok(metadata) => metadata,
+        Err(code)    => return code
+    };
+    let args: Vec<String> = args[1..].iter().map(|arg| arg.to_string_lossy().into_owned()).collect();
+
+    match args.get(0).map(|command| command.as_str()) {
+        None => report(&metadata),
+        Some("set") if args.len() == 3 || args.len() == 4 => {
+            let (kind, target) = match parse_target(&metadata, &args[1]) {
+                Ok(Some(target)) => target,
+                Ok(None)         => return not_found(&args[1]),
+                Err(e)           => return failed(&args[1], e)
+            };
+            let max_bytes = match parse_limit(&args[2], true) {
+                Ok(max_bytes) => max_bytes,
+                Err(_)        => return usage_error(USAGE)
+            };
+            let max_inodes = match args.get(3).map(|limit| parse_limit(limit, false)) {
+                Some(Ok(max_inodes)) => max_inodes,
+                Some(Err(_))         => return usage_error(USAGE),
+                None                 => None
+            };
+
+            match metadata.set_quota(kind, &target, max_bytes, max_inodes) {
+                Ok(_)  => EXIT_OK,
+                Err(_) => {
+                    println!("Failed to set quota for {}", args[1]);
+                    EXIT_FAILURE
+                }
+            }
+        },
+        Some("remove") if args.len() == 2 => {
+            let (kind, target) = match parse_target(&metadata, &args[1]) {
+                Ok(Some(target)) => target,
+                Ok(None)         => return not_found(&args[1]),
+                Err(e)           => return failed(&args[1], e)
+            };
+
+            match metadata.remove_quota(kind, &target) {
+                Ok(true)  => EXIT_OK,
+                Ok(false) => not_found(&args[1]),
+                Err(_)    => {
+                    println!("Failed to remove quota for {}", args[1]);
+                    EXIT_FAILURE
+                }
+            }
+        },
+        _ => usage_error(USAGE)
+    }
+}
+
+fn report(metadata: &Metadata) -> i32 {
+    let quotas = match metadata.get_quotas() {
+        Ok(quotas) => quotas,
+        Err(e)     => {
+            println!("Unable to read the quotas: {}", e);
+            return EXIT_FAILURE;
+        }
+    };
+    println!("{:<40} {:>12} {:>12} {:>10} {:>10}", "QUOTA", "BYTES", "LIMIT", "INODES", "LIMIT");
+
+    for quota in quotas {
+        let name = match quota.kind {
+            QuotaKind::Subtree => match metadata.get_by_id(&quota.target).and_then(|inode| metadata.get_volume_path(&inode)) {
+                Ok(path) => path.to_string_lossy().into_owned(),
+                Err(_)   => format!("<removed {}>", quota.target)
+            },
+            QuotaKind::User => format!("uid:{}", quota.target)
+        };
+        let limit = |max: Option<u64>| match max {
+            Some(max) => max.to_string(),
+            None      => "-".to_string()
+        };
+
+        println!("{:<40} {:>12} {:>12} {:>10} {:>10}",
+                 name, quota.used_bytes, limit(quota.max_bytes), quota.used_inodes, limit(quota.max_inodes));
+    }
+    EXIT_OK
+}
+
+/// A `uid:N` target, or a path in the volume
+fn parse_target(metadata: &Metadata, target: &String) -> Result<Option<(QuotaKind, String)>, Error> {
+    if target.starts_with("uid:") {
+        return Ok(target[4..].parse::<u32>().ok().map(|uid| (QuotaKind::User, uid.to_string())));
+    }
+
+    match optional(metadata.resolve(Path::new(target)))? {
+        Some(ref inode) if inode.kind.is_directory() => Ok(Some((QuotaKind::Subtree, inode.id.clone()))),
+        _                                            => Ok(None)
+    }
+}
+
+/// A number with an optional K, M, G or T suffix for bytes, `-` for no limit
+fn parse_limit(limit: &String, bytes: bool) -> Result<Option<u64>, ()> {
+    if limit == "-" {
+        return Ok(None);
+    }
+
+    let (number, multiplier) = match limit.chars().last() {
+        Some('K') if bytes => (&limit[..limit.len() - 1], 1u64 << 10),
+        Some('M') if bytes => (&limit[..limit.len() - 1], 1u64 << 20),
+        Some('G') if bytes => (&limit[..limit.len() - 1], 1u64 << 30),
+        Some('T') if bytes => (&limit[..limit.len() - 1], 1u64 << 40),
+        _                  => (&limit[..], 1)
+    };
+
+    match number.parse::<u64>() {
+        Ok(number) => Ok(Some(number * multiplier)),
+        Err(_)     => Err(())
+    }
+}
+
+fn not_found(target: &String) -> i32 {
+    println!("No such directory or quota: {}", target);
+    EXIT_FAILURE
+}
+
+fn failed(target: &String, e: Error) -> i32 {
+    println!("{}: {}", target, e);
+    EXIT_FAILURE
+}
@@ -0,0 +1,67 @@
+use std::ffi::OsString;
+use std::path::Path;
+use local::LocalFileOperations;
+use rustc_serialize::json::ToJson;
+use control::{Response, restore_error};
+use volume::Volume;
+use super::{open_volume, load_config, connect, usage_error, remote_hydrator, EXIT_OK, EXIT_FAILURE};
+
+pub const USAGE: &'static [&'static str] = &["restore <local_path> <path> <version>"];
+
+/// `markfs restore <local_path> <path> <version>`
+///
+/// The restored content is fetched from the remotes holding its hash, when
+/// none of them does the file is left unchanged.
+pub fn restore(args: &[OsString]) -> i32 {
+    if args.len() != 3 {
+        return usage_error(USAGE);
+    }
+
+    // The mount downloads with its own peers and reports the progress
+    if let Some(mut client) = connect(&args[0]) {
+        let mut arguments = Response::new();
+        arguments.insert("path".to_string(), args[1].to_string_lossy().into_owned().to_json());
+        arguments.insert("version".to_string(), args[2].to_string_lossy().into_owned().to_json());
+        return match client.request("restore", arguments) {
+            Ok(_)      => EXIT_OK,
+            Err(error) => {
+                println!("{}", error);
+                EXIT_FAILURE
+            }
+        };
+    }
+
+    let metadata = match open_volume(&args[0]) {
+        Ok(metadata) => metadata,
+        Err(code)    => return code
+    };
+    match metadata.resolve(Path::new(&args[1])) {
+        Ok(ref inode) if inode.kind.is_regular_file() => (),
+        Ok(_)  => {
+            println!("No such file: {}", args[1].to_string_lossy());
+            return EXIT_FAILURE;
+        },
+        Err(e) => {
+            println!("{}: {}", args[1].to_string_lossy(), e);
+            return EXIT_FAILURE;
+        }
+    }
+
+    let config = match load_config(Some(&args[0])) {
+        Ok(config) => config,
+        Err(code)  => return code
+    };
+    let mut volume = Volume::new(metadata, LocalFileOperations::new(&args[0]));
+    volume.configure(&config);
+    volume.set_hydrator(remote_hydrator(&args[0]));
+
+    let path = args[1].to_string_lossy();
+    let version = args[2].to_string_lossy().into_owned();
+    match volume.restore(Path::new(&args[1]), &version) {
+        Ok(_)  => EXIT_OK,
+        Err(e) => {
+            println!("{}", restore_error(&path, &version, e));
+            EXIT_FAILURE
+        }
+    }
+}
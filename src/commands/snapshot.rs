@@ -0,0 +1,43 @@
+use std::ffi::OsString;
+use peer::Remote;
+use s3::S3Remote;
+use super::{load_config, open_volume, usage_error, EXIT_OK, EXIT_FAILURE};
+
+pub const USAGE: &'static [&'static str] = &["snapshot <local_path>"];
+
+/// `markfs snapshot <local_path>`, upload a copy of the metadata to the first peer
+///
+/// Ignored entries are part of the copy, see `Metadata::snapshot`.
+pub fn snapshot(args: &[OsString]) -> i32 {
+    if args.len() != 1 {
+        return usage_error(USAGE);
+    }
+
+    let metadata = match open_volume(&args[0]) {
+        Ok(metadata) => metadata,
+        Err(code)    => return code
+    };
+    let config = match load_config(Some(&args[0])) {
+        Ok(config) => config,
+        Err(code)  => return code
+    };
+    let remote = match config.peers.get(0).map(|peer| S3Remote::new(peer.clone())) {
+        Some(Ok(remote)) => remote,
+        Some(Err(e))     => {
+            println!("Unable to connect to the S3 bucket: {:?}", e);
+            return EXIT_FAILURE;
+        },
+        None => {
+            println!("No peer configured to store the snapshot");
+            return EXIT_FAILURE;
+        }
+    };
+
+    match remote.put_snapshot(&metadata) {
+        Ok(_)  => EXIT_OK,
+        Err(e) => {
+            println!("Snapshot failed: {:?}", e);
+            EXIT_FAILURE
+        }
+    }
+}
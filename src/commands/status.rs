@@ -0,0 +1,45 @@
+use std::ffi::OsString;
+use control::Response;
+use super::{open_volume, connect, usage_error, EXIT_OK, EXIT_FAILURE};
+
+pub const USAGE: &'static [&'static str] = &["status <local_path>"];
+
+/// `markfs status <local_path>`
+pub fn status(args: &[OsString]) -> i32 {
+    if args.len() != 1 {
+        return usage_error(USAGE);
+    }
+
+    let metadata = match open_volume(&args[0]) {
+        Ok(metadata) => metadata,
+        Err(code)    => return code
+    };
+    let status = match metadata.get_status() {
+        Ok(status) => status,
+        Err(e)     => {
+            println!("Unable to read the status: {}", e);
+            return EXIT_FAILURE;
+        }
+    };
+
+    println!("{:<20} {}", "Inodes", status.inodes);
+    println!("{:<20} {}", "Files", status.files);
+    println!("{:<20} {}", "Placeholders", status.placeholders);
+    println!("{:<20} {}", "Pinned", status.pinned);
+    println!("{:<20} {}", "Stored bytes", status.hydrated_bytes);
+    println!("{:<20} {}", "Not uploaded", status.unsynced);
+    println!("{:<20} {}", "Corrupt", status.corrupt);
+    println!("{:<20} {}", "Unfinished actions", status.unfinished_actions);
+
+    // What the mount is doing right now
+    match connect(&args[0]).map(|mut client| client.request("status", Response::new())) {
+        Some(Ok(response)) => {
+            let paused = response.get("paused").and_then(|paused| paused.as_boolean()).unwrap_or(false);
+            println!("{:<20} {}", "Uploads", if paused { "paused" } else { "running" });
+            println!("{:<20} {}", "Downloading", response.get("hydrating").and_then(|hydrating| hydrating.as_u64()).unwrap_or(0));
+        },
+        Some(Err(error)) => println!("{:<20} {}", "Mount", error),
+        None             => println!("{:<20} {}", "Mount", "not mounted")
+    }
+    EXIT_OK
+}
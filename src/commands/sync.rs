@@ -0,0 +1,151 @@
+use std::ffi::OsString;
+use std::path::Path;
+use config::VolumeConfig;
+use control::Response;
+use metadata::Metadata;
+use local::LocalFileOperations;
+use sync_rules::{SyncRules, SyncConfig, ExcludedMode, EXCLUDED_MODE_SETTING};
+use super::{open_volume, connect, usage_error, remote_hydrator, EXIT_OK, EXIT_FAILURE};
+
+pub const USAGE: &'static [&'static str] = &[
+    "sync <local_path>",
+    "sync <local_path> include|exclude|remove <path>",
+    "sync <local_path> mode hide|placeholder",
+    "sync <local_path> pull"
+];
+
+/// `markfs sync <local_path> [include <path> | exclude <path> | remove <path> | mode <hide|placeholder> | pull]`
+pub fn sync(args: &[OsString]) -> i32 {
+    if args.is_empty() {
+        return usage_error(USAGE);
+    }
+
+    let local_path = args[0].clone();
+    let metadata = match open_volume(&local_path) {
+        Ok(metadata) => metadata,
+        Err(code)    => return code
+    };
+    let args: Vec<String> = args[1..].iter().map(|arg| arg.to_string_lossy().into_owned()).collect();
+
+    match args.get(0).map(|command| command.as_str()) {
+        None => report(&metadata, &local_path),
+        Some(command @ "include") | Some(command @ "exclude") if args.len() == 2 => {
+            match metadata.set_sync_rule(&volume_path(&args[1]), command == "include") {
+                Ok(_)  => reload(&local_path),
+                Err(_) => {
+                    println!("Failed to {} {}", command, args[1]);
+                    EXIT_FAILURE
+                }
+            }
+        },
+        Some("remove") if args.len() == 2 => {
+            match metadata.remove_sync_rule(&volume_path(&args[1])) {
+                Ok(true)  => reload(&local_path),
+                Ok(false) => {
+                    println!("No sync rule for {}", args[1]);
+                    EXIT_FAILURE
+                },
+                Err(_) => {
+                    println!("Failed to remove the sync rule for {}", args[1]);
+                    EXIT_FAILURE
+                }
+            }
+        },
+        Some("mode") if args.len() == 2 => {
+            let mode = match ExcludedMode::from_str(&args[1]) {
+                Some(mode) => mode,
+                None       => return usage_error(USAGE)
+            };
+
+            match metadata.set_setting(EXCLUDED_MODE_SETTING, mode.as_str()) {
+                Ok(_)  => reload(&local_path),
+                Err(_) => {
+                    println!("Failed to set the mode");
+                    EXIT_FAILURE
+                }
+            }
+        },
+        Some("pull") if args.len() == 1 => pull(&metadata, &local_path),
+        _ => usage_error(USAGE)
+    }
+}
+
+/// Let a running mount pick up the changed rules
+fn reload(local_path: &OsString) -> i32 {
+    if let Some(mut client) = connect(local_path) {
+        if let Err(error) = client.request("reload_sync", Response::new()) {
+            println!("The rule is saved, but the mount kept the old rules: {}", error);
+            return EXIT_FAILURE;
+        }
+    }
+    EXIT_OK
+}
+
+/// The rules of the volume and the configuration file together, like the mount applies them
+fn load(metadata: &Metadata, local_path: &OsString) -> Result<SyncRules, i32> {
+    let sync_config = match VolumeConfig::load(Some(local_path)) {
+        Ok(config) => config.sync,
+        Err(e)     => {
+            println!("Ignoring the rules of the configuration file: {}", e);
+            SyncConfig::default()
+        }
+    };
+
+    SyncRules::load(metadata, &sync_config).map_err(|e| {
+        println!("Unable to read the sync rules: {}", e);
+        EXIT_FAILURE
+    })
+}
+
+fn report(metadata: &Metadata, local_path: &OsString) -> i32 {
+    let sync_rules = match load(metadata, local_path) {
+        Ok(sync_rules) => sync_rules,
+        Err(code)      => return code
+    };
+    println!("Excluded paths: {}", sync_rules.excluded_mode.as_str());
+
+    for rule in sync_rules.rules() {
+        println!("{:<8} {}", if rule.include { "include" } else { "exclude" }, rule.path.display());
+    }
+    EXIT_OK
+}
+
+/// Download the placeholders the rules include
+fn pull(metadata: &Metadata, local_path: &OsString) -> i32 {
+    let hydrator = remote_hydrator(local_path);
+    let storage = LocalFileOperations::new(local_path);
+
+    let placeholders = match metadata.get_by_ino(1).and_then(|root| metadata.get_placeholders_in(&root)) {
+        Ok(placeholders) => placeholders,
+        Err(e)           => {
+            println!("Unable to find the placeholders, see `markfs fsck`: {}", e);
+            return EXIT_FAILURE;
+        }
+    };
+    let sync_rules = match load(metadata, local_path) {
+        Ok(sync_rules) => sync_rules,
+        Err(code)      => return code
+    };
+    let mut failed = 0;
+    for placeholder in placeholders {
+        match metadata.get_volume_path(&placeholder) {
+            Ok(ref path) if sync_rules.is_synced(path) => (),
+            _                                          => continue
+        }
+        if hydrator.hydrate_placeholder(&storage, metadata, &placeholder).is_err() {
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        println!("{} files could not be downloaded", failed);
+        return EXIT_FAILURE;
+    }
+    EXIT_OK
+}
+
+/// Rules are kept as volume paths, like `/artifacts`
+fn volume_path(path: &String) -> String {
+    let trimmed = path.trim_matches('/');
+    Path::new("/").join(trimmed).to_string_lossy().into_owned()
+}
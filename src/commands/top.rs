@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Write};
+use std::mem;
+use std::thread;
+use std::time::{Duration, Instant};
+use libc;
+use rustc_serialize::json::Json;
+use time;
+use control::Response;
+use metrics::{self, OpStats, Transfers, RecentError};
+use super::{request, usage_error, EXIT_OK};
+use super::metrics::{parse_stats, milliseconds};
+
+pub const USAGE: &'static [&'static str] = &["top <local_path> [--interval <seconds>]"];
+
+const DEFAULT_INTERVAL_SECS: u64 = 2;
+
+/// Rows per table, so everything fits on a regular terminal
+const ROWS: usize = 10;
+
+const CLEAR_SCREEN: &'static str = "\x1b[2J\x1b[H";
+
+/// One refresh of what the mount reported
+struct Sample {
+    taken: Instant,
+    operations: BTreeMap<String, OpStats>,
+    transfers: BTreeMap<String, Transfers>,
+    busiest: Vec<(u64, String)>,
+    recent_errors: Vec<RecentError>,
+    status: Response
+}
+
+/// `markfs top <local_path> [--interval <seconds>]`, live activity of a mounted volume
+///
+/// Quits on `q` or Ctrl-C. Every refresh connects to the control socket
+/// again, so other commands aren't kept waiting in between.
+pub fn top(args: &[OsString]) -> i32 {
+    let interval = match args.len() {
+        1 => DEFAULT_INTERVAL_SECS,
+        3 if args[1] == "--interval" => match args[2].to_str().and_then(|secs| secs.parse::<u64>().ok()) {
+            Some(secs) if secs > 0 => secs,
+            _                      => return usage_error(USAGE)
+        },
+        _ => return usage_error(USAGE)
+    };
+    let interval = Duration::from_secs(interval);
+
+    let terminal = RawTerminal::enable();
+    let mut previous: Option<Sample> = None;
+    loop {
+        let sample = match take_sample(&args[0]) {
+            Ok(sample) => sample,
+            Err(code)  => return code
+        };
+
+        let mut screen = String::new();
+        render(&mut screen, &args[0], interval, &sample, previous.as_ref());
+        print!("{}{}", CLEAR_SCREEN, screen);
+        let _ = io::stdout().flush();
+        previous = Some(sample);
+
+        let quit = match terminal {
+            Some(ref terminal) => terminal.wait_for_quit(interval),
+            None               => {
+                thread::sleep(interval);
+                false
+            }
+        };
+        if quit {
+            return EXIT_OK;
+        }
+    }
+}
+
+fn take_sample(local_path: &OsString) -> Result<Sample, i32> {
+    let metrics = request(local_path, "metrics", Response::new())?;
+    let status = request(local_path, "status", Response::new())?;
+
+    let transfers = match metrics.get("transfers") {
+        Some(&Json::Object(ref transfers)) => transfers.iter()
+            .filter_map(|(peer, transfer)| Transfers::from_json(transfer).map(|transfer| (peer.clone(), transfer)))
+            .collect(),
+        _ => BTreeMap::new()
+    };
+    let busiest = match metrics.get("busiest") {
+        Some(&Json::Array(ref files)) => files.iter().map(|file| {
+            let operations = file.find("operations").and_then(|operations| operations.as_u64()).unwrap_or(0);
+            let path = match file.find("path").and_then(|path| path.as_string()) {
+                Some(path) => path.to_string(),
+                None       => format!("(inode {})", file.find("ino").and_then(|ino| ino.as_u64()).unwrap_or(0))
+            };
+            (operations, path)
+        }).collect(),
+        _ => Vec::new()
+    };
+    let recent_errors = match metrics.get("recent_errors") {
+        Some(&Json::Array(ref errors)) => errors.iter().filter_map(RecentError::from_json).collect(),
+        _                              => Vec::new()
+    };
+
+    Ok(Sample {
+        taken: Instant::now(),
+        operations: parse_stats(metrics.get("operations")),
+        transfers,
+        busiest,
+        recent_errors,
+        status
+    })
+}
+
+fn render(screen: &mut String, local_path: &OsString, interval: Duration, sample: &Sample, previous: Option<&Sample>) {
+    let elapsed = previous.map_or(0.0, |previous| metrics::seconds(sample.taken.duration_since(previous.taken)));
+    // None on the first refresh, there's nothing to compare with yet
+    let per_second = |now: u64, before: Option<u64>| -> Option<f64> {
+        match before {
+            Some(before) if elapsed > 0.0 => Some(now.saturating_sub(before) as f64 / elapsed),
+            _                             => None
+        }
+    };
+    let status = |name: &str| sample.status.get(name).and_then(|value| value.as_u64()).unwrap_or(0);
+    let paused = sample.status.get("paused").and_then(|paused| paused.as_boolean()).unwrap_or(false);
+
+    let _ = writeln!(screen, "markfs top {}, every {}s, q to quit", local_path.to_string_lossy(), interval.as_secs());
+    let _ = writeln!(screen, "");
+    let _ = writeln!(screen, "Sync queue: {} files not uploaded, {} unfinished actions, uploads {}, {} downloading",
+                     status("unsynced"), status("unfinished_actions"), if paused { "paused" } else { "running" }, status("hydrating"));
+    let _ = writeln!(screen, "");
+
+    // Busiest operations first
+    let mut operations: Vec<(&String, &OpStats, Option<&OpStats>)> = sample.operations.iter()
+        .map(|(name, op)| (name, op, previous.and_then(|previous| previous.operations.get(name))))
+        .collect();
+    operations.sort_by(|a, b| {
+        let recent = |&(_, op, before): &(&String, &OpStats, Option<&OpStats>)| op.count.saturating_sub(before.map_or(0, |before| before.count));
+        recent(b).cmp(&recent(a)).then(b.1.count.cmp(&a.1.count))
+    });
+
+    let _ = writeln!(screen, "{:<16} {:>10} {:>10} {:>10} {:>10} {:>12}", "OPERATION", "OPS/S", "ERRORS/S", "AVG MS", "P99 MS", "TOTAL");
+    for &(name, op, before) in operations.iter().take(ROWS) {
+        let count_before = previous.map(|_| before.map_or(0, |before| before.count));
+        let errors_before = previous.map(|_| before.map_or(0, |before| before.errors));
+        let _ = writeln!(screen, "{:<16} {:>10} {:>10} {:>10.3} {:>10} {:>12}", name,
+                         format_rate(per_second(op.count, count_before), 1.0), format_rate(per_second(op.errors, errors_before), 1.0),
+                         op.average_seconds() * 1000.0, milliseconds(op.quantile_seconds(0.99)), op.count);
+    }
+    let _ = writeln!(screen, "");
+
+    let _ = writeln!(screen, "{:>10} {}", "OPS", "BUSIEST FILES");
+    for &(operations, ref path) in sample.busiest.iter().take(ROWS) {
+        let _ = writeln!(screen, "{:>10} {}", operations, path);
+    }
+    let _ = writeln!(screen, "");
+
+    let _ = writeln!(screen, "{:<24} {:>14} {:>14} {:>14} {:>14}", "PEER", "UPLOAD KB/S", "DOWNLOAD KB/S", "UPLOADED", "DOWNLOADED");
+    for (peer, transfer) in sample.transfers.iter() {
+        let before = previous.map(|previous| previous.transfers.get(peer).cloned().unwrap_or(Transfers::default()));
+        let _ = writeln!(screen, "{:<24} {:>14} {:>14} {:>14} {:>14}", peer,
+                         format_rate(per_second(transfer.uploaded_bytes, before.as_ref().map(|before| before.uploaded_bytes)), 1024.0),
+                         format_rate(per_second(transfer.downloaded_bytes, before.as_ref().map(|before| before.downloaded_bytes)), 1024.0),
+                         transfer.uploaded_bytes, transfer.downloaded_bytes);
+    }
+    let _ = writeln!(screen, "");
+
+    let _ = writeln!(screen, "{:<10} {:<16} {:<24} {}", "TIME", "RECENT ERRORS", "SUBJECT", "ERROR");
+    for error in sample.recent_errors.iter().rev().take(ROWS) {
+        let time = time::strftime("%H:%M:%S", &time::at(error.time)).unwrap_or(String::new());
+        let _ = writeln!(screen, "{:<10} {:<16} {:<24} {}", time, error.name, error.subject, io::Error::from_raw_os_error(error.errno));
+    }
+}
+
+/// A rate in units of `unit`, `-` when unknown
+fn format_rate(rate: Option<f64>, unit: f64) -> String {
+    match rate {
+        Some(rate) => format!("{:.1}", rate / unit),
+        None       => "-".to_string()
+    }
+}
+
+/// Reads keys as they're typed, restores the terminal when dropped
+struct RawTerminal {
+    original: libc::termios
+}
+
+impl RawTerminal {
+    /// None when the input isn't a terminal, like in scripts
+    fn enable() -> Option<RawTerminal> {
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) != 1 {
+                return None;
+            }
+            let mut original: libc::termios = mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return None;
+            }
+
+            // Ctrl-C arrives as a key, so the terminal is always restored
+            let mut raw = original;
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+            raw.c_cc[libc::VMIN] = 0;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return None;
+            }
+            Some(RawTerminal { original })
+        }
+    }
+
+    /// Wait for the next refresh, true when `q` or Ctrl-C was pressed
+    fn wait_for_quit(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            let remaining = deadline - now;
+            let mut poll = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+            let millis = remaining.as_secs() * 1000 + remaining.subsec_nanos() as u64 / 1_000_000;
+            if unsafe { libc::poll(&mut poll, 1, millis as libc::c_int) } <= 0 {
+                continue;
+            }
+
+            let mut key = 0u8;
+            if unsafe { libc::read(libc::STDIN_FILENO, &mut key as *mut u8 as *mut libc::c_void, 1) } != 1 {
+                // Closed input never sends a key
+                thread::sleep(remaining);
+                return false;
+            }
+            if key == b'q' || key == b'Q' || key == 3 {
+                return true;
+            }
+        }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original) };
+    }
+}
@@ -0,0 +1,20 @@
+use std::ffi::OsString;
+use mounted_volume::unmount_path;
+use super::{usage_error, EXIT_OK, EXIT_FAILURE};
+
+pub const USAGE: &'static [&'static str] = &["unmount <mountpoint>"];
+
+/// `markfs unmount <mountpoint>`, the mount process exits once it's unmounted
+pub fn unmount(args: &[OsString]) -> i32 {
+    if args.len() != 1 {
+        return usage_error(USAGE);
+    }
+
+    match unmount_path(&args[0]) {
+        Ok(()) => EXIT_OK,
+        Err(e) => {
+            println!("{}", e);
+            EXIT_FAILURE
+        }
+    }
+}
@@ -0,0 +1,624 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use rustc_serialize::json::Json;
+use time::Timespec;
+use s3::{S3Config, MIN_PART_SIZE, MAX_RETRIES};
+use markfs::ExternalSymlinkPolicy;
+use sync_rules::{SyncConfig, SyncRule, ExcludedMode};
+
+/// Overrides the location of the configuration file
+pub const CONFIG_ENV: &'static str = "MARKFS_CONFIG";
+
+const DEFAULT_TTL_MS: u64 = 1000;
+const DEFAULT_SCRUB_BYTES_PER_SECOND: u64 = 4 * 1024 * 1024;
+
+/// Settings of a volume, globally they're the defaults of every volume
+const VOLUME_KEYS: &'static [&'static str] = &["attr_ttl_ms", "entry_ttl_ms", "ownership", "state_dir", "cache_budget",
+                                               "peers", "bandwidth", "retention", "ignore", "mount_options", "read_only", "mountpoint",
+                                               "metrics_address", "external_symlinks", "sync"];
+const OWNERSHIP_KEYS: &'static [&'static str] = &["uids", "gids"];
+const PEER_KEYS: &'static [&'static str] = &["type", "bucket", "endpoint", "region", "prefix", "access_key", "secret_key",
+                                             "multipart_threshold", "part_size", "retries", "snapshot_secs", "replicate_secs",
+                                             "cache_bytes"];
+const BANDWIDTH_KEYS: &'static [&'static str] = &["scrub", "upload", "download"];
+const RETENTION_KEYS: &'static [&'static str] = &["versions", "days"];
+const SYNC_KEYS: &'static [&'static str] = &["include", "exclude", "excluded"];
+
+/// A configuration file that can't be read or holds an invalid setting
+#[derive(Debug)]
+pub struct ConfigError {
+    pub file: PathBuf,
+    /// The offending setting, like `peers[0].part_size`, empty for the whole file
+    pub key: String,
+    pub message: String
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.key.is_empty() {
+            write!(f, "{}: {}", self.file.display(), self.message)
+        } else {
+            write!(f, "{}: {}: {}", self.file.display(), self.key, self.message)
+        }
+    }
+}
+
+/// Maps user and group ids of this device to the ones stored in the volume
+///
+/// Ids without a mapping are stored as they are.
+#[derive(Debug, Clone, Default)]
+pub struct IdMap {
+    uids: HashMap<u32, u32>,
+    gids: HashMap<u32, u32>
+}
+
+impl IdMap {
+    pub fn uid_to_volume(&self, uid: u32) -> u32 {
+        *self.uids.get(&uid).unwrap_or(&uid)
+    }
+
+    pub fn gid_to_volume(&self, gid: u32) -> u32 {
+        *self.gids.get(&gid).unwrap_or(&gid)
+    }
+
+    pub fn uid_to_local(&self, uid: u32) -> u32 {
+        self.uids.iter().find(|&(_, &volume)| volume == uid).map_or(uid, |(&local, _)| local)
+    }
+
+    pub fn gid_to_local(&self, gid: u32) -> u32 {
+        self.gids.iter().find(|&(_, &volume)| volume == gid).map_or(gid, |(&local, _)| local)
+    }
+}
+
+/// How long earlier versions of a file are kept, applied by `markfs gc`
+#[derive(Debug, Clone, Default)]
+pub struct Retention {
+    /// Keep at most this many earlier versions per file
+    pub versions: Option<u32>,
+    /// Drop earlier versions created longer ago
+    pub max_age: Option<Duration>
+}
+
+/// Settings of one volume, its own section overrides the global settings
+///
+/// Everything is read from a JSON file, `$MARKFS_CONFIG` or
+/// `~/.config/markfs/config.json`. Without a file the defaults apply.
+///
+/// ```json
+/// {
+///     "attr_ttl_ms": 1000,
+///     "ownership": { "uids": { "1000": 501 }, "gids": { "1000": 20 } },
+///     "bandwidth": { "scrub": 4194304, "upload": 0, "download": 0 },
+///     "ignore": ["*.tmp", ".DS_Store"],
+///     "mount_options": ["allow_other", "default_permissions"],
+///     "external_symlinks": "keep",
+///     "volumes": {
+///         "/data/photos": {
+///             "state_dir": "/var/lib/markfs/photos",
+///             "cache_budget": 10737418240,
+///             "peers": [{ "type": "s3", "bucket": "photos", "region": "eu-west-1" }],
+///             "retention": { "versions": 10, "days": 90 },
+///             "read_only": false,
+///             "mountpoint": "/mnt/photos",
+///             "metrics_address": "127.0.0.1:9184",
+///             "sync": { "include": ["/artifacts"], "exclude": ["/artifacts/tmp"], "excluded": "hide" }
+///         }
+///     }
+/// }
+/// ```
+#[derive(Clone)]
+pub struct VolumeConfig {
+    /// How long the kernel may cache attributes and lookups
+    pub attr_ttl: Timespec,
+    pub entry_ttl: Timespec,
+    pub id_map: IdMap,
+    /// Holds `metadata.sqlite`, the local path by default
+    pub state_dir: OsString,
+    /// Keep at most this many bytes of content locally
+    pub cache_budget: Option<u64>,
+    /// The first peer receives uploads, all of them serve placeholders and repairs
+    pub peers: Vec<S3Config>,
+    pub scrub_bytes_per_second: u64,
+    /// Shared by all transfers of the volume, 0 for no limit, see `Bandwidth`
+    pub upload_bytes_per_second: u64,
+    pub download_bytes_per_second: u64,
+    pub retention: Retention,
+    /// Patterns like in a `.markfsignore` at the root of the volume
+    pub ignore: Vec<String>,
+    /// Passed to FUSE as `-o <option>`, like `allow_other` or `fsname=photos`
+    pub mount_options: Vec<String>,
+    /// Mutations fail with EROFS, also set by the `ro` mount option
+    pub read_only: bool,
+    /// Where `markfs daemon` mounts the volume when it starts
+    pub mountpoint: Option<OsString>,
+    /// Serve the metrics of the mount in the Prometheus format, on a loopback address
+    pub metrics_address: Option<SocketAddr>,
+    /// `keep` or `reject` symlinks with an absolute target outside the mount
+    pub external_symlink_policy: ExternalSymlinkPolicy,
+    /// Selective sync rules, next to the ones set with `markfs sync`
+    pub sync: SyncConfig
+}
+
+impl VolumeConfig {
+    /// Read and validate the configuration of the volume at `local_path`,
+    /// only the global settings when there's no local path
+    ///
+    /// Every volume section is validated, so a typo anywhere is reported right away.
+    pub fn load(local_path: Option<&OsString>) -> Result<VolumeConfig, ConfigError> {
+        check_env()?;
+        let file = config_file();
+        let parser = Parser { file: file.clone() };
+
+        let mut global = match read_json(&file) {
+            Ok(Some(Json::Object(object))) => object,
+            Ok(Some(_))                    => return Err(parser.error("", "must be a JSON object")),
+            Ok(None)                       => BTreeMap::new(),
+            Err(message)                   => return Err(parser.error("", &message))
+        };
+
+        let volumes = match global.remove("volumes") {
+            Some(Json::Object(volumes)) => volumes,
+            Some(_)                     => return Err(parser.error("volumes", "must be an object keyed by local path")),
+            None                        => BTreeMap::new()
+        };
+
+        parser.check_keys("", &global, VOLUME_KEYS)?;
+        for key in ["state_dir", "mountpoint", "metrics_address"].iter() {
+            if global.contains_key(*key) {
+                return Err(parser.error(key, "can only be set for a single volume, under volumes"));
+            }
+        }
+        let global_settings: Vec<(String, &Json)> = global.iter().map(|(key, value)| (key.clone(), value)).collect();
+
+        let mut config = parser.parse(&global_settings, local_path)?;
+        for (path, section) in volumes.iter() {
+            let prefix = format!("volumes[\"{}\"].", path);
+            let section = match *section {
+                Json::Object(ref section) => section,
+                _                         => return Err(parser.error(&prefix[..prefix.len() - 1], "must be an object"))
+            };
+            if !Path::new(path).is_absolute() {
+                return Err(parser.error(&prefix[..prefix.len() - 1], "the local path must be absolute"));
+            }
+            parser.check_keys(&prefix, section, VOLUME_KEYS)?;
+
+            // A volume's own setting replaces the global one as a whole
+            let mut settings: Vec<(String, &Json)> = global_settings.iter()
+                .filter(|&&(ref key, _)| !section.contains_key(key))
+                .cloned()
+                .collect();
+            settings.extend(section.iter().map(|(key, value)| (format!("{}{}", prefix, key), value)));
+
+            if local_path.map_or(false, |local_path| same_path(Path::new(path), Path::new(local_path))) {
+                config = parser.parse(&settings, local_path)?;
+            } else {
+                parser.parse(&settings, Some(&OsString::from(path.as_str())))?;
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Every volume with a section in the configuration file, by local path
+    pub fn load_all() -> Result<Vec<(OsString, VolumeConfig)>, ConfigError> {
+        // Validates the whole file
+        VolumeConfig::load(None)?;
+
+        let local_paths: Vec<String> = match read_json(&config_file()) {
+            Ok(Some(Json::Object(mut global))) => match global.remove("volumes") {
+                Some(Json::Object(volumes)) => volumes.keys().cloned().collect(),
+                _                           => Vec::new()
+            },
+            _ => Vec::new()
+        };
+
+        let mut configs = Vec::new();
+        for local_path in local_paths {
+            let local_path = OsString::from(local_path);
+            let config = VolumeConfig::load(Some(&local_path))?;
+            configs.push((local_path, config));
+        }
+        Ok(configs)
+    }
+
+    /// Options from the command line or the control socket add to the configured ones
+    pub fn add_mount_options(&mut self, mount_options: Vec<String>, read_only: bool) {
+        self.mount_options.extend(mount_options);
+        self.read_only |= read_only || self.mount_options.iter().any(|option| option == "ro");
+        if self.read_only && !self.mount_options.iter().any(|option| option == "ro") {
+            self.mount_options.push("ro".to_string());
+        }
+    }
+}
+
+/// Validate a peer given outside the configuration file, like over the
+/// control socket, errors name `source` instead of the file
+pub fn parse_peer(source: &str, peer: &Json, local_path: &OsString) -> Result<S3Config, ConfigError> {
+    let parser = Parser { file: PathBuf::from(source) };
+    let object = parser.object("peer", peer, PEER_KEYS)?;
+    parser.peer("peer", object, Some(local_path))
+}
+
+fn config_file() -> PathBuf {
+    if let Some(file) = env::var_os(CONFIG_ENV) {
+        return PathBuf::from(file);
+    }
+
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(config_dir) => PathBuf::from(config_dir),
+        None             => PathBuf::from(env::var_os("HOME").unwrap_or(OsString::from("/"))).join(".config")
+    };
+    config_dir.join("markfs").join("config.json")
+}
+
+/// Fail on the environment variables that used to configure a volume, so
+/// they aren't silently ignored
+fn check_env() -> Result<(), ConfigError> {
+    for (name, _) in env::vars_os() {
+        let name = name.to_string_lossy();
+        if let Some(key) = replacing_setting(&name) {
+            return Err(ConfigError {
+                file: PathBuf::from("environment"),
+                key: name.to_string(),
+                message: format!("is no longer read, set {} in {} instead", key, config_file().display())
+            });
+        }
+    }
+    Ok(())
+}
+
+/// The setting replacing a removed environment variable
+fn replacing_setting(name: &str) -> Option<String> {
+    if name == "MARKFS_CACHE_BUDGET" {
+        return Some("cache_budget".to_string());
+    }
+    if name.starts_with("MARKFS_S3_") {
+        return Some(format!("peers[].{}", name["MARKFS_S3_".len()..].to_lowercase()));
+    }
+    None
+}
+
+/// The parsed file, None when it doesn't exist
+fn read_json(file: &Path) -> Result<Option<Json>, String> {
+    let mut content = String::new();
+    match File::open(file).and_then(|mut f| f.read_to_string(&mut content)) {
+        Ok(_)  => (),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("unable to read: {}", e))
+    }
+
+    match Json::from_str(&content) {
+        Ok(json) => Ok(Some(json)),
+        Err(e)   => Err(format!("not valid JSON: {}", e))
+    }
+}
+
+fn same_path(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _              => a == b
+    }
+}
+
+/// Validates settings, errors name the file and the full key
+struct Parser {
+    file: PathBuf
+}
+
+impl Parser {
+    fn error(&self, key: &str, message: &str) -> ConfigError {
+        ConfigError {
+            file: self.file.clone(),
+            key: key.to_string(),
+            message: message.to_string()
+        }
+    }
+
+    fn check_keys(&self, prefix: &str, object: &BTreeMap<String, Json>, allowed: &[&str]) -> Result<(), ConfigError> {
+        match object.keys().find(|key| !allowed.contains(&key.as_str())) {
+            Some(key) => Err(self.error(&format!("{}{}", prefix, key), &format!("unknown setting, expected one of {}", allowed.join(", ")))),
+            None      => Ok(())
+        }
+    }
+
+    /// Build the config of a volume from its settings, keyed by their full key
+    fn parse(&self, settings: &[(String, &Json)], local_path: Option<&OsString>) -> Result<VolumeConfig, ConfigError> {
+        let mut config = VolumeConfig {
+            attr_ttl: ttl(DEFAULT_TTL_MS),
+            entry_ttl: ttl(DEFAULT_TTL_MS),
+            id_map: IdMap::default(),
+            state_dir: local_path.cloned().unwrap_or(OsString::new()),
+            cache_budget: None,
+            peers: Vec::new(),
+            scrub_bytes_per_second: DEFAULT_SCRUB_BYTES_PER_SECOND,
+            upload_bytes_per_second: 0,
+            download_bytes_per_second: 0,
+            retention: Retention::default(),
+            ignore: Vec::new(),
+            mount_options: Vec::new(),
+            read_only: false,
+            mountpoint: None,
+            metrics_address: None,
+            external_symlink_policy: ExternalSymlinkPolicy::Keep,
+            sync: SyncConfig::default()
+        };
+
+        for &(ref key, value) in settings.iter() {
+            match key.rsplit('.').next().unwrap_or(key) {
+                "attr_ttl_ms"  => config.attr_ttl = ttl(self.number(key, value)?),
+                "entry_ttl_ms" => config.entry_ttl = ttl(self.number(key, value)?),
+                "cache_budget" => config.cache_budget = Some(self.number(key, value)?),
+                "state_dir"    => {
+                    let state_dir = self.string(key, value)?;
+                    if !Path::new(&state_dir).is_absolute() {
+                        return Err(self.error(key, "must be an absolute path"));
+                    }
+                    config.state_dir = OsString::from(state_dir);
+                },
+                "ownership" => {
+                    let ownership = self.object(key, value, OWNERSHIP_KEYS)?;
+                    if let Some(uids) = ownership.get("uids") {
+                        config.id_map.uids = self.id_map(&format!("{}.uids", key), uids)?;
+                    }
+                    if let Some(gids) = ownership.get("gids") {
+                        config.id_map.gids = self.id_map(&format!("{}.gids", key), gids)?;
+                    }
+                },
+                "peers" => {
+                    let peers = match *value {
+                        Json::Array(ref peers) => peers,
+                        _                      => return Err(self.error(key, "must be a list"))
+                    };
+                    for (i, peer) in peers.iter().enumerate() {
+                        let peer_key = format!("{}[{}]", key, i);
+                        let peer = self.object(&peer_key, peer, PEER_KEYS)?;
+                        config.peers.push(self.peer(&peer_key, peer, local_path)?);
+                    }
+                },
+                "bandwidth" => {
+                    let bandwidth = self.object(key, value, BANDWIDTH_KEYS)?;
+                    if let Some(scrub) = bandwidth.get("scrub") {
+                        config.scrub_bytes_per_second = self.number(&format!("{}.scrub", key), scrub)?;
+                    }
+                    if let Some(upload) = bandwidth.get("upload") {
+                        config.upload_bytes_per_second = self.number(&format!("{}.upload", key), upload)?;
+                    }
+                    if let Some(download) = bandwidth.get("download") {
+                        config.download_bytes_per_second = self.number(&format!("{}.download", key), download)?;
+                    }
+                },
+                "retention" => {
+                    let retention = self.object(key, value, RETENTION_KEYS)?;
+                    if let Some(versions) = retention.get("versions") {
+                        let versions_key = format!("{}.versions", key);
+                        match self.number(&versions_key, versions)? {
+                            0        => return Err(self.error(&versions_key, "must be at least 1, leave it out to keep every version")),
+                            versions => config.retention.versions = Some(versions as u32)
+                        }
+                    }
+                    if let Some(days) = retention.get("days") {
+                        config.retention.max_age = Some(Duration::from_secs(self.number(&format!("{}.days", key), days)? * 24 * 60 * 60));
+                    }
+                },
+                "ignore" => {
+                    let patterns = match *value {
+                        Json::Array(ref patterns) => patterns,
+                        _                         => return Err(self.error(key, "must be a list of patterns"))
+                    };
+                    for (i, pattern) in patterns.iter().enumerate() {
+                        config.ignore.push(self.string(&format!("{}[{}]", key, i), pattern)?);
+                    }
+                },
+                "mount_options" => {
+                    let options = match *value {
+                        Json::Array(ref options) => options,
+                        _                        => return Err(self.error(key, "must be a list of options"))
+                    };
+                    for (i, option) in options.iter().enumerate() {
+                        let option_key = format!("{}[{}]", key, i);
+                        let option = self.string(&option_key, option)?;
+                        if option.is_empty() || option.contains(',') {
+                            return Err(self.error(&option_key, "must be a single option, like allow_other"));
+                        }
+                        config.mount_options.push(option);
+                    }
+                },
+                "mountpoint" => {
+                    let mountpoint = self.string(key, value)?;
+                    if !Path::new(&mountpoint).is_absolute() {
+                        return Err(self.error(key, "must be an absolute path"));
+                    }
+                    config.mountpoint = Some(OsString::from(mountpoint));
+                },
+                "metrics_address" => {
+                    let address = match self.string(key, value)?.parse::<SocketAddr>() {
+                        Ok(address) => address,
+                        Err(_)      => return Err(self.error(key, "must be an address with a port, like 127.0.0.1:9184"))
+                    };
+                    if !address.ip().is_loopback() {
+                        return Err(self.error(key, "must be a loopback address, the metrics aren't protected"));
+                    }
+                    config.metrics_address = Some(address);
+                },
+                "external_symlinks" => {
+                    config.external_symlink_policy = match self.string(key, value)?.as_str() {
+                        "keep"   => ExternalSymlinkPolicy::Keep,
+                        "reject" => ExternalSymlinkPolicy::Reject,
+                        _        => return Err(self.error(key, "must be keep or reject"))
+                    };
+                },
+                "sync" => {
+                    let sync = self.object(key, value, SYNC_KEYS)?;
+                    for &(name, include) in [("include", true), ("exclude", false)].iter() {
+                        let paths = match sync.get(name) {
+                            Some(&Json::Array(ref paths)) => paths,
+                            Some(_)                       => return Err(self.error(&format!("{}.{}", key, name), "must be a list of volume paths")),
+                            None                          => continue
+                        };
+                        for (i, path) in paths.iter().enumerate() {
+                            let path_key = format!("{}.{}[{}]", key, name, i);
+                            let path = self.string(&path_key, path)?;
+                            if !path.starts_with('/') {
+                                return Err(self.error(&path_key, "must be a volume path, like /artifacts"));
+                            }
+                            config.sync.rules.push(SyncRule {
+                                path: Path::new("/").join(path.trim_matches('/')),
+                                include
+                            });
+                        }
+                    }
+                    if let Some(excluded) = sync.get("excluded") {
+                        let excluded_key = format!("{}.excluded", key);
+                        config.sync.excluded_mode = match ExcludedMode::from_str(&self.string(&excluded_key, excluded)?) {
+                            Some(mode) => Some(mode),
+                            None       => return Err(self.error(&excluded_key, "must be hide or placeholder"))
+                        };
+                    }
+                },
+                "read_only" => {
+                    config.read_only = match value.as_boolean() {
+                        Some(read_only) => read_only,
+                        None            => return Err(self.error(key, "must be true or false"))
+                    };
+                },
+                _ => return Err(self.error(key, "unknown setting"))
+            }
+        }
+        config.read_only |= config.mount_options.iter().any(|option| option == "ro");
+
+        // A peer used on its own, like by `markfs snapshot`, keeps to the limits too
+        for peer in config.peers.iter_mut() {
+            peer.upload_bytes_per_second = config.upload_bytes_per_second;
+            peer.download_bytes_per_second = config.download_bytes_per_second;
+        }
+
+        Ok(config)
+    }
+
+    fn peer(&self, key: &str, peer: &BTreeMap<String, Json>, local_path: Option<&OsString>) -> Result<S3Config, ConfigError> {
+        match peer.get("type") {
+            Some(&Json::String(ref kind)) if kind == "s3" => (),
+            Some(_) => return Err(self.error(&format!("{}.type", key), "unknown peer type, expected s3")),
+            None    => return Err(self.error(&format!("{}.type", key), "missing, expected s3"))
+        }
+
+        let bucket = match peer.get("bucket") {
+            Some(bucket) => self.string(&format!("{}.bucket", key), bucket)?,
+            None         => return Err(self.error(&format!("{}.bucket", key), "missing"))
+        };
+
+        let mut config = S3Config::new(bucket, local_path.unwrap_or(&OsString::new()));
+        for (name, value) in peer.iter() {
+            let setting_key = format!("{}.{}", key, name);
+            match name.as_str() {
+                "endpoint"            => config.endpoint = Some(self.string(&setting_key, value)?),
+                "region"              => config.region = self.string(&setting_key, value)?,
+                "prefix"              => config.prefix = self.string(&setting_key, value)?,
+                "access_key"          => config.access_key = Some(self.string(&setting_key, value)?),
+                "secret_key"          => config.secret_key = Some(self.string(&setting_key, value)?),
+                "multipart_threshold" => config.multipart_threshold = self.number(&setting_key, value)?,
+                "part_size"           => {
+                    config.part_size = self.number(&setting_key, value)? as usize;
+                    if config.part_size < MIN_PART_SIZE {
+                        return Err(self.error(&setting_key, &format!("must be at least {} bytes", MIN_PART_SIZE)));
+                    }
+                },
+                "retries"             => {
+                    match self.number(&setting_key, value)? {
+                        retries if retries > MAX_RETRIES as u64 => return Err(self.error(&setting_key, &format!("must be at most {}", MAX_RETRIES))),
+                        retries                                 => config.retries = retries as u32
+                    }
+                },
+                "cache_bytes"         => config.cache_bytes = self.number(&setting_key, value)?,
+                "snapshot_secs"       => config.snapshot_interval = Some(Duration::from_secs(self.number(&setting_key, value)?)),
+                "replicate_secs"      => {
+                    match self.number(&setting_key, value)? {
+                        0    => return Err(self.error(&setting_key, "must be at least 1")),
+                        secs => config.replicate_interval = Duration::from_secs(secs)
+                    }
+                },
+                _                     => ()
+            }
+        }
+
+        if config.access_key.is_some() != config.secret_key.is_some() {
+            return Err(self.error(key, "access_key and secret_key must be set together"));
+        }
+        Ok(config)
+    }
+
+    /// Local id to volume id, both ways unambiguous
+    fn id_map(&self, key: &str, value: &Json) -> Result<HashMap<u32, u32>, ConfigError> {
+        let object = match *value {
+            Json::Object(ref object) => object,
+            _                        => return Err(self.error(key, "must be an object of local id to volume id"))
+        };
+
+        let mut ids = HashMap::new();
+        for (local, volume) in object.iter() {
+            let id_key = format!("{}.{}", key, local);
+            let local = match local.parse::<u32>() {
+                Ok(local) => local,
+                Err(_)    => return Err(self.error(&id_key, "the local id must be a number"))
+            };
+            let volume = self.number(&id_key, volume)?;
+            if volume > u32::max_value() as u64 {
+                return Err(self.error(&id_key, "the volume id is out of range"));
+            }
+            if ids.values().any(|&mapped| mapped == volume as u32) {
+                return Err(self.error(&id_key, &format!("volume id {} is already mapped from another local id", volume)));
+            }
+            ids.insert(local, volume as u32);
+        }
+        Ok(ids)
+    }
+
+    fn object<'a>(&self, key: &str, value: &'a Json, allowed: &[&str]) -> Result<&'a BTreeMap<String, Json>, ConfigError> {
+        match *value {
+            Json::Object(ref object) => {
+                self.check_keys(&format!("{}.", key), object, allowed)?;
+                Ok(object)
+            },
+            _ => Err(self.error(key, "must be an object"))
+        }
+    }
+
+    fn number(&self, key: &str, value: &Json) -> Result<u64, ConfigError> {
+        match value.as_u64() {
+            Some(number) => Ok(number),
+            None         => Err(self.error(key, "must be a non-negative integer"))
+        }
+    }
+
+    fn string(&self, key: &str, value: &Json) -> Result<String, ConfigError> {
+        match *value {
+            Json::String(ref string) => Ok(string.clone()),
+            _                        => Err(self.error(key, "must be a string"))
+        }
+    }
+}
+
+fn ttl(ms: u64) -> Timespec {
+    Timespec::new((ms / 1000) as i64, ((ms % 1000) * 1_000_000) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::replacing_setting;
+
+    #[test]
+    fn removed_environment_variables() {
+        assert_eq!(replacing_setting("MARKFS_CACHE_BUDGET"), Some("cache_budget".to_string()));
+        assert_eq!(replacing_setting("MARKFS_S3_BUCKET"), Some("peers[].bucket".to_string()));
+        assert_eq!(replacing_setting("MARKFS_S3_SNAPSHOT_SECS"), Some("peers[].snapshot_secs".to_string()));
+        assert_eq!(replacing_setting("MARKFS_CONFIG"), None);
+        assert_eq!(replacing_setting("MARKFS_TEST_S3_BUCKET"), None);
+    }
+}
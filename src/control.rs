@@ -0,0 +1,389 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use libc;
+use rustc_serialize::json::{Json, ToJson};
+use config::{self, VolumeConfig};
+use error::Error;
+use hydrate::{self, Hydrator};
+use local::LocalFileOperations;
+use metadata::Metadata;
+use metrics::Metrics;
+use s3::S3Remote;
+use sync_rules::{SyncRules, SharedSyncRules};
+use throttle::Bandwidth;
+use volume::Volume;
+
+/// Control socket of a mounted volume, in its state directory
+pub const SOCKET_FILE: &'static str = "markfs.sock";
+
+pub type Response = BTreeMap<String, Json>;
+
+/// Files reported by the `metrics` command, unless the request asks for another number
+const BUSIEST_FILES: usize = 10;
+
+/// A connection sending nothing for this long is closed, so it can't hold up the others
+const IDLE_TIMEOUT_SECS: u64 = 30;
+
+pub fn socket_path(state_dir: &OsString) -> PathBuf {
+    Path::new(state_dir).join(SOCKET_FILE)
+}
+
+/// Answers requests about a mounted volume, used by the CLI and scripts
+///
+/// A connection sends one JSON object per line, like
+/// `{"command": "pin", "path": "/photos"}`, and gets one back per line,
+/// `{"ok": true, ...}` or `{"ok": false, "error": "..."}`.
+/// Connections are served one at a time, see `serve`.
+pub struct ControlServer {
+    local_path: OsString,
+    config: VolumeConfig,
+    hydrator: Hydrator,
+    paused: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+    metrics: Metrics,
+    sync_rules: SharedSyncRules,
+    bandwidth: Bandwidth
+}
+
+impl ControlServer {
+    /// `hydrator` and `paused` are shared with the mount
+    pub fn new(local_path: &OsString, config: &VolumeConfig, hydrator: Hydrator, paused: Arc<AtomicBool>) -> ControlServer {
+        ControlServer {
+            local_path: local_path.clone(),
+            config: config.clone(),
+            hydrator,
+            paused,
+            stopped: Arc::new(AtomicBool::new(false)),
+            metrics: Metrics::new(),
+            sync_rules: Arc::new(Mutex::new(SyncRules::all())),
+            bandwidth: Bandwidth::new(config.upload_bytes_per_second, config.download_bytes_per_second)
+        }
+    }
+
+    /// Stop listening once the flag is set, see `wake`
+    pub fn set_stop_flag(&mut self, stopped: Arc<AtomicBool>) {
+        self.stopped = stopped;
+    }
+
+    /// Report these metrics, shared with the mount
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.metrics = metrics;
+    }
+
+    /// Limit added peers with these throttles, shared with the mount
+    pub fn set_bandwidth(&mut self, bandwidth: Bandwidth) {
+        self.bandwidth = bandwidth;
+    }
+
+    /// Replace these rules on `reload_sync`, shared with the mount
+    pub fn set_sync_rules(&mut self, sync_rules: SharedSyncRules) {
+        self.sync_rules = sync_rules;
+    }
+
+    /// Listen on its own thread, with its own metadata connection
+    pub fn spawn(self) -> io::Result<thread::JoinHandle<()>> {
+        let path = socket_path(&self.config.state_dir);
+
+        // Left behind by a mount that didn't exit cleanly, the pidfile guards against a running one
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        let listener = bind(&path)?;
+
+        Ok(thread::spawn(move || {
+            let metadata = match Metadata::new(&self.config.state_dir) {
+                Ok(metadata) => metadata,
+                Err(e)       => {
+                    error!("Control socket unavailable, unable to open the metadata: {}", e);
+                    return;
+                }
+            };
+            let mut volume = Volume::new(metadata, LocalFileOperations::new(&self.local_path));
+            volume.configure(&self.config);
+            volume.set_hydrator(self.hydrator.clone());
+            volume.set_metrics(self.metrics.clone());
+
+            for stream in listener.incoming() {
+                if self.stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        match serve(stream, |request| self.handle(&mut volume, request)) {
+                            Ok(()) => (),
+                            Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => warn!("{}", e),
+                            Err(e) => debug!("Control connection failed: {}", e)
+                        }
+                    },
+                    Err(e) => warn!("Unable to accept a control connection: {}", e)
+                }
+            }
+            let _ = fs::remove_file(&path);
+        }))
+    }
+
+    fn handle(&self, volume: &mut Volume<LocalFileOperations>, request: &Response) -> Result<Response, String> {
+        let mut response = Response::new();
+
+        match string_arg(request, "command")?.as_str() {
+            "status" => {
+                let status = volume.metadata().get_status().map_err(|e| e.to_string())?;
+                response.insert("inodes".to_string(), status.inodes.to_json());
+                response.insert("files".to_string(), status.files.to_json());
+                response.insert("placeholders".to_string(), status.placeholders.to_json());
+                response.insert("pinned".to_string(), status.pinned.to_json());
+                response.insert("hydrated_bytes".to_string(), status.hydrated_bytes.to_json());
+                response.insert("unsynced".to_string(), status.unsynced.to_json());
+                response.insert("corrupt".to_string(), status.corrupt.to_json());
+                response.insert("unfinished_actions".to_string(), status.unfinished_actions.to_json());
+                response.insert("paused".to_string(), self.paused.load(Ordering::SeqCst).to_json());
+                response.insert("hydrating".to_string(), (self.hydrator.hydrations().lock().unwrap().len() as u64).to_json());
+            },
+            "progress" => {
+                let hydrations: Vec<Json> = self.hydrator.hydrations().lock().unwrap().iter().map(|(&ino, progress)| {
+                    let mut hydration = Response::new();
+                    hydration.insert("ino".to_string(), ino.to_json());
+                    hydration.insert("path".to_string(), progress.path.to_string_lossy().into_owned().to_json());
+                    hydration.insert("done".to_string(), progress.done.to_json());
+                    hydration.insert("total".to_string(), progress.total.to_json());
+                    Json::Object(hydration)
+                }).collect();
+                response.insert("hydrations".to_string(), Json::Array(hydrations));
+            },
+            "cancel" => {
+                let ino = number_arg(request, "ino")?;
+                if !hydrate::cancel(&self.hydrator.hydrations(), ino) {
+                    return Err(format!("No running hydration of inode {}", ino));
+                }
+            },
+            "metrics" => match request.get("format").and_then(|format| format.as_string()) {
+                Some("prometheus") => {
+                    response.insert("text".to_string(), self.metrics.to_prometheus().to_json());
+                },
+                Some("json") | None => {
+                    if let Json::Object(metrics) = self.metrics.to_json() {
+                        response.extend(metrics);
+                    }
+                    let limit = match request.get("busiest") {
+                        Some(_) => number_arg(request, "busiest")? as usize,
+                        None    => BUSIEST_FILES
+                    };
+                    let busiest: Vec<Json> = self.metrics.busiest_files(limit).into_iter().map(|(ino, operations)| {
+                        let mut file = Response::new();
+                        file.insert("ino".to_string(), ino.to_json());
+                        file.insert("operations".to_string(), operations.to_json());
+                        if let Ok(path) = volume.metadata().get_by_ino(ino).and_then(|inode| volume.metadata().get_volume_path(&inode)) {
+                            file.insert("path".to_string(), path.to_string_lossy().into_owned().to_json());
+                        }
+                        Json::Object(file)
+                    }).collect();
+                    response.insert("busiest".to_string(), Json::Array(busiest));
+                },
+                Some(format) => return Err(format!("Unknown format {}, expected json or prometheus", format))
+            },
+            "pause"  => self.paused.store(true, Ordering::SeqCst),
+            "resume" => self.paused.store(false, Ordering::SeqCst),
+            "peers" => {
+                response.insert("peers".to_string(), self.hydrator.peer_names().to_json());
+            },
+            "add_peer" => {
+                let peer = request.get("peer").ok_or("Missing argument peer".to_string())?;
+                let peer_config = config::parse_peer("request", peer, &self.local_path).map_err(|e| e.to_string())?;
+                let mut remote = S3Remote::new(peer_config).map_err(|e| format!("Unable to connect to the S3 bucket: {:?}", e))?;
+                remote.set_metrics(self.metrics.clone());
+                remote.set_bandwidth(self.bandwidth.clone());
+                self.hydrator.add_peer(Box::new(remote));
+            },
+            "remove_peer" => {
+                let name = string_arg(request, "name")?;
+                if !self.hydrator.remove_peer(&name) {
+                    return Err(format!("No peer {}", name));
+                }
+            },
+            command @ "pin" | command @ "unpin" => {
+                let path = string_arg(request, "path")?;
+                let inode = volume.metadata().resolve(Path::new(&path)).map_err(|e| format!("{}: {}", path, e))?;
+                let inode = volume.metadata().set_pinned(&inode, command == "pin").map_err(|_| format!("Failed to {} {}", command, path))?;
+                if command == "pin" {
+                    let failed = self.hydrator.hydrate_subtree(volume.storage(), volume.metadata(), &inode).map_err(|e| e.to_string())?;
+                    response.insert("failed".to_string(), (failed as u64).to_json());
+                }
+            },
+            "reload_sync" => {
+                let sync_rules = SyncRules::load(volume.metadata(), &self.config.sync).map_err(|e| format!("Unable to read the sync rules: {}", e))?;
+                *self.sync_rules.lock().unwrap() = sync_rules;
+            },
+            "restore" if self.config.read_only => return Err("The volume is mounted read-only".to_string()),
+            "restore" => {
+                let path = string_arg(request, "path")?;
+                let version = string_arg(request, "version")?;
+                match volume.metadata().resolve(Path::new(&path)) {
+                    Ok(ref inode) if inode.kind.is_regular_file() => (),
+                    Ok(_)  => return Err(format!("No such file: {}", path)),
+                    Err(e) => return Err(format!("{}: {}", path, e))
+                }
+                volume.restore(Path::new(&path), &version).map_err(|e| restore_error(&path, &version, e))?;
+            },
+            command => return Err(format!("Unknown command {}", command))
+        }
+
+        Ok(response)
+    }
+}
+
+/// Listen on a socket only its owner can connect to
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Answer the requests of one connection with `handle`, until it's closed
+/// or idle for `IDLE_TIMEOUT_SECS`
+///
+/// Only root and the user running us are served, whatever the permissions
+/// of the socket.
+pub fn serve<F: FnMut(&Response) -> Result<Response, String>>(stream: UnixStream, mut handle: F) -> io::Result<()> {
+    let uid = peer_uid(&stream)?;
+    if uid != 0 && uid != unsafe { libc::geteuid() } {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("Refused a connection from uid {}", uid)));
+    }
+
+    stream.set_read_timeout(Some(Duration::from_secs(IDLE_TIMEOUT_SECS)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(IDLE_TIMEOUT_SECS)))?;
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let result = match Json::from_str(&line) {
+            Ok(Json::Object(request)) => handle(&request),
+            Ok(_)                     => Err("A request must be a JSON object".to_string()),
+            Err(e)                    => Err(format!("Invalid request: {}", e))
+        };
+
+        let mut response = match result {
+            Ok(response) => response,
+            Err(error)   => {
+                let mut response = Response::new();
+                response.insert("error".to_string(), error.to_json());
+                response
+            }
+        };
+        let ok = !response.contains_key("error");
+        response.insert("ok".to_string(), ok.to_json());
+        writeln!(writer, "{}", Json::Object(response))?;
+    }
+    Ok(())
+}
+
+/// User id of the process on the other end
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut credentials: libc::ucred = unsafe { ::std::mem::zeroed() };
+    let mut length = ::std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED,
+                         &mut credentials as *mut libc::ucred as *mut libc::c_void, &mut length)
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(credentials.uid)
+}
+
+/// User id of the process on the other end
+#[cfg(not(target_os = "linux"))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut uid: libc::uid_t = 0;
+    let mut gid: libc::gid_t = 0;
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+/// Let a listener blocked in accept see that it's stopped
+pub fn wake(path: &Path) {
+    let _ = UnixStream::connect(path);
+}
+
+/// Why a version couldn't be restored, for `markfs restore`
+pub fn restore_error(path: &str, version: &str, e: Error) -> String {
+    match e {
+        Error::NotFound        => format!("No version {} of {}", version, path),
+        Error::InvalidArgument => format!("Version {} of {} was never finished", version, path),
+        e                      => format!("Unable to restore {} to version {}, it is left unchanged: {}", path, version, e)
+    }
+}
+
+pub fn string_arg(request: &Response, name: &str) -> Result<String, String> {
+    match request.get(name) {
+        Some(&Json::String(ref value)) => Ok(value.clone()),
+        Some(_)                        => Err(format!("Argument {} must be a string", name)),
+        None                           => Err(format!("Missing argument {}", name))
+    }
+}
+
+fn number_arg(request: &Response, name: &str) -> Result<u64, String> {
+    match request.get(name) {
+        Some(value) => value.as_u64().ok_or(format!("Argument {} must be a non-negative integer", name)),
+        None        => Err(format!("Missing argument {}", name))
+    }
+}
+
+/// Connection to the control socket of a mounted volume
+pub struct ControlClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream
+}
+
+impl ControlClient {
+    /// None when the volume isn't mounted
+    pub fn connect(state_dir: &OsString) -> Option<ControlClient> {
+        ControlClient::connect_to(&socket_path(state_dir))
+    }
+
+    /// None when nothing listens on the socket
+    pub fn connect_to(path: &Path) -> Option<ControlClient> {
+        let stream = match UnixStream::connect(path) {
+            Ok(stream) => stream,
+            Err(_)     => return None
+        };
+        match stream.try_clone() {
+            Ok(writer) => Some(ControlClient { reader: BufReader::new(stream), writer }),
+            Err(_)     => None
+        }
+    }
+
+    /// Send a command with its arguments, the error of a failed command is returned as Err
+    pub fn request(&mut self, command: &str, mut arguments: Response) -> Result<Response, String> {
+        arguments.insert("command".to_string(), command.to_json());
+        writeln!(self.writer, "{}", Json::Object(arguments)).map_err(|e| e.to_string())?;
+
+        let mut line = String::new();
+        self.reader.read_line(&mut line).map_err(|e| e.to_string())?;
+
+        let mut response = match Json::from_str(&line) {
+            Ok(Json::Object(response)) => response,
+            _                          => return Err("Invalid response from the mount".to_string())
+        };
+        match response.remove("error") {
+            Some(Json::String(error)) => Err(error),
+            Some(_)                   => Err("Invalid response from the mount".to_string()),
+            None                      => Ok(response)
+        }
+    }
+}
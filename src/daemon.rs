@@ -0,0 +1,386 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::{CString, OsString};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use libc;
+use rustc_serialize::json::{Json, ToJson};
+use config::VolumeConfig;
+use control::{self, Response, string_arg};
+use mounted_volume::MountedVolume;
+
+/// Pidfile of `markfs mount` in the state directory, unless `--pidfile` says otherwise
+pub const PID_FILE: &'static str = "markfs.pid";
+
+/// Serves the volumes of one user, `markfs daemon`
+///
+/// Its socket speaks the protocol of the control socket of a volume, with
+/// the commands `volumes`, `mount`, `unmount` and `shutdown`. Every mounted
+/// volume keeps its own control socket in its state directory.
+#[derive(Clone)]
+pub struct Daemon {
+    volumes: Arc<Mutex<BTreeMap<OsString, MountedVolume>>>,
+    stopped: Arc<AtomicBool>
+}
+
+impl Daemon {
+    pub fn new() -> Daemon {
+        Daemon {
+            volumes: Arc::new(Mutex::new(BTreeMap::new())),
+            stopped: Arc::new(AtomicBool::new(false))
+        }
+    }
+
+    /// Mount the volume at `mountpoint`, or at its configured one
+    pub fn mount(&self, local_path: &OsString, mountpoint: Option<OsString>, mount_options: Vec<String>, read_only: bool) -> Result<(), String> {
+        let local_path = canonical_path(local_path)?;
+        let mut config = VolumeConfig::load(Some(&local_path)).map_err(|e| format!("Invalid configuration: {}", e))?;
+        let mountpoint = mountpoint.or(config.mountpoint.clone())
+            .ok_or(format!("No mountpoint given or configured for {}", local_path.to_string_lossy()))?;
+        config.add_mount_options(mount_options, read_only);
+
+        self.reap();
+        let mut volumes = self.volumes.lock().unwrap();
+        if volumes.contains_key(&local_path) {
+            return Err(format!("{} is already mounted", local_path.to_string_lossy()));
+        }
+        if volumes.values().any(|volume| volume.mountpoint == mountpoint) {
+            return Err(format!("Another volume is mounted at {}", mountpoint.to_string_lossy()));
+        }
+
+        let volume = MountedVolume::mount(&local_path, &mountpoint, config)?;
+        info!("Mounted {:?} at {:?}", local_path, mountpoint);
+        volumes.insert(local_path, volume);
+        Ok(())
+    }
+
+    /// Unmount the volume and wait for its threads
+    ///
+    /// The other volumes stay available in the meantime.
+    pub fn unmount(&self, local_path: &OsString) -> Result<(), String> {
+        let local_path = canonical_path(local_path)?;
+        let volume = match self.volumes.lock().unwrap().remove(&local_path) {
+            Some(volume) => volume,
+            None         => return Err(format!("{} is not mounted", local_path.to_string_lossy()))
+        };
+
+        if volume.is_mounted() && volume.unmount().is_err() {
+            self.volumes.lock().unwrap().insert(local_path.clone(), volume);
+            return Err(format!("Unable to unmount {}, still in use?", local_path.to_string_lossy()));
+        }
+        volume.wait()
+    }
+
+    /// Local path, mountpoint and whether it's still mounted, of every volume
+    pub fn volumes(&self) -> Vec<(OsString, OsString, bool)> {
+        self.volumes.lock().unwrap().values()
+            .map(|volume| (volume.local_path.clone(), volume.mountpoint.clone(), volume.is_mounted()))
+            .collect()
+    }
+
+    /// Make `serve` return, from a signal handler or the `shutdown` command
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Ok(path) = socket_path() {
+            control::wake(&path);
+        }
+    }
+
+    /// Answer requests on the daemon socket until stopped, then unmount every volume
+    pub fn serve(&self, listener: UnixListener) {
+        for stream in listener.incoming() {
+            if self.stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    match control::serve(stream, |request| self.handle(request)) {
+                        Ok(()) => (),
+                        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => warn!("{}", e),
+                        Err(e) => debug!("Daemon connection failed: {}", e)
+                    }
+                },
+                Err(e) => warn!("Unable to accept a daemon connection: {}", e)
+            }
+            if self.stopped.load(Ordering::SeqCst) {
+                break;
+            }
+        }
+
+        self.unmount_all();
+    }
+
+    fn unmount_all(&self) {
+        let local_paths: Vec<OsString> = self.volumes.lock().unwrap().keys().cloned().collect();
+        for local_path in local_paths {
+            if let Err(e) = self.unmount(&local_path) {
+                warn!("{}", e);
+            }
+        }
+    }
+
+    /// Forget volumes that were unmounted from the outside
+    fn reap(&self) {
+        let reaped: Vec<MountedVolume> = {
+            let mut volumes = self.volumes.lock().unwrap();
+            let unmounted: Vec<OsString> = volumes.iter()
+                .filter(|&(_, volume)| !volume.is_mounted())
+                .map(|(local_path, _)| local_path.clone())
+                .collect();
+            unmounted.iter().filter_map(|local_path| volumes.remove(local_path)).collect()
+        };
+
+        for volume in reaped {
+            if let Err(e) = volume.wait() {
+                warn!("{}", e);
+            }
+        }
+    }
+
+    fn handle(&self, request: &Response) -> Result<Response, String> {
+        let mut response = Response::new();
+
+        match string_arg(request, "command")?.as_str() {
+            "volumes" => {
+                let volumes: Vec<Json> = self.volumes().into_iter().map(|(local_path, mountpoint, mounted)| {
+                    let mut volume = Response::new();
+                    volume.insert("local_path".to_string(), local_path.to_string_lossy().into_owned().to_json());
+                    volume.insert("mountpoint".to_string(), mountpoint.to_string_lossy().into_owned().to_json());
+                    volume.insert("mounted".to_string(), mounted.to_json());
+                    Json::Object(volume)
+                }).collect();
+                response.insert("volumes".to_string(), Json::Array(volumes));
+            },
+            "mount" => {
+                let local_path = OsString::from(string_arg(request, "local_path")?);
+                let mountpoint = match request.get("mountpoint") {
+                    Some(_) => Some(OsString::from(string_arg(request, "mountpoint")?)),
+                    None    => None
+                };
+                let mount_options = match request.get("options") {
+                    Some(&Json::Array(ref options)) => options.iter().filter_map(|option| option.as_string()).map(|option| option.to_string()).collect(),
+                    Some(_)                         => return Err("Argument options must be an array of strings".to_string()),
+                    None                            => Vec::new()
+                };
+                let read_only = request.get("read_only").and_then(|read_only| read_only.as_boolean()).unwrap_or(false);
+                self.mount(&local_path, mountpoint, mount_options, read_only)?;
+            },
+            "unmount" => {
+                let local_path = OsString::from(string_arg(request, "local_path")?);
+                self.unmount(&local_path)?;
+            },
+            "shutdown" => self.stopped.store(true, Ordering::SeqCst),
+            command => return Err(format!("Unknown command {}", command))
+        }
+
+        Ok(response)
+    }
+}
+
+/// Volumes are known by the canonical form of their local path
+fn canonical_path(local_path: &OsString) -> Result<OsString, String> {
+    fs::canonicalize(local_path)
+        .map(|path| path.into_os_string())
+        .map_err(|e| format!("Unable to open {}: {}", local_path.to_string_lossy(), e))
+}
+
+/// Socket of the daemon, in the runtime directory of the user
+pub fn socket_path() -> Result<PathBuf, String> {
+    runtime_dir().map(|runtime_dir| runtime_dir.join("markfs.sock"))
+}
+
+/// Pidfile of the daemon, unless `--pidfile` says otherwise
+pub fn pidfile_path() -> Result<PathBuf, String> {
+    runtime_dir().map(|runtime_dir| runtime_dir.join("markfs.pid"))
+}
+
+/// `$XDG_RUNTIME_DIR`, or else a directory of our own in the temporary directory
+///
+/// Anyone can create the latter first, so it's only used when we own it and
+/// nobody else has access.
+fn runtime_dir() -> Result<PathBuf, String> {
+    if let Some(runtime_dir) = env::var_os("XDG_RUNTIME_DIR") {
+        return Ok(PathBuf::from(runtime_dir));
+    }
+
+    let uid = unsafe { libc::getuid() };
+    let path = env::temp_dir().join(format!("markfs-{}", uid));
+    match fs::DirBuilder::new().mode(0o700).create(&path) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+        Err(e) => return Err(format!("Unable to create {}: {}", path.display(), e))
+    }
+
+    let metadata = fs::symlink_metadata(&path).map_err(|e| format!("Unable to open {}: {}", path.display(), e))?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(format!("{} must be a directory of uid {} that only it can access", path.display(), uid));
+    }
+    Ok(path)
+}
+
+/// Bind the daemon socket, unless another daemon is listening on it
+pub fn bind() -> Result<UnixListener, String> {
+    let path = socket_path()?;
+    if control::ControlClient::connect_to(&path).is_some() {
+        return Err(format!("The daemon is already running, see {}", path.display()));
+    }
+
+    // Left behind by a daemon that didn't exit cleanly
+    if path.exists() {
+        fs::remove_file(&path).map_err(|e| format!("Unable to remove {}: {}", path.display(), e))?;
+    }
+    control::bind(&path).map_err(|e| format!("Unable to create {}: {}", path.display(), e))
+}
+
+/// Which side of `daemonize` we're on
+pub enum Fork {
+    /// The child, which tells the parent once it's ready
+    Child(Ready),
+    /// The parent, with the exit code the child reported, None when it
+    /// exited before it was ready
+    Parent(Option<i32>)
+}
+
+/// Write end of the pipe the parent of `daemonize` waits on
+///
+/// Dropped without `notify`, like when the child fails to start, the
+/// parent sees the pipe close and fails.
+pub struct Ready(libc::c_int);
+
+impl Ready {
+    /// Let the parent exit with `code`, then detach from the terminal
+    ///
+    /// Until now errors still reach the terminal the child was started from.
+    pub fn notify(self, code: i32) {
+        let byte = [code as u8];
+        unsafe { libc::write(self.0, byte.as_ptr() as *const libc::c_void, 1) };
+
+        let dev_null = CString::new("/dev/null").unwrap();
+        unsafe {
+            let fd = libc::open(dev_null.as_ptr(), libc::O_RDWR);
+            if fd >= 0 {
+                for &stdio in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO].iter() {
+                    libc::dup2(fd, stdio);
+                }
+                if fd > libc::STDERR_FILENO {
+                    libc::close(fd);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Ready {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// Fork, the child leaves the session of the terminal and the working
+/// directory, so it doesn't keep a file system busy
+///
+/// The parent waits until the child calls `Ready::notify`. Paths have to be
+/// absolute before calling this.
+pub fn daemonize() -> io::Result<Fork> {
+    let mut fds = [0 as libc::c_int; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (read_fd, write_fd) = (fds[0], fds[1]);
+
+    match unsafe { libc::fork() } {
+        -1 => {
+            let e = io::Error::last_os_error();
+            unsafe {
+                libc::close(read_fd);
+                libc::close(write_fd);
+            }
+            Err(e)
+        },
+        0  => {
+            let root = CString::new("/").unwrap();
+            unsafe {
+                libc::close(read_fd);
+                libc::setsid();
+                libc::chdir(root.as_ptr());
+            }
+            Ok(Fork::Child(Ready(write_fd)))
+        },
+        pid => {
+            unsafe { libc::close(write_fd) };
+            let mut byte = [0u8];
+            let n = loop {
+                let n = unsafe { libc::read(read_fd, byte.as_mut_ptr() as *mut libc::c_void, 1) };
+                if n >= 0 || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                    break n;
+                }
+            };
+            unsafe { libc::close(read_fd) };
+
+            if n == 1 {
+                if byte[0] == 0 {
+                    println!("Running in the background, pid {}", pid);
+                }
+                Ok(Fork::Parent(Some(byte[0] as i32)))
+            } else {
+                println!("The background process {} exited before it was ready", pid);
+                Ok(Fork::Parent(None))
+            }
+        }
+    }
+}
+
+/// Write our pid, unless the file holds the pid of a process that's still running
+pub fn write_pidfile(path: &Path) -> Result<(), String> {
+    let mut content = String::new();
+    if File::open(path).and_then(|mut file| file.read_to_string(&mut content)).is_ok() {
+        if let Ok(pid) = content.trim().parse::<libc::pid_t>() {
+            if unsafe { libc::kill(pid, 0) } == 0 {
+                return Err(format!("Already running as pid {}, see {}", pid, path.display()));
+            }
+        }
+    }
+
+    File::create(path)
+        .and_then(|mut file| writeln!(file, "{}", process::id()))
+        .map_err(|e| format!("Unable to write {}: {}", path.display(), e))
+}
+
+/// Block the termination signals, threads spawned afterwards inherit the mask
+pub fn block_signals() -> libc::sigset_t {
+    unsafe {
+        let mut signals: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        for &signal in [libc::SIGTERM, libc::SIGINT, libc::SIGHUP].iter() {
+            libc::sigaddset(&mut signals, signal);
+        }
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut());
+        signals
+    }
+}
+
+/// Call `shut_down` for every termination signal, until it returns true
+pub fn spawn_signal_handler<F: FnMut() -> bool + Send + 'static>(signals: libc::sigset_t, mut shut_down: F) {
+    thread::spawn(move || {
+        loop {
+            let mut signal = 0;
+            if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+                continue;
+            }
+
+            info!("Received signal {}", signal);
+            if shut_down() {
+                break;
+            }
+        }
+    });
+}
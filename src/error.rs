@@ -1,6 +1,140 @@
+use std::error;
+use std::fmt;
+use std::io;
+use libc;
+use rusqlite;
+use rusqlite::ffi::ErrorCode;
 
+#[cfg(target_os = "macos")]
+const NO_ATTRIBUTE: libc::c_int = libc::ENOATTR;
+#[cfg(not(target_os = "macos"))]
+const NO_ATTRIBUTE: libc::c_int = libc::ENODATA;
 
+/// Everything that can go wrong in a volume, each with the errno FUSE replies with
 #[derive(Debug)]
 pub enum Error {
+    Io(io::Error),
+    /// The metadata database failed
+    Sqlite(rusqlite::Error),
+    /// A remote store failed or returned something unusable
+    Remote(String),
+    /// A replayed action doesn't fit the local tree, holds the conflicting name
+    Conflict(String),
+    NotFound,
+    Exists,
+    NotADirectory,
+    IsADirectory,
+    NotEmpty,
+    /// Over the quota of a subtree, which acts as the size of the device
+    NoSpace,
+    /// Over the quota of the user
+    QuotaExceeded,
+    /// Only the owner or root may do this
+    PermissionDenied,
+    /// The mode bits or ACL deny access
+    AccessDenied,
+    ReadOnly,
+    InvalidArgument,
+    NoAttribute,
+    NotSupported,
+    OutOfRange,
+    ArgumentTooBig,
+    NotImplemented,
+    /// No open file with this handle
+    BadFileHandle
+}
+
+impl Error {
+    /// Convert to libc error
+    pub fn errno(&self) -> libc::c_int {
+        match *self {
+            Error::Io(ref e)         => e.raw_os_error().unwrap_or(libc::EIO),
+            Error::Sqlite(ref e)     => sqlite_errno(e),
+            Error::Remote(_)         => libc::EIO,
+            Error::Conflict(_)       => libc::EBUSY,
+            Error::NotFound          => libc::ENOENT,
+            Error::Exists            => libc::EEXIST,
+            Error::NotADirectory     => libc::ENOTDIR,
+            Error::IsADirectory      => libc::EISDIR,
+            Error::NotEmpty          => libc::ENOTEMPTY,
+            Error::NoSpace           => libc::ENOSPC,
+            Error::QuotaExceeded     => libc::EDQUOT,
+            Error::PermissionDenied  => libc::EPERM,
+            Error::AccessDenied      => libc::EACCES,
+            Error::ReadOnly          => libc::EROFS,
+            Error::InvalidArgument   => libc::EINVAL,
+            Error::NoAttribute       => NO_ATTRIBUTE,
+            Error::NotSupported      => libc::ENOTSUP,
+            Error::OutOfRange        => libc::ERANGE,
+            Error::ArgumentTooBig    => libc::E2BIG,
+            Error::NotImplemented    => libc::ENOSYS,
+            Error::BadFileHandle     => libc::EBADF
+        }
+    }
+}
+
+/// A lookup that may find nothing, with any other failure still an error
+pub fn optional<T>(result: Result<T, Error>) -> Result<Option<T>, Error> {
+    match result {
+        Ok(value)            => Ok(Some(value)),
+        Err(Error::NotFound) => Ok(None),
+        Err(e)               => Err(e)
+    }
+}
+
+/// A busy or full database is worth a retry or some space, anything else is an I/O error
+fn sqlite_errno(e: &rusqlite::Error) -> libc::c_int {
+    match *e {
+        rusqlite::Error::QueryReturnedNoRows => libc::ENOENT,
+        rusqlite::Error::SqliteFailure(ref failure, _) => match failure.code {
+            ErrorCode::DatabaseBusy     => libc::EBUSY,
+            ErrorCode::DatabaseLocked   => libc::EBUSY,
+            ErrorCode::DiskFull         => libc::ENOSPC,
+            ErrorCode::ReadOnly         => libc::EROFS,
+            ErrorCode::PermissionDenied => libc::EACCES,
+            _                           => libc::EIO
+        },
+        _ => libc::EIO
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e)           => write!(f, "{}", e),
+            Error::Sqlite(ref e)       => write!(f, "Metadata database: {}", e),
+            Error::Remote(ref message) => write!(f, "Remote: {}", message),
+            Error::Conflict(ref name)  => write!(f, "Conflicting change to {}", name),
+            _                          => write!(f, "{}", io::Error::from_raw_os_error(self.errno()))
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        "MarkFS error"
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Sqlite(e)
+    }
+}
 
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e)                          => e,
+            Error::Remote(message)                => io::Error::new(io::ErrorKind::Other, message),
+            Error::Sqlite(_) | Error::Conflict(_) => io::Error::new(io::ErrorKind::Other, e.to_string()),
+            e                                     => io::Error::from_raw_os_error(e.errno())
+        }
+    }
 }
@@ -0,0 +1,46 @@
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use sha1::Sha1;
+use storage::{StorageBackend, FileStream};
+use throttle::Throttle;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Copy while hashing, throttled to `bytes_per_second` unless it's 0
+///
+/// Returns the hash as recorded in `file_version.hash`, None when reading
+/// or writing fails.
+pub fn copy_hashed(reader: &mut Read, writer: &mut Write, bytes_per_second: u64) -> Option<String> {
+    let mut sha1 = Sha1::new();
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut throttle = Throttle::new(bytes_per_second);
+
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0)  => break,
+            Ok(n)  => n,
+            Err(_) => return None
+        };
+        sha1.update(&buffer[..n]);
+        if writer.write_all(&buffer[..n]).is_err() {
+            return None;
+        }
+
+        throttle.consume(n);
+    }
+
+    Some(sha1.digest().to_string())
+}
+
+/// Hash of a stored blob, as recorded in `file_version.hash`
+pub fn hash_reader(reader: &mut Read) -> Option<String> {
+    copy_hashed(reader, &mut io::sink(), 0)
+}
+
+pub fn hash_file(storage: &StorageBackend, path: &Path) -> Option<String> {
+    match storage.open(path, 0) {
+        Ok(handle) => hash_reader(&mut FileStream::new(handle)),
+        Err(_)     => None
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::Duration;
+use libc::{O_RDONLY, O_WRONLY, O_TRUNC, O_CREAT, EINTR, EIO};
+use sha1::Sha1;
+use uuid::Uuid;
+use error::Error;
+use metadata::{Metadata, INode, FileVersion};
+use peer::Peer;
+use storage::{StorageBackend, FileStream};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How often a hydration checks whether another one of the same inode finished
+const WAIT_POLL_MS: u64 = 100;
+
+/// A peer by its name, locked while it fetches, so listing the peers
+/// doesn't wait for a download
+type NamedPeer = (String, Arc<Mutex<Box<Peer + Send>>>);
+
+/// Progress of one running hydration
+#[derive(Debug, Clone)]
+pub struct Progress {
+    pub path: PathBuf,
+    pub done: u64,
+    pub total: u64,
+    pub cancelled: bool
+}
+
+/// Running hydrations by inode number, shared with whoever reports or cancels them
+pub type Hydrations = Arc<Mutex<HashMap<u64, Progress>>>;
+
+/// Cancel a running hydration, the open waiting for it fails with EINTR
+pub fn cancel(hydrations: &Hydrations, ino: u64) -> bool {
+    match hydrations.lock().unwrap().get_mut(&ino) {
+        Some(progress) => {
+            progress.cancelled = true;
+            true
+        },
+        None => false
+    }
+}
+
+/// Fetches the content of placeholder inodes from peers
+///
+/// A placeholder is a regular file whose current version is known in the
+/// metadata, with its size and hash, but only stored as an empty file locally.
+///
+/// Clones share their peers and running hydrations, so the control socket
+/// can manage the peers of a mount. The list of peers is only locked to
+/// copy it, never during a download.
+#[derive(Clone)]
+pub struct Hydrator {
+    peers: Arc<Mutex<Vec<NamedPeer>>>,
+    hydrations: Hydrations
+}
+
+impl Hydrator {
+    pub fn new() -> Hydrator {
+        Hydrator {
+            peers: Arc::new(Mutex::new(Vec::new())),
+            hydrations: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+
+    pub fn add_peer(&self, peer: Box<Peer + Send>) {
+        let name = peer.name().to_string();
+        self.peers.lock().unwrap().push((name, Arc::new(Mutex::new(peer))));
+    }
+
+    /// Remove the peers with this name, returns whether there were any
+    pub fn remove_peer(&self, name: &str) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        let count = peers.len();
+        peers.retain(|&(ref peer_name, _)| peer_name != name);
+        peers.len() < count
+    }
+
+    pub fn peer_names(&self) -> Vec<String> {
+        self.peers.lock().unwrap().iter().map(|&(ref name, _)| name.clone()).collect()
+    }
+
+    pub fn hydrations(&self) -> Hydrations {
+        self.hydrations.clone()
+    }
+
+    /// Fill the local file of a placeholder from the first peer holding its content
+    ///
+    /// The file is written in place, so hard links see the content as well.
+    /// On failure or cancellation it is truncated back to an empty placeholder.
+    /// One inode is hydrated once at a time, from an open, the pin queue or the
+    /// replicator. A caller finding another hydration running waits for it,
+    /// and is done when it filled the file.
+    ///
+    /// The download runs on the calling thread. From `open` that is the only
+    /// thread of the FUSE session, so every other request on the mount waits
+    /// until it's done; `markfs progress --cancel` stops a download that hangs.
+    pub fn hydrate(&self, storage: &StorageBackend, inode: &INode, path: &Path) -> Result<(), Error> {
+        if self.wait_for(inode, path) && storage.stat(path).map(|stat| stat.size == inode.size).unwrap_or(false) {
+            self.hydrations.lock().unwrap().remove(&inode.ino);
+            return Ok(());
+        }
+
+        let peers: Vec<NamedPeer> = self.peers.lock().unwrap().clone();
+        let mut result = Err(Error::Io(::std::io::Error::from_raw_os_error(EIO)));
+        for &(ref name, ref peer) in peers.iter() {
+            let fetched = peer.lock().unwrap().fetch(&inode.hash);
+            let mut reader = match fetched {
+                Some(reader) => reader,
+                None         => continue
+            };
+
+            info!("Hydrating {:?} from {}", path, name);
+            result = storage.open(path, O_WRONLY | O_TRUNC | O_CREAT)
+                .and_then(|handle| self.copy(inode, &mut reader, &mut FileStream::new(handle)));
+
+            match result {
+                Ok(_) => break,
+                Err(ref e) if e.errno() == EINTR => break,
+                Err(ref e) => warn!("Hydrating {:?} from {} failed: {:?}", path, name, e)
+            }
+        }
+
+        if result.is_err() {
+            let _ = storage.open(path, O_WRONLY | O_TRUNC | O_CREAT);
+        }
+        self.hydrations.lock().unwrap().remove(&inode.ino);
+        result
+    }
+
+    /// Register a hydration of the inode, after waiting for a running one,
+    /// returns whether there was one
+    fn wait_for(&self, inode: &INode, path: &Path) -> bool {
+        let mut waited = false;
+        loop {
+            {
+                let mut hydrations = self.hydrations.lock().unwrap();
+                if !hydrations.contains_key(&inode.ino) {
+                    hydrations.insert(inode.ino, Progress {
+                        path: path.to_path_buf(),
+                        done: 0,
+                        total: inode.size,
+                        cancelled: false
+                    });
+                    return waited;
+                }
+            }
+            waited = true;
+            thread::sleep(Duration::from_millis(WAIT_POLL_MS));
+        }
+    }
+
+    /// Hydrate every placeholder at or below the inode, returns how many failed
+    pub fn hydrate_subtree(&self, storage: &StorageBackend, metadata: &Metadata, inode: &INode) -> Result<usize, Error> {
+        Ok(metadata.get_placeholders_in(inode)?.iter()
+            .filter(|placeholder| self.hydrate_placeholder(storage, metadata, placeholder).is_err())
+            .count())
+    }
+
+    /// Hydrate the subtrees of the inode ids sent to the queue on a background
+    /// thread, with its own metadata connection
+    ///
+    /// Pinning through the mount queues its downloads here, so the FUSE thread
+    /// doesn't wait for them.
+    pub fn spawn_queue<S: StorageBackend + Send + 'static>(&self, state_dir: &OsString, storage: S) -> Sender<String> {
+        let (sender, receiver) = channel::<String>();
+        let state_dir = state_dir.clone();
+        let hydrator = self.clone();
+
+        thread::spawn(move || {
+            let metadata = match Metadata::new(&state_dir) {
+                Ok(metadata) => metadata,
+                Err(e)       => {
+                    error!("Hydration queue stopped, unable to open the metadata: {}", e);
+                    return;
+                }
+            };
+
+            for id in receiver.iter() {
+                let result = metadata.get_by_id(&id).and_then(|inode| hydrator.hydrate_subtree(&storage, &metadata, &inode));
+                match result {
+                    Ok(0)      => (),
+                    Ok(failed) => warn!("{} pinned files could not be downloaded", failed),
+                    Err(e)     => warn!("Unable to download pinned inode {}: {}", id, e)
+                }
+            }
+        });
+
+        sender
+    }
+
+    /// Hydrate a placeholder and record it in the metadata
+    pub fn hydrate_placeholder(&self, storage: &StorageBackend, metadata: &Metadata, placeholder: &INode) -> Result<(), Error> {
+        let mut path_buf = PathBuf::new();
+        metadata.get_path(placeholder, &mut path_buf)?;
+
+        if placeholder.size > 0 {
+            self.hydrate(storage, placeholder, path_buf.as_path())?;
+        }
+
+        metadata.set_hydrated(placeholder, true)?;
+        Ok(())
+    }
+
+    /// Put the content of an earlier version of a file in place, returns that version
+    ///
+    /// The content is fetched next to the file and checked against its hash
+    /// first. Only then is it copied in place, so hard links and open handles
+    /// see it. When no peer holds it the file is left alone. The caller
+    /// records the restored version, see `WriteVersion::restoring`.
+    pub fn restore(&self, storage: &StorageBackend, metadata: &Metadata, inode: &INode, version: &String) -> Result<FileVersion, Error> {
+        let restored = metadata.get_version(inode, version)?;
+        // Never finished, there's no content to restore
+        if restored.hash.is_empty() {
+            return Err(Error::InvalidArgument);
+        }
+        if inode.hydrated && inode.hash == restored.hash {
+            return Ok(restored);
+        }
+
+        let mut path_buf = PathBuf::new();
+        metadata.get_path(inode, &mut path_buf)?;
+        let fetched_path = path_buf.with_file_name(format!(".{}.markfs-restore-{}", inode.name, Uuid::new_v4()));
+
+        let fetched = INode {
+            size: restored.size,
+            hash: restored.hash.clone(),
+            ..inode.clone()
+        };
+        storage.create(&fetched_path)?;
+        let fetch = if fetched.size > 0 { self.hydrate(storage, &fetched, &fetched_path) } else { Ok(()) };
+        let result = fetch.and_then(|_| {
+            let mut reader = FileStream::new(storage.open(&fetched_path, O_RDONLY)?);
+            let mut writer = FileStream::new(storage.open(path_buf.as_path(), O_WRONLY | O_TRUNC)?);
+            io::copy(&mut reader, &mut writer)?;
+            Ok(())
+        });
+        if let Err(e) = storage.remove(&fetched_path) {
+            warn!("Unable to remove {:?}: {}", fetched_path, e);
+        }
+        result?;
+
+        Ok(restored)
+    }
+
+    fn copy(&self, inode: &INode, reader: &mut Read, writer: &mut Write) -> Result<(), Error> {
+        let mut sha1 = Sha1::new();
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let mut done: u64 = 0;
+
+        loop {
+            let n = reader.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            sha1.update(&buffer[..n]);
+            writer.write_all(&buffer[..n])?;
+            done += n as u64;
+
+            match self.hydrations.lock().unwrap().get_mut(&inode.ino) {
+                Some(ref progress) if progress.cancelled => {
+                    info!("Hydration of {:?} cancelled", progress.path);
+                    return Err(Error::Io(::std::io::Error::from_raw_os_error(EINTR)));
+                },
+                Some(progress) => progress.done = done,
+                None           => ()
+            }
+        }
+
+        if sha1.digest().to_string() != inode.hash {
+            return Err(Error::Remote(format!("Content of {} does not match its hash", inode.id)));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use time::Timespec;
+use metadata::{Metadata, INode};
+use storage::{StorageBackend, FileStream};
+use error::{Error, optional};
+
+/// Per directory list of paths that stay on this device
+pub const IGNORE_FILE: &'static str = ".markfsignore";
+
+/// One line of an ignore file
+struct Pattern {
+    glob: Vec<u8>,
+    negated: bool,
+    directory_only: bool,
+    /// Matched against the path relative to the ignore file, not only the name
+    anchored: bool
+}
+
+/// Patterns of one `.markfsignore`, with gitignore semantics
+pub struct IgnoreFile {
+    patterns: Vec<Pattern>
+}
+
+impl IgnoreFile {
+    pub fn parse(content: &str) -> IgnoreFile {
+        let mut patterns = Vec::new();
+
+        for line in content.lines() {
+            let mut line = line.trim_right_matches(|c| c == ' ' || c == '\r');
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let negated = line.starts_with('!');
+            if negated {
+                line = &line[1..];
+            } else if line.starts_with("\\!") || line.starts_with("\\#") {
+                line = &line[1..];
+            }
+
+            let directory_only = line.ends_with('/');
+            let line = line.trim_right_matches('/');
+            if line.is_empty() {
+                continue;
+            }
+
+            let anchored = line.contains('/');
+            patterns.push(Pattern {
+                glob: line.trim_left_matches('/').as_bytes().to_vec(),
+                negated,
+                directory_only,
+                anchored
+            });
+        }
+
+        IgnoreFile { patterns }
+    }
+
+    /// Whether the path, relative to the directory of this file, is ignored
+    ///
+    /// The last matching pattern decides, None when no pattern matches.
+    pub fn matched(&self, relative: &str, is_directory: bool) -> Option<bool> {
+        let name = relative.rsplit('/').next().unwrap_or(relative);
+
+        self.patterns.iter().rev()
+            .find(|pattern| {
+                (is_directory || !pattern.directory_only)
+                    && glob_match(&pattern.glob, (if pattern.anchored { relative } else { name }).as_bytes())
+            })
+            .map(|pattern| !pattern.negated)
+    }
+}
+
+/// Parsed ignore files by directory id, reloaded when they change
+pub struct Ignores {
+    /// Patterns from the configuration, checked before any ignore file
+    global: IgnoreFile,
+    cache: HashMap<String, (Timespec, IgnoreFile)>
+}
+
+impl Ignores {
+    pub fn new() -> Ignores {
+        Ignores {
+            global: IgnoreFile::parse(""),
+            cache: HashMap::new()
+        }
+    }
+
+    /// Ignore these patterns everywhere, relative to the root like its ignore file
+    ///
+    /// The ignore file at the root can still negate them.
+    pub fn set_global(&mut self, patterns: &[String]) {
+        self.global = IgnoreFile::parse(&patterns.join("\n"));
+    }
+
+    /// Whether the entry `name` in `parent` is ignored, by the ignore file of
+    /// any directory above it or because one of those directories is ignored
+    pub fn is_ignored(&mut self, metadata: &Metadata, storage: &StorageBackend, parent: &INode, name: &str, is_directory: bool) -> Result<bool, Error> {
+        // Directories from the root down to the parent
+        let mut directories = vec![parent.clone()];
+        while directories[0].parent != directories[0].id {
+            match metadata.get_by_id(&directories[0].parent) {
+                Ok(directory)        => directories.insert(0, directory),
+                Err(Error::NotFound) => break,
+                Err(e)               => return Err(e)
+            }
+        }
+
+        // Every directory below the root, then the entry itself
+        let mut names: Vec<(String, bool)> = directories[1..].iter().map(|directory| (directory.name.clone(), true)).collect();
+        names.push((name.to_string(), is_directory));
+
+        for entry in 0..names.len() {
+            let relative: Vec<&str> = names[..entry + 1].iter().map(|&(ref name, _)| name.as_str()).collect();
+            let mut ignored = self.global.matched(&relative.join("/"), names[entry].1).unwrap_or(false);
+            for level in 0..entry + 1 {
+                let relative: Vec<&str> = names[level..entry + 1].iter().map(|&(ref name, _)| name.as_str()).collect();
+                if let Some(matched) = self.load(metadata, storage, &directories[level])?.matched(&relative.join("/"), names[entry].1) {
+                    ignored = matched;
+                }
+            }
+            if ignored {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn load(&mut self, metadata: &Metadata, storage: &StorageBackend, directory: &INode) -> Result<&IgnoreFile, Error> {
+        let ignore_inode = optional(metadata.lookup(directory, &IGNORE_FILE.to_string()))?;
+        let ctime = ignore_inode.as_ref().map_or(Timespec::new(0, 0), |inode| inode.ctime);
+
+        let stale = match self.cache.get(&directory.id) {
+            Some(&(cached, _)) => cached != ctime,
+            None               => true
+        };
+
+        if stale {
+            let mut content = String::new();
+            if let Some(ref inode) = ignore_inode {
+                let mut path_buf = PathBuf::new();
+                metadata.get_path(inode, &mut path_buf)?;
+
+                // A placeholder reads as empty, its patterns apply once it's hydrated
+                if let Ok(handle) = storage.open(path_buf.as_path(), 0) {
+                    let _ = FileStream::new(handle).read_to_string(&mut content);
+                }
+            }
+            self.cache.insert(directory.id.clone(), (ctime, IgnoreFile::parse(&content)));
+        }
+
+        Ok(&self.cache[&directory.id].1)
+    }
+}
+
+/// Match a gitignore glob, `*` and `?` stop at `/`, `**` doesn't
+fn glob_match(glob: &[u8], text: &[u8]) -> bool {
+    if glob.is_empty() {
+        return text.is_empty();
+    }
+
+    if glob.starts_with(b"**") {
+        let rest = &glob[2..];
+        if rest.is_empty() {
+            return true;
+        }
+        if rest[0] == b'/' {
+            // Zero or more directories
+            let rest = &rest[1..];
+            return glob_match(rest, text)
+                || text.iter().enumerate().any(|(i, &c)| c == b'/' && glob_match(rest, &text[i + 1..]));
+        }
+    }
+
+    match glob[0] {
+        b'*' => {
+            let rest = &glob[1..];
+            for i in 0..text.len() + 1 {
+                if glob_match(rest, &text[i..]) {
+                    return true;
+                }
+                if i < text.len() && text[i] == b'/' {
+                    break;
+                }
+            }
+            false
+        },
+        b'?' => !text.is_empty() && text[0] != b'/' && glob_match(&glob[1..], &text[1..]),
+        b'[' => {
+            if text.is_empty() || text[0] == b'/' {
+                return false;
+            }
+            match match_class(&glob[1..], text[0]) {
+                Some((matched, length)) => matched && glob_match(&glob[1 + length..], &text[1..]),
+                // No closing bracket, so a literal
+                None => text[0] == b'[' && glob_match(&glob[1..], &text[1..])
+            }
+        },
+        b'\\' if glob.len() > 1 => !text.is_empty() && text[0] == glob[1] && glob_match(&glob[2..], &text[1..]),
+        c => !text.is_empty() && text[0] == c && glob_match(&glob[1..], &text[1..])
+    }
+}
+
+/// Match a character class after its `[`, returns the result and the length up to and including `]`
+fn match_class(class: &[u8], c: u8) -> Option<(bool, usize)> {
+    let negated = !class.is_empty() && (class[0] == b'!' || class[0] == b'^');
+    let mut i = if negated { 1 } else { 0 };
+    let mut matched = false;
+    let mut first = true;
+
+    while i < class.len() {
+        if class[i] == b']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+
+        if i + 2 < class.len() && class[i + 1] == b'-' && class[i + 2] != b']' {
+            matched |= class[i] <= c && c <= class[i + 2];
+            i += 3;
+        } else {
+            matched |= class[i] == c;
+            i += 1;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob_match, IgnoreFile};
+
+    #[test]
+    fn globs() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("",          "",            true),
+            ("",          "a",           false),
+            ("foo",       "foo",         true),
+            ("foo",       "foobar",      false),
+            ("*",         "",            true),
+            ("*.tmp",     "a.tmp",       true),
+            ("*.tmp",     "a.tmpx",      false),
+            ("*.tmp",     "dir/a.tmp",   false),
+            ("?.txt",     "a.txt",       true),
+            ("?.txt",     "ab.txt",      false),
+            ("?",         "/",           false),
+            ("**",        "a/b/c",       true),
+            ("**/build",  "build",       true),
+            ("**/build",  "a/b/build",   true),
+            ("**/build",  "a/builder",   false),
+            ("a/**",      "a/x/y",       true),
+            ("a/**/b",    "a/b",         true),
+            ("a/**/b",    "a/x/y/b",     true),
+            ("a/**/b",    "b/x/a/b",     false),
+            ("[abc].c",   "b.c",         true),
+            ("[!abc].c",  "b.c",         false),
+            ("[^abc].c",  "d.c",         true),
+            ("[a-z]x",    "qx",          true),
+            ("[a-z]x",    "Qx",          false),
+            ("[]]",       "]",           true),
+            ("[ab",       "[ab",         true),
+            ("[a]",       "/",           false),
+            ("\\*",       "*",           true),
+            ("\\*",       "a",           false),
+        ];
+
+        for &(glob, text, expected) in cases {
+            assert_eq!(glob_match(glob.as_bytes(), text.as_bytes()), expected, "{:?} against {:?}", glob, text);
+        }
+    }
+
+    #[test]
+    fn ignore_files() {
+        let ignore_file = IgnoreFile::parse("# build output\n*.log\n!keep.log\nbuild/\n/docs/tmp\n\\#hash\n");
+        let cases: &[(&str, bool, Option<bool>)] = &[
+            ("a.log",      false, Some(true)),
+            ("sub/a.log",  false, Some(true)),
+            ("keep.log",   false, Some(false)),
+            ("build",      true,  Some(true)),
+            ("build",      false, None),
+            ("docs/tmp",   true,  Some(true)),
+            ("x/docs/tmp", true,  None),
+            ("#hash",      false, Some(true)),
+            ("README",     false, None),
+        ];
+
+        for &(relative, is_directory, expected) in cases {
+            assert_eq!(ignore_file.matched(relative, is_directory), expected, "{:?}", relative);
+        }
+    }
+}
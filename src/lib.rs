@@ -0,0 +1,70 @@
+//! MarkFS volumes, mounted over FUSE or read and written through `Volume`
+//!
+//! ```no_run
+//! use std::ffi::OsString;
+//! use std::path::Path;
+//!
+//! let mut volume = markfs::Volume::open(&OsString::from("/srv/photos")).unwrap();
+//! volume.write(Path::new("notes.txt"), 0, b"Hello").unwrap();
+//! for inode in volume.list(Path::new("")).unwrap() {
+//!     println!("{} {}", inode.name, inode.size);
+//! }
+//! ```
+
+extern crate fuse;
+extern crate time;
+extern crate libc;
+extern crate rusqlite;
+extern crate uuid;
+extern crate sha1;
+extern crate bincode;
+extern crate rustc_serialize;
+extern crate futures;
+extern crate rusoto_core;
+extern crate rusoto_s3;
+#[macro_use]
+extern crate log;
+
+mod types;
+
+mod error;
+mod markfs;
+mod metadata;
+mod schema;
+mod storage;
+mod local;
+mod memory;
+mod action_runner;
+mod actions;
+mod hash;
+mod hydrate;
+mod ignore;
+mod peer;
+mod permission;
+mod scrubber;
+mod replicator;
+mod sync_rules;
+mod s3;
+mod uploader;
+mod config;
+mod throttle;
+mod control;
+mod metrics;
+mod volume;
+mod mounted_volume;
+mod daemon;
+
+/// The `markfs` command line
+#[doc(hidden)]
+pub mod commands;
+
+pub use volume::Volume;
+pub use markfs::{MarkFS, ExternalSymlinkPolicy};
+pub use mounted_volume::MountedVolume;
+pub use metadata::{Metadata, INode, INodeKind, FileVersion, AuditEntry};
+pub use storage::{StorageBackend, FileHandle};
+pub use local::LocalFileOperations;
+pub use memory::MemoryStorage;
+pub use config::{VolumeConfig, ConfigError};
+pub use error::Error;
+pub use metrics::{Metrics, OpStats, Transfers, RecentError};
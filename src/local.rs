@@ -1,61 +1,174 @@
 use std;
-use std::path::Path;
+use std::ffi::{CString, OsString};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use std::fs::{OpenOptions, File};
 use std::io::SeekFrom;
 use std::io::prelude::*;
-//use libc::O_RDONLY;
+use libc;
+use libc::{O_ACCMODE, O_WRONLY, O_RDWR, O_APPEND, O_TRUNC, O_CREAT};
+use time::Timespec;
 
-use markfs::FileHandle;
+use error::Error;
+use storage::{StorageBackend, FileHandle, Stat, StatKind, Capacity};
 
 pub struct LocalFileHandle {
 	file: File
 }
 
 impl LocalFileHandle {
-	pub fn new(path: &Path, _flags: i32) -> LocalFileHandle {
+	pub fn new(path: &Path, flags: i32) -> Result<LocalFileHandle, Error> {
 		let mut options = OpenOptions::new();
 
-		//if flags & O_RDONLY == O_RDONLY {
-			options.read(true);
-		//}
-
-		LocalFileHandle {
-			file: options.open(path).unwrap()
+		match flags & O_ACCMODE {
+			O_WRONLY => { options.write(true); },
+			O_RDWR   => { options.read(true).write(true); },
+			_        => { options.read(true); }
+		}
+		if flags & O_APPEND == O_APPEND {
+			options.append(true);
+		}
+		if flags & O_TRUNC == O_TRUNC && flags & O_ACCMODE != 0 {
+			options.truncate(true);
 		}
+		if flags & O_CREAT == O_CREAT && flags & O_ACCMODE != 0 {
+			options.create(true);
+		}
+
+		Ok(LocalFileHandle {
+			file: options.open(path)?
+		})
 	}
 }
 
 impl FileHandle for LocalFileHandle {
-	fn read(&mut self, offset: i64, size: u32) -> Result<Vec<u8>, ()> {
-        self.file.seek(SeekFrom::Start(offset as u64)).unwrap();
-
-        let mut data = Vec::<u8>::with_capacity(size as usize);
-        unsafe { data.set_len(size as usize) };
-
-        match self.file.read(&mut data) {
-        	Ok(n) => {
-        		data.truncate(n);
-        		Ok(data)
-        	},
-        	Err(_e) => Err(())
+	fn read(&mut self, offset: u64, size: u32) -> Result<Vec<u8>, Error> {
+        self.file.seek(SeekFrom::Start(offset))?;
+
+        let mut data = vec![0u8; size as usize];
+        let mut n = 0;
+
+        // Fill the buffer, a short read only happens at the end of the file
+        while n < data.len() {
+        	match self.file.read(&mut data[n..])? {
+        		0 => break,
+        		read => n += read
+        	}
         }
+        data.truncate(n);
+        Ok(data)
+	}
+
+	fn write(&mut self, offset: u64, data: &[u8]) -> Result<u32, Error> {
+		self.file.seek(SeekFrom::Start(offset))?;
+		self.file.write_all(data)?;
+		Ok(data.len() as u32)
+	}
+
+	fn set_len(&mut self, size: u64) -> Result<(), Error> {
+		Ok(self.file.set_len(size)?)
 	}
 }
 
-pub struct LocalFileOperations;
+/// Stores the tree of the volume in a local directory
+pub struct LocalFileOperations {
+	root: PathBuf
+}
 
 impl LocalFileOperations {
-	pub fn create_dir(path: &Path) -> Result<(), ()> {
-		match std::fs::create_dir(path) {
-			Ok(_)  => Ok(()),
-			Err(_) => Err(())
+	pub fn new(local_path: &OsString) -> LocalFileOperations {
+		LocalFileOperations {
+			root: PathBuf::from(local_path)
 		}
 	}
 
-	pub fn rename(old_path: &Path, new_path: &Path) -> Result<(), ()> {
-		match std::fs::rename(old_path, new_path) {
-			Ok(_)  => Ok(()),
-			Err(_) => Err(())
+	fn full_path(&self, path: &Path) -> PathBuf {
+		self.root.join(path)
+	}
+}
+
+impl StorageBackend for LocalFileOperations {
+	fn open(&self, path: &Path, flags: i32) -> Result<Box<FileHandle + Send>, Error> {
+		Ok(Box::new(LocalFileHandle::new(&self.full_path(path), flags)?))
+	}
+
+	fn create(&self, path: &Path) -> Result<(), Error> {
+		OpenOptions::new().write(true).create_new(true).open(self.full_path(path))?;
+		Ok(())
+	}
+
+	fn create_dir(&self, path: &Path) -> Result<(), Error> {
+		Ok(std::fs::create_dir(self.full_path(path))?)
+	}
+
+	fn rename(&self, old_path: &Path, new_path: &Path) -> Result<(), Error> {
+		Ok(std::fs::rename(self.full_path(old_path), self.full_path(new_path))?)
+	}
+
+	fn remove(&self, path: &Path) -> Result<(), Error> {
+		let full_path = self.full_path(path);
+
+		if std::fs::symlink_metadata(&full_path)?.is_dir() {
+			Ok(std::fs::remove_dir(full_path)?)
+		} else {
+			Ok(std::fs::remove_file(full_path)?)
 		}
 	}
-}
\ No newline at end of file
+
+	fn link(&self, path: &Path, new_path: &Path) -> Result<(), Error> {
+		Ok(std::fs::hard_link(self.full_path(path), self.full_path(new_path))?)
+	}
+
+	fn stat(&self, path: &Path) -> Result<Stat, Error> {
+		let metadata = std::fs::symlink_metadata(self.full_path(path))?;
+
+		let kind = if metadata.is_dir() {
+			StatKind::Directory
+		} else if metadata.is_file() {
+			StatKind::RegularFile
+		} else {
+			StatKind::Other
+		};
+		let mtime = match metadata.modified()?.duration_since(std::time::UNIX_EPOCH) {
+			Ok(duration) => Timespec::new(duration.as_secs() as i64, duration.subsec_nanos() as i32),
+			Err(_)       => Timespec::new(0, 0)
+		};
+
+		Ok(Stat {
+			kind,
+			size: metadata.len(),
+			mtime
+		})
+	}
+
+	fn list(&self, path: &Path) -> Result<Vec<OsString>, Error> {
+		let mut names = Vec::new();
+		for entry in std::fs::read_dir(self.full_path(path))? {
+			names.push(entry?.file_name());
+		}
+		Ok(names)
+	}
+
+	fn capacity(&self) -> Result<Capacity, Error> {
+		let path = match CString::new(self.root.as_os_str().as_bytes()) {
+			Ok(path) => path,
+			Err(e)   => return Err(Error::Io(e.into()))
+		};
+
+		let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+		if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+			return Err(Error::Io(std::io::Error::last_os_error()));
+		}
+
+		Ok(Capacity {
+			blocks: stat.f_blocks as u64,
+			bfree: stat.f_bfree as u64,
+			bavail: stat.f_bavail as u64,
+			files: stat.f_files as u64,
+			ffree: stat.f_ffree as u64,
+			bsize: stat.f_bsize as u32,
+			frsize: if stat.f_frsize > 0 { stat.f_frsize as u32 } else { stat.f_bsize as u32 }
+		})
+	}
+}
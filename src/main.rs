@@ -1,31 +1,13 @@
-extern crate fuse;
-extern crate time;
-extern crate libc;
-extern crate rusqlite;
-extern crate uuid;
+extern crate markfs;
+extern crate env_logger;
 
 use std::env;
 use std::ffi::OsString;
-use markfs::MarkFS;
-
-mod types;
-
-mod error;
-mod markfs;
-mod metadata;
-mod file;
-mod local;
 
 fn main () {
+    // Logging is configured with RUST_LOG, like RUST_LOG=markfs=debug
+    env_logger::init().unwrap();
     let args: Vec<OsString> = env::args_os().collect();
 
-    if args.len() != 3 {
-        println!("Usage: {:?} <local_path> <mountpoint>", &args[0]);
-        ::std::process::exit(-1);
-    }
-
-    let local_path = &args[1];
-    let mountpoint = &args[2];
-
-    fuse::mount(MarkFS::new(local_path), mountpoint, &[]).unwrap();
+    ::std::process::exit(markfs::commands::run(&args[1..]));
 }
@@ -1,41 +1,369 @@
 use std::ffi::{OsStr, OsString};
-use std::collections::HashMap;
-use std::path::PathBuf;
-use fuse::{Filesystem, Request, FileType, FileAttr, ReplyEntry, ReplyAttr, ReplyDirectory, ReplyOpen, ReplyEmpty, ReplyData};
-use time::Timespec;
-use libc::{ENOENT, ENOSYS};
-use metadata::{Metadata, INode, INodeKind};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+use fuse::{Filesystem, Request, FileType, FileAttr, ReplyEntry, ReplyAttr, ReplyDirectory, ReplyOpen, ReplyEmpty, ReplyData, ReplyXattr, ReplyCreate, ReplyWrite, ReplyStatfs};
+use time::{self, Timespec};
+use libc::{ENOENT, ENOSYS, EINVAL, EPERM, ERANGE, EACCES, EROFS, O_ACCMODE, O_RDONLY, O_WRONLY, O_TRUNC};
+use uuid::Uuid;
+use metadata::{Metadata, INode, INodeKind, Ownership, QuotaKind};
+use permission::{self, Acl, R_OK, W_OK, X_OK};
+use actions::{CreateSymlink, SetXattr, RemoveXattr, check_xattr_name};
+use error::{Error, optional};
+use hydrate::{Hydrator, Hydrations};
+use sync_rules::{SyncRules, SyncConfig, SharedSyncRules, ExcludedMode};
+use storage::StorageBackend;
+use config::{VolumeConfig, IdMap};
+use volume::{Volume, OpenFiles};
+use uploader::Upload;
+use action_runner::ActionRunner;
+use metrics::Metrics;
 
-use local::LocalFileHandle;
-use local::LocalFileOperations;
+const NAME_MAX: u32 = 255;
 
-const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
+/// Virtual extended attribute to pin an inode, see `Metadata::set_pinned`
+const PINNED_XATTR: &'static str = "user.markfs.pinned";
 
-pub trait FileHandle {
-    fn read(&mut self, offset: i64, size: u32) -> Result<Vec<u8>, ()>;
+/// Number of files considered per eviction round
+const EVICTION_BATCH: u32 = 100;
+
+/// How to store symlinks with an absolute target outside the mount
+///
+/// Absolute targets inside the mount are always stored relative to the link,
+/// so they resolve on every device, wherever the volume is mounted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExternalSymlinkPolicy {
+    /// Store the target as given, it may dangle on other devices
+    Keep,
+    /// Refuse to create the symlink
+    Reject
 }
 
-pub struct MarkFS {
-    local_path: OsString,
-    metadata: Metadata,
-    open_fh: HashMap<u64, Box<FileHandle>>,
-    last_fh: u64
+/// FUSE adapter of a `Volume`, adds permissions, the cache and symlink policies
+pub struct MarkFS<S: StorageBackend> {
+    volume: Volume<S>,
+    mountpoint: PathBuf,
+    external_symlink_policy: ExternalSymlinkPolicy,
+    cache_budget: Option<u64>,
+    sync_rules: SharedSyncRules,
+    attr_ttl: Timespec,
+    entry_ttl: Timespec,
+    id_map: IdMap,
+    /// Groups of each host user and primary group, with the time they were looked up
+    groups: RefCell<HashMap<(u32, u32), (Timespec, Vec<u32>)>>,
+    metrics: Metrics
 }
 
-impl MarkFS {
-    pub fn new(local_path: &OsString) -> MarkFS {
-        MarkFS {
-            local_path: local_path.clone(),
-            metadata: Metadata::new(&local_path),
-            open_fh: HashMap::new(),
-            last_fh: 0
+impl<S: StorageBackend> MarkFS<S> {
+    pub fn new(metadata: Metadata, storage: S, mountpoint: &OsString) -> Result<MarkFS<S>, Error> {
+        let sync_rules = SyncRules::load(&metadata, &SyncConfig::default())?;
+        let metrics = Metrics::new();
+        let mut volume = Volume::new(metadata, storage);
+        volume.set_metrics(metrics.clone());
+
+        Ok(MarkFS {
+            volume: volume,
+            mountpoint: Path::new(mountpoint).canonicalize().unwrap_or(PathBuf::from(mountpoint)),
+            external_symlink_policy: ExternalSymlinkPolicy::Keep,
+            cache_budget: None,
+            sync_rules: Arc::new(Mutex::new(sync_rules)),
+            attr_ttl: Timespec::new(1, 0),
+            entry_ttl: Timespec::new(1, 0),
+            id_map: IdMap::default(),
+            groups: RefCell::new(HashMap::new()),
+            metrics: metrics
+        })
+    }
+
+    /// Apply the settings of the configuration file that concern the mount
+    pub fn configure(&mut self, config: &VolumeConfig) {
+        self.attr_ttl = config.attr_ttl;
+        self.entry_ttl = config.entry_ttl;
+        self.id_map = config.id_map.clone();
+        self.set_external_symlink_policy(config.external_symlink_policy);
+        if let Some(cache_budget) = config.cache_budget {
+            self.set_cache_budget(cache_budget);
         }
+        match SyncRules::load(self.volume.metadata(), &config.sync) {
+            Ok(sync_rules) => *self.sync_rules.lock().unwrap() = sync_rules,
+            Err(e)         => warn!("Unable to read the sync rules, keeping the ones of the volume: {}", e)
+        }
+        self.volume.configure(config);
+    }
+
+    /// Id of the requesting user as stored in the volume
+    fn uid(&self, req: &Request) -> u32 {
+        self.id_map.uid_to_volume(req.uid())
+    }
+
+    fn gid(&self, req: &Request) -> u32 {
+        self.id_map.gid_to_volume(req.gid())
+    }
+
+    /// Primary and supplementary groups of the requesting user as stored in the volume
+    ///
+    /// Looked up again once the attribute TTL expired, like the kernel does
+    /// with the attributes themselves.
+    fn gids(&self, req: &Request) -> Vec<u32> {
+        let now = time::get_time();
+        let ttl = time::Duration::seconds(self.attr_ttl.sec) + time::Duration::nanoseconds(self.attr_ttl.nsec as i64);
+        let key = (req.uid(), req.gid());
+        let cached = match self.groups.borrow().get(&key) {
+            Some(&(looked_up, ref groups)) if now - looked_up < ttl => Some(groups.clone()),
+            _                                                       => None
+        };
+        let groups = match cached {
+            Some(groups) => groups,
+            None         => {
+                let groups = permission::host_groups(req.uid(), req.gid());
+                self.groups.borrow_mut().insert(key, (now, groups.clone()));
+                groups
+            }
+        };
+
+        let mut gids = vec![self.gid(req)];
+        for gid in groups.into_iter().map(|gid| self.id_map.gid_to_volume(gid)) {
+            if !gids.contains(&gid) {
+                gids.push(gid);
+            }
+        }
+        gids
+    }
+
+    /// Counters and latencies of every operation, shared with the control socket
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    /// Fetch the content of placeholders with this hydrator
+    pub fn set_hydrator(&mut self, hydrator: Hydrator) {
+        self.volume.set_hydrator(hydrator);
+    }
+
+    /// Limit the locally stored content, least recently accessed files
+    /// that a remote also holds are turned back into placeholders
+    pub fn set_cache_budget(&mut self, cache_budget: u64) {
+        self.cache_budget = Some(cache_budget);
+    }
+
+    /// Evict until the hydrated content fits in the cache budget
+    ///
+    /// Dirty files are never synced, so only open files need to be skipped.
+    fn evict(&mut self) {
+        let cache_budget = match self.cache_budget {
+            Some(cache_budget) => cache_budget,
+            None               => return
+        };
+
+        let (mut hydrated_size, candidates) = match self.eviction_candidates(cache_budget) {
+            Ok(Some(candidates)) => candidates,
+            Ok(None)             => return,
+            Err(e)               => {
+                warn!("Unable to evict from the cache: {}", e);
+                return;
+            }
+        };
+
+        let open_ino: HashSet<u64> = self.volume.open_files().lock().unwrap().values().cloned().collect();
+        // Hard links share the content, it only frees its size once
+        let mut evicted = HashSet::new();
+        for inode in candidates {
+            if hydrated_size <= cache_budget {
+                break;
+            }
+            if open_ino.contains(&inode.ino) || !evicted.insert(inode.ino) {
+                continue;
+            }
+
+            let path_buf = match self.volume.storage_path(&inode) {
+                Ok(path_buf) => path_buf,
+                Err(_)       => continue
+            };
+
+            if self.volume.storage().open(path_buf.as_path(), O_WRONLY | O_TRUNC).is_ok() && self.volume.metadata().set_hydrated(&inode, false).is_ok() {
+                debug!("Evicted {:?} from the cache", path_buf);
+                hydrated_size -= inode.size;
+            }
+        }
+    }
+
+    /// The hydrated size and the files to evict first, None when within the budget
+    fn eviction_candidates(&self, cache_budget: u64) -> Result<Option<(u64, Vec<INode>)>, Error> {
+        let hydrated_size = self.volume.metadata().hydrated_size()?;
+        if hydrated_size <= cache_budget {
+            return Ok(None);
+        }
+        Ok(Some((hydrated_size, self.volume.metadata().get_eviction_candidates(EVICTION_BATCH)?)))
+    }
+
+    /// Whether the selective sync rules leave the inode out of the mount
+    fn hidden(&self, inode: &INode) -> Result<bool, Error> {
+        let sync_rules = self.sync_rules.lock().unwrap();
+        if sync_rules.excluded_mode != ExcludedMode::Hide || sync_rules.is_empty() {
+            return Ok(false);
+        }
+        Ok(!sync_rules.is_visible(&self.volume.metadata().get_volume_path(inode)?))
+    }
+
+    /// The rules deciding what is hidden, reloaded by the control socket
+    pub fn sync_rules(&self) -> SharedSyncRules {
+        self.sync_rules.clone()
+    }
+
+    /// Running hydrations, to report progress or cancel them from another thread
+    pub fn hydrations(&self) -> Hydrations {
+        self.volume.hydrations()
+    }
+
+    /// Runs the actions of the mount, see `Volume::action_runner`
+    pub fn action_runner(&self) -> ActionRunner {
+        self.volume.action_runner()
+    }
+
+    /// Inode numbers by open file handle, see `Volume::open_files`
+    pub fn open_files(&self) -> OpenFiles {
+        self.volume.open_files()
+    }
+
+    /// Download pinned subtrees in the background, see `Volume::set_hydrate_queue`
+    pub fn set_hydrate_queue(&mut self, hydrate_queue: Sender<String>) {
+        self.volume.set_hydrate_queue(hydrate_queue);
+    }
+
+    /// Queue every newly written version for upload
+    pub fn set_upload_queue(&mut self, upload_queue: Sender<Upload>) {
+        self.volume.set_upload_queue(upload_queue);
+    }
+
+    pub fn set_external_symlink_policy(&mut self, policy: ExternalSymlinkPolicy) {
+        self.external_symlink_policy = policy;
+    }
+
+    /// Apply the symlink policy to a target, returns the target to store
+    fn symlink_target(&self, parent: &INode, link: &Path) -> Result<Option<String>, Error> {
+        if link.is_relative() {
+            return Ok(link.to_str().map(|target| target.to_string()));
+        }
+
+        match link.strip_prefix(&self.mountpoint) {
+            Ok(inside) => {
+                // Walk up from the directory of the link to the root of the mount
+                let depth = self.volume.storage_path(parent)?.components().count();
+
+                let mut target = PathBuf::new();
+                for _ in 0..depth {
+                    target.push("..");
+                }
+                target.push(inside);
+                if target.as_os_str().is_empty() {
+                    target.push(".");
+                }
+                Ok(target.to_str().map(|target| target.to_string()))
+            },
+            Err(_) => {
+                match self.external_symlink_policy {
+                    ExternalSymlinkPolicy::Keep   => Ok(link.to_str().map(|target| target.to_string())),
+                    ExternalSymlinkPolicy::Reject => Ok(None)
+                }
+            }
+        }
+    }
+
+    fn get_acl(&self, inode: &INode, name: &str) -> Result<Option<Acl>, Error> {
+        Ok(self.volume.metadata().get_xattr(inode, &name.to_string())?.and_then(|data| Acl::parse(&data)))
+    }
+
+    /// Check the mode bits or access ACL against the uid and gid of the request
+    fn check_access(&self, req: &Request, inode: &INode, mask: u32) -> Result<(), Error> {
+        let acl = self.get_acl(inode, permission::ACL_ACCESS)?;
+        if permission::check_access(inode, acl.as_ref(), self.uid(req), &self.gids(req), mask) {
+            Ok(())
+        } else {
+            Err(Error::AccessDenied)
+        }
+    }
+
+    /// Whether the request may remove or rename the entry in its parent
+    fn check_removal(&self, req: &Request, parent: &INode, inode: &INode) -> Result<(), Error> {
+        self.check_access(req, parent, W_OK | X_OK)?;
+        if permission::check_sticky(parent, inode, self.uid(req)) {
+            Ok(())
+        } else {
+            Err(Error::AccessDenied)
+        }
+    }
+
+    /// Pins keep content on this device, the owner and anyone who may write the file can set them
+    fn check_pin_access(&self, req: &Request, inode: &INode) -> Result<(), Error> {
+        let uid = self.uid(req);
+        if uid == 0 || uid == inode.uid {
+            return Ok(());
+        }
+        self.check_access(req, inode, W_OK)
+    }
+
+    /// User attributes follow the file permissions, only the owner may change ACLs
+    fn check_xattr_access(&self, req: &Request, inode: &INode, name: &str, write: bool) -> Result<(), Error> {
+        if !permission::is_acl_name(name) {
+            self.check_access(req, inode, if write { W_OK } else { R_OK })
+        } else if !write || self.uid(req) == 0 || self.uid(req) == inode.uid {
+            Ok(())
+        } else {
+            Err(Error::AccessDenied)
+        }
+    }
+
+    /// A new inode takes the access ACL from the default ACL of its parent,
+    /// directories take the default ACL as well
+    ///
+    /// Set by `uid` through `SetXattr` actions, which mirror the access ACL in the mode.
+    fn inherit_acl(&mut self, uid: u32, parent: &INode, inode: INode) -> Result<INode, Error> {
+        let default_acl = match self.get_acl(parent, permission::ACL_DEFAULT)? {
+            Some(acl) => acl,
+            None      => return Ok(inode)
+        };
+
+        let mut acls = vec![(permission::ACL_ACCESS, default_acl.inherit(inode.mode))];
+        if inode.kind.is_directory() {
+            acls.push((permission::ACL_DEFAULT, default_acl));
+        }
+
+        let local_only = self.volume.is_ignored_inode(&inode)?;
+        for (name, acl) in acls {
+            let mut action = SetXattr {
+                id: inode.id.clone(),
+                name: name.to_string(),
+                value: acl.to_bytes(),
+                flags: 0
+            };
+            self.volume.run_action(uid, &mut action, local_only)?;
+        }
+        self.volume.metadata().lookup(parent, &inode.name)
+    }
+
+    /// The inode number of the parent and the visible children of a directory
+    fn directory_entries(&self, inode: &INode) -> Result<(u64, Vec<INode>), Error> {
+        // Like the root, a directory with a missing parent is its own parent
+        let parent_ino = optional(self.volume.metadata().get_by_id(&inode.parent))?.map_or(inode.ino, |parent| parent.ino);
+
+        let children = self.volume.metadata().get_children(inode)?;
+        let sync_rules = self.sync_rules.lock().unwrap();
+        if sync_rules.excluded_mode != ExcludedMode::Hide || sync_rules.is_empty() {
+            return Ok((parent_ino, children));
+        }
+
+        // One path lookup for the directory instead of one per child
+        let path = self.volume.metadata().get_volume_path(inode)?;
+        Ok((parent_ino, children.into_iter().filter(|child| sync_rules.is_visible(&path.join(&child.name))).collect()))
     }
 
     fn inode_kind_to_file_type(&self, kind: &INodeKind) -> FileType {
         match *kind {
             INodeKind::Directory   => FileType::Directory,
-            INodeKind::RegularFile => FileType::RegularFile
+            INodeKind::RegularFile => FileType::RegularFile,
+            INodeKind::Symlink     => FileType::Symlink
         }
     }
 
@@ -49,235 +377,941 @@ impl MarkFS {
             ctime: inode.ctime,
             crtime: inode.crtime,
             kind: self.inode_kind_to_file_type(&inode.kind),
-            perm: 0o775,
+            perm: inode.mode as u16,
             nlink: inode.nlink,
-            uid: 501,
-            gid: 20,
+            uid: self.id_map.uid_to_local(inode.uid),
+            gid: self.id_map.gid_to_local(inode.gid),
             rdev: 0,
             flags: 0
         }
     }
-
-    fn get_path(&self, inode: &INode, path_buf: &mut PathBuf) {
-        if inode.ino == 1 {
-            path_buf.push(&self.local_path);
-        } else {
-            let parent_inode = self.metadata.get_by_id(&inode.parent).unwrap();
-            self.get_path(&parent_inode, path_buf);
-            path_buf.push(&inode.name);
-        }
-    }
 }
 
-impl Filesystem for MarkFS {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let parent_inode = match self.metadata.get_by_ino(parent) {
-            Some(inode) => inode,
-            None => {
-                reply.error(ENOENT);
+impl<S: StorageBackend> Filesystem for MarkFS<S> {
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let op = self.metrics.operation("lookup", parent, None);
+        let parent_inode = match self.volume.metadata().get_by_ino(parent) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
                 return;
             }
         };
+        if let Err(e) = self.check_access(req, &parent_inode, X_OK) {
+            reply.error(op.fail(e.errno()));
+            return;
+        }
         let name_string = match name.to_str() {
             Some(slice) => slice.to_string(),
             None => {
-                reply.error(ENOENT);
+                reply.error(op.fail(ENOENT));
                 return;
             }
         };
 
-        match self.metadata.lookup(&parent_inode, &name_string) {
-            Some(inode) => {
-                reply.entry(&TTL, &self.inode_to_fileattr(inode), 0);
+        let inode = match self.volume.metadata().lookup(&parent_inode, &name_string) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+        match self.hidden(&inode) {
+            Ok(true) => {
+                reply.error(op.fail(ENOENT));
             },
-            None => {
-                reply.error(ENOENT);
+            Ok(false) => {
+                reply.entry(&self.entry_ttl, &self.inode_to_fileattr(inode), 0);
+            },
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
             }
         }
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        match self.metadata.get_by_ino(ino) {
-            Some(inode) => {
-                reply.attr(&TTL, &self.inode_to_fileattr(inode));
+        let op = self.metrics.operation("getattr", ino, None);
+        match self.volume.metadata().get_by_ino(ino) {
+            Ok(inode) => {
+                reply.attr(&self.attr_ttl, &self.inode_to_fileattr(inode));
             },
-            None => { 
-                reply.error(ENOENT);
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
             }
         }
     }
 
-    fn mkdir(&mut self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32, reply: ReplyEntry) {
-        let parent_inode = match self.metadata.get_by_ino(_parent) {
-            Some(inode) => inode,
-            None => {
-                reply.error(ENOENT);
+    fn setattr(&mut self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<Timespec>, mtime: Option<Timespec>, _fh: Option<u64>, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, _flags: Option<u32>, reply: ReplyAttr) {
+        let op = self.metrics.operation("setattr", ino, _fh);
+        if self.volume.is_read_only() {
+            reply.error(op.fail(EROFS));
+            return;
+        }
+        let inode = match self.volume.metadata().get_by_ino(ino) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+
+        let uid = uid.map(|uid| self.id_map.uid_to_volume(uid));
+        let gid = gid.map(|gid| self.id_map.gid_to_volume(gid));
+        let is_root = self.uid(req) == 0;
+        let is_owner = self.uid(req) == inode.uid;
+
+        // Only root may give files away, the owner may change the group to one of its own
+        if let Some(uid) = uid {
+            if uid != inode.uid && !is_root {
+                reply.error(op.fail(EPERM));
+                return;
+            }
+        }
+        if let Some(gid) = gid {
+            if gid != inode.gid && !is_root && !(is_owner && self.gids(req).contains(&gid)) {
+                reply.error(op.fail(EPERM));
+                return;
+            }
+        }
+        if mode.is_some() && !is_root && !is_owner {
+            reply.error(op.fail(EPERM));
+            return;
+        }
+
+        // Others with write access may set the times to now, which FUSE doesn't
+        // tell apart from setting them to a given time
+        if (atime.is_some() || mtime.is_some()) && !is_root && !is_owner {
+            if let Err(e) = self.check_access(req, &inode, W_OK) {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        }
+
+        // A truncate through an open handle was checked on open
+        let req_uid = self.uid(req);
+        let inode = match size {
+            Some(size) => {
+                if _fh.is_none() {
+                    if let Err(e) = self.check_access(req, &inode, W_OK) {
+                        reply.error(op.fail(e.errno()));
+                        return;
+                    }
+                }
+                match self.volume.truncate(req_uid, &inode, _fh, size) {
+                    Ok(inode) => inode,
+                    Err(e) => {
+                        reply.error(op.fail(e.errno()));
+                        return;
+                    }
+                }
+            },
+            None => inode
+        };
+
+        if mode.is_none() && uid.is_none() && gid.is_none() && atime.is_none() && mtime.is_none() {
+            reply.attr(&self.attr_ttl, &self.inode_to_fileattr(inode));
+            return;
+        }
+
+        match self.volume.set_attr(req_uid, &inode, mode, uid, gid, atime, mtime) {
+            Ok(inode) => {
+                reply.attr(&self.attr_ttl, &self.inode_to_fileattr(inode));
+            },
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+            }
+        }
+    }
+
+    fn access(&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+        let op = self.metrics.operation("access", ino, None);
+        match self.volume.metadata().get_by_ino(ino) {
+            Ok(inode) => {
+                // F_OK only checks for existence
+                if self.volume.is_read_only() && mask & W_OK != 0 {
+                    reply.error(op.fail(EROFS));
+                } else if mask == 0 {
+                    reply.ok();
+                } else {
+                    match self.check_access(req, &inode, mask & (R_OK | W_OK | X_OK)) {
+                        Ok(()) => reply.ok(),
+                        Err(e) => reply.error(op.fail(e.errno()))
+                    }
+                }
+            },
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+            }
+        }
+    }
+
+    fn mkdir(&mut self, req: &Request, _parent: u64, _name: &OsStr, _mode: u32, reply: ReplyEntry) {
+        let op = self.metrics.operation("mkdir", _parent, None);
+        if self.volume.is_read_only() {
+            reply.error(op.fail(EROFS));
+            return;
+        }
+        let parent_inode = match self.volume.metadata().get_by_ino(_parent) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
                 return;
             }
         };
+        if let Err(e) = self.check_access(req, &parent_inode, W_OK | X_OK) {
+            reply.error(op.fail(e.errno()));
+            return;
+        }
         let name_string = match _name.to_str() {
             Some(slice) => slice.to_string(),
             None => {
-                reply.error(ENOENT);
+                reply.error(op.fail(ENOENT));
+                return;
+            }
+        };
+
+        let ownership = Ownership {
+            mode: _mode,
+            uid: self.uid(req),
+            gid: self.gid(req)
+        };
+
+        let inode = match self.volume.create_dir(&parent_inode, &name_string, &ownership) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+        match self.inherit_acl(ownership.uid, &parent_inode, inode) {
+            Ok(inode) => {
+                reply.entry(&self.entry_ttl, &self.inode_to_fileattr(inode), 0);
+            },
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+            }
+        }
+    }
+
+    fn symlink(&mut self, req: &Request, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
+        let op = self.metrics.operation("symlink", parent, None);
+        if self.volume.is_read_only() {
+            reply.error(op.fail(EROFS));
+            return;
+        }
+        let parent_inode = match self.volume.metadata().get_by_ino(parent) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+        if let Err(e) = self.check_access(req, &parent_inode, W_OK | X_OK) {
+            reply.error(op.fail(e.errno()));
+            return;
+        }
+        if let Err(e) = self.volume.check_quota(&parent_inode, self.uid(req), 0, 1) {
+            reply.error(op.fail(e.errno()));
+            return;
+        }
+        let name_string = match name.to_str() {
+            Some(slice) => slice.to_string(),
+            None => {
+                reply.error(op.fail(ENOENT));
+                return;
+            }
+        };
+        let target = match self.symlink_target(&parent_inode, link) {
+            Ok(Some(target)) => target,
+            Ok(None) => {
+                reply.error(op.fail(Error::PermissionDenied.errno()));
+                return;
+            },
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
                 return;
             }
         };
 
-        match self.metadata.create_dir(&parent_inode, &name_string) {
+        let mut action = CreateSymlink {
+            id: Uuid::new_v4().to_string(),
+            parent: parent_inode.id.clone(),
+            name: name_string,
+            target: target,
+            uid: self.uid(req),
+            gid: self.gid(req)
+        };
+        let uid = self.uid(req);
+        let result = self.volume.is_ignored(&parent_inode, &action.name, false)
+            .and_then(|local_only| self.volume.run_action(uid, &mut action, local_only));
+
+        match result {
+            Ok(()) => {
+                match self.volume.metadata().get_by_id(&action.id) {
+                    Ok(inode) => reply.entry(&self.entry_ttl, &self.inode_to_fileattr(inode), 0),
+                    Err(e)    => reply.error(op.fail(e.errno()))
+                }
+            },
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+            }
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        let op = self.metrics.operation("readlink", ino, None);
+        match self.volume.metadata().get_by_ino(ino) {
             Ok(inode) => {
-                let mut path_buf = PathBuf::new();
-                self.get_path(&inode, &mut path_buf);
-
-                match LocalFileOperations::create_dir(&path_buf.as_path()) {
-                    Ok(_) => {
-                        reply.entry(&TTL, &self.inode_to_fileattr(inode), 0);
-                    },
-                    Err(_) => {
-                        reply.error(ENOSYS);
-                    }
+                if inode.kind.is_symlink() {
+                    reply.data(inode.target.as_bytes());
+                } else {
+                    reply.error(op.fail(EINVAL));
                 }
             },
-            Err(_) => {
-                reply.error(ENOENT);
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
             }
         }
     }
 
-    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
-        match self.metadata.get_by_ino(ino) {
-            Some(inode) => {
-                if inode.kind.is_directory() {
+    fn readdir(&mut self, req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let op = self.metrics.operation("readdir", ino, Some(_fh));
+        match self.volume.metadata().get_by_ino(ino) {
+            Ok(inode) => {
+                if let Err(e) = self.check_access(req, &inode, R_OK) {
+                    reply.error(op.fail(e.errno()));
+                } else if inode.kind.is_directory() {
                     if offset == 0 {
-                        let parent = self.metadata.get_by_id(&inode.parent).unwrap();
+                        let (parent_ino, children) = match self.directory_entries(&inode) {
+                            Ok(entries) => entries,
+                            Err(e)      => {
+                                reply.error(op.fail(e.errno()));
+                                return;
+                            }
+                        };
 
                         reply.add(inode.ino, 0, FileType::Directory, ".");
-                        reply.add(parent.ino, 1, FileType::Directory, "..");
+                        reply.add(parent_ino, 1, FileType::Directory, "..");
 
                         let mut index = 2;
-                        for child in self.metadata.get_children(&inode) {
+                        for child in children {
                             reply.add(child.ino, index, self.inode_kind_to_file_type(&child.kind), child.name);
                             index += 1;
                         }
                     }
                     reply.ok();
                 } else {
-                    reply.error(ENOENT);
+                    reply.error(op.fail(ENOENT));
                 }
             },
-            None => {
-                reply.error(ENOENT);
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
             }
         }
     }
 
-    fn open(&mut self, _req: &Request, _ino: u64, _flags: u32, reply: ReplyOpen) {
-        match self.metadata.get_by_ino(_ino) {
-            Some(inode) => {
-                if inode.kind.is_regular_file() {
-                    let mut path_buf = PathBuf::new();
-                    self.get_path(&inode, &mut path_buf);
+    fn open(&mut self, req: &Request, _ino: u64, _flags: u32, reply: ReplyOpen) {
+        let op = self.metrics.operation("open", _ino, None);
+        let inode = match self.volume.metadata().get_by_ino(_ino) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
 
-                    let file_handle = LocalFileHandle::new(path_buf.as_path(), _flags as i32);
-                    self.last_fh += 1;
-                    self.open_fh.insert(self.last_fh, Box::new(file_handle));
+        let flags = _flags as i32;
+        let mut mask = match flags & O_ACCMODE {
+            O_RDONLY => R_OK,
+            O_WRONLY => W_OK,
+            _        => R_OK | W_OK
+        };
+        if flags & O_TRUNC == O_TRUNC {
+            mask |= W_OK;
+        }
+        if self.volume.is_read_only() && mask & W_OK != 0 {
+            reply.error(op.fail(EROFS));
+            return;
+        }
+        if let Err(e) = self.check_access(req, &inode, mask) {
+            reply.error(op.fail(e.errno()));
+            return;
+        }
+        if !inode.kind.is_regular_file() {
+            reply.error(op.fail(ENOSYS));
+            return;
+        }
 
-                    reply.opened(self.last_fh, _flags);
-                } else {
-                    reply.error(ENOSYS);
-                }
+        let uid = self.uid(req);
+        match self.volume.open_handle(uid, &inode, flags) {
+            Ok(fh) => {
+                // Hydrating may have grown the cache beyond its budget
+                self.evict();
+                reply.opened(fh, _flags);
             },
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+            }
+        }
+    }
+
+    fn create(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, flags: u32, reply: ReplyCreate) {
+        let op = self.metrics.operation("create", parent, None);
+        if self.volume.is_read_only() {
+            reply.error(op.fail(EROFS));
+            return;
+        }
+        let parent_inode = match self.volume.metadata().get_by_ino(parent) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+        let name_string = match name.to_str() {
+            Some(slice) => slice.to_string(),
             None => {
-                reply.error(ENOENT);
+                reply.error(op.fail(ENOENT));
+                return;
+            }
+        };
+        if let Err(e) = self.check_access(req, &parent_inode, W_OK | X_OK) {
+            reply.error(op.fail(e.errno()));
+            return;
+        }
+
+        let ownership = Ownership {
+            mode: mode,
+            uid: self.uid(req),
+            gid: self.gid(req)
+        };
+
+        let inode = match self.volume.create_file(&parent_inode, &name_string, &ownership) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+        let inode = match self.inherit_acl(ownership.uid, &parent_inode, inode) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+
+        match self.volume.open_handle(ownership.uid, &inode, flags as i32) {
+            Ok(fh) => {
+                reply.created(&self.entry_ttl, &self.inode_to_fileattr(inode), 0, fh, flags);
+            },
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
             }
         }
     }
 
     fn release(&mut self, _req: &Request, _ino: u64, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
-        match self.open_fh.remove(&_fh) {
-            Some(_) => {
+        let op = self.metrics.operation("release", _ino, Some(_fh));
+        match self.volume.release_handle(_fh) {
+            Ok(committed) => {
+                // The finished version may have grown the cache beyond its budget
+                if committed {
+                    self.evict();
+                }
                 reply.ok();
             },
-            None => {
-                reply.error(ENOSYS);
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
             }
         }
     }
 
     fn read (&mut self, _req: &Request, _ino: u64, _fh: u64, offset: i64, _size: u32, reply: ReplyData) {
-        match self.open_fh.get_mut(&_fh) {
-            Some(ref mut file_handle) => {
-                match file_handle.read(offset, _size) {
-                    Ok(data) => {
-                        reply.data(data.as_slice());
-                    },
-                    Err(_e) => {
-                        reply.error(ENOSYS);
-                    }
-                }
+        let op = self.metrics.operation("read", _ino, Some(_fh));
+        match self.volume.read_handle(_fh, offset as u64, _size) {
+            Ok(data) => {
+                reply.data(data.as_slice());
             },
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+            }
+        }
+    }
+
+    fn write(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+        let op = self.metrics.operation("write", ino, Some(fh));
+        if self.volume.is_read_only() {
+            reply.error(op.fail(EROFS));
+            return;
+        }
+        match self.volume.write_handle(fh, offset as u64, data) {
+            Ok(written) => {
+                reply.written(written);
+            },
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+            }
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        let op = self.metrics.operation("statfs", _ino, None);
+        let capacity = match self.volume.storage().capacity() {
+            Ok(capacity) => capacity,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+
+        let frsize = if capacity.frsize > 0 { capacity.frsize as u64 } else { 4096 };
+        let mut blocks = capacity.blocks;
+        let mut bfree = capacity.bfree;
+        let mut bavail = capacity.bavail;
+
+        // The quota on the root of the volume takes the place of the backing store
+        let counts = self.volume.metadata().count_inodes().and_then(|inodes| {
+            let root = self.volume.metadata().get_by_ino(1)?;
+            Ok((inodes, self.volume.metadata().get_quota(QuotaKind::Subtree, &root.id)?))
+        });
+        let (inodes, root_quota) = match counts {
+            Ok(counts) => counts,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+        let mut files = inodes + capacity.ffree;
+        let mut ffree = capacity.ffree;
+
+        if let Some(ref quota) = root_quota {
+            if let Some(max_bytes) = quota.max_bytes {
+                let used = quota.used_bytes;
+                let free = if max_bytes > used { (max_bytes - used) / frsize } else { 0 };
+
+                blocks = max_bytes / frsize;
+                bfree = if free < bfree { free } else { bfree };
+                bavail = if free < bavail { free } else { bavail };
+            }
+            if let Some(max_inodes) = quota.max_inodes {
+                files = max_inodes;
+                ffree = if max_inodes > quota.used_inodes { max_inodes - quota.used_inodes } else { 0 };
+            }
+        }
+
+        reply.statfs(blocks, bfree, bavail, files, ffree, capacity.bsize, NAME_MAX, frsize as u32);
+    }
+
+    fn rename(&mut self, req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, reply: ReplyEmpty) {
+        let op = self.metrics.operation("rename", _parent, None);
+        if self.volume.is_read_only() {
+            reply.error(op.fail(EROFS));
+            return;
+        }
+        let parent_inode = match self.volume.metadata().get_by_ino(_parent) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+        let new_parent_inode = match self.volume.metadata().get_by_ino(_newparent) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+        let name_string = match _name.to_str() {
+            Some(slice) => slice.to_string(),
+            None => {
+                reply.error(op.fail(ENOENT));
+                return;
+            }
+        };
+        let new_name_string = match _newname.to_str() {
+            Some(slice) => slice.to_string(),
             None => {
-                reply.error(ENOSYS);
+                reply.error(op.fail(ENOSYS));
+                return;
+            }
+        };
+
+        let old_inode = match self.volume.metadata().lookup(&parent_inode, &name_string) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+
+        if let Err(e) = self.check_removal(req, &parent_inode, &old_inode).and_then(|_| self.check_access(req, &new_parent_inode, W_OK | X_OK)) {
+            reply.error(op.fail(e.errno()));
+            return;
+        }
+
+        // Sticky directories protect the entry being replaced as well
+        match self.volume.metadata().lookup(&new_parent_inode, &new_name_string) {
+            Ok(ref replaced) if !permission::check_sticky(&new_parent_inode, replaced, self.uid(req)) => {
+                reply.error(op.fail(EACCES));
+                return;
+            },
+            Ok(_) | Err(Error::NotFound) => (),
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        }
+
+        let uid = self.uid(req);
+        match self.volume.move_inode(uid, &parent_inode, &old_inode, &new_parent_inode, &new_name_string) {
+            Ok(()) => {
+                reply.ok();
+            },
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
             }
         }
     }
 
-    fn rename(&mut self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, reply: ReplyEmpty) {
-        let parent_inode = match self.metadata.get_by_ino(_parent) {
-            Some(inode) => inode,
+    fn link(&mut self, req: &Request, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        let op = self.metrics.operation("link", ino, None);
+        if self.volume.is_read_only() {
+            reply.error(op.fail(EROFS));
+            return;
+        }
+        let inode = match self.volume.metadata().get_by_ino(ino) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+        let new_parent_inode = match self.volume.metadata().get_by_ino(newparent) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+        let new_name_string = match newname.to_str() {
+            Some(slice) => slice.to_string(),
             None => {
-                reply.error(ENOENT);
+                reply.error(op.fail(ENOENT));
                 return;
             }
         };
-        let new_parent_inode = match self.metadata.get_by_ino(_newparent) {
-            Some(inode) => inode,
+        if let Err(e) = self.check_access(req, &new_parent_inode, W_OK | X_OK) {
+            reply.error(op.fail(e.errno()));
+            return;
+        }
+
+        let uid = self.uid(req);
+        match self.volume.link(uid, &inode, &new_parent_inode, &new_name_string) {
+            Ok(new_inode) => {
+                reply.entry(&self.entry_ttl, &self.inode_to_fileattr(new_inode), 0);
+            },
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+            }
+        }
+    }
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let op = self.metrics.operation("unlink", parent, None);
+        if self.volume.is_read_only() {
+            reply.error(op.fail(EROFS));
+            return;
+        }
+        let parent_inode = match self.volume.metadata().get_by_ino(parent) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+        let name_string = match name.to_str() {
+            Some(slice) => slice.to_string(),
             None => {
-                reply.error(ENOENT);
+                reply.error(op.fail(ENOENT));
                 return;
             }
         };
-        let name_string = match _name.to_str() {
+
+        let inode = match self.volume.metadata().lookup(&parent_inode, &name_string) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+        if inode.kind.is_directory() {
+            reply.error(op.fail(Error::IsADirectory.errno()));
+            return;
+        }
+        if let Err(e) = self.check_removal(req, &parent_inode, &inode) {
+            reply.error(op.fail(e.errno()));
+            return;
+        }
+
+        let uid = self.uid(req);
+        match self.volume.unlink(uid, &parent_inode, &inode) {
+            Ok(()) => {
+                reply.ok();
+            },
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+            }
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let op = self.metrics.operation("rmdir", parent, None);
+        if self.volume.is_read_only() {
+            reply.error(op.fail(EROFS));
+            return;
+        }
+        let parent_inode = match self.volume.metadata().get_by_ino(parent) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+        let name_string = match name.to_str() {
             Some(slice) => slice.to_string(),
             None => {
-                reply.error(ENOENT);
+                reply.error(op.fail(ENOENT));
                 return;
             }
         };
-        let new_name_string = match _newname.to_str() {
+
+        let inode = match self.volume.metadata().lookup(&parent_inode, &name_string) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+        if !inode.kind.is_directory() {
+            reply.error(op.fail(Error::NotADirectory.errno()));
+            return;
+        }
+        if let Err(e) = self.check_removal(req, &parent_inode, &inode) {
+            reply.error(op.fail(e.errno()));
+            return;
+        }
+
+        let uid = self.uid(req);
+        match self.volume.remove_dir(uid, &parent_inode, &inode) {
+            Ok(()) => {
+                reply.ok();
+            },
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+            }
+        }
+    }
+
+    fn setxattr(&mut self, req: &Request, ino: u64, name: &OsStr, value: &[u8], flags: u32, _position: u32, reply: ReplyEmpty) {
+        let op = self.metrics.operation("setxattr", ino, None);
+        if self.volume.is_read_only() {
+            reply.error(op.fail(EROFS));
+            return;
+        }
+        let inode = match self.volume.metadata().get_by_ino(ino) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+        let name_string = match name.to_str() {
             Some(slice) => slice.to_string(),
             None => {
-                reply.error(ENOSYS);
+                reply.error(op.fail(Error::NotSupported.errno()));
                 return;
             }
         };
+        // Pins are local to this device, so they bypass the action log
+        if name_string == PINNED_XATTR {
+            let pinned = match value {
+                b"1" => true,
+                b"0" => false,
+                _    => {
+                    reply.error(op.fail(EINVAL));
+                    return;
+                }
+            };
+            match self.check_pin_access(req, &inode).and_then(|_| self.volume.set_pinned(&inode, pinned)) {
+                Ok(_)  => reply.ok(),
+                Err(e) => reply.error(op.fail(e.errno()))
+            }
+            return;
+        }
+        if let Err(e) = self.check_xattr_access(req, &inode, &name_string, true) {
+            reply.error(op.fail(e.errno()));
+            return;
+        }
 
-        match self.metadata.lookup(&parent_inode, &name_string) {
-            Some(old_inode) => {
-                match self.metadata.rename(&old_inode, &new_parent_inode, &new_name_string) {
-                    Ok(new_inode) => {
-                        let mut path_buf_old = PathBuf::new();
-                        self.get_path(&old_inode, &mut path_buf_old);
+        let mut action = SetXattr {
+            id: inode.id.clone(),
+            name: name_string,
+            value: value.to_vec(),
+            flags: flags
+        };
+        let uid = self.uid(req);
+        let result = self.volume.is_ignored_inode(&inode)
+            .and_then(|local_only| self.volume.run_action(uid, &mut action, local_only));
 
-                        let mut path_buf_new = PathBuf::new();
-                        self.get_path(&new_inode, &mut path_buf_new);
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(op.fail(e.errno()))
+        }
+    }
 
-                        match LocalFileOperations::rename(&path_buf_old.as_path(), &path_buf_new.as_path()) {
-                            Ok(()) => {
-                                reply.ok();
-                            },
-                            Err(_) => {
-                                reply.error(ENOSYS);
-                            }
-                        }
-                    },
-                    Err(_) => {
-                        reply.error(ENOSYS);
-                    }
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let op = self.metrics.operation("getxattr", ino, None);
+        let inode = match self.volume.metadata().get_by_ino(ino) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+        let name_string = match name.to_str() {
+            Some(slice) => slice.to_string(),
+            None => {
+                reply.error(op.fail(Error::NoAttribute.errno()));
+                return;
+            }
+        };
+        if let Err(e) = check_xattr_name(&name_string) {
+            reply.error(op.fail(e.errno()));
+            return;
+        }
+        if let Err(e) = self.check_xattr_access(req, &inode, &name_string, false) {
+            reply.error(op.fail(e.errno()));
+            return;
+        }
+
+        let value = if name_string == PINNED_XATTR {
+            Ok(if inode.pinned { Some(b"1".to_vec()) } else { None })
+        } else {
+            self.volume.metadata().get_xattr(&inode, &name_string)
+        };
+
+        match value {
+            Ok(Some(value)) => {
+                if size == 0 {
+                    reply.size(value.len() as u32);
+                } else if value.len() > size as usize {
+                    reply.error(op.fail(ERANGE));
+                } else {
+                    reply.data(value.as_slice());
                 }
             },
+            Ok(None) => {
+                reply.error(op.fail(Error::NoAttribute.errno()));
+            },
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+            }
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        let op = self.metrics.operation("listxattr", ino, None);
+        let inode = match self.volume.metadata().get_by_ino(ino) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+
+        let xattrs = match self.volume.metadata().list_xattr(&inode) {
+            Ok(xattrs) => xattrs,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+
+        // Null-terminated names, concatenated
+        let mut names = Vec::<u8>::new();
+        for (name, _) in xattrs {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        if inode.pinned {
+            names.extend_from_slice(PINNED_XATTR.as_bytes());
+            names.push(0);
+        }
+
+        if size == 0 {
+            reply.size(names.len() as u32);
+        } else if names.len() > size as usize {
+            reply.error(op.fail(ERANGE));
+        } else {
+            reply.data(names.as_slice());
+        }
+    }
+
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let op = self.metrics.operation("removexattr", ino, None);
+        if self.volume.is_read_only() {
+            reply.error(op.fail(EROFS));
+            return;
+        }
+        let inode = match self.volume.metadata().get_by_ino(ino) {
+            Ok(inode) => inode,
+            Err(e) => {
+                reply.error(op.fail(e.errno()));
+                return;
+            }
+        };
+        let name_string = match name.to_str() {
+            Some(slice) => slice.to_string(),
             None => {
-                reply.error(ENOENT);
+                reply.error(op.fail(Error::NoAttribute.errno()));
+                return;
             }
+        };
+        if let Err(e) = check_xattr_name(&name_string) {
+            reply.error(op.fail(e.errno()));
+            return;
+        }
+        if name_string == PINNED_XATTR {
+            if let Err(e) = self.check_pin_access(req, &inode) {
+                reply.error(op.fail(e.errno()));
+            } else if !inode.pinned {
+                reply.error(op.fail(Error::NoAttribute.errno()));
+            } else {
+                match self.volume.set_pinned(&inode, false) {
+                    Ok(_)  => reply.ok(),
+                    Err(e) => reply.error(op.fail(e.errno()))
+                }
+            }
+            return;
+        }
+        if let Err(e) = self.check_xattr_access(req, &inode, &name_string, true) {
+            reply.error(op.fail(e.errno()));
+            return;
+        }
+
+        let mut action = RemoveXattr {
+            id: inode.id.clone(),
+            name: name_string
+        };
+        let uid = self.uid(req);
+        let result = self.volume.is_ignored_inode(&inode)
+            .and_then(|local_only| self.volume.run_action(uid, &mut action, local_only));
+
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(op.fail(e.errno()))
         }
     }
 }
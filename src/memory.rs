@@ -0,0 +1,545 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use libc;
+use libc::{O_ACCMODE, O_APPEND, O_TRUNC, O_CREAT};
+use time;
+use time::Timespec;
+
+use error::Error;
+use storage::{StorageBackend, FileHandle, Stat, StatKind, Capacity};
+
+const BLOCK_SIZE: u64 = 4096;
+
+/// Largest number of files and directories reported by statfs
+const MAX_FILES: u64 = 1 << 20;
+
+struct MemoryFile {
+    data: Vec<u8>,
+    mtime: Timespec,
+    /// Names in the tree, the content counts as used until the last one is removed
+    links: u32
+}
+
+impl MemoryFile {
+    fn new() -> Node {
+        Node::File(Arc::new(Mutex::new(MemoryFile {
+            data: Vec::new(),
+            mtime: time::get_time(),
+            links: 1
+        })))
+    }
+}
+
+#[derive(Clone)]
+enum Node {
+    Directory(Timespec),
+    /// Hard links share the file
+    File(Arc<Mutex<MemoryFile>>)
+}
+
+/// The entries of the tree, and the bytes of content they hold
+struct Tree {
+    nodes: HashMap<PathBuf, Node>,
+    used: u64
+}
+
+impl Tree {
+    /// Account for a file resized to `size` bytes, ENOSPC when it doesn't fit in the capacity
+    ///
+    /// Called under the lock of the tree, so the check and the write it allows are atomic.
+    fn resize(&mut self, file: &MemoryFile, size: u64, capacity: u64) -> Result<(), Error> {
+        // An unlinked file still open is no longer part of the tree
+        if file.links == 0 {
+            return Ok(());
+        }
+        let current = file.data.len() as u64;
+        if size > current {
+            if self.used + (size - current) > capacity {
+                return Err(error(libc::ENOSPC));
+            }
+            self.used += size - current;
+        } else {
+            self.used -= current - size;
+        }
+        Ok(())
+    }
+
+    /// Account for a node removed from the tree, the content of a file goes with its last name
+    fn release(&mut self, node: Node) {
+        if let Node::File(ref file) = node {
+            let mut file = file.lock().unwrap();
+            file.links -= 1;
+            if file.links == 0 {
+                self.used -= file.data.len() as u64;
+            }
+        }
+    }
+}
+
+pub struct MemoryFileHandle {
+    file: Arc<Mutex<MemoryFile>>,
+    storage: MemoryStorage,
+    append: bool
+}
+
+impl FileHandle for MemoryFileHandle {
+    fn read(&mut self, offset: u64, size: u32) -> Result<Vec<u8>, Error> {
+        let file = self.file.lock().unwrap();
+
+        let start = if offset as usize > file.data.len() { file.data.len() } else { offset as usize };
+        let end = if start + size as usize > file.data.len() { file.data.len() } else { start + size as usize };
+        Ok(file.data[start..end].to_vec())
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<u32, Error> {
+        let mut tree = self.storage.tree.lock().unwrap();
+        let mut file = self.file.lock().unwrap();
+
+        let start = if self.append { file.data.len() } else { offset as usize };
+        if file.data.len() < start + data.len() {
+            tree.resize(&file, (start + data.len()) as u64, self.storage.capacity)?;
+            file.data.resize(start + data.len(), 0);
+        }
+        file.data[start..start + data.len()].copy_from_slice(data);
+        file.mtime = time::get_time();
+        Ok(data.len() as u32)
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), Error> {
+        let mut tree = self.storage.tree.lock().unwrap();
+        let mut file = self.file.lock().unwrap();
+
+        tree.resize(&file, size, self.storage.capacity)?;
+        file.data.resize(size as usize, 0);
+        file.mtime = time::get_time();
+        Ok(())
+    }
+}
+
+/// Stores the tree of the volume in memory, for tests and ephemeral mounts
+///
+/// Clones share the same tree.
+#[derive(Clone)]
+pub struct MemoryStorage {
+    tree: Arc<Mutex<Tree>>,
+    capacity: u64
+}
+
+impl MemoryStorage {
+    /// An empty tree, holding at most `capacity` bytes
+    pub fn new(capacity: u64) -> MemoryStorage {
+        let mut nodes = HashMap::new();
+        nodes.insert(PathBuf::new(), Node::Directory(time::get_time()));
+
+        MemoryStorage {
+            tree: Arc::new(Mutex::new(Tree {
+                nodes,
+                used: 0
+            })),
+            capacity
+        }
+    }
+}
+
+fn error(errno: libc::c_int) -> Error {
+    Error::Io(io::Error::from_raw_os_error(errno))
+}
+
+/// The parent of a new entry must be an existing directory, and the entry must not exist
+fn check_new(nodes: &HashMap<PathBuf, Node>, path: &Path) -> Result<(), Error> {
+    if nodes.contains_key(path) {
+        return Err(error(libc::EEXIST));
+    }
+    match path.parent().and_then(|parent| nodes.get(parent)) {
+        Some(&Node::Directory(_)) => Ok(()),
+        Some(_)                   => Err(error(libc::ENOTDIR)),
+        None                      => Err(error(libc::ENOENT))
+    }
+}
+
+fn has_children(nodes: &HashMap<PathBuf, Node>, path: &Path) -> bool {
+    nodes.keys().any(|key| key.parent() == Some(path))
+}
+
+impl StorageBackend for MemoryStorage {
+    fn open(&self, path: &Path, flags: i32) -> Result<Box<FileHandle + Send>, Error> {
+        let mut tree = self.tree.lock().unwrap();
+
+        if flags & O_CREAT == O_CREAT && flags & O_ACCMODE != 0 && !tree.nodes.contains_key(path) {
+            check_new(&tree.nodes, path)?;
+            tree.nodes.insert(path.to_path_buf(), MemoryFile::new());
+        }
+
+        let file = match tree.nodes.get(path) {
+            Some(&Node::File(ref file)) => file.clone(),
+            Some(&Node::Directory(_))   => return Err(error(libc::EISDIR)),
+            None                        => return Err(error(libc::ENOENT))
+        };
+        if flags & O_TRUNC == O_TRUNC && flags & O_ACCMODE != 0 {
+            let mut locked = file.lock().unwrap();
+            tree.resize(&locked, 0, self.capacity)?;
+            locked.data.clear();
+            locked.mtime = time::get_time();
+        }
+        Ok(Box::new(MemoryFileHandle {
+            file,
+            storage: self.clone(),
+            append: flags & O_APPEND == O_APPEND
+        }))
+    }
+
+    fn create(&self, path: &Path) -> Result<(), Error> {
+        let mut tree = self.tree.lock().unwrap();
+
+        check_new(&tree.nodes, path)?;
+        tree.nodes.insert(path.to_path_buf(), MemoryFile::new());
+        Ok(())
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<(), Error> {
+        let mut tree = self.tree.lock().unwrap();
+
+        check_new(&tree.nodes, path)?;
+        tree.nodes.insert(path.to_path_buf(), Node::Directory(time::get_time()));
+        Ok(())
+    }
+
+    fn rename(&self, old_path: &Path, new_path: &Path) -> Result<(), Error> {
+        let mut tree = self.tree.lock().unwrap();
+
+        let node = match tree.nodes.get(old_path) {
+            Some(node) => node.clone(),
+            None       => return Err(error(libc::ENOENT))
+        };
+        if old_path == new_path {
+            return Ok(());
+        }
+        if new_path.starts_with(old_path) {
+            return Err(error(libc::EINVAL));
+        }
+
+        match (tree.nodes.get(new_path), &node) {
+            (Some(&Node::Directory(_)), &Node::File(_)) => return Err(error(libc::EISDIR)),
+            (Some(&Node::File(_)), &Node::Directory(_)) => return Err(error(libc::ENOTDIR)),
+            (Some(&Node::Directory(_)), _) if has_children(&tree.nodes, new_path) => return Err(error(libc::ENOTEMPTY)),
+            (Some(_), _) => (),
+            (None, _)    => check_new(&tree.nodes, new_path)?
+        }
+
+        // Move the entry with everything below it
+        let moved: Vec<PathBuf> = tree.nodes.keys().filter(|key| key.starts_with(old_path)).cloned().collect();
+        for key in moved {
+            let node = tree.nodes.remove(&key).unwrap();
+            let new_key = new_path.join(key.strip_prefix(old_path).unwrap());
+            let replaced = tree.nodes.insert(new_key, node);
+            if let Some(replaced) = replaced {
+                tree.release(replaced);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), Error> {
+        let mut tree = self.tree.lock().unwrap();
+
+        match tree.nodes.get(path) {
+            Some(&Node::Directory(_)) if has_children(&tree.nodes, path) => return Err(error(libc::ENOTEMPTY)),
+            Some(_)                                                      => (),
+            None                                                         => return Err(error(libc::ENOENT))
+        }
+        let removed = tree.nodes.remove(path).unwrap();
+        tree.release(removed);
+        Ok(())
+    }
+
+    fn link(&self, path: &Path, new_path: &Path) -> Result<(), Error> {
+        let mut tree = self.tree.lock().unwrap();
+
+        let file = match tree.nodes.get(path) {
+            Some(&Node::File(ref file)) => file.clone(),
+            Some(&Node::Directory(_))   => return Err(error(libc::EPERM)),
+            None                        => return Err(error(libc::ENOENT))
+        };
+        check_new(&tree.nodes, new_path)?;
+        file.lock().unwrap().links += 1;
+        tree.nodes.insert(new_path.to_path_buf(), Node::File(file));
+        Ok(())
+    }
+
+    fn stat(&self, path: &Path) -> Result<Stat, Error> {
+        let tree = self.tree.lock().unwrap();
+        let nodes = &tree.nodes;
+
+        match nodes.get(path) {
+            Some(&Node::Directory(mtime)) => Ok(Stat {
+                kind: StatKind::Directory,
+                size: 0,
+                mtime
+            }),
+            Some(&Node::File(ref file)) => {
+                let file = file.lock().unwrap();
+                Ok(Stat {
+                    kind: StatKind::RegularFile,
+                    size: file.data.len() as u64,
+                    mtime: file.mtime
+                })
+            },
+            None => Err(error(libc::ENOENT))
+        }
+    }
+
+    fn list(&self, path: &Path) -> Result<Vec<OsString>, Error> {
+        let tree = self.tree.lock().unwrap();
+        let nodes = &tree.nodes;
+
+        match nodes.get(path) {
+            Some(&Node::Directory(_)) => (),
+            Some(_)                   => return Err(error(libc::ENOTDIR)),
+            None                      => return Err(error(libc::ENOENT))
+        }
+
+        Ok(nodes.keys()
+            .filter(|key| key.parent() == Some(path))
+            .filter_map(|key| key.file_name().map(|name| name.to_os_string()))
+            .collect())
+    }
+
+    fn capacity(&self) -> Result<Capacity, Error> {
+        let tree = self.tree.lock().unwrap();
+        let nodes = &tree.nodes;
+
+        let free = if self.capacity > tree.used { (self.capacity - tree.used) / BLOCK_SIZE } else { 0 };
+        let files = nodes.len() as u64;
+
+        Ok(Capacity {
+            blocks: self.capacity / BLOCK_SIZE,
+            bfree: free,
+            bavail: free,
+            files: MAX_FILES,
+            ffree: if MAX_FILES > files { MAX_FILES - files } else { 0 },
+            bsize: BLOCK_SIZE as u32,
+            frsize: BLOCK_SIZE as u32
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use libc::{self, O_RDONLY, O_RDWR, O_WRONLY, O_CREAT, O_TRUNC, O_APPEND};
+    use storage::StorageBackend;
+    use metadata::{Metadata, Ownership};
+    use volume::Volume;
+    use super::MemoryStorage;
+
+    fn errno<T>(result: Result<T, ::error::Error>) -> i32 {
+        match result {
+            Ok(_)  => 0,
+            Err(e) => e.errno()
+        }
+    }
+
+    fn volume(capacity: u64) -> Volume<MemoryStorage> {
+        Volume::new(Metadata::in_memory().unwrap(), MemoryStorage::new(capacity))
+    }
+
+    fn uid() -> u32 {
+        unsafe { libc::getuid() }
+    }
+
+    #[test]
+    fn write_and_read_back() {
+        let storage = MemoryStorage::new(1024);
+        storage.create(Path::new("file")).unwrap();
+
+        let mut handle = storage.open(Path::new("file"), O_RDWR).unwrap();
+        assert_eq!(handle.write(0, b"hello").unwrap(), 5);
+        assert_eq!(handle.write(3, b"p!").unwrap(), 2);
+        assert_eq!(handle.read(0, 100).unwrap(), b"help!".to_vec());
+        assert_eq!(handle.read(10, 100).unwrap(), Vec::<u8>::new());
+        assert_eq!(storage.stat(Path::new("file")).unwrap().size, 5);
+    }
+
+    #[test]
+    fn append_and_truncate() {
+        let storage = MemoryStorage::new(1024);
+        storage.create(Path::new("file")).unwrap();
+
+        storage.open(Path::new("file"), O_WRONLY).unwrap().write(0, b"abc").unwrap();
+        storage.open(Path::new("file"), O_WRONLY | O_APPEND).unwrap().write(0, b"def").unwrap();
+        assert_eq!(storage.open(Path::new("file"), O_RDONLY).unwrap().read(0, 100).unwrap(), b"abcdef".to_vec());
+
+        storage.open(Path::new("file"), O_WRONLY | O_TRUNC).unwrap();
+        assert_eq!(storage.stat(Path::new("file")).unwrap().size, 0);
+    }
+
+    #[test]
+    fn open_creates_with_o_creat() {
+        let storage = MemoryStorage::new(1024);
+
+        assert_eq!(errno(storage.open(Path::new("file"), O_WRONLY)), libc::ENOENT);
+        storage.open(Path::new("file"), O_WRONLY | O_CREAT).unwrap();
+        assert_eq!(storage.stat(Path::new("file")).unwrap().size, 0);
+        assert_eq!(errno(storage.open(Path::new("missing/file"), O_WRONLY | O_CREAT)), libc::ENOENT);
+    }
+
+    #[test]
+    fn writes_beyond_the_capacity_fail_with_enospc() {
+        let storage = MemoryStorage::new(8);
+        storage.create(Path::new("file")).unwrap();
+        let mut handle = storage.open(Path::new("file"), O_RDWR).unwrap();
+
+        handle.write(0, b"12345678").unwrap();
+        assert_eq!(errno(handle.write(8, b"9")), libc::ENOSPC);
+        // Overwriting doesn't grow the file
+        handle.write(0, b"abcdefgh").unwrap();
+        assert_eq!(storage.stat(Path::new("file")).unwrap().size, 8);
+    }
+
+    #[test]
+    fn set_len_beyond_the_capacity_fails_with_enospc() {
+        let storage = MemoryStorage::new(8);
+        storage.create(Path::new("file")).unwrap();
+        let mut handle = storage.open(Path::new("file"), O_RDWR).unwrap();
+
+        handle.set_len(8).unwrap();
+        assert_eq!(errno(handle.set_len(9)), libc::ENOSPC);
+        handle.set_len(2).unwrap();
+        assert_eq!(storage.stat(Path::new("file")).unwrap().size, 2);
+    }
+
+    #[test]
+    fn hard_links_count_once() {
+        let storage = MemoryStorage::new(8 * 4096);
+        storage.create(Path::new("file")).unwrap();
+        storage.open(Path::new("file"), O_WRONLY).unwrap().set_len(4096).unwrap();
+        storage.link(Path::new("file"), Path::new("link")).unwrap();
+
+        assert_eq!(storage.capacity().unwrap().bfree, 7);
+        assert_eq!(storage.stat(Path::new("link")).unwrap().size, 4096);
+    }
+
+    #[test]
+    fn directories() {
+        let storage = MemoryStorage::new(1024);
+        storage.create_dir(Path::new("dir")).unwrap();
+        storage.create(Path::new("dir/file")).unwrap();
+
+        assert_eq!(errno(storage.remove(Path::new("dir"))), libc::ENOTEMPTY);
+        storage.rename(Path::new("dir"), Path::new("moved")).unwrap();
+        assert_eq!(storage.list(Path::new("moved")).unwrap(), vec![::std::ffi::OsString::from("file")]);
+        assert_eq!(errno(storage.stat(Path::new("dir/file"))), libc::ENOENT);
+    }
+
+    #[test]
+    fn unlinking_frees_the_content() {
+        let storage = MemoryStorage::new(8 * 4096);
+        storage.create(Path::new("file")).unwrap();
+        storage.open(Path::new("file"), O_WRONLY).unwrap().set_len(4096).unwrap();
+        storage.link(Path::new("file"), Path::new("link")).unwrap();
+
+        storage.remove(Path::new("file")).unwrap();
+        assert_eq!(storage.capacity().unwrap().bfree, 7);
+        storage.remove(Path::new("link")).unwrap();
+        assert_eq!(storage.capacity().unwrap().bfree, 8);
+
+        // Replaced by a rename
+        storage.create(Path::new("a")).unwrap();
+        storage.open(Path::new("a"), O_WRONLY).unwrap().set_len(4096).unwrap();
+        storage.create(Path::new("b")).unwrap();
+        storage.open(Path::new("b"), O_WRONLY).unwrap().set_len(4096).unwrap();
+        storage.rename(Path::new("a"), Path::new("b")).unwrap();
+        assert_eq!(storage.capacity().unwrap().bfree, 7);
+    }
+
+    #[test]
+    fn volume_writes_and_reads_back() {
+        let mut volume = volume(1024);
+
+        assert_eq!(volume.write(Path::new("file"), 0, b"hello").unwrap(), 5);
+        volume.write(Path::new("file"), 3, b"p!").unwrap();
+        assert_eq!(volume.read(Path::new("file"), 0, 100).unwrap(), b"help!".to_vec());
+        assert_eq!(volume.stat(Path::new("file")).unwrap().size, 5);
+        assert_eq!(volume.storage().stat(Path::new("file")).unwrap().size, 5);
+    }
+
+    #[test]
+    fn volume_writes_beyond_the_capacity_fail_with_enospc() {
+        let mut volume = volume(8);
+
+        volume.write(Path::new("file"), 0, b"12345678").unwrap();
+        assert_eq!(errno(volume.write(Path::new("file"), 8, b"9")), libc::ENOSPC);
+        assert_eq!(volume.read(Path::new("file"), 0, 100).unwrap(), b"12345678".to_vec());
+    }
+
+    #[test]
+    fn volume_renames_links_and_unlinks() {
+        let mut volume = volume(1024);
+        let root = volume.stat(Path::new("")).unwrap();
+        let ownership = Ownership {
+            mode: 0o755,
+            uid: uid(),
+            gid: unsafe { libc::getgid() }
+        };
+        volume.create_dir(&root, &"dir".to_string(), &ownership).unwrap();
+        volume.write(Path::new("dir/file"), 0, b"hello").unwrap();
+
+        volume.rename(Path::new("dir/file"), Path::new("moved")).unwrap();
+        assert_eq!(errno(volume.stat(Path::new("dir/file"))), libc::ENOENT);
+        assert_eq!(volume.read(Path::new("moved"), 0, 100).unwrap(), b"hello".to_vec());
+
+        let moved = volume.stat(Path::new("moved")).unwrap();
+        let dir = volume.stat(Path::new("dir")).unwrap();
+        volume.link(uid(), &moved, &dir, &"link".to_string()).unwrap();
+        assert_eq!(volume.stat(Path::new("moved")).unwrap().nlink, 2);
+
+        volume.unlink(uid(), &root, &moved).unwrap();
+        assert_eq!(errno(volume.stat(Path::new("moved"))), libc::ENOENT);
+        assert_eq!(volume.stat(Path::new("dir/link")).unwrap().nlink, 1);
+        assert_eq!(volume.read(Path::new("dir/link"), 0, 100).unwrap(), b"hello".to_vec());
+        assert_eq!(volume.storage().stat(Path::new("dir/link")).unwrap().size, 5);
+    }
+
+    #[test]
+    fn volume_refuses_to_move_a_directory_into_itself() {
+        let mut volume = volume(1024);
+        let root = volume.stat(Path::new("")).unwrap();
+        let ownership = Ownership {
+            mode: 0o755,
+            uid: uid(),
+            gid: unsafe { libc::getgid() }
+        };
+        let dir = volume.create_dir(&root, &"dir".to_string(), &ownership).unwrap();
+        volume.create_dir(&dir, &"sub".to_string(), &ownership).unwrap();
+
+        assert_eq!(errno(volume.rename(Path::new("dir"), Path::new("dir/sub/dir"))), libc::EINVAL);
+        assert!(volume.stat(Path::new("dir/sub")).is_ok());
+    }
+
+    #[test]
+    fn volume_removes_empty_directories() {
+        let mut volume = volume(1024);
+        let root = volume.stat(Path::new("")).unwrap();
+        let ownership = Ownership {
+            mode: 0o755,
+            uid: uid(),
+            gid: unsafe { libc::getgid() }
+        };
+        let dir = volume.create_dir(&root, &"dir".to_string(), &ownership).unwrap();
+        volume.write(Path::new("dir/file"), 0, b"hello").unwrap();
+        assert_eq!(volume.stat(Path::new("")).unwrap().nlink, 3);
+
+        assert_eq!(errno(volume.remove_dir(uid(), &root, &dir)), libc::ENOTEMPTY);
+        let file = volume.stat(Path::new("dir/file")).unwrap();
+        assert_eq!(errno(volume.remove_dir(uid(), &dir, &file)), libc::ENOTDIR);
+
+        volume.unlink(uid(), &dir, &file).unwrap();
+        volume.remove_dir(uid(), &root, &dir).unwrap();
+        assert_eq!(errno(volume.stat(Path::new("dir"))), libc::ENOENT);
+        assert_eq!(errno(volume.storage().stat(Path::new("dir"))), libc::ENOENT);
+        assert_eq!(volume.stat(Path::new("")).unwrap().nlink, 2);
+    }
+}
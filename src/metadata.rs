@@ -1,15 +1,28 @@
 use std::ffi::OsString;
-use std::path::Path;
-use rusqlite::Connection;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use rusqlite::{self, Connection, Row};
 use rusqlite::types::ToSql;
 use time;
 use time::Timespec;
 use uuid::Uuid;
+use error::{Error, optional};
+use schema;
+
+/// Device local setting holding the id of this device
+const DEVICE_ID_SETTING: &'static str = "device.id";
+
+/// How long a connection waits for another one to release the write lock,
+/// before its query fails with SQLITE_BUSY
+const BUSY_TIMEOUT_MS: u32 = 5000;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum INodeKind {
     Directory = 0,
     RegularFile,
+    Symlink,
 }
 
 impl INodeKind {
@@ -17,6 +30,7 @@ impl INodeKind {
         match i {
             0 => Some(INodeKind::Directory),
             1 => Some(INodeKind::RegularFile),
+            2 => Some(INodeKind::Symlink),
             _ => None
         }
     }
@@ -28,6 +42,10 @@ impl INodeKind {
     pub fn is_regular_file(&self) -> bool {
         *self == INodeKind::RegularFile
     }
+
+    pub fn is_symlink(&self) -> bool {
+        *self == INodeKind::Symlink
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -43,7 +61,128 @@ pub struct INode {
     pub ctime: Timespec,
     pub crtime: Timespec,
     pub nlink: u32,
-    pub current_version: String
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub current_version: String,
+    pub target: String,
+    /// Hash of the current version, empty until the first write is released
+    pub hash: String,
+    /// Whether the content of the current version is stored locally
+    pub hydrated: bool,
+    /// Always keep the content of this inode, or this directory's subtree, on this device
+    pub pinned: bool
+}
+
+/// Permission bits and owner of a new inode
+#[derive(Debug, Clone, Copy)]
+pub struct Ownership {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32
+}
+
+#[derive(Debug, Clone)]
+pub struct FileVersion {
+    pub id: String,
+    pub version: String,
+    pub source_version: String,
+    pub size: u64,
+    pub hash: String,
+    pub last_scrub: Option<Timespec>,
+    pub corrupt: bool,
+    pub created: Option<Timespec>
+}
+
+/// Counts for `markfs status`
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub inodes: u64,
+    pub files: u64,
+    pub placeholders: u64,
+    pub pinned: u64,
+    pub hydrated_bytes: u64,
+    /// Files whose current content no remote holds yet
+    pub unsynced: u64,
+    pub corrupt: u64,
+    /// Actions that never finished, e.g. because of a crash
+    pub unfinished_actions: u64
+}
+
+/// Entry of the action log
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub seq: i64,
+    pub name: String,
+    pub time: Timespec,
+    /// Id of the device the action was made on, see `Metadata::device_id`
+    pub device: String,
+    pub uid: u32,
+    pub finished: bool,
+    pub success: bool,
+    pub local_only: bool
+}
+
+/// An action as replicated to peers, with the device and user it came from
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct ActionEnvelope {
+    /// Sequence number in the log of the originating device
+    pub seq: i64,
+    pub device: String,
+    pub uid: u32,
+    pub name: String,
+    /// The encoded action
+    pub data: Vec<u8>
+}
+
+/// Entry of the audit log, who changed what on which device
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub seq: i64,
+    pub time: Timespec,
+    /// Id of the device the change was made on, see `Metadata::device_id`
+    pub device: String,
+    /// Id of the user in the volume
+    pub uid: u32,
+    /// The action or operation, like `unlink` or `set_xattr`
+    pub name: String,
+    /// Ids of the affected inodes
+    pub inodes: Vec<String>,
+    /// Volume path at the time of the change
+    pub path: String,
+    /// Where a rename moved the path to
+    pub new_path: Option<String>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuotaKind {
+    /// Everything below a directory, the target is the inode id
+    Subtree = 0,
+    /// Everything owned by a user, the target is the uid
+    User,
+}
+
+impl QuotaKind {
+    pub fn from_i32(i: i32) -> Option<QuotaKind> {
+        match i {
+            0 => Some(QuotaKind::Subtree),
+            1 => Some(QuotaKind::User),
+            _ => None
+        }
+    }
+}
+
+/// Limits in bytes and inodes, with the usage tracked as versions come and go
+///
+/// Hard links are charged to the subtree they were created in.
+#[derive(Debug, Clone)]
+pub struct Quota {
+    pub kind: QuotaKind,
+    pub target: String,
+    pub max_bytes: Option<u64>,
+    pub max_inodes: Option<u64>,
+    pub used_bytes: u64,
+    pub used_inodes: u64
 }
 
 pub struct Metadata {
@@ -51,123 +190,1093 @@ pub struct Metadata {
 }
 
 impl Metadata {
-    pub fn new(local_path: &OsString) -> Metadata {
-        let path_buf = Path::new(local_path).join("metadata.sqlite");
-        let conn = Connection::open(path_buf.as_path()).unwrap();
-
-        let create_table = conn.execute("
-            CREATE TABLE inode (
-                ino             INTEGER PRIMARY KEY,
-                id              TEXT NOT NULL,
-                parent          TEXT NOT NULL,
-                name            TEXT NOT NULL,
-                kind            INTEGER NOT NULL,
-                atime           TEXT NOT NULL,
-                mtime           TEXT NOT NULL,
-                ctime           TEXT NOT NULL,
-                crtime          TEXT NOT NULL,
-                nlink           INTEGER NOT NULL,
-                current_version TEXT
-            )", &[]);
-
-        if create_table.is_ok() {
-            let root_guid = Uuid::new_v4().to_string();
-            let root_name = "";
-            let create_time = time::get_time();
-
-            conn.execute("INSERT INTO inode (ino, id, parent, name, kind, atime, mtime, ctime, crtime, nlink)
-                          VALUES (?1, ?2, ?2, ?3, ?4, ?5, ?5, ?5, ?5, ?6)",
-                         &[&1, &root_guid, &root_name, &(INodeKind::Directory as i32), &create_time, &2]).unwrap();
-
-            let world_guid = Uuid::new_v4().to_string();
-            let world_name = "world";
-
-            conn.execute("INSERT INTO inode (id, parent, name, kind, atime, mtime, ctime, crtime, nlink)
-                          VALUES (?1, ?2, ?3, ?4, ?5, ?5, ?5, ?5, ?6)",
-                         &[&world_guid, &root_guid, &world_name, &(INodeKind::Directory as i32), &create_time, &1]).unwrap();
-
-            let hello_txt_guid = Uuid::new_v4().to_string();
-            let hello_txt_name = "hello.txt";
-            let version = "1";
-            let source_version = "";
-            let hash = "";
-
-            conn.execute("INSERT INTO inode (id, parent, name, kind, atime, mtime, ctime, crtime, nlink, current_version)
-                          VALUES (?1, ?2, ?3, ?4, ?5, ?5, ?5, ?5, ?6, ?7)",
-                         &[&hello_txt_guid, &world_guid, &hello_txt_name, &(INodeKind::RegularFile as i32), &create_time, &1, &version]).unwrap();
-
-            conn.execute("
-                CREATE TABLE file_version (
-                    id              TEXT NOT NULL,
-                    version         TEXT NOT NULL,
-                    source_version  TEXT NOT NULL,
-                    size            INTEGER NOT NULL,
-                    hash            TEXT NOT NULL
-                )", &[]).unwrap();
-
-
-            conn.execute("INSERT INTO file_version (id, version, source_version, size, hash)
-                          VALUES (?1, ?2, ?3, ?4, ?5)",
-                         &[&hello_txt_guid, &version, &source_version, &13, &hash]).unwrap();
-        }
-
-        Metadata {
+    /// Open or create `metadata.sqlite` in the state directory of a volume
+    pub fn new(state_dir: &OsString) -> Result<Metadata, Error> {
+        let path_buf = Path::new(state_dir).join("metadata.sqlite");
+        let conn = Connection::open(path_buf.as_path())?;
+
+        Metadata::init(conn)
+    }
+
+    /// Metadata that lives as long as the connection, for tests and ephemeral mounts
+    pub fn in_memory() -> Result<Metadata, Error> {
+        let conn = Connection::open_in_memory()?;
+
+        Metadata::init(conn)
+    }
+
+    /// Create the tables and the initial tree, or migrate them to this version
+    ///
+    /// The mount, scrubber, uploader, control socket and CLI each open their
+    /// own connection, so every connection waits for the others' writes.
+    fn init(conn: Connection) -> Result<Metadata, Error> {
+        conn.execute_batch(&format!("PRAGMA busy_timeout = {}", BUSY_TIMEOUT_MS))?;
+
+        // All or nothing, and never racing another process migrating the schema
+        conn.execute_batch("BEGIN IMMEDIATE")?;
+        let result = schema::migrate(&conn);
+        let end = if result.is_ok() { "COMMIT" } else { "ROLLBACK" };
+        conn.execute_batch(end)?;
+        result?;
+
+        Ok(Metadata {
             conn: conn
+        })
+    }
+
+    /// Path in the storage backend, relative to the root of the volume
+    pub fn get_path(&self, inode: &INode, path_buf: &mut PathBuf) -> Result<(), Error> {
+        if inode.ino != 1 {
+            // A dangling parent leaves the inode at the root, rather than failing every caller
+            match self.get_by_id(&inode.parent) {
+                Ok(parent_inode)     => self.get_path(&parent_inode, path_buf)?,
+                Err(Error::NotFound) => warn!("Parent {} of inode {} is missing", inode.parent, inode.id),
+                Err(e)               => return Err(e)
+            }
+            path_buf.push(&inode.name);
+        }
+        Ok(())
+    }
+
+    /// Whether `inode` is `ancestor` or lies below it
+    pub fn is_within(&self, inode: &INode, ancestor: &String) -> Result<bool, Error> {
+        let mut current = inode.clone();
+        loop {
+            if current.id == *ancestor {
+                return Ok(true);
+            }
+            if current.ino == 1 {
+                return Ok(false);
+            }
+            current = match optional(self.get_by_id(&current.parent))? {
+                Some(parent) => parent,
+                None         => return Ok(false)
+            };
+        }
+    }
+
+    /// Path as seen from the root of the mount
+    pub fn get_volume_path(&self, inode: &INode) -> Result<PathBuf, Error> {
+        let mut path_buf = PathBuf::from("/");
+        self.get_path(inode, &mut path_buf)?;
+        Ok(path_buf)
+    }
+
+    /// Find the inode for a path relative to the root of the volume
+    pub fn resolve(&self, path: &Path) -> Result<INode, Error> {
+        let mut inode = self.get_by_ino(1)?;
+
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => {
+                    let name_string = name.to_str().ok_or(Error::NotFound)?.to_string();
+                    inode = self.lookup(&inode, &name_string)?;
+                },
+                Component::ParentDir => {
+                    inode = self.get_by_id(&inode.parent)?;
+                },
+                _ => ()
+            }
         }
+        Ok(inode)
     }
 
-    pub fn get_by_ino(&self, ino: u64) -> Option<INode> {
-        self.query_inode("inode.ino = ?1", &[&(ino as u32)]).pop()
+    /// The inode by its first name, `Error::NotFound` when there's none
+    pub fn get_by_ino(&self, ino: u64) -> Result<INode, Error> {
+        self.query_inode("inode.ino = ?1", &[&(ino as u32)])?.pop().ok_or(Error::NotFound)
     }
 
-    pub fn get_by_id(&self, id: &String) -> Option<INode> {
-        self.query_inode("inode.id = ?1", &[&id.as_str()]).pop()
+    /// The inode by its first name, `Error::NotFound` when there's none
+    pub fn get_by_id(&self, id: &String) -> Result<INode, Error> {
+        self.query_inode("inode.id = ?1", &[&id.as_str()])?.pop().ok_or(Error::NotFound)
     }
 
-    pub fn lookup(&self, parent: &INode, name: &String) -> Option<INode> {
-        self.query_inode("inode.parent = ?1 AND inode.name = ?2", &[&parent.id.as_str(), &name.as_str()]).pop()
+    /// The inode by this name, which for a hard link may not be its first
+    pub fn lookup(&self, parent: &INode, name: &String) -> Result<INode, Error> {
+        let id: String = match self.conn.query_row("SELECT id FROM dentry WHERE parent = ?1 AND name = ?2",
+                                                   &[&parent.id.as_str(), &name.as_str()], |row| row.get(0)) {
+            Ok(id)                                     => id,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Err(Error::NotFound),
+            Err(e)                                     => return Err(Error::from(e))
+        };
+        self.named_inode(&parent.id, name, &id)
     }
 
-    pub fn get_children(&self, parent: &INode) -> Vec<INode> {
-        self.query_inode("inode.parent = ?1 AND inode.id <> ?1", &[&parent.id.as_str()])
+    /// The inodes in a directory, each by its name in there
+    pub fn get_children(&self, parent: &INode) -> Result<Vec<INode>, Error> {
+        let entries = self.query_rows("
+            SELECT name, id
+              FROM dentry
+             WHERE parent = ?1
+               AND id <> ?1
+          ORDER BY name", &[&parent.id.as_str()], |row| (row.get::<_, String>(0), row.get::<_, String>(1)))?;
+
+        let mut children = Vec::with_capacity(entries.len());
+        for (name, id) in entries {
+            match optional(self.named_inode(&parent.id, &name, &id))? {
+                Some(child) => children.push(child),
+                None        => warn!("Skipping entry {} without an inode", name)
+            }
+        }
+        Ok(children)
     }
 
-    pub fn create_dir(&self, parent: &INode, name: &String) -> Result<INode, ()> {
-        let id = Uuid::new_v4().to_string();
+    pub fn create_dir(&self, id: &String, parent: &INode, name: &String, ownership: &Ownership) -> Result<INode, Error> {
         let create_time = time::get_time();
 
+        self.in_transaction(|| {
+            self.insert_inode(id, INodeKind::Directory, &create_time, 2, ownership, None)?;
+            self.insert_dentry(parent, name, id)?;
+            self.charge(Some(parent), Some(ownership.uid), 0, 1)?;
+            // The new directory's ".." links to the parent
+            self.adjust_nlink(&parent.id, 1)?;
+            self.get_by_id(id)
+        })
+    }
+
+    /// Symlinks live in the metadata only, the target is served by readlink
+    pub fn create_symlink(&self, id: &String, parent: &INode, name: &String, target: &String, ownership: &Ownership) -> Result<INode, Error> {
+        let create_time = time::get_time();
+
+        self.in_transaction(|| {
+            self.insert_inode(id, INodeKind::Symlink, &create_time, 1, ownership, Some(target))?;
+            self.insert_dentry(parent, name, id)?;
+            self.charge(Some(parent), Some(ownership.uid), 0, 1)?;
+            self.get_by_id(id)
+        })
+    }
+
+    /// Create an empty regular file, with an empty first version
+    pub fn create_file(&self, id: &String, version: &String, parent: &INode, name: &String, ownership: &Ownership) -> Result<INode, Error> {
+        let create_time = time::get_time();
+
+        self.in_transaction(|| {
+            self.insert_inode(id, INodeKind::RegularFile, &create_time, 1, ownership, None)?;
+            self.insert_dentry(parent, name, id)?;
+            self.charge(Some(parent), Some(ownership.uid), 0, 1)?;
+
+            match self.conn.execute("
+                INSERT INTO file_version (id, version, source_version, size, hash, created)
+                VALUES (?1, ?2, '', 0, '', ?3)", &[id, version, &create_time]) {
+                Ok(_)  => (),
+                Err(e) => return Err(Error::from(e))
+            }
+            match self.conn.execute("UPDATE inode SET current_version = ?2 WHERE id = ?1", &[id, version]) {
+                Ok(_)  => (),
+                Err(e) => return Err(Error::from(e))
+            }
+
+            self.get_by_id(id)
+        })
+    }
+
+    /// Create a regular file known from a peer, without local content
+    ///
+    /// The id and version are those of the peer, so its later actions apply to it.
+    pub fn create_placeholder(&self, id: &String, version: &String, parent: &INode, name: &String, ownership: &Ownership, size: u64, hash: &String) -> Result<INode, Error> {
+        let create_time = time::get_time();
+
+        self.in_transaction(|| {
+            self.insert_inode(id, INodeKind::RegularFile, &create_time, 1, ownership, None)?;
+            self.insert_dentry(parent, name, id)?;
+            self.charge(Some(parent), Some(ownership.uid), size as i64, 1)?;
+
+            match self.conn.execute("
+                INSERT INTO file_version (id, version, source_version, size, hash, hydrated, created)
+                VALUES (?1, ?2, '', ?3, ?4, 0, ?5)", &[id, version, &(size as i64), hash, &create_time]) {
+                Ok(_)  => (),
+                Err(e) => return Err(Error::from(e))
+            }
+            match self.conn.execute("UPDATE inode SET current_version = ?2 WHERE id = ?1", &[id, version]) {
+                Ok(_)  => (),
+                Err(e) => return Err(Error::from(e))
+            }
+
+            self.get_by_id(id)
+        })
+    }
+
+    /// Record whether the content of the current version is stored locally
+    pub fn set_hydrated(&self, inode: &INode, hydrated: bool) -> Result<INode, Error> {
         match self.conn.execute("
-            INSERT INTO inode (id, parent, name, kind, atime, mtime, ctime, crtime, nlink)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?5, ?5, ?5, ?6)",
-            &[&id, &parent.id.as_str(), &name.as_str(), &(INodeKind::Directory as i32), &create_time, &1]) {
+            UPDATE file_version
+               SET hydrated = ?3
+             WHERE id = ?1
+               AND version = ?2", &[&inode.id, &inode.current_version, &(hydrated as i32)]) {
+            Ok(_)  => Ok(INode {
+                hydrated: hydrated,
+                ..inode.clone()
+            }),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    /// Record that a remote holds the content with this hash
+    pub fn set_synced(&self, id: &String, version: &String) -> Result<(), Error> {
+        match self.conn.execute("UPDATE file_version SET synced = 1 WHERE id = ?1 AND version = ?2", &[id, version]) {
+            Ok(_)  => Ok(()),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    /// Files whose current content is stored locally but on no remote yet
+    pub fn get_unsynced(&self) -> Result<Vec<INode>, Error> {
+        self.query_inode("inode.kind = ?1
+                          AND file_version.hydrated = 1
+                          AND file_version.synced = 0
+                          AND file_version.hash != ''", &[&(INodeKind::RegularFile as i32)])
+    }
+
+    /// Bytes of content stored locally, counting current versions only
+    pub fn hydrated_size(&self) -> Result<u64, Error> {
+        Ok(self.query_number("
+            SELECT coalesce(sum(file_version.size), 0)
+              FROM inode
+              JOIN file_version ON inode.id = file_version.id
+                               AND inode.current_version = file_version.version
+             WHERE file_version.hydrated = 1")? as u64)
+    }
+
+    /// Hydrated files a remote also holds, least recently accessed first
+    ///
+    /// Pinned inodes and everything below pinned directories are left out.
+    /// Each inode is returned once, under one of its names, like every
+    /// `query_inode`.
+    pub fn get_eviction_candidates(&self, limit: u32) -> Result<Vec<INode>, Error> {
+        self.query_inode("inode.kind = ?1
+                          AND file_version.hydrated = 1
+                          AND file_version.synced = 1
+                          AND file_version.hash != ''
+                          AND inode.id NOT IN (
+                              WITH RECURSIVE pinned_tree(id) AS (
+                                  SELECT id FROM inode WHERE pinned = 1
+                                  UNION
+                                  SELECT dentry.id FROM dentry JOIN pinned_tree ON dentry.parent = pinned_tree.id
+                              )
+                              SELECT id FROM pinned_tree)
+                        ORDER BY inode.atime ASC
+                        LIMIT ?2", &[&(INodeKind::RegularFile as i32), &limit])
+    }
+
+    /// Pin or unpin an inode, pinning a directory covers its whole subtree
+    pub fn set_pinned(&self, inode: &INode, pinned: bool) -> Result<INode, Error> {
+        match self.conn.execute("UPDATE inode SET pinned = ?2 WHERE id = ?1", &[&inode.id, &(pinned as i32)]) {
+            Ok(_)  => Ok(INode {
+                pinned: pinned,
+                ..inode.clone()
+            }),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    /// Placeholders at or below the inode, the ones to hydrate when it's pinned
+    pub fn get_placeholders_in(&self, inode: &INode) -> Result<Vec<INode>, Error> {
+        self.query_inode("inode.kind = ?2
+                          AND file_version.hydrated = 0
+                          AND inode.id IN (
+                              WITH RECURSIVE subtree(id) AS (
+                                  SELECT ?1
+                                  UNION
+                                  SELECT dentry.id FROM dentry JOIN subtree ON dentry.parent = subtree.id
+                              )
+                              SELECT id FROM subtree)", &[&inode.id, &(INodeKind::RegularFile as i32)])
+    }
+
+    /// Record an access, for the eviction order
+    pub fn touch_atime(&self, inode: &INode) -> Result<INode, Error> {
+        let access_time = time::get_time();
 
-            Ok(_)  => Ok(self.query_inode("inode.id = ?1", &[&id.as_str()]).pop().unwrap()),
-            Err(_) => Err(())
+        match self.conn.execute("UPDATE inode SET atime = ?2 WHERE id = ?1", &[&inode.id, &access_time]) {
+            Ok(_)  => Ok(INode {
+                atime: access_time,
+                ..inode.clone()
+            }),
+            Err(e) => Err(Error::from(e))
         }
     }
 
-    pub fn rename(&self, inode: &INode, new_parent_inode: &INode, new_name: &String) -> Result<INode, ()> {
+    /// All versions of a file, oldest first
+    pub fn get_versions(&self, inode: &INode) -> Result<Vec<FileVersion>, Error> {
+        self.query_rows("
+            SELECT id, version, source_version, size, hash, last_scrub, corrupt, created
+              FROM file_version
+             WHERE id = ?1
+             ORDER BY created ASC", &[&inode.id], |row| {
+            let size: i64 = row.get(3);
+            let corrupt: i32 = row.get(6);
+
+            FileVersion {
+                id: row.get(0),
+                version: row.get(1),
+                source_version: row.get(2),
+                size: size as u64,
+                hash: row.get(4),
+                last_scrub: row.get(5),
+                corrupt: corrupt != 0,
+                created: row.get(7)
+            }
+        })
+    }
+
+    /// One version of a file
+    pub fn get_version(&self, inode: &INode, version: &String) -> Result<FileVersion, Error> {
+        match self.get_versions(inode)?.into_iter().find(|file_version| file_version.version == *version) {
+            Some(file_version) => Ok(file_version),
+            None               => Err(Error::NotFound)
+        }
+    }
+
+    /// Start a new version before the file is modified
+    ///
+    /// Every write session gets its own version, so writes to one file on two
+    /// devices never share an id. The current version is kept, so a finished
+    /// one can be restored from any peer holding its hash. Without a hash it's
+    /// still being written.
+    pub fn begin_version(&self, inode: &INode) -> Result<INode, Error> {
+        let version = Uuid::new_v4().to_string();
+        let create_time = time::get_time();
+
+        self.in_transaction(|| {
+            match self.conn.execute("
+                INSERT INTO file_version (id, version, source_version, size, hash, created)
+                VALUES (?1, ?2, ?3, ?4, '', ?5)",
+                &[&inode.id, &version, &inode.current_version, &(inode.size as i64), &create_time]) {
+                Ok(_)  => (),
+                Err(e) => return Err(Error::from(e))
+            }
+            match self.conn.execute("UPDATE inode SET current_version = ?2 WHERE id = ?1", &[&inode.id, &version]) {
+                Ok(_)  => (),
+                Err(e) => return Err(Error::from(e))
+            }
+
+            self.get_by_id(&inode.id)
+        })
+    }
+
+    /// Record a finished version and make it the current one
+    ///
+    /// A version begun on this device gets its size and hash. Any other, made
+    /// by a peer or restoring an earlier one, is added, with its content
+    /// stored locally when `hydrated`.
+    pub fn finish_version(&self, inode: &INode, version: &String, source_version: &String, size: u64, hash: &String, hydrated: bool) -> Result<INode, Error> {
+        let finish_time = time::get_time();
+
+        self.in_transaction(|| {
+            let updated = match self.conn.execute("
+                UPDATE file_version
+                   SET size = ?3,
+                       hash = ?4,
+                       hydrated = ?5,
+                       synced = 0
+                 WHERE id = ?1
+                   AND version = ?2", &[&inode.id, version, &(size as i64), hash, &(hydrated as i32)]) {
+                Ok(updated) => updated,
+                Err(e)      => return Err(Error::from(e))
+            };
+            if updated == 0 {
+                match self.conn.execute("
+                    INSERT INTO file_version (id, version, source_version, size, hash, hydrated, created)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    &[&inode.id, version, source_version, &(size as i64), hash, &(hydrated as i32), &finish_time]) {
+                    Ok(_)  => (),
+                    Err(e) => return Err(Error::from(e))
+                }
+            }
+            match self.conn.execute("
+                UPDATE inode
+                   SET current_version = ?2,
+                       mtime = ?3,
+                       ctime = ?3
+                 WHERE id = ?1", &[&inode.id, version, &finish_time]) {
+                Ok(_)  => (),
+                Err(e) => return Err(Error::from(e))
+            }
+
+            // Writes on this device are charged as they go
+            let parent = optional(self.get_by_id(&inode.parent))?;
+            self.charge(parent.as_ref(), Some(inode.uid), size as i64 - inode.size as i64, 0)?;
+
+            self.get_by_id(&inode.id)
+        })
+    }
+
+    /// Drop earlier versions beyond the newest `keep` of a file or created
+    /// before `max_age`, returns how many were dropped and the hashes no
+    /// version refers to anymore
+    ///
+    /// The current version is always kept. So are versions without a creation
+    /// time when only `max_age` applies. The caller removes the blobs of the
+    /// returned hashes from the peers and the download cache.
+    pub fn prune_versions(&self, keep: Option<u32>, max_age: Option<Duration>) -> Result<(u64, Vec<String>), Error> {
+        let now = time::get_time();
+        let cutoff = max_age.map(|max_age| Timespec::new(now.sec - max_age.as_secs() as i64, now.nsec));
+
+        let versions: Vec<(String, String, Option<Timespec>, String)> = self.query_rows("
+            SELECT file_version.id, file_version.version, file_version.created, file_version.hash
+              FROM file_version
+              JOIN inode ON inode.id = file_version.id
+             WHERE file_version.version != inode.current_version
+             ORDER BY file_version.id, file_version.created DESC", &[], |row| (row.get(0), row.get(1), row.get(2), row.get(3)))?;
+
+        let mut pruned = Vec::new();
+        let mut last_id = String::new();
+        let mut kept = 0;
+        for (id, version, created, hash) in versions {
+            if id != last_id {
+                last_id = id.clone();
+                kept = 0;
+            }
+
+            let too_many = keep.map_or(false, |keep| kept >= keep);
+            let too_old = match (cutoff, created) {
+                (Some(cutoff), Some(created)) => created < cutoff,
+                _                             => false
+            };
+            if too_many || too_old {
+                pruned.push((id, version, hash));
+            } else {
+                kept += 1;
+            }
+        }
+
+        self.in_transaction(|| {
+            for &(ref id, ref version, _) in pruned.iter() {
+                match self.conn.execute("DELETE FROM file_version WHERE id = ?1 AND version = ?2", &[id, version]) {
+                    Ok(_)  => (),
+                    Err(e) => return Err(Error::from(e))
+                }
+            }
+
+            // Identical content is stored once, so a blob may still be in use
+            let mut unused: Vec<String> = Vec::new();
+            for &(_, _, ref hash) in pruned.iter() {
+                if hash.is_empty() || unused.contains(hash) {
+                    continue;
+                }
+                let references: i64 = self.conn.query_row("SELECT count(*) FROM file_version WHERE hash = ?1", &[hash], |row| row.get(0))?;
+                if references == 0 {
+                    unused.push(hash.clone());
+                }
+            }
+            Ok((pruned.len() as u64, unused))
+        })
+    }
+
+    /// Record the size of the current version after a write
+    pub fn set_size(&self, inode: &INode, size: u64) -> Result<INode, Error> {
+        let modify_time = time::get_time();
+
+        self.in_transaction(|| {
+            match self.conn.execute("
+                UPDATE file_version
+                   SET size = ?3,
+                       synced = 0
+                 WHERE id = ?1
+                   AND version = ?2", &[&inode.id, &inode.current_version, &(size as i64)]) {
+                Ok(_)  => (),
+                Err(e) => return Err(Error::from(e))
+            }
+            match self.conn.execute("
+                UPDATE inode
+                   SET mtime = ?2,
+                       ctime = ?2
+                 WHERE id = ?1", &[&inode.id, &modify_time]) {
+                Ok(_)  => (),
+                Err(e) => return Err(Error::from(e))
+            }
+
+            let parent = optional(self.get_by_id(&inode.parent))?;
+            self.charge(parent.as_ref(), Some(inode.uid), size as i64 - inode.size as i64, 0)?;
+
+            Ok(INode {
+                size: size,
+                mtime: modify_time,
+                ctime: modify_time,
+                ..inode.clone()
+            })
+        })
+    }
+
+    pub fn count_inodes(&self) -> Result<u64, Error> {
+        Ok(self.query_number("SELECT count(*) FROM inode")? as u64)
+    }
+
+    /// Change permission bits and owner
+    pub fn set_ownership(&self, inode: &INode, ownership: &Ownership) -> Result<INode, Error> {
+        let change_time = time::get_time();
+
+        self.in_transaction(|| {
+            match self.conn.execute("
+                UPDATE inode
+                   SET mode = ?2,
+                       uid = ?3,
+                       gid = ?4,
+                       ctime = ?5
+                 WHERE id = ?1", &[&inode.id, &ownership.mode, &ownership.uid, &ownership.gid, &change_time]) {
+                Ok(_)  => (),
+                Err(e) => return Err(Error::from(e))
+            }
+
+            if ownership.uid != inode.uid {
+                let size = inode.size as i64;
+                self.charge(None, Some(inode.uid), -size, -1)?;
+                self.charge(None, Some(ownership.uid), size, 1)?;
+            }
+
+            Ok(INode {
+                mode: ownership.mode,
+                uid: ownership.uid,
+                gid: ownership.gid,
+                ctime: change_time,
+                ..inode.clone()
+            })
+        })
+    }
+
+    /// Set the access and modification times, like utimensat(2)
+    pub fn set_times(&self, inode: &INode, atime: Option<Timespec>, mtime: Option<Timespec>) -> Result<INode, Error> {
+        let change_time = time::get_time();
+        let atime = atime.unwrap_or(inode.atime);
+        let mtime = mtime.unwrap_or(inode.mtime);
+
         match self.conn.execute("
             UPDATE inode
-               SET parent = ?2,
-                   name = ?3
-             WHERE id = ?1", &[&inode.id, &new_parent_inode.id, &new_name.as_str()]) {
-            Ok(_)   => Ok(INode {
+               SET atime = ?2,
+                   mtime = ?3,
+                   ctime = ?4
+             WHERE id = ?1", &[&inode.id, &atime, &mtime, &change_time]) {
+            Ok(_)  => Ok(INode {
+                atime: atime,
+                mtime: mtime,
+                ctime: change_time,
+                ..inode.clone()
+            }),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    /// Add another name for an existing inode
+    pub fn link(&self, inode: &INode, new_parent_inode: &INode, new_name: &String) -> Result<INode, Error> {
+        self.in_transaction(|| {
+            self.insert_dentry(new_parent_inode, new_name, &inode.id)?;
+            self.adjust_nlink(&inode.id, 1)?;
+            self.lookup(new_parent_inode, new_name)
+        })
+    }
+
+    /// Remove a name, returns the inode with its remaining link count
+    ///
+    /// The inode itself, with its versions, is removed with its last name.
+    pub fn unlink(&self, inode: &INode) -> Result<INode, Error> {
+        self.in_transaction(|| {
+            self.remove_dentry(inode)
+        })
+    }
+
+    /// Remove an empty directory, which takes its ".." link to the parent along
+    pub fn remove_dir(&self, inode: &INode) -> Result<(), Error> {
+        self.in_transaction(|| {
+            let children: i64 = self.conn.query_row("SELECT count(*) FROM dentry WHERE parent = ?1 AND id <> ?1", &[&inode.id], |row| row.get(0))?;
+            if children > 0 {
+                return Err(Error::NotEmpty);
+            }
+            self.remove_dentry(inode)?;
+            Ok(())
+        })
+    }
+
+    /// Move a name, replacing the inode that had the new name, if any
+    pub fn rename(&self, inode: &INode, new_parent_inode: &INode, new_name: &String) -> Result<INode, Error> {
+        self.in_transaction(|| {
+            if let Some(replaced) = optional(self.lookup(new_parent_inode, new_name))? {
+                if replaced.id == inode.id {
+                    return Ok(replaced);
+                }
+                self.remove_dentry(&replaced)?;
+            }
+
+            match self.conn.execute("
+                UPDATE dentry
+                   SET parent = ?3,
+                       name = ?4
+                 WHERE parent = ?1
+                   AND name = ?2", &[&inode.parent, &inode.name, &new_parent_inode.id, &new_name.as_str()]) {
+                Ok(_)  => (),
+                Err(e) => return Err(Error::from(e))
+            }
+
+            // Move the ".." link of a directory to the new parent
+            if inode.kind.is_directory() && inode.parent != new_parent_inode.id {
+                self.adjust_nlink(&inode.parent, -1)?;
+                self.adjust_nlink(&new_parent_inode.id, 1)?;
+            }
+
+            // Move the usage to the quotas of the new subtree
+            if inode.parent != new_parent_inode.id {
+                let (bytes, inodes) = self.usage_of(inode)?;
+                let old_parent = optional(self.get_by_id(&inode.parent))?;
+                self.charge(old_parent.as_ref(), None, -bytes, -inodes)?;
+                self.charge(Some(new_parent_inode), None, bytes, inodes)?;
+            }
+
+            Ok(INode {
                 parent: new_parent_inode.id.clone(),
                 name: new_name.clone(),
                 ..inode.clone()
-            }),
-            Err(_) => Err(())
+            })
+        })
+    }
+
+    pub fn get_xattr(&self, inode: &INode, name: &String) -> Result<Option<Vec<u8>>, Error> {
+        match self.conn.query_row("
+            SELECT value
+              FROM xattr
+             WHERE id = ?1
+               AND name = ?2", &[&inode.id, &name.as_str()], |row| row.get(0)) {
+            Ok(value)                                 => Ok(Some(value)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e)                                    => Err(Error::from(e))
+        }
+    }
+
+    pub fn list_xattr(&self, inode: &INode) -> Result<Vec<(String, usize)>, Error> {
+        self.query_rows("
+            SELECT name, length(value)
+              FROM xattr
+             WHERE id = ?1
+             ORDER BY name", &[&inode.id], |row| {
+            let size: i64 = row.get(1);
+            (row.get(0), size as usize)
+        })
+    }
+
+    pub fn set_xattr(&self, inode: &INode, name: &String, value: &Vec<u8>) -> Result<(), Error> {
+        let change_time = time::get_time();
+
+        self.in_transaction(|| {
+            match self.conn.execute("
+                INSERT OR REPLACE INTO xattr (id, name, value)
+                VALUES (?1, ?2, ?3)", &[&inode.id, &name.as_str(), value]) {
+                Ok(_)  => (),
+                Err(e) => return Err(Error::from(e))
+            }
+            self.touch_ctime(&inode.id, &change_time)
+        })
+    }
+
+    /// Returns whether the attribute existed
+    pub fn remove_xattr(&self, inode: &INode, name: &String) -> Result<bool, Error> {
+        let change_time = time::get_time();
+
+        self.in_transaction(|| {
+            let removed = match self.conn.execute("
+                DELETE FROM xattr
+                 WHERE id = ?1
+                   AND name = ?2", &[&inode.id, &name.as_str()]) {
+                Ok(n)  => n > 0,
+                Err(e) => return Err(Error::from(e))
+            };
+            if removed {
+                self.touch_ctime(&inode.id, &change_time)?;
+            }
+            Ok(removed)
+        })
+    }
+
+    fn touch_ctime(&self, id: &String, change_time: &Timespec) -> Result<(), Error> {
+        match self.conn.execute("UPDATE inode SET ctime = ?2 WHERE id = ?1", &[id, change_time]) {
+            Ok(_)  => Ok(()),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    /// Selective sync rules of this device, as `(volume path, include)`
+    pub fn get_sync_rules(&self) -> Result<Vec<(String, bool)>, Error> {
+        self.query_rows("SELECT path, include FROM sync_rule ORDER BY path", &[], |row| {
+            let include: i32 = row.get(1);
+            (row.get(0), include != 0)
+        })
+    }
+
+    pub fn set_sync_rule(&self, path: &String, include: bool) -> Result<(), Error> {
+        match self.conn.execute("INSERT OR REPLACE INTO sync_rule (path, include) VALUES (?1, ?2)", &[path, &(include as i32)]) {
+            Ok(_)  => Ok(()),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    /// Returns false when there was no rule for the path
+    pub fn remove_sync_rule(&self, path: &String) -> Result<bool, Error> {
+        match self.conn.execute("DELETE FROM sync_rule WHERE path = ?1", &[path]) {
+            Ok(count) => Ok(count > 0),
+            Err(e)    => Err(Error::from(e))
+        }
+    }
+
+    /// Device local setting
+    pub fn get_setting(&self, name: &str) -> Result<Option<String>, Error> {
+        match self.conn.query_row("SELECT value FROM setting WHERE name = ?1", &[&name], |row| row.get(0)) {
+            Ok(value)                                 => Ok(Some(value)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e)                                    => Err(Error::from(e))
+        }
+    }
+
+    pub fn set_setting(&self, name: &str, value: &str) -> Result<(), Error> {
+        match self.conn.execute("INSERT OR REPLACE INTO setting (name, value) VALUES (?1, ?2)", &[&name, &value]) {
+            Ok(_)  => Ok(()),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    /// Id of this device, created the first time it's asked for
+    ///
+    /// Kept with the device local settings, so a copy of the volume on
+    /// another device gets an id of its own once it's initialized there.
+    pub fn device_id(&self) -> Result<String, Error> {
+        if let Some(device_id) = self.get_setting(DEVICE_ID_SETTING)? {
+            return Ok(device_id);
+        }
+
+        let device_id = Uuid::new_v4().to_string();
+        self.set_setting(DEVICE_ID_SETTING, &device_id)?;
+        Ok(device_id)
+    }
+
+    /// Record a change in the audit log, `entry.seq` is ignored
+    pub fn add_audit_entry(&self, entry: &AuditEntry) -> Result<i64, Error> {
+        match self.conn.execute("
+            INSERT INTO audit_log (time, device, uid, name, inodes, path, new_path)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            &[&entry.time, &entry.device, &(entry.uid as i64), &entry.name, &entry.inodes.join(" "), &entry.path, &entry.new_path]) {
+            Ok(_)  => Ok(self.conn.last_insert_rowid()),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    /// The last `limit` audit entries of a volume path and everything below
+    /// it, or of the whole volume without a path, oldest first
+    pub fn get_audit_log(&self, path: Option<&str>, since: Option<Timespec>, limit: u32) -> Result<Vec<AuditEntry>, Error> {
+        let path = path.map(|path| path.trim_right_matches('/').to_string());
+        let below = path.as_ref().map(|path| format!("{}/", path));
+        let since = since.unwrap_or(Timespec::new(0, 0));
+
+        // The paths below compare by prefix, LIKE would need escaping
+        self.query_rows("
+            SELECT * FROM (
+                SELECT seq, time, device, uid, name, inodes, path, new_path
+                  FROM audit_log
+                 WHERE time >= ?3
+                   AND (?1 IS NULL
+                        OR path = ?1 OR substr(path, 1, length(?2)) = ?2
+                        OR new_path = ?1 OR substr(new_path, 1, length(?2)) = ?2)
+                 ORDER BY seq DESC
+                 LIMIT ?4)
+             ORDER BY seq", &[&path, &below, &since, &limit], |row| {
+            let uid: i64 = row.get(3);
+            let inodes: String = row.get(5);
+
+            AuditEntry {
+                seq: row.get(0),
+                time: row.get(1),
+                device: row.get(2),
+                uid: uid as u32,
+                name: row.get(4),
+                inodes: inodes.split_whitespace().map(|id| id.to_string()).collect(),
+                path: row.get(6),
+                new_path: row.get(7)
+            }
+        })
+    }
+
+    pub fn get_quotas(&self) -> Result<Vec<Quota>, Error> {
+        let quotas = self.query_rows("
+            SELECT kind, target, max_bytes, max_inodes, used_bytes, used_inodes
+              FROM quota
+             ORDER BY kind, target", &[], |row| {
+            let max_bytes: Option<i64> = row.get(2);
+            let max_inodes: Option<i64> = row.get(3);
+            let used_bytes: i64 = row.get(4);
+            let used_inodes: i64 = row.get(5);
+
+            // Quotas of an unknown kind are skipped
+            QuotaKind::from_i32(row.get(0)).map(|kind| Quota {
+                kind: kind,
+                target: row.get(1),
+                max_bytes: max_bytes.map(|max| max as u64),
+                max_inodes: max_inodes.map(|max| max as u64),
+                used_bytes: used_bytes as u64,
+                used_inodes: used_inodes as u64
+            })
+        })?;
+        Ok(quotas.into_iter().filter_map(|quota| quota).collect())
+    }
+
+    pub fn get_quota(&self, kind: QuotaKind, target: &String) -> Result<Option<Quota>, Error> {
+        Ok(self.get_quotas()?.into_iter().find(|quota| quota.kind == kind && quota.target == *target))
+    }
+
+    /// Define or change a quota, the usage is counted from scratch
+    pub fn set_quota(&self, kind: QuotaKind, target: &String, max_bytes: Option<u64>, max_inodes: Option<u64>) -> Result<(), Error> {
+        let (used_bytes, used_inodes) = match kind {
+            QuotaKind::Subtree => self.subtree_usage(target)?,
+            QuotaKind::User    => self.user_usage(target)?
+        };
+
+        match self.conn.execute("
+            INSERT OR REPLACE INTO quota (kind, target, max_bytes, max_inodes, used_bytes, used_inodes)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            &[&(kind as i32), target, &max_bytes.map(|max| max as i64), &max_inodes.map(|max| max as i64), &used_bytes, &used_inodes]) {
+            Ok(_)  => Ok(()),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    pub fn remove_quota(&self, kind: QuotaKind, target: &String) -> Result<bool, Error> {
+        match self.conn.execute("DELETE FROM quota WHERE kind = ?1 AND target = ?2", &[&(kind as i32), target]) {
+            Ok(n)  => Ok(n > 0),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    /// Check whether adding bytes and inodes below the parent, owned by the uid,
+    /// stays within the quotas, returns the kind of the quota that would be exceeded
+    pub fn check_quota(&self, parent: &INode, uid: u32, bytes: i64, inodes: i64) -> Result<Option<QuotaKind>, Error> {
+        for quota in self.quotas_for(Some(parent), Some(uid))? {
+            let over_bytes = bytes > 0 && quota.max_bytes.map_or(false, |max| quota.used_bytes as i64 + bytes > max as i64);
+            let over_inodes = inodes > 0 && quota.max_inodes.map_or(false, |max| quota.used_inodes as i64 + inodes > max as i64);
+            if over_bytes || over_inodes {
+                return Ok(Some(quota.kind));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether moving the inode below the new parent stays within the subtree
+    /// quotas that don't count it yet, returns the kind of the quota that would be exceeded
+    pub fn check_move_quota(&self, inode: &INode, new_parent: &INode) -> Result<Option<QuotaKind>, Error> {
+        let old_parent = optional(self.get_by_id(&inode.parent))?;
+        let counted: Vec<String> = self.quotas_for(old_parent.as_ref(), None)?.into_iter().map(|quota| quota.target).collect();
+        let (bytes, inodes) = self.usage_of(inode)?;
+
+        for quota in self.quotas_for(Some(new_parent), None)? {
+            if counted.contains(&quota.target) {
+                continue;
+            }
+            let over_bytes = bytes > 0 && quota.max_bytes.map_or(false, |max| quota.used_bytes as i64 + bytes > max as i64);
+            let over_inodes = inodes > 0 && quota.max_inodes.map_or(false, |max| quota.used_inodes as i64 + inodes > max as i64);
+            if over_bytes || over_inodes {
+                return Ok(Some(quota.kind));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Quotas of the subtrees the parent is in, and of the user
+    fn quotas_for(&self, parent: Option<&INode>, uid: Option<u32>) -> Result<Vec<Quota>, Error> {
+        let mut ancestors = Vec::new();
+        if let Some(parent) = parent {
+            let mut current = parent.clone();
+            loop {
+                ancestors.push(current.id.clone());
+                if current.ino == 1 {
+                    break;
+                }
+                current = match self.get_by_id(&current.parent) {
+                    Ok(inode)            => inode,
+                    Err(Error::NotFound) => break,
+                    Err(e)               => return Err(e)
+                };
+            }
+        }
+        let uid_string = uid.map(|uid| uid.to_string());
+
+        Ok(self.get_quotas()?.into_iter().filter(|quota| match quota.kind {
+            QuotaKind::Subtree => ancestors.contains(&quota.target),
+            QuotaKind::User    => uid_string.as_ref() == Some(&quota.target)
+        }).collect())
+    }
+
+    fn charge(&self, parent: Option<&INode>, uid: Option<u32>, bytes: i64, inodes: i64) -> Result<(), Error> {
+        if bytes == 0 && inodes == 0 {
+            return Ok(());
+        }
+
+        for quota in self.quotas_for(parent, uid)? {
+            match self.conn.execute("
+                UPDATE quota
+                   SET used_bytes = max(used_bytes + ?3, 0),
+                       used_inodes = max(used_inodes + ?4, 0)
+                 WHERE kind = ?1
+                   AND target = ?2", &[&(quota.kind as i32), &quota.target, &bytes, &inodes]) {
+                Ok(_)  => (),
+                Err(e) => return Err(Error::from(e))
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes and inodes of an inode, including everything below a directory
+    fn usage_of(&self, inode: &INode) -> Result<(i64, i64), Error> {
+        if inode.kind.is_directory() {
+            let (bytes, inodes) = self.subtree_usage(&inode.id)?;
+            Ok((bytes, inodes + 1))
+        } else {
+            Ok((inode.size as i64, 1))
+        }
+    }
+
+    fn subtree_usage(&self, id: &String) -> Result<(i64, i64), Error> {
+        Ok(self.conn.query_row("
+            WITH RECURSIVE subtree(id) AS (
+                SELECT id FROM dentry WHERE parent = ?1 AND id <> ?1
+                UNION
+                SELECT dentry.id FROM dentry JOIN subtree ON dentry.parent = subtree.id
+            )
+            SELECT coalesce(sum(file_version.size), 0), count(*)
+              FROM subtree
+              JOIN inode ON inode.id = subtree.id
+              LEFT OUTER JOIN file_version ON inode.id = file_version.id
+                                          AND inode.current_version = file_version.version",
+            &[id], |row| (row.get(0), row.get(1)))?)
+    }
+
+    fn user_usage(&self, uid: &String) -> Result<(i64, i64), Error> {
+        Ok(self.conn.query_row("
+            SELECT coalesce(sum(file_version.size), 0), count(*)
+              FROM inode
+              LEFT OUTER JOIN file_version ON inode.id = file_version.id
+                                          AND inode.current_version = file_version.version
+             WHERE inode.uid = ?1",
+            &[uid], |row| (row.get(0), row.get(1)))?)
+    }
+
+    fn insert_inode(&self, id: &String, kind: INodeKind, create_time: &Timespec, nlink: u32, ownership: &Ownership, target: Option<&String>) -> Result<(), Error> {
+        match self.conn.execute("
+            INSERT INTO inode (id, kind, atime, mtime, ctime, crtime, nlink, mode, uid, gid, target)
+            VALUES (?1, ?2, ?3, ?3, ?3, ?3, ?4, ?5, ?6, ?7, ?8)",
+            &[id, &(kind as i32), create_time, &nlink, &(ownership.mode & 0o7777), &ownership.uid, &ownership.gid, &target]) {
+            Ok(_)  => Ok(()),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    fn insert_dentry(&self, parent: &INode, name: &String, id: &String) -> Result<(), Error> {
+        match self.conn.execute("
+            INSERT INTO dentry (parent, name, id)
+            VALUES (?1, ?2, ?3)", &[&parent.id.as_str(), &name.as_str(), id]) {
+            Ok(_)  => Ok(()),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    fn remove_dentry(&self, inode: &INode) -> Result<INode, Error> {
+        match self.conn.execute("
+            DELETE FROM dentry
+             WHERE parent = ?1
+               AND name = ?2", &[&inode.parent, &inode.name]) {
+            Ok(_)  => (),
+            Err(e) => return Err(Error::from(e))
+        }
+
+        let nlink = if inode.kind.is_directory() {
+            // A directory goes with its only name, taking the ".." link to its parent
+            self.adjust_nlink(&inode.parent, -1)?;
+            0
+        } else {
+            self.adjust_nlink(&inode.id, -1)?;
+            inode.nlink - 1
+        };
+
+        if nlink == 0 {
+            let parent = optional(self.get_by_id(&inode.parent))?;
+            self.charge(parent.as_ref(), Some(inode.uid), -(inode.size as i64), -1)?;
+
+            for sql in ["DELETE FROM inode WHERE id = ?1",
+                        "DELETE FROM file_version WHERE id = ?1",
+                        "DELETE FROM xattr WHERE id = ?1"].iter() {
+                match self.conn.execute(sql, &[&inode.id]) {
+                    Ok(_)  => (),
+                    Err(e) => return Err(Error::from(e))
+                }
+            }
+        }
+
+        Ok(INode {
+            nlink: nlink,
+            ..inode.clone()
+        })
+    }
+
+    fn adjust_nlink(&self, id: &String, delta: i32) -> Result<(), Error> {
+        let change_time = time::get_time();
+
+        match self.conn.execute("
+            UPDATE inode
+               SET nlink = nlink + ?2,
+                   ctime = ?3
+             WHERE id = ?1", &[id, &delta, &change_time]) {
+            Ok(_)  => Ok(()),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    /// Run `f`, keeping its changes only when it succeeds
+    ///
+    /// Savepoints nest, so an action runs in one while the methods it calls
+    /// open their own.
+    pub fn in_transaction<T, F: FnOnce() -> Result<T, Error>>(&self, f: F) -> Result<T, Error> {
+        self.conn.execute_batch("SAVEPOINT markfs")?;
+
+        let result = f();
+
+        let end = if result.is_ok() { "RELEASE markfs" } else { "ROLLBACK TO markfs; RELEASE markfs" };
+        match self.conn.execute_batch(end) {
+            Ok(_)  => result,
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    /// Inodes by their first name, the one they were created with until it's removed
+    ///
+    /// A hard linked inode has a row per name in `dentry`, so the names are
+    /// resolved here rather than joined into the query.
+    fn query_inode(&self, where_clause: &str, params: &[&ToSql]) -> Result<Vec<INode>, Error> {
+        let mut inodes = Vec::new();
+        for inode in self.query_inode_rows(where_clause, params)? {
+            match self.conn.query_row("
+                SELECT parent, name
+                  FROM dentry
+                 WHERE id = ?1
+              ORDER BY rowid
+                 LIMIT 1", &[&inode.id], |row| (row.get(0), row.get(1))) {
+                Ok((parent, name)) => inodes.push(INode {
+                    parent: parent,
+                    name: name,
+                    ..inode
+                }),
+                Err(rusqlite::Error::QueryReturnedNoRows) => warn!("Skipping inode {} without a name", inode.ino),
+                Err(e) => return Err(Error::from(e))
+            }
         }
+        Ok(inodes)
+    }
 
+    /// The inode reached by a name
+    fn named_inode(&self, parent: &String, name: &String, id: &String) -> Result<INode, Error> {
+        let inode = self.query_inode_rows("inode.id = ?1", &[&id.as_str()])?.pop().ok_or(Error::NotFound)?;
+        Ok(INode {
+            parent: parent.clone(),
+            name: name.clone(),
+            ..inode
+        })
     }
 
-    fn query_inode(&self, where_clause: &str, params: &[&ToSql]) -> Vec<INode> {
+    /// Inodes with their current version, without a name yet
+    fn query_inode_rows(&self, where_clause: &str, params: &[&ToSql]) -> Result<Vec<INode>, Error> {
         let sql = format!("
             SELECT inode.ino,
                    inode.id,
-                   inode.parent,
-                   inode.name,
                    inode.kind,
                    inode.atime,
                    inode.mtime,
@@ -175,43 +1284,358 @@ impl Metadata {
                    inode.crtime,
                    inode.nlink,
                    inode.current_version,
-                   file_version.size
+                   file_version.size,
+                   inode.target,
+                   inode.mode,
+                   inode.uid,
+                   inode.gid,
+                   file_version.hash,
+                   file_version.hydrated,
+                   inode.pinned
            FROM inode
            LEFT OUTER JOIN file_version ON inode.id = file_version.id
                                        AND inode.current_version = file_version.version
            WHERE {}", where_clause);
-        let mut inode_stmt = self.conn.prepare(sql.as_str()).unwrap();
-        let mut inode_rows = inode_stmt.query(params).unwrap();
-
-        let mut inodes = Vec::new();
-        while let Some(result_row) = inode_rows.next() {
-            let row = result_row.unwrap();
 
+        let inodes = self.query_rows(sql.as_str(), params, |row| {
             let ino: i64 = row.get(0);
-            let size: i64 = match row.get(11) {
+            let size: i64 = match row.get(9) {
                 Some(file_version_size) => file_version_size,
                 None                    => 0
             };
+            let target: String = match row.get(10) {
+                Some(target) => target,
+                None         => String::new()
+            };
+            let kind = match INodeKind::from_i32(row.get(2)) {
+                Some(kind) => kind,
+                None       => {
+                    warn!("Skipping inode {} of an unknown kind", ino);
+                    return None;
+                }
+            };
+            let hydrated: Option<i32> = row.get(15);
+            let pinned: i32 = row.get(16);
 
-            inodes.push(INode {
+            Some(INode {
                 ino: ino as u64,
                 id: row.get(1),
-                parent: row.get(2),
-                name: row.get(3),
-                kind: INodeKind::from_i32(row.get(4)).unwrap(),
-                size: size as u64,
-                atime: row.get(5),
-                mtime: row.get(6),
-                ctime: row.get(7),
-                crtime: row.get(8),
-                nlink: row.get(9),
-                current_version: match row.get(10) {
+                parent: String::new(),
+                name: String::new(),
+                size: if kind.is_symlink() { target.len() as u64 } else { size as u64 },
+                kind: kind,
+                atime: row.get(3),
+                mtime: row.get(4),
+                ctime: row.get(5),
+                crtime: row.get(6),
+                nlink: row.get(7),
+                mode: row.get(11),
+                uid: row.get(12),
+                gid: row.get(13),
+                current_version: match row.get(8) {
                     Some(version) => version,
                     None          => String::new()
-                }
-            });
+                },
+                target: target,
+                hash: match row.get(14) {
+                    Some(hash) => hash,
+                    None       => String::new()
+                },
+                hydrated: hydrated.map_or(true, |hydrated| hydrated != 0),
+                pinned: pinned != 0
+            })
+        })?;
+        Ok(inodes.into_iter().filter_map(|inode| inode).collect())
+    }
+
+    /// Current versions of regular files, the corrupt ones and then the least
+    /// recently scrubbed first
+    pub fn get_versions_to_scrub(&self, limit: u32) -> Result<Vec<(INode, FileVersion)>, Error> {
+        let versions = self.query_rows("
+            SELECT inode.ino,
+                   file_version.id,
+                   file_version.version,
+                   file_version.source_version,
+                   file_version.size,
+                   file_version.hash,
+                   file_version.last_scrub,
+                   file_version.corrupt,
+                   file_version.created
+              FROM inode
+              JOIN file_version ON inode.id = file_version.id
+                               AND inode.current_version = file_version.version
+             WHERE inode.kind = ?1
+               AND file_version.hydrated = 1
+             ORDER BY file_version.corrupt DESC, file_version.last_scrub ASC
+             LIMIT ?2", &[&(INodeKind::RegularFile as i32), &limit], |row| {
+            let ino: i64 = row.get(0);
+            let size: i64 = row.get(4);
+            let corrupt: i32 = row.get(7);
+
+            (ino as u64, FileVersion {
+                id: row.get(1),
+                version: row.get(2),
+                source_version: row.get(3),
+                size: size as u64,
+                hash: row.get(5),
+                last_scrub: row.get(6),
+                corrupt: corrupt != 0,
+                created: row.get(8)
+            })
+        })?;
+
+        let mut inodes = Vec::new();
+        for (ino, file_version) in versions {
+            if let Some(inode) = optional(self.get_by_ino(ino))? {
+                inodes.push((inode, file_version));
+            }
+        }
+        Ok(inodes)
+    }
+
+    /// Append an action made by `uid` on `device` to the log, returns its sequence number
+    ///
+    /// Local only actions are never handed out for replication.
+    pub fn log_action(&self, name: &str, data: &Vec<u8>, device: &str, uid: u32, local_only: bool) -> Result<i64, Error> {
+        let log_time = time::get_time();
+
+        match self.conn.execute("
+            INSERT INTO action_log (name, data, time, device, uid, local_only)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)", &[&name, data, &log_time, &device, &(uid as i64), &(local_only as i32)]) {
+            Ok(_)  => Ok(self.conn.last_insert_rowid()),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    pub fn finish_action(&self, seq: i64, success: bool) -> Result<(), Error> {
+        match self.conn.execute("
+            UPDATE action_log
+               SET finished = 1,
+                   success = ?2
+             WHERE seq = ?1", &[&seq, &(success as i32)]) {
+            Ok(_)  => Ok(()),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    /// Successful actions made on the device after the given sequence number,
+    /// to replicate to peers
+    ///
+    /// Actions replayed from peers are left out, their own device hands them out.
+    pub fn get_actions_since(&self, device: &str, seq: i64) -> Result<Vec<ActionEnvelope>, Error> {
+        self.query_rows("
+            SELECT seq, device, uid, name, data
+              FROM action_log
+             WHERE seq > ?2
+               AND device = ?1
+               AND success = 1
+               AND local_only = 0
+             ORDER BY seq", &[&device, &seq], |row| {
+            let uid: i64 = row.get(2);
+
+            ActionEnvelope {
+                seq: row.get(0),
+                device: row.get(1),
+                uid: uid as u32,
+                name: row.get(3),
+                data: row.get(4)
+            }
+        })
+    }
+
+    /// Keep an action of a peer for later, see `get_pending_actions`
+    pub fn add_pending_action(&self, envelope: &ActionEnvelope) -> Result<(), Error> {
+        match self.conn.execute("
+            INSERT INTO pending_action (seq, device, uid, name, data)
+            VALUES (?1, ?2, ?3, ?4, ?5)", &[&envelope.seq, &envelope.device, &(envelope.uid as i64), &envelope.name, &envelope.data]) {
+            Ok(_)  => Ok(()),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    /// Actions of peers that weren't replayed yet because the sync rules hide
+    /// their target, by id in the order they were received
+    pub fn get_pending_actions(&self) -> Result<Vec<(i64, ActionEnvelope)>, Error> {
+        self.query_rows("
+            SELECT id, seq, device, uid, name, data
+              FROM pending_action
+             ORDER BY id", &[], |row| {
+            let uid: i64 = row.get(3);
+
+            (row.get(0), ActionEnvelope {
+                seq: row.get(1),
+                device: row.get(2),
+                uid: uid as u32,
+                name: row.get(4),
+                data: row.get(5)
+            })
+        })
+    }
+
+    pub fn remove_pending_action(&self, id: i64) -> Result<(), Error> {
+        match self.conn.execute("DELETE FROM pending_action WHERE id = ?1", &[&id]) {
+            Ok(_)  => Ok(()),
+            Err(e) => Err(Error::from(e))
         }
-        inodes
     }
 
+    /// Actions after the given sequence number, including local and failed ones
+    pub fn get_action_log(&self, seq: i64, limit: u32) -> Result<Vec<LogEntry>, Error> {
+        self.query_rows("
+            SELECT seq, name, time, device, uid, finished, success, local_only
+              FROM action_log
+             WHERE seq > ?1
+             ORDER BY seq
+             LIMIT ?2", &[&seq, &limit], |row| {
+            let uid: i64 = row.get(4);
+            let finished: i32 = row.get(5);
+            let success: i32 = row.get(6);
+            let local_only: i32 = row.get(7);
+
+            LogEntry {
+                seq: row.get(0),
+                name: row.get(1),
+                time: row.get(2),
+                device: row.get(3),
+                uid: uid as u32,
+                finished: finished != 0,
+                success: success != 0,
+                local_only: local_only != 0
+            }
+        })
+    }
+
+    pub fn get_status(&self) -> Result<Status, Error> {
+        let count = |sql: &str| self.query_number(sql).map(|count| count as u64);
+        let current = "FROM inode
+                       JOIN file_version ON inode.id = file_version.id
+                                        AND inode.current_version = file_version.version";
+
+        Ok(Status {
+            inodes: self.count_inodes()?,
+            files: count(&format!("SELECT count(*) {}", current))?,
+            placeholders: count(&format!("SELECT count(*) {} WHERE file_version.hydrated = 0", current))?,
+            pinned: count("SELECT count(*) FROM inode WHERE pinned = 1")?,
+            hydrated_bytes: self.hydrated_size()?,
+            unsynced: count(&format!("SELECT count(*) {} WHERE file_version.synced = 0 AND file_version.hash != ''", current))?,
+            corrupt: count(&format!("SELECT count(*) {} WHERE file_version.corrupt = 1", current))?,
+            unfinished_actions: count("SELECT count(*) FROM action_log WHERE finished = 0")?
+        })
+    }
+
+    /// Every inode, for checks walking the whole volume
+    pub fn get_all(&self) -> Result<Vec<INode>, Error> {
+        self.query_inode("inode.ino > 0 ORDER BY inode.ino", &[])
+    }
+
+    /// Inconsistencies within the metadata itself, empty when there are none
+    pub fn check_integrity(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        let messages: Vec<String> = match self.conn.prepare("PRAGMA integrity_check")
+            .and_then(|mut stmt| stmt.query_map(&[], |row| row.get(0))?.collect()) {
+            Ok(messages) => messages,
+            Err(e)       => vec![e.to_string()]
+        };
+        for message in messages {
+            if message != "ok" {
+                problems.push(format!("Database: {}", message));
+            }
+        }
+
+        let checks = [
+            ("Entry without inode", "
+                SELECT dentry.parent || '/' || dentry.name
+                  FROM dentry
+                 WHERE dentry.id NOT IN (SELECT id FROM inode)"),
+            ("Inode without entry", "
+                SELECT inode.id
+                  FROM inode
+                 WHERE inode.id NOT IN (SELECT id FROM dentry)"),
+            ("File without current version", "
+                SELECT inode.id
+                  FROM inode
+                 WHERE inode.kind = 1
+                   AND NOT EXISTS (SELECT 1 FROM file_version
+                                    WHERE file_version.id = inode.id
+                                      AND file_version.version = inode.current_version)"),
+            ("Wrong link count", "
+                SELECT inode.id
+                  FROM inode
+                 WHERE inode.kind = 0
+                   AND inode.nlink != 2 + (SELECT count(*) FROM dentry JOIN inode child ON dentry.id = child.id
+                                            WHERE dentry.parent = inode.id AND child.kind = 0 AND child.id != inode.id)")
+        ];
+        for &(description, sql) in checks.iter() {
+            let subjects: Result<Vec<String>, _> = self.conn.prepare(sql)
+                .and_then(|mut stmt| stmt.query_map(&[], |row| row.get(0))?.collect());
+            match subjects {
+                Ok(subjects) => problems.extend(subjects.into_iter().map(|subject| format!("{}: {}", description, subject))),
+                Err(e)       => problems.push(format!("{}: check failed, {}", description, e))
+            }
+        }
+
+        problems
+    }
+
+    /// Consistent copy of the database file, for off-site snapshots
+    ///
+    /// Holds the write lock while reading, so no transaction commits halfway.
+    /// The copy is the whole database: entries a `.markfsignore` keeps on this
+    /// device are in it with their names and attributes, only their content
+    /// is never uploaded.
+    pub fn snapshot(&self) -> Result<Vec<u8>, Error> {
+        let file: String = match self.conn.query_row("PRAGMA database_list", &[], |row| row.get(2)) {
+            Ok(file) => file,
+            Err(e)   => return Err(Error::from(e))
+        };
+
+        // In-memory metadata has no file to copy
+        if file.is_empty() {
+            return Err(Error::NotSupported);
+        }
+
+        self.conn.execute_batch("BEGIN IMMEDIATE")?;
+
+        let mut data = Vec::new();
+        let result = match File::open(&file).and_then(|mut f| f.read_to_end(&mut data)) {
+            Ok(_)  => Ok(data),
+            Err(e) => Err(Error::from(e))
+        };
+
+        match self.conn.execute_batch("ROLLBACK") {
+            Ok(_)  => result,
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    /// Rows of a query that only reads
+    fn query_rows<T, F: FnMut(&Row) -> T>(&self, sql: &str, params: &[&ToSql], f: F) -> Result<Vec<T>, Error> {
+        let rows = self.conn.prepare(sql).and_then(|mut stmt| {
+            let rows = stmt.query_map(params, f)?.collect::<Result<Vec<T>, _>>();
+            rows
+        })?;
+        Ok(rows)
+    }
+
+    /// A count or sum, zero when there are no rows to count
+    fn query_number(&self, sql: &str) -> Result<i64, Error> {
+        let number = self.conn.query_row(sql, &[], |row| row.get::<_, Option<i64>>(0))?;
+        Ok(number.unwrap_or(0))
+    }
+
+    pub fn set_scrubbed(&self, file_version: &FileVersion, corrupt: bool) -> Result<(), Error> {
+        let scrub_time = time::get_time();
+
+        match self.conn.execute("
+            UPDATE file_version
+               SET last_scrub = ?3,
+                   corrupt = ?4
+             WHERE id = ?1
+               AND version = ?2",
+            &[&file_version.id, &file_version.version, &scrub_time, &(corrupt as i32)]) {
+            Ok(_)  => Ok(()),
+            Err(e) => Err(Error::from(e))
+        }
+    }
 }
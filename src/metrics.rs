@@ -0,0 +1,425 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::mem;
+use std::thread;
+use std::time::{Duration, Instant};
+use libc::c_int;
+use rustc_serialize::json::{Json, ToJson};
+use time::{self, Timespec};
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: &'static [f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Number of failed operations kept for `recent_errors`
+const RECENT_ERRORS: usize = 20;
+
+/// Operations per inode are counted over the last one or two windows
+const FILE_WINDOW_SECS: u64 = 10;
+
+/// Counters and a latency histogram of one operation
+#[derive(Debug, Clone, Default)]
+pub struct OpStats {
+    pub count: u64,
+    pub errors: u64,
+    pub total_seconds: f64,
+    /// Operations per bucket of `BUCKETS`, the last one counts the slower ones
+    pub buckets: Vec<u64>
+}
+
+impl OpStats {
+    fn record(&mut self, duration: Duration, failed: bool) {
+        let seconds = seconds(duration);
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; BUCKETS.len() + 1];
+        }
+
+        self.count += 1;
+        if failed {
+            self.errors += 1;
+        }
+        self.total_seconds += seconds;
+        let bucket = BUCKETS.iter().position(|&bound| seconds <= bound).unwrap_or(BUCKETS.len());
+        self.buckets[bucket] += 1;
+    }
+
+    /// Parse the stats as reported by the control socket
+    pub fn from_json(json: &Json) -> Option<OpStats> {
+        let buckets = match json.find("buckets") {
+            Some(&Json::Array(ref buckets)) => buckets.iter().map(|bucket| bucket.as_u64().unwrap_or(0)).collect(),
+            _                               => Vec::new()
+        };
+        Some(OpStats {
+            count: json.find("count")?.as_u64()?,
+            errors: json.find("errors")?.as_u64()?,
+            total_seconds: json.find("total_seconds")?.as_f64()?,
+            buckets
+        })
+    }
+
+    pub fn average_seconds(&self) -> f64 {
+        if self.count == 0 { 0.0 } else { self.total_seconds / self.count as f64 }
+    }
+
+    /// Upper bound of the bucket holding the given fraction of the operations,
+    /// infinite when they're slower than the last bucket
+    pub fn quantile_seconds(&self, quantile: f64) -> f64 {
+        let target = (self.count as f64 * quantile).ceil() as u64;
+        let mut cumulative = 0;
+        for (i, &bound) in BUCKETS.iter().enumerate() {
+            cumulative += self.buckets.get(i).cloned().unwrap_or(0);
+            if cumulative >= target {
+                return bound;
+            }
+        }
+        ::std::f64::INFINITY
+    }
+}
+
+impl ToJson for OpStats {
+    fn to_json(&self) -> Json {
+        let mut stats = BTreeMap::new();
+        stats.insert("count".to_string(), self.count.to_json());
+        stats.insert("errors".to_string(), self.errors.to_json());
+        stats.insert("total_seconds".to_string(), self.total_seconds.to_json());
+        stats.insert("buckets".to_string(), self.buckets.to_json());
+        Json::Object(stats)
+    }
+}
+
+/// Bytes moved to and from one peer
+#[derive(Debug, Clone, Default)]
+pub struct Transfers {
+    pub uploaded_bytes: u64,
+    pub downloaded_bytes: u64
+}
+
+impl Transfers {
+    pub fn from_json(json: &Json) -> Option<Transfers> {
+        Some(Transfers {
+            uploaded_bytes: json.find("uploaded_bytes")?.as_u64()?,
+            downloaded_bytes: json.find("downloaded_bytes")?.as_u64()?
+        })
+    }
+}
+
+impl ToJson for Transfers {
+    fn to_json(&self) -> Json {
+        let mut transfers = BTreeMap::new();
+        transfers.insert("uploaded_bytes".to_string(), self.uploaded_bytes.to_json());
+        transfers.insert("downloaded_bytes".to_string(), self.downloaded_bytes.to_json());
+        Json::Object(transfers)
+    }
+}
+
+/// A failed operation or action
+#[derive(Debug, Clone)]
+pub struct RecentError {
+    pub time: Timespec,
+    pub name: String,
+    /// The inode number of an operation, the inode id of an action
+    pub subject: String,
+    pub errno: c_int
+}
+
+impl RecentError {
+    pub fn from_json(json: &Json) -> Option<RecentError> {
+        Some(RecentError {
+            time: Timespec::new(json.find("time")?.as_i64()?, 0),
+            name: json.find("name")?.as_string()?.to_string(),
+            subject: json.find("subject")?.as_string()?.to_string(),
+            errno: json.find("errno")?.as_i64()? as c_int
+        })
+    }
+}
+
+impl ToJson for RecentError {
+    fn to_json(&self) -> Json {
+        let mut error = BTreeMap::new();
+        error.insert("time".to_string(), self.time.sec.to_json());
+        error.insert("name".to_string(), self.name.to_json());
+        error.insert("subject".to_string(), self.subject.to_json());
+        error.insert("errno".to_string(), (self.errno as i64).to_json());
+        Json::Object(error)
+    }
+}
+
+struct Stats {
+    operations: BTreeMap<&'static str, OpStats>,
+    actions: BTreeMap<String, OpStats>,
+    transfers: BTreeMap<String, Transfers>,
+    recent_errors: VecDeque<RecentError>,
+    /// Operations per inode in the current and the previous window
+    files: HashMap<u64, u64>,
+    previous_files: HashMap<u64, u64>,
+    window_start: Instant
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats {
+            operations: BTreeMap::new(),
+            actions: BTreeMap::new(),
+            transfers: BTreeMap::new(),
+            recent_errors: VecDeque::new(),
+            files: HashMap::new(),
+            previous_files: HashMap::new(),
+            window_start: Instant::now()
+        }
+    }
+}
+
+impl Stats {
+    fn add_error(&mut self, name: &str, subject: String, errno: c_int) {
+        if self.recent_errors.len() == RECENT_ERRORS {
+            self.recent_errors.pop_front();
+        }
+        self.recent_errors.push_back(RecentError {
+            time: time::get_time(),
+            name: name.to_string(),
+            subject,
+            errno
+        });
+    }
+
+    fn count_file(&mut self, ino: u64) {
+        let window = Duration::from_secs(FILE_WINDOW_SECS);
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= window {
+            // Nothing happened during the last window when it's long gone
+            let files = mem::replace(&mut self.files, HashMap::new());
+            self.previous_files = if elapsed < window * 2 { files } else { HashMap::new() };
+            self.window_start = Instant::now();
+        }
+        *self.files.entry(ino).or_insert(0) += 1;
+    }
+}
+
+/// Counters and latencies of the FUSE operations and actions of a mount
+///
+/// Clones share their counters, so the control socket and the Prometheus
+/// endpoint report what the mount records.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    stats: Arc<Mutex<Stats>>
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Start timing a FUSE operation, recorded and logged when the `Op` is dropped
+    pub fn operation(&self, name: &'static str, ino: u64, fh: Option<u64>) -> Op {
+        Op {
+            metrics: self.clone(),
+            name,
+            ino,
+            fh,
+            start: Instant::now(),
+            errno: Cell::new(None)
+        }
+    }
+
+    pub fn record_operation(&self, name: &'static str, ino: u64, duration: Duration, errno: Option<c_int>) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.operations.entry(name).or_insert_with(OpStats::default).record(duration, errno.is_some());
+        stats.count_file(ino);
+        if let Some(errno) = errno {
+            stats.add_error(name, format!("ino {}", ino), errno);
+        }
+    }
+
+    /// `target` is the id of the inode acted on
+    pub fn record_action(&self, name: &str, target: &str, duration: Duration, errno: Option<c_int>) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.actions.entry(name.to_string()).or_insert_with(OpStats::default).record(duration, errno.is_some());
+        if let Some(errno) = errno {
+            stats.add_error(name, format!("id {}", target), errno);
+        }
+    }
+
+    pub fn record_upload(&self, peer: &str, bytes: u64) {
+        self.stats.lock().unwrap().transfers.entry(peer.to_string()).or_insert_with(Transfers::default).uploaded_bytes += bytes;
+    }
+
+    pub fn record_download(&self, peer: &str, bytes: u64) {
+        self.stats.lock().unwrap().transfers.entry(peer.to_string()).or_insert_with(Transfers::default).downloaded_bytes += bytes;
+    }
+
+    pub fn operations(&self) -> BTreeMap<String, OpStats> {
+        self.stats.lock().unwrap().operations.iter().map(|(&name, stats)| (name.to_string(), stats.clone())).collect()
+    }
+
+    pub fn actions(&self) -> BTreeMap<String, OpStats> {
+        self.stats.lock().unwrap().actions.clone()
+    }
+
+    /// Bytes moved by peer name
+    pub fn transfers(&self) -> BTreeMap<String, Transfers> {
+        self.stats.lock().unwrap().transfers.clone()
+    }
+
+    /// The last failed operations and actions, oldest first
+    pub fn recent_errors(&self) -> Vec<RecentError> {
+        self.stats.lock().unwrap().recent_errors.iter().cloned().collect()
+    }
+
+    /// Inode numbers with the most operations in the last 10 to 20 seconds, and their count
+    pub fn busiest_files(&self, limit: usize) -> Vec<(u64, u64)> {
+        let stats = self.stats.lock().unwrap();
+        let mut files = stats.previous_files.clone();
+        for (&ino, &count) in stats.files.iter() {
+            *files.entry(ino).or_insert(0) += count;
+        }
+
+        let mut files: Vec<(u64, u64)> = files.into_iter().collect();
+        files.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        files.truncate(limit);
+        files
+    }
+
+    /// Everything recorded, as answered by the `metrics` command of the control socket
+    pub fn to_json(&self) -> Json {
+        let mut metrics = BTreeMap::new();
+        metrics.insert("operations".to_string(), self.operations().to_json());
+        metrics.insert("actions".to_string(), self.actions().to_json());
+        metrics.insert("buckets".to_string(), BUCKETS.to_vec().to_json());
+        metrics.insert("transfers".to_string(), self.transfers().to_json());
+        metrics.insert("recent_errors".to_string(), self.recent_errors().to_json());
+        Json::Object(metrics)
+    }
+
+    /// Everything recorded, in the Prometheus text format
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        write_family(&mut text, "markfs_operation", "FUSE operation", "op", &self.operations());
+        write_family(&mut text, "markfs_action", "action", "action", &self.actions());
+
+        let transfers = self.transfers();
+        let _ = writeln!(text, "# HELP markfs_peer_uploaded_bytes_total Bytes uploaded to each peer");
+        let _ = writeln!(text, "# TYPE markfs_peer_uploaded_bytes_total counter");
+        for (peer, transfer) in transfers.iter() {
+            let _ = writeln!(text, "markfs_peer_uploaded_bytes_total{{peer=\"{}\"}} {}", peer, transfer.uploaded_bytes);
+        }
+        let _ = writeln!(text, "# HELP markfs_peer_downloaded_bytes_total Bytes downloaded from each peer");
+        let _ = writeln!(text, "# TYPE markfs_peer_downloaded_bytes_total counter");
+        for (peer, transfer) in transfers.iter() {
+            let _ = writeln!(text, "markfs_peer_downloaded_bytes_total{{peer=\"{}\"}} {}", peer, transfer.downloaded_bytes);
+        }
+        text
+    }
+}
+
+fn write_family(text: &mut String, family: &str, description: &str, label: &str, stats: &BTreeMap<String, OpStats>) {
+    let _ = writeln!(text, "# HELP {}s_total Number of each {}", family, description);
+    let _ = writeln!(text, "# TYPE {}s_total counter", family);
+    for (name, op) in stats.iter() {
+        let _ = writeln!(text, "{}s_total{{{}=\"{}\"}} {}", family, label, name, op.count);
+    }
+
+    let _ = writeln!(text, "# HELP {}_errors_total Number of each {} that failed", family, description);
+    let _ = writeln!(text, "# TYPE {}_errors_total counter", family);
+    for (name, op) in stats.iter() {
+        let _ = writeln!(text, "{}_errors_total{{{}=\"{}\"}} {}", family, label, name, op.errors);
+    }
+
+    let _ = writeln!(text, "# HELP {}_duration_seconds Latency of each {}", family, description);
+    let _ = writeln!(text, "# TYPE {}_duration_seconds histogram", family);
+    for (name, op) in stats.iter() {
+        let mut cumulative = 0;
+        for (i, &bound) in BUCKETS.iter().enumerate() {
+            cumulative += op.buckets.get(i).cloned().unwrap_or(0);
+            let _ = writeln!(text, "{}_duration_seconds_bucket{{{}=\"{}\",le=\"{}\"}} {}", family, label, name, bound, cumulative);
+        }
+        let _ = writeln!(text, "{}_duration_seconds_bucket{{{}=\"{}\",le=\"+Inf\"}} {}", family, label, name, op.count);
+        let _ = writeln!(text, "{}_duration_seconds_sum{{{}=\"{}\"}} {}", family, label, name, op.total_seconds);
+        let _ = writeln!(text, "{}_duration_seconds_count{{{}=\"{}\"}} {}", family, label, name, op.count);
+    }
+}
+
+/// A running FUSE operation, see `Metrics::operation`
+pub struct Op {
+    metrics: Metrics,
+    name: &'static str,
+    ino: u64,
+    fh: Option<u64>,
+    start: Instant,
+    errno: Cell<Option<c_int>>
+}
+
+impl Op {
+    /// Mark the operation as failed, returns the errno to reply with
+    pub fn fail(&self, errno: c_int) -> c_int {
+        self.errno.set(Some(errno));
+        errno
+    }
+}
+
+impl Drop for Op {
+    fn drop(&mut self) {
+        let duration = self.start.elapsed();
+        let fh = self.fh.map_or("-".to_string(), |fh| fh.to_string());
+        let result = self.errno.get().map_or("ok".to_string(), |errno| format!("errno {}", errno));
+        debug!("op={} ino={} fh={} duration_us={} result={}", self.name, self.ino, fh, micros(duration), result);
+
+        self.metrics.record_operation(self.name, self.ino, duration, self.errno.get());
+    }
+}
+
+pub fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+pub fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + duration.subsec_nanos() as u64 / 1000
+}
+
+/// Serve the metrics over HTTP in the Prometheus text format, on its own thread
+///
+/// Meant for a localhost address, there's no authentication. Stops once
+/// the flag is set, see `wake`.
+pub fn spawn_endpoint(address: SocketAddr, metrics: Metrics, stopped: Arc<AtomicBool>) -> io::Result<thread::JoinHandle<()>> {
+    let listener = TcpListener::bind(address)?;
+
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            if stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = respond(stream, &metrics) {
+                        debug!("Metrics connection failed: {}", e);
+                    }
+                },
+                Err(e) => warn!("Unable to accept a metrics connection: {}", e)
+            }
+        }
+    }))
+}
+
+/// Answer any GET with the metrics, the path doesn't matter
+fn respond(stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut writer = stream.try_clone()?;
+
+    // Skip the request line and headers
+    for line in BufReader::new(stream).lines() {
+        if line?.trim().is_empty() {
+            break;
+        }
+    }
+
+    let body = metrics.to_prometheus();
+    write!(writer, "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)
+}
+
+/// Let an endpoint blocked in accept see that it's stopped
+pub fn wake(address: &SocketAddr) {
+    let _ = TcpStream::connect(address);
+}
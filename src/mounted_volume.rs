@@ -0,0 +1,228 @@
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use fuse;
+use markfs::MarkFS;
+use metadata::Metadata;
+use local::LocalFileOperations;
+use storage::StorageBackend;
+use scrubber::Scrubber;
+use replicator::Replicator;
+use uploader;
+use s3::S3Remote;
+use hydrate::Hydrator;
+use config::VolumeConfig;
+use control::{self, ControlClient, ControlServer, socket_path};
+use metrics;
+use throttle::Bandwidth;
+
+/// Pause of the background scrubber between batches
+const SCRUB_INTERVAL_SECS: u64 = 60;
+
+#[cfg(target_os = "macos")]
+const UNMOUNT_COMMAND: (&'static str, &'static [&'static str]) = ("umount", &[]);
+#[cfg(not(target_os = "macos"))]
+const UNMOUNT_COMMAND: (&'static str, &'static [&'static str]) = ("fusermount", &["-u"]);
+
+/// A volume stored at a local path, mounted with its background threads and control socket
+///
+/// The mount runs on its own thread, so one process can serve several volumes.
+/// Its uploader, scrubber and control socket stop once it's unmounted.
+pub struct MountedVolume {
+    pub local_path: OsString,
+    pub mountpoint: OsString,
+    pub config: VolumeConfig,
+    stopped: Arc<AtomicBool>,
+    session: thread::JoinHandle<Result<(), String>>
+}
+
+impl MountedVolume {
+    /// Returns once the volume is mounted, or with the reason it couldn't be
+    pub fn mount(local_path: &OsString, mountpoint: &OsString, config: VolumeConfig) -> Result<MountedVolume, String> {
+        if !Path::new(&config.state_dir).join("metadata.sqlite").is_file() {
+            return Err(format!("No volume at {}, create one with `markfs init`", local_path.to_string_lossy()));
+        }
+        if ControlClient::connect(&config.state_dir).is_some() {
+            return Err(format!("{} is already mounted", local_path.to_string_lossy()));
+        }
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let (ready, setup) = mpsc::channel();
+
+        let session = {
+            let local_path = local_path.clone();
+            let mountpoint = mountpoint.clone();
+            let config = config.clone();
+            let stopped = stopped.clone();
+
+            // The metadata connection can't leave the thread that opens it
+            thread::spawn(move || {
+                let paused = Arc::new(AtomicBool::new(false));
+                let markfs = match setup_volume(&local_path, &mountpoint, &config, paused.clone(), stopped.clone()) {
+                    Ok(markfs) => markfs,
+                    Err(e)     => {
+                        stopped.store(true, Ordering::SeqCst);
+                        if let Some(ref address) = config.metrics_address {
+                            metrics::wake(address);
+                        }
+                        let _ = ready.send(Err(e.clone()));
+                        return Err(e);
+                    }
+                };
+
+                let mounted = ready.clone();
+                let result = run(markfs, &mountpoint, &config.mount_options, move || {
+                    let _ = mounted.send(Ok(()));
+                });
+                // Nobody listens anymore when the mount existed
+                if let Err(ref e) = result {
+                    let _ = ready.send(Err(e.clone()));
+                }
+
+                // Let the uploader drain and the other threads see they're stopped
+                stopped.store(true, Ordering::SeqCst);
+                paused.store(false, Ordering::SeqCst);
+                control::wake(&socket_path(&config.state_dir));
+                if let Some(ref address) = config.metrics_address {
+                    metrics::wake(address);
+                }
+                info!("Unmounted {:?} from {:?}", local_path, mountpoint);
+                result
+            })
+        };
+
+        match setup.recv() {
+            Ok(Ok(()))  => (),
+            Ok(Err(e))  => return Err(e),
+            Err(_)      => return Err(format!("Unable to set up {}", local_path.to_string_lossy()))
+        }
+
+        Ok(MountedVolume {
+            local_path: local_path.clone(),
+            mountpoint: mountpoint.clone(),
+            config,
+            stopped,
+            session
+        })
+    }
+
+    /// False once the mount returned, like after an unmount from the outside
+    pub fn is_mounted(&self) -> bool {
+        !self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Ask the system to unmount, `wait` returns afterwards
+    pub fn unmount(&self) -> Result<(), String> {
+        unmount_path(&self.mountpoint)
+    }
+
+    /// Block until the volume is unmounted
+    pub fn wait(self) -> Result<(), String> {
+        match self.session.join() {
+            Ok(result) => result,
+            Err(_)     => Err(format!("The mount of {} panicked", self.local_path.to_string_lossy()))
+        }
+    }
+}
+
+/// Open the volume and start its uploader, scrubber and control socket
+fn setup_volume(local_path: &OsString, mountpoint: &OsString, config: &VolumeConfig, paused: Arc<AtomicBool>, stopped: Arc<AtomicBool>) -> Result<MarkFS<LocalFileOperations>, String> {
+    let scrub_storage = LocalFileOperations::new(local_path);
+    let mut scrubber = Scrubber::new(&config.state_dir, scrub_storage, config.scrub_bytes_per_second, Duration::from_secs(SCRUB_INTERVAL_SECS));
+    scrubber.set_stop_flag(stopped.clone());
+
+    let metadata = Metadata::new(&config.state_dir).map_err(|e| format!("Unable to open the metadata: {}", e))?;
+    let mut markfs = MarkFS::new(metadata, LocalFileOperations::new(local_path), mountpoint).map_err(|e| format!("Unable to read the sync rules: {}", e))?;
+    markfs.configure(config);
+
+    // Off-site copies, the peers double as sources for repairs and placeholders
+    let hydrator = Hydrator::new();
+    let bandwidth = Bandwidth::new(config.upload_bytes_per_second, config.download_bytes_per_second);
+    for (i, peer) in config.peers.iter().enumerate() {
+        match (S3Remote::new(peer.clone()), S3Remote::new(peer.clone())) {
+            (Ok(mut scrub_peer), Ok(mut hydrate_peer)) => {
+                scrub_peer.set_metrics(markfs.metrics());
+                scrub_peer.set_bandwidth(bandwidth.clone());
+                hydrate_peer.set_metrics(markfs.metrics());
+                hydrate_peer.set_bandwidth(bandwidth.clone());
+                scrubber.add_peer(Box::new(scrub_peer));
+                hydrator.add_peer(Box::new(hydrate_peer));
+            },
+            _ => return Err(format!("Unable to connect to the S3 bucket {}", peer.bucket))
+        }
+
+        // Only the first peer receives uploads, and exchanges the actions with other devices
+        if i == 0 {
+            match (S3Remote::new(peer.clone()), S3Remote::new(peer.clone())) {
+                (Ok(mut uploader), Ok(mut replicate_peer)) => {
+                    uploader.set_metrics(markfs.metrics());
+                    uploader.set_bandwidth(bandwidth.clone());
+                    markfs.set_upload_queue(uploader::spawn(uploader, &config.state_dir, LocalFileOperations::new(local_path), peer.snapshot_interval, paused.clone()));
+
+                    replicate_peer.set_metrics(markfs.metrics());
+                    replicate_peer.set_bandwidth(bandwidth.clone());
+                    let mut replicator = Replicator::new(Box::new(replicate_peer), &config.state_dir, LocalFileOperations::new(local_path), peer.replicate_interval);
+                    replicator.set_action_runner(markfs.action_runner());
+                    replicator.set_open_files(markfs.open_files());
+                    replicator.set_sync_rules(markfs.sync_rules());
+                    replicator.set_hydrator(hydrator.clone());
+                    replicator.set_pause_flag(paused.clone());
+                    replicator.set_stop_flag(stopped.clone());
+                    replicator.spawn();
+                },
+                _ => return Err(format!("Unable to connect to the S3 bucket {}", peer.bucket))
+            }
+        }
+    }
+
+    // Placeholders are filled from the peers on first open, pinned ones right away
+    markfs.set_hydrator(hydrator.clone());
+    markfs.set_hydrate_queue(hydrator.spawn_queue(&config.state_dir, LocalFileOperations::new(local_path)));
+
+    if let Some(address) = config.metrics_address {
+        if let Err(e) = metrics::spawn_endpoint(address, markfs.metrics(), stopped.clone()) {
+            return Err(format!("Unable to serve the metrics at {}: {}", address, e));
+        }
+    }
+
+    let mut control_server = ControlServer::new(local_path, config, hydrator, paused);
+    control_server.set_stop_flag(stopped);
+    control_server.set_metrics(markfs.metrics());
+    control_server.set_bandwidth(bandwidth);
+    control_server.set_sync_rules(markfs.sync_rules());
+    if let Err(e) = control_server.spawn() {
+        return Err(format!("Unable to create the control socket: {}", e));
+    }
+
+    scrubber.set_open_files(markfs.open_files());
+    scrubber.spawn();
+    Ok(markfs)
+}
+
+/// Mount with FUSE, calls `mounted` once the mount exists and returns once unmounted
+pub fn run<S: StorageBackend, F: FnOnce()>(markfs: MarkFS<S>, mountpoint: &OsString, mount_options: &[String], mounted: F) -> Result<(), String> {
+    let options: Vec<&OsStr> = mount_options.iter()
+        .flat_map(|option| vec![OsStr::new("-o"), OsStr::new(option.as_str())])
+        .collect();
+
+    let mut session = fuse::Session::new(markfs, Path::new(mountpoint), &options)
+        .map_err(|e| format!("Unable to mount at {}: {}", mountpoint.to_string_lossy(), e))?;
+    mounted();
+    session.run()
+        .map_err(|e| format!("Unable to serve the mount at {}: {}", mountpoint.to_string_lossy(), e))
+}
+
+/// Unmount with the system tool
+pub fn unmount_path(mountpoint: &OsString) -> Result<(), String> {
+    let (program, program_args) = UNMOUNT_COMMAND;
+    match Command::new(program).args(program_args).arg(mountpoint).status() {
+        Ok(ref status) if status.success() => Ok(()),
+        Ok(_)  => Err(format!("Unable to unmount {}, still in use?", mountpoint.to_string_lossy())),
+        Err(e) => Err(format!("Unable to run {}: {}", program, e))
+    }
+}
@@ -0,0 +1,47 @@
+use std::io::Read;
+use error::Error;
+use metadata::{Metadata, ActionEnvelope};
+
+/// A device or remote that can hand out stored version blobs
+///
+/// Blobs are content-addressed by the hash recorded in `file_version.hash`,
+/// so any peer holding the same content can serve it.
+pub trait Peer {
+
+    /// Return the name, used in logging
+    fn name(&self) -> &str;
+
+    /// Open the blob with the given hash, if this peer holds it
+    fn fetch(&self, hash: &str) -> Option<Box<Read>>;
+}
+
+/// A peer that also stores what the devices of a volume share: blobs,
+/// metadata snapshots and actions
+///
+/// `S3Remote` stores them in a bucket. The uploader and `Replicator` only
+/// go through this trait, so another kind of remote can take its place.
+pub trait Remote: Peer {
+
+    /// Whether the remote already holds the blob
+    fn has_blob(&self, hash: &str) -> bool;
+
+    /// Store the blob read from `reader`, unless the remote already holds it
+    ///
+    /// Fails when the content read doesn't match `hash`.
+    fn put_blob(&self, hash: &str, size: u64, reader: &mut Read) -> Result<(), Error>;
+
+    /// Remove the blob and any local copy of it, see `Metadata::prune_versions`
+    fn delete_blob(&self, hash: &str) -> Result<(), Error>;
+
+    /// Store a copy of the metadata database, see `Metadata::snapshot` for what it holds
+    fn put_snapshot(&self, metadata: &Metadata) -> Result<(), Error>;
+
+    /// Store an action made on this device, for the other devices to replay
+    fn put_action(&self, envelope: &ActionEnvelope) -> Result<(), Error>;
+
+    /// Ids of the devices that stored actions
+    fn get_action_devices(&self) -> Result<Vec<String>, Error>;
+
+    /// Actions of a device after the given sequence number, oldest first
+    fn get_actions(&self, device: &str, seq: i64) -> Result<Vec<ActionEnvelope>, Error>;
+}
@@ -0,0 +1,240 @@
+use std::mem;
+use std::ptr;
+use libc::{self, c_char, c_int, gid_t, passwd};
+use metadata::INode;
+
+pub const R_OK: u32 = 4;
+pub const W_OK: u32 = 2;
+pub const X_OK: u32 = 1;
+
+pub const ACL_ACCESS: &'static str = "system.posix_acl_access";
+pub const ACL_DEFAULT: &'static str = "system.posix_acl_default";
+
+const ACL_VERSION: u32 = 2;
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AclEntry {
+    pub tag: u16,
+    pub perm: u16,
+    pub id: u32
+}
+
+/// POSIX ACL, in the format of the `system.posix_acl_*` extended attributes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Acl {
+    pub entries: Vec<AclEntry>
+}
+
+impl Acl {
+    /// Parse the little endian attribute value, None when malformed
+    pub fn parse(data: &[u8]) -> Option<Acl> {
+        if data.len() < 4 || (data.len() - 4) % 8 != 0 || read_u32(&data[0..4]) != ACL_VERSION {
+            return None;
+        }
+
+        let entries: Vec<AclEntry> = data[4..].chunks(8).map(|chunk| AclEntry {
+            tag: read_u16(&chunk[0..2]),
+            perm: read_u16(&chunk[2..4]),
+            id: read_u32(&chunk[4..8])
+        }).collect();
+
+        // The owner, owning group and other entries are mandatory, and a mask
+        // is required as soon as there are named users or groups
+        let count = |tag: u16| entries.iter().filter(|entry| entry.tag == tag).count();
+        let named = count(ACL_USER) + count(ACL_GROUP);
+        if count(ACL_USER_OBJ) != 1 || count(ACL_GROUP_OBJ) != 1 || count(ACL_OTHER) != 1
+            || count(ACL_MASK) > 1 || (named > 0 && count(ACL_MASK) == 0)
+            || entries.iter().any(|entry| entry.perm & !0o7 != 0 || entry.tag & !0x3f != 0) {
+            return None;
+        }
+
+        Some(Acl { entries })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(4 + self.entries.len() * 8);
+        write_u32(&mut data, ACL_VERSION);
+        for entry in self.entries.iter() {
+            write_u16(&mut data, entry.tag);
+            write_u16(&mut data, entry.perm);
+            write_u32(&mut data, entry.id);
+        }
+        data
+    }
+
+    /// Permission bits of the mode, as mirrored by this ACL
+    pub fn mode(&self) -> u32 {
+        let perm = |tag: u16| self.entries.iter()
+            .find(|entry| entry.tag == tag)
+            .map(|entry| entry.perm as u32)
+            .unwrap_or(0);
+
+        let group = if self.has(ACL_MASK) { perm(ACL_MASK) } else { perm(ACL_GROUP_OBJ) };
+        (perm(ACL_USER_OBJ) << 6) | (group << 3) | perm(ACL_OTHER)
+    }
+
+    /// Apply a chmod, the group bits go to the mask if there is one
+    pub fn with_mode(&self, mode: u32) -> Acl {
+        let has_mask = self.has(ACL_MASK);
+        let entries = self.entries.iter().map(|entry| {
+            let perm = match entry.tag {
+                ACL_USER_OBJ                => Some((mode >> 6) & 0o7),
+                ACL_MASK                    => Some((mode >> 3) & 0o7),
+                ACL_GROUP_OBJ if !has_mask  => Some((mode >> 3) & 0o7),
+                ACL_OTHER                   => Some(mode & 0o7),
+                _                           => None
+            };
+            AclEntry {
+                perm: perm.map(|perm| perm as u16).unwrap_or(entry.perm),
+                ..entry.clone()
+            }
+        }).collect();
+
+        Acl { entries }
+    }
+
+    /// Access ACL of a new inode, created in a directory with this default ACL
+    pub fn inherit(&self, mode: u32) -> Acl {
+        let has_mask = self.has(ACL_MASK);
+        let entries = self.entries.iter().map(|entry| {
+            let bits = match entry.tag {
+                ACL_USER_OBJ                => (mode >> 6) & 0o7,
+                ACL_MASK                    => (mode >> 3) & 0o7,
+                ACL_GROUP_OBJ if !has_mask  => (mode >> 3) & 0o7,
+                ACL_OTHER                   => mode & 0o7,
+                _                           => 0o7
+            };
+            AclEntry {
+                perm: entry.perm & bits as u16,
+                ..entry.clone()
+            }
+        }).collect();
+
+        Acl { entries }
+    }
+
+    fn has(&self, tag: u16) -> bool {
+        self.entries.iter().any(|entry| entry.tag == tag)
+    }
+
+    fn allows(&self, inode: &INode, uid: u32, gids: &[u32], mask: u32) -> bool {
+        let mask_perm = self.entries.iter()
+            .find(|entry| entry.tag == ACL_MASK)
+            .map(|entry| entry.perm as u32)
+            .unwrap_or(0o7);
+
+        if inode.uid == uid {
+            return self.entries.iter()
+                .find(|entry| entry.tag == ACL_USER_OBJ)
+                .map_or(false, |entry| entry.perm as u32 & mask == mask);
+        }
+
+        if let Some(entry) = self.entries.iter().find(|entry| entry.tag == ACL_USER && entry.id == uid) {
+            return entry.perm as u32 & mask_perm & mask == mask;
+        }
+
+        // Any matching group entry that grants the access will do
+        let groups: Vec<&AclEntry> = self.entries.iter().filter(|entry| {
+            (entry.tag == ACL_GROUP_OBJ && gids.contains(&inode.gid)) || (entry.tag == ACL_GROUP && gids.contains(&entry.id))
+        }).collect();
+        if !groups.is_empty() {
+            return groups.iter().any(|entry| entry.perm as u32 & mask_perm & mask == mask);
+        }
+
+        self.entries.iter()
+            .find(|entry| entry.tag == ACL_OTHER)
+            .map_or(false, |entry| entry.perm as u32 & mask == mask)
+    }
+}
+
+/// Check whether the user, member of `gids`, may access the inode with the
+/// given `R_OK`, `W_OK` and `X_OK` mask, using the access ACL when there is one
+pub fn check_access(inode: &INode, acl: Option<&Acl>, uid: u32, gids: &[u32], mask: u32) -> bool {
+    if uid == 0 {
+        // Root may do anything, but only execute when someone may
+        return mask & X_OK == 0 || inode.kind.is_directory() || inode.mode & 0o111 != 0;
+    }
+
+    match acl {
+        Some(acl) => acl.allows(inode, uid, gids, mask),
+        None      => {
+            let bits = if inode.uid == uid {
+                (inode.mode >> 6) & 0o7
+            } else if gids.contains(&inode.gid) {
+                (inode.mode >> 3) & 0o7
+            } else {
+                inode.mode & 0o7
+            };
+            bits & mask == mask
+        }
+    }
+}
+
+/// Sticky directories only let owners remove or rename entries
+pub fn check_sticky(parent: &INode, inode: &INode, uid: u32) -> bool {
+    parent.mode & 0o1000 == 0 || uid == 0 || uid == parent.uid || uid == inode.uid
+}
+
+pub fn is_acl_name(name: &str) -> bool {
+    name == ACL_ACCESS || name == ACL_DEFAULT
+}
+
+extern {
+    // Not in the libc crate for every target yet
+    fn getgrouplist(user: *const c_char, group: gid_t, groups: *mut gid_t, ngroups: *mut c_int) -> c_int;
+}
+
+/// Supplementary groups of a host user from the group database, including
+/// `gid`, empty when the user has no entry
+///
+/// FUSE only passes the primary group of the caller, so they are looked up
+/// like `id` does.
+pub fn host_groups(uid: u32, gid: u32) -> Vec<u32> {
+    let mut pwd: passwd = unsafe { mem::zeroed() };
+    let mut buffer = vec![0 as c_char; 16 * 1024];
+    let mut result: *mut passwd = ptr::null_mut();
+    let found = unsafe { libc::getpwuid_r(uid, &mut pwd, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if found != 0 || result.is_null() {
+        return Vec::new();
+    }
+
+    let mut count: c_int = 32;
+    loop {
+        let mut groups = vec![0 as gid_t; count as usize];
+        let capacity = count;
+        if unsafe { getgrouplist(pwd.pw_name, gid, groups.as_mut_ptr(), &mut count) } >= 0 {
+            groups.truncate(count as usize);
+            return groups;
+        }
+
+        // Too small, the count is now the number of groups
+        if count <= capacity {
+            return Vec::new();
+        }
+    }
+}
+
+fn read_u16(data: &[u8]) -> u16 {
+    data[0] as u16 | (data[1] as u16) << 8
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    read_u16(&data[0..2]) as u32 | (read_u16(&data[2..4]) as u32) << 16
+}
+
+fn write_u16(data: &mut Vec<u8>, value: u16) {
+    data.push(value as u8);
+    data.push((value >> 8) as u8);
+}
+
+fn write_u32(data: &mut Vec<u8>, value: u32) {
+    write_u16(data, value as u16);
+    write_u16(data, (value >> 16) as u16);
+}
@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use bincode;
+use action_runner::ActionRunner;
+use actions::{self, WriteVersion};
+use error::{Error, optional};
+use hydrate::Hydrator;
+use metadata::{Metadata, ActionEnvelope};
+use peer::Remote;
+use storage::StorageBackend;
+use sync_rules::{SyncRules, SharedSyncRules};
+use volume::OpenFiles;
+
+/// Setting with the last action of this device stored in the bucket
+const PUBLISHED_SETTING: &'static str = "replication.published";
+
+/// Prefix of the settings with the last action replayed per device
+const REPLAYED_SETTING: &'static str = "replication.replayed.";
+
+/// How often a paused or sleeping replicator checks whether it should stop
+const POLL_MS: u64 = 500;
+
+/// Exchanges actions with the other devices through a peer
+///
+/// The actions made on this device are stored in the bucket, see
+/// `Remote::put_action`, and those of the other devices are replayed in
+/// the order they were made. How far each device got is kept in the settings,
+/// so nothing is sent or replayed twice across mounts.
+///
+/// A device whose action fails to replay is retried in the next round, a
+/// conflicting change is logged and skipped, the local one wins. A new
+/// version of a file that is open here waits until it's closed.
+///
+/// Actions on paths the sync rules hide are kept as pending and tried again
+/// every round, so they apply once the rules change. With sync rules, the
+/// placeholders they include are downloaded after each round; without
+/// rules content is only fetched when opened.
+pub struct Replicator<S: StorageBackend> {
+    remote: Box<Remote + Send>,
+    state_dir: OsString,
+    storage: S,
+    action_runner: ActionRunner,
+    open_files: OpenFiles,
+    sync_rules: SharedSyncRules,
+    hydrator: Hydrator,
+    interval: Duration,
+    paused: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>
+}
+
+impl<S: StorageBackend + Send + 'static> Replicator<S> {
+    pub fn new(remote: Box<Remote + Send>, state_dir: &OsString, storage: S, interval: Duration) -> Replicator<S> {
+        Replicator {
+            remote,
+            state_dir: state_dir.clone(),
+            storage,
+            action_runner: ActionRunner::new(),
+            open_files: Arc::new(Mutex::new(HashMap::new())),
+            sync_rules: Arc::new(Mutex::new(SyncRules::all())),
+            hydrator: Hydrator::new(),
+            interval,
+            paused: Arc::new(AtomicBool::new(false)),
+            stopped: Arc::new(AtomicBool::new(false))
+        }
+    }
+
+    /// Replay with the runner of the mount, so replays never run concurrently with its actions
+    pub fn set_action_runner(&mut self, action_runner: ActionRunner) {
+        self.action_runner = action_runner;
+    }
+
+    /// Leave files with a handle in here alone, see `Volume::open_files`
+    pub fn set_open_files(&mut self, open_files: OpenFiles) {
+        self.open_files = open_files;
+    }
+
+    /// Follow the rules of the mount, see `MarkFS::sync_rules`
+    pub fn set_sync_rules(&mut self, sync_rules: SharedSyncRules) {
+        self.sync_rules = sync_rules;
+    }
+
+    /// Download included placeholders with the peers of the mount
+    pub fn set_hydrator(&mut self, hydrator: Hydrator) {
+        self.hydrator = hydrator;
+    }
+
+    /// Wait while the flag is set, like the uploader
+    pub fn set_pause_flag(&mut self, paused: Arc<AtomicBool>) {
+        self.paused = paused;
+    }
+
+    /// Stop the spawned replicator after its current round once the flag is set
+    pub fn set_stop_flag(&mut self, stopped: Arc<AtomicBool>) {
+        self.stopped = stopped;
+    }
+
+    /// Run the replicator on its own thread, with its own metadata connection
+    pub fn spawn(self) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let metadata = match Metadata::new(&self.state_dir) {
+                Ok(metadata) => metadata,
+                Err(e)       => {
+                    error!("Replicator stopped, unable to open the metadata: {}", e);
+                    return;
+                }
+            };
+
+            while !self.stopped.load(Ordering::SeqCst) {
+                if !self.paused.load(Ordering::SeqCst) {
+                    self.replicate(&metadata);
+                }
+
+                let started = Instant::now();
+                while started.elapsed() < self.interval && !self.stopped.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(POLL_MS));
+                }
+            }
+        })
+    }
+
+    /// Store the new actions of this device and replay those of the others
+    pub fn replicate(&self, metadata: &Metadata) {
+        let device = match metadata.device_id() {
+            Ok(device) => device,
+            Err(e)     => {
+                warn!("Unable to replicate, no device id: {}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = self.publish(metadata, &device) {
+            warn!("Unable to store the actions of this device: {}", e);
+        }
+
+        // Rules reloaded through the control socket apply from the next round
+        let sync_rules = self.sync_rules.lock().unwrap().clone();
+        if let Err(e) = self.replay_pending(metadata, &sync_rules) {
+            warn!("Unable to replay the pending actions, retrying later: {}", e);
+        }
+
+        let devices = match self.remote.get_action_devices() {
+            Ok(devices) => devices,
+            Err(e)      => {
+                warn!("Unable to list the devices with actions: {}", e);
+                return;
+            }
+        };
+
+        for other in devices.iter().filter(|other| **other != device) {
+            if let Err(e) = self.replay_device(metadata, &sync_rules, other) {
+                warn!("Unable to replay the actions of device {}, retrying later: {}", other, e);
+            }
+        }
+
+        if let Err(e) = self.hydrate_included(metadata, &sync_rules) {
+            warn!("Unable to download the included files: {}", e);
+        }
+    }
+
+    fn publish(&self, metadata: &Metadata, device: &str) -> Result<(), Error> {
+        let published = last_seq(metadata, PUBLISHED_SETTING)?;
+        for envelope in metadata.get_actions_since(device, published)? {
+            self.remote.put_action(&envelope)?;
+            metadata.set_setting(PUBLISHED_SETTING, &envelope.seq.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn replay_device(&self, metadata: &Metadata, sync_rules: &SyncRules, device: &str) -> Result<(), Error> {
+        let setting = format!("{}{}", REPLAYED_SETTING, device);
+        let replayed = last_seq(metadata, &setting)?;
+
+        for envelope in self.remote.get_actions(device, replayed)? {
+            if self.is_open(metadata, &envelope)? {
+                debug!("Replay of action {} of device {} waits for the file to be closed", envelope.seq, device);
+                return Ok(());
+            }
+
+            match actions::replay(&self.action_runner, metadata, &self.storage, sync_rules, &envelope) {
+                Ok(true)                => (),
+                Ok(false)               => metadata.add_pending_action(&envelope)?,
+                Err(Error::Conflict(e)) => warn!("Skipped action {} of device {}, conflicting change to {}", envelope.seq, device, e),
+                Err(e)                  => return Err(e)
+            }
+            metadata.set_setting(&setting, &envelope.seq.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Replay the held back actions the rules no longer hide, in the order they came in
+    fn replay_pending(&self, metadata: &Metadata, sync_rules: &SyncRules) -> Result<(), Error> {
+        for (id, envelope) in metadata.get_pending_actions()? {
+            if self.is_open(metadata, &envelope)? {
+                return Ok(());
+            }
+
+            match actions::replay(&self.action_runner, metadata, &self.storage, sync_rules, &envelope) {
+                Ok(true)                => (),
+                Ok(false)               => continue,
+                Err(Error::Conflict(e)) => warn!("Skipped action {} of device {}, conflicting change to {}", envelope.seq, envelope.device, e),
+                Err(e)                  => return Err(e)
+            }
+            metadata.remove_pending_action(id)?;
+        }
+        Ok(())
+    }
+
+    /// Download the placeholders the sync rules include
+    fn hydrate_included(&self, metadata: &Metadata, sync_rules: &SyncRules) -> Result<(), Error> {
+        if sync_rules.is_empty() {
+            return Ok(());
+        }
+
+        let root = metadata.get_by_ino(1)?;
+        let mut failed = 0;
+        for placeholder in metadata.get_placeholders_in(&root)? {
+            if !sync_rules.is_synced(&metadata.get_volume_path(&placeholder)?) {
+                continue;
+            }
+            if self.hydrator.hydrate_placeholder(&self.storage, metadata, &placeholder).is_err() {
+                failed += 1;
+            }
+        }
+
+        if failed > 0 {
+            warn!("{} included files could not be downloaded, retrying later", failed);
+        }
+        Ok(())
+    }
+
+    /// Whether the action writes a new version of a file that is open here
+    fn is_open(&self, metadata: &Metadata, envelope: &ActionEnvelope) -> Result<bool, Error> {
+        if envelope.name != WriteVersion::NAME {
+            return Ok(false);
+        }
+        let action: WriteVersion = match bincode::decode(&envelope.data) {
+            Ok(action) => action,
+            Err(e)     => return Err(Error::Remote(format!("Unable to decode a replicated action: {}", e)))
+        };
+
+        Ok(match optional(metadata.get_by_id(&action.id))? {
+            Some(inode) => self.open_files.lock().unwrap().values().any(|ino| *ino == inode.ino),
+            None        => false
+        })
+    }
+}
+
+fn last_seq(metadata: &Metadata, setting: &str) -> Result<i64, Error> {
+    Ok(metadata.get_setting(setting)?.and_then(|seq| seq.parse::<i64>().ok()).unwrap_or(0))
+}
@@ -0,0 +1,673 @@
+use std::cmp;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use futures::Stream;
+use rusoto_core::{Region, DefaultCredentialsProvider};
+use rusoto_core::reactor::RequestDispatcher;
+use rusoto_core::credential::StaticProvider;
+use rusoto_s3::{S3, S3Client, HeadBucketRequest, HeadObjectRequest, GetObjectRequest, PutObjectRequest, DeleteObjectRequest,
+                ListObjectsV2Request, CreateMultipartUploadRequest, UploadPartRequest, CompleteMultipartUploadRequest,
+                AbortMultipartUploadRequest, CompletedMultipartUpload, CompletedPart};
+use sha1::Sha1;
+use time;
+use bincode;
+use error::Error;
+use metadata::{Metadata, ActionEnvelope};
+use peer::{Peer, Remote};
+use throttle::Bandwidth;
+use metrics::Metrics;
+
+/// Directory in the local path caching downloaded blobs
+pub const CACHE_DIR: &'static str = ".markfs-cache";
+
+/// S3 refuses multipart parts smaller than this, except for the last one
+pub const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// More retries would only keep a failing request going for hours
+pub const MAX_RETRIES: u32 = 10;
+
+const DEFAULT_REGION: &'static str = "us-east-1";
+const DEFAULT_MULTIPART_THRESHOLD: u64 = 16 * 1024 * 1024;
+const DEFAULT_PART_SIZE: usize = 8 * 1024 * 1024;
+const DEFAULT_RETRIES: u32 = 5;
+const DEFAULT_REPLICATE_SECS: u64 = 30;
+const DEFAULT_CACHE_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// First retry waits this long, doubling after every attempt up to `RETRY_MAX_BACKOFF_MS`
+const RETRY_BACKOFF_MS: u64 = 200;
+const RETRY_MAX_BACKOFF_MS: u64 = 60 * 1000;
+
+/// Where and how to reach the bucket
+#[derive(Clone)]
+pub struct S3Config {
+    /// Custom endpoint, e.g. `http://localhost:9000` for a MinIO server
+    pub endpoint: Option<String>,
+    pub region: String,
+    pub bucket: String,
+    /// Prefix of every key, so volumes can share a bucket
+    pub prefix: String,
+    /// Static credentials, the default AWS provider chain is used when absent
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+    /// Downloaded blobs are kept here, named by their hash
+    pub cache_dir: PathBuf,
+    /// Size the cache is trimmed to
+    pub cache_bytes: u64,
+    pub multipart_threshold: u64,
+    pub part_size: usize,
+    pub retries: u32,
+    /// Upload a copy of the metadata this often, never when None
+    pub snapshot_interval: Option<Duration>,
+    /// Exchange actions with the other devices this often, see `Replicator`
+    pub replicate_interval: Duration,
+    /// Bandwidth limits, 0 for none
+    pub upload_bytes_per_second: u64,
+    pub download_bytes_per_second: u64
+}
+
+impl S3Config {
+    /// Defaults for everything but the bucket
+    pub fn new(bucket: String, local_path: &OsString) -> S3Config {
+        S3Config {
+            endpoint: None,
+            region: DEFAULT_REGION.to_string(),
+            bucket: bucket,
+            prefix: String::new(),
+            access_key: None,
+            secret_key: None,
+            cache_dir: Path::new(local_path).join(CACHE_DIR),
+            cache_bytes: DEFAULT_CACHE_BYTES,
+            multipart_threshold: DEFAULT_MULTIPART_THRESHOLD,
+            part_size: DEFAULT_PART_SIZE,
+            retries: DEFAULT_RETRIES,
+            snapshot_interval: None,
+            replicate_interval: Duration::from_secs(DEFAULT_REPLICATE_SECS),
+            upload_bytes_per_second: 0,
+            download_bytes_per_second: 0
+        }
+    }
+}
+
+/// Off-site copy of version blobs in an S3-compatible bucket
+///
+/// Blobs are content-addressed under `<prefix>blobs/`, keyed by the hash in
+/// `file_version.hash`, so identical content is stored once. Metadata snapshots
+/// go to `<prefix>metadata/<time>.sqlite`, the actions of every device to
+/// `<prefix>actions/<device>/<seq>`.
+pub struct S3Remote {
+    client: Box<S3 + Send>,
+    config: S3Config,
+    metrics: Metrics,
+    /// Shared by every transfer, see `set_bandwidth`
+    bandwidth: Bandwidth
+}
+
+impl S3Remote {
+    pub fn new(config: S3Config) -> Result<S3Remote, Error> {
+        let region = match config.endpoint {
+            Some(ref endpoint) => Region::Custom { name: config.region.clone(), endpoint: endpoint.clone() },
+            None               => match config.region.parse::<Region>() {
+                Ok(region) => region,
+                Err(_)     => return Err(Error::Remote(format!("Unknown region {}", config.region)))
+            }
+        };
+
+        let client: Box<S3 + Send> = match (config.access_key.clone(), config.secret_key.clone()) {
+            (Some(access_key), Some(secret_key)) => {
+                let provider = StaticProvider::new_minimal(access_key, secret_key);
+                Box::new(S3Client::new(RequestDispatcher::default(), provider, region))
+            },
+            _ => match DefaultCredentialsProvider::new() {
+                Ok(provider) => Box::new(S3Client::new(RequestDispatcher::default(), provider, region)),
+                Err(e)       => return Err(Error::Remote(e.to_string()))
+            }
+        };
+
+        fs::create_dir_all(&config.cache_dir)?;
+
+        Ok(S3Remote {
+            client,
+            bandwidth: Bandwidth::new(config.upload_bytes_per_second, config.download_bytes_per_second),
+            config,
+            metrics: Metrics::new()
+        })
+    }
+
+    /// Count the transferred bytes in these metrics, shared with the mount
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.metrics = metrics;
+    }
+
+    /// Limit the transfers together with the other remotes of the mount
+    pub fn set_bandwidth(&mut self, bandwidth: Bandwidth) {
+        self.bandwidth = bandwidth;
+    }
+
+    /// Blobs are spread over prefixes by the first two characters of their hash
+    fn blob_key(&self, hash: &str) -> Result<String, Error> {
+        check_hash(hash)?;
+        Ok(format!("{}blobs/{}/{}", self.config.prefix, &hash[..2], hash))
+    }
+
+    fn action_key(&self, device: &str, seq: i64) -> String {
+        // Zero padded, so the keys list in the order of the log
+        format!("{}actions/{}/{:020}", self.config.prefix, device, seq)
+    }
+
+    /// Whether the bucket exists and the credentials give access to it
+    pub fn is_reachable(&self) -> bool {
+        let request = HeadBucketRequest {
+            bucket: self.config.bucket.clone(),
+            ..Default::default()
+        };
+
+        self.client.head_bucket(&request).sync().is_ok()
+    }
+
+    fn put_multipart(&self, hash: &str, reader: &mut Read) -> Result<(), Error> {
+        let key = self.blob_key(hash)?;
+        let upload_id = self.retry("create_multipart_upload", || {
+            let request = CreateMultipartUploadRequest {
+                bucket: self.config.bucket.clone(),
+                key: key.clone(),
+                ..Default::default()
+            };
+            match self.client.create_multipart_upload(&request).sync() {
+                Ok(output) => output.upload_id.ok_or("No upload id returned".to_string()),
+                Err(e)     => Err(e.to_string())
+            }
+        })?;
+
+        let result = self.upload_parts(hash, &key, &upload_id, reader);
+        if result.is_err() {
+            let request = AbortMultipartUploadRequest {
+                bucket: self.config.bucket.clone(),
+                key: key.clone(),
+                upload_id: upload_id.clone(),
+                ..Default::default()
+            };
+            let _ = self.client.abort_multipart_upload(&request).sync();
+        }
+        result
+    }
+
+    fn upload_parts(&self, hash: &str, key: &String, upload_id: &String, reader: &mut Read) -> Result<(), Error> {
+        let part_size = if self.config.part_size < MIN_PART_SIZE { MIN_PART_SIZE } else { self.config.part_size };
+        let mut sha1 = Sha1::new();
+        let mut parts = Vec::new();
+
+        loop {
+            let mut part = Vec::with_capacity(part_size);
+            reader.take(part_size as u64).read_to_end(&mut part)?;
+            if part.is_empty() && !parts.is_empty() {
+                break;
+            }
+            sha1.update(&part);
+            self.bandwidth.upload.lock().unwrap().consume(part.len());
+
+            let part_number = parts.len() as i64 + 1;
+            let e_tag = self.retry("upload_part", || {
+                let request = UploadPartRequest {
+                    bucket: self.config.bucket.clone(),
+                    key: key.clone(),
+                    upload_id: upload_id.clone(),
+                    part_number: part_number,
+                    content_length: Some(part.len() as i64),
+                    body: Some(part.clone()),
+                    ..Default::default()
+                };
+                self.client.upload_part(&request).sync().map(|output| output.e_tag).map_err(|e| e.to_string())
+            })?;
+            self.metrics.record_upload(&self.config.bucket, part.len() as u64);
+
+            parts.push(CompletedPart {
+                e_tag,
+                part_number: Some(part_number)
+            });
+
+            if part.len() < part_size {
+                break;
+            }
+        }
+
+        if sha1.digest().to_string() != hash {
+            return Err(Error::Remote(format!("Content of blob {} changed during upload", hash)));
+        }
+
+        self.retry("complete_multipart_upload", || {
+            let request = CompleteMultipartUploadRequest {
+                bucket: self.config.bucket.clone(),
+                key: key.clone(),
+                upload_id: upload_id.clone(),
+                multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts.clone()) }),
+                ..Default::default()
+            };
+            self.client.complete_multipart_upload(&request).sync().map(|_| ()).map_err(|e| e.to_string())
+        })
+    }
+
+    /// Path of the blob in the local cache, downloading it first when needed
+    ///
+    /// After a download the least recently downloaded blobs are removed from
+    /// the cache until it fits in `cache_bytes` again.
+    pub fn get_blob(&self, hash: &str) -> Result<PathBuf, Error> {
+        let key = self.blob_key(hash)?;
+        let cache_path = self.config.cache_dir.join(hash);
+        if cache_path.exists() {
+            return Ok(cache_path);
+        }
+
+        // Write aside first, so a crash never leaves a truncated blob in the cache
+        let temp_path = self.config.cache_dir.join(format!("{}.part", hash));
+        let (_, downloaded) = match self.get_object(&key, || File::create(&temp_path)) {
+            Ok(result) => result,
+            Err(e)     => {
+                let _ = fs::remove_file(&temp_path);
+                return Err(e);
+            }
+        };
+        if downloaded != hash {
+            let _ = fs::remove_file(&temp_path);
+            return Err(Error::Remote(format!("Blob {} in the bucket is corrupt", hash)));
+        }
+        fs::rename(&temp_path, &cache_path)?;
+
+        if let Err(e) = trim_cache(&self.config.cache_dir, self.config.cache_bytes, &cache_path) {
+            warn!("Unable to trim the blob cache {:?}: {}", self.config.cache_dir, e);
+        }
+        Ok(cache_path)
+    }
+
+    /// Keys below the prefix after `start_after`, and with a delimiter the
+    /// prefixes grouping the others
+    fn list_objects(&self, prefix: &str, delimiter: Option<&str>, start_after: Option<String>) -> Result<(Vec<String>, Vec<String>), Error> {
+        let mut keys = Vec::new();
+        let mut prefixes = Vec::new();
+        let mut continuation_token = None;
+
+        loop {
+            let output = self.retry("list_objects_v2", || {
+                let request = ListObjectsV2Request {
+                    bucket: self.config.bucket.clone(),
+                    prefix: Some(prefix.to_string()),
+                    delimiter: delimiter.map(|delimiter| delimiter.to_string()),
+                    start_after: start_after.clone(),
+                    continuation_token: continuation_token.clone(),
+                    ..Default::default()
+                };
+                self.client.list_objects_v2(&request).sync().map_err(|e| e.to_string())
+            })?;
+
+            keys.extend(output.contents.unwrap_or(Vec::new()).into_iter().filter_map(|object| object.key));
+            prefixes.extend(output.common_prefixes.unwrap_or(Vec::new()).into_iter().filter_map(|common| common.prefix));
+
+            match output.next_continuation_token {
+                Some(token) if output.is_truncated == Some(true) => continuation_token = Some(token),
+                _                                                => return Ok((keys, prefixes))
+            }
+        }
+    }
+
+    /// Download an object chunk by chunk into a writer, throttled and counted as a download
+    ///
+    /// Each attempt starts over in a new writer from `create`. Returns the
+    /// writer of the attempt that succeeded, with the hash of the content.
+    fn get_object<W: Write, F: FnMut() -> io::Result<W>>(&self, key: &str, mut create: F) -> Result<(W, String), Error> {
+        self.retry("get_object", || {
+            let request = GetObjectRequest {
+                bucket: self.config.bucket.clone(),
+                key: key.to_string(),
+                ..Default::default()
+            };
+            let output = self.client.get_object(&request).sync().map_err(|e| e.to_string())?;
+
+            let mut writer = create().map_err(|e| e.to_string())?;
+            let mut sha1 = Sha1::new();
+            if let Some(body) = output.body {
+                for chunk in body.wait() {
+                    let chunk = chunk.map_err(|e| e.to_string())?;
+                    self.bandwidth.download.lock().unwrap().consume(chunk.len());
+                    self.metrics.record_download(&self.config.bucket, chunk.len() as u64);
+                    sha1.update(&chunk);
+                    writer.write_all(&chunk).map_err(|e| e.to_string())?;
+                }
+            }
+            Ok((writer, sha1.digest().to_string()))
+        })
+    }
+
+    /// Run `f` until it succeeds, backing off between attempts
+    fn retry<T, F: FnMut() -> Result<T, String>>(&self, what: &str, mut f: F) -> Result<T, Error> {
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Ok(value) => return Ok(value),
+                Err(e)    => {
+                    attempt += 1;
+                    if attempt > self.config.retries {
+                        return Err(Error::Remote(format!("{} failed: {}", what, e)));
+                    }
+                    debug!("{} failed, attempt {}: {}", what, attempt, e);
+                    let backoff = RETRY_BACKOFF_MS << cmp::min(attempt - 1, 16);
+                    thread::sleep(Duration::from_millis(cmp::min(backoff, RETRY_MAX_BACKOFF_MS)));
+                }
+            }
+        }
+    }
+}
+
+impl Remote for S3Remote {
+    fn has_blob(&self, hash: &str) -> bool {
+        let key = match self.blob_key(hash) {
+            Ok(key) => key,
+            Err(_)  => return false
+        };
+        let request = HeadObjectRequest {
+            bucket: self.config.bucket.clone(),
+            key: key,
+            ..Default::default()
+        };
+
+        self.client.head_object(&request).sync().is_ok()
+    }
+
+    /// The content is hashed while uploading. When it no longer matches `hash`,
+    /// the file changed in the meantime and the upload is abandoned.
+    fn put_blob(&self, hash: &str, size: u64, reader: &mut Read) -> Result<(), Error> {
+        if self.has_blob(hash) {
+            return Ok(());
+        }
+
+        if size < self.config.multipart_threshold {
+            let mut data = Vec::with_capacity(size as usize);
+            reader.read_to_end(&mut data)?;
+            if sha1_of(&data) != hash {
+                return Err(Error::Remote(format!("Content of blob {} changed during upload", hash)));
+            }
+            self.bandwidth.upload.lock().unwrap().consume(data.len());
+
+            let key = self.blob_key(hash)?;
+            self.retry("put_object", || {
+                let request = PutObjectRequest {
+                    bucket: self.config.bucket.clone(),
+                    key: key.clone(),
+                    content_length: Some(data.len() as i64),
+                    body: Some(data.clone()),
+                    ..Default::default()
+                };
+                self.client.put_object(&request).sync().map(|_| ()).map_err(|e| e.to_string())
+            })?;
+            self.metrics.record_upload(&self.config.bucket, data.len() as u64);
+            return Ok(());
+        }
+
+        self.put_multipart(hash, reader)
+    }
+
+    fn delete_blob(&self, hash: &str) -> Result<(), Error> {
+        let key = self.blob_key(hash)?;
+        self.retry("delete_object", || {
+            let request = DeleteObjectRequest {
+                bucket: self.config.bucket.clone(),
+                key: key.clone(),
+                ..Default::default()
+            };
+            self.client.delete_object(&request).sync().map(|_| ()).map_err(|e| e.to_string())
+        })?;
+
+        match fs::remove_file(self.config.cache_dir.join(hash)) {
+            Ok(_)                                             => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e)                                            => Err(Error::from(e))
+        }
+    }
+
+    fn put_action(&self, envelope: &ActionEnvelope) -> Result<(), Error> {
+        let data = match bincode::encode(envelope, bincode::SizeLimit::Infinite) {
+            Ok(data) => data,
+            Err(e)   => return Err(Error::Remote(format!("Unable to encode action {}: {}", envelope.seq, e)))
+        };
+
+        let key = self.action_key(&envelope.device, envelope.seq);
+        self.retry("put_object", || {
+            let request = PutObjectRequest {
+                bucket: self.config.bucket.clone(),
+                key: key.clone(),
+                content_length: Some(data.len() as i64),
+                body: Some(data.clone()),
+                ..Default::default()
+            };
+            self.client.put_object(&request).sync().map(|_| ()).map_err(|e| e.to_string())
+        })?;
+        self.metrics.record_upload(&self.config.bucket, data.len() as u64);
+        Ok(())
+    }
+
+    fn get_action_devices(&self) -> Result<Vec<String>, Error> {
+        let prefix = format!("{}actions/", self.config.prefix);
+        let (_, devices) = self.list_objects(&prefix, Some("/"), None)?;
+
+        Ok(devices.iter()
+            .map(|device| device[prefix.len()..].trim_right_matches('/').to_string())
+            .collect())
+    }
+
+    fn get_actions(&self, device: &str, seq: i64) -> Result<Vec<ActionEnvelope>, Error> {
+        let prefix = format!("{}actions/{}/", self.config.prefix, device);
+        let (keys, _) = self.list_objects(&prefix, None, Some(self.action_key(device, seq)))?;
+
+        let mut envelopes = Vec::with_capacity(keys.len());
+        for key in keys {
+            let (data, _): (Vec<u8>, String) = self.get_object(&key, || Ok(Vec::new()))?;
+            match bincode::decode::<ActionEnvelope>(&data) {
+                Ok(envelope) => envelopes.push(envelope),
+                Err(e)       => return Err(Error::Remote(format!("Action {} in the bucket is corrupt: {}", key, e)))
+            }
+        }
+        Ok(envelopes)
+    }
+
+    fn put_snapshot(&self, metadata: &Metadata) -> Result<(), Error> {
+        let data = match metadata.snapshot() {
+            Ok(data) => data,
+            Err(_)   => return Err(Error::Remote("Metadata snapshot failed".to_string()))
+        };
+
+        let key = format!("{}metadata/{}.sqlite", self.config.prefix, time::get_time().sec);
+        self.retry("put_object", || {
+            let request = PutObjectRequest {
+                bucket: self.config.bucket.clone(),
+                key: key.clone(),
+                content_length: Some(data.len() as i64),
+                body: Some(data.clone()),
+                ..Default::default()
+            };
+            self.client.put_object(&request).sync().map(|_| ()).map_err(|e| e.to_string())
+        })?;
+        self.metrics.record_upload(&self.config.bucket, data.len() as u64);
+        Ok(())
+    }
+}
+
+impl Peer for S3Remote {
+    fn name(&self) -> &str {
+        &self.config.bucket
+    }
+
+    fn fetch(&self, hash: &str) -> Option<Box<Read>> {
+        match self.get_blob(hash).and_then(|path| File::open(path).map_err(Error::from)) {
+            Ok(file) => Some(Box::new(file)),
+            Err(e)   => {
+                debug!("No blob {} in bucket {}: {:?}", hash, self.config.bucket, e);
+                None
+            }
+        }
+    }
+}
+
+/// Hashes are hex, checked before they end up in a key or a cache path
+fn check_hash(hash: &str) -> Result<(), Error> {
+    if hash.len() < 2 || !hash.chars().all(|c| c.is_digit(16)) {
+        return Err(Error::Remote(format!("Invalid blob hash {:?}", hash)));
+    }
+    Ok(())
+}
+
+/// Remove the least recently downloaded blobs until the cache holds at most
+/// `max_bytes`, never `keep`
+///
+/// Downloads in progress, named `<hash>.part`, are left alone.
+fn trim_cache(cache_dir: &Path, max_bytes: u64, keep: &Path) -> io::Result<()> {
+    let mut blobs = Vec::new();
+    for entry in fs::read_dir(cache_dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() && entry.path().extension().map_or(true, |extension| extension != "part") {
+            blobs.push((metadata.modified()?, metadata.len(), entry.path()));
+        }
+    }
+    blobs.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut total: u64 = blobs.iter().map(|&(_, size, _)| size).sum();
+    for (_, size, path) in blobs {
+        if total <= max_bytes {
+            break;
+        }
+        if path == keep {
+            continue;
+        }
+        fs::remove_file(&path)?;
+        total -= size;
+    }
+    Ok(())
+}
+
+fn sha1_of(data: &[u8]) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(data);
+    sha1.digest().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Cursor, Read, Write};
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+    use uuid::Uuid;
+    use peer::{Peer, Remote};
+    use metadata::ActionEnvelope;
+    use super::{S3Config, S3Remote, MIN_PART_SIZE, check_hash, trim_cache, sha1_of};
+
+    fn temp_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!("markfs-s3-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn short_and_non_hex_hashes_are_rejected() {
+        assert!(check_hash("").is_err());
+        assert!(check_hash("a").is_err());
+        assert!(check_hash("../etc/passwd").is_err());
+        assert!(check_hash("ab").is_ok());
+        assert!(check_hash(&sha1_of(b"content")).is_ok());
+    }
+
+    #[test]
+    fn trim_cache_removes_the_oldest_blobs() {
+        let dir = temp_dir();
+        for name in ["old", "middle", "new"].iter() {
+            File::create(dir.join(name)).unwrap().write_all(&[0; 10]).unwrap();
+            // Modification times need to differ
+            thread::sleep(Duration::from_millis(20));
+        }
+        File::create(dir.join("download.part")).unwrap().write_all(&[0; 10]).unwrap();
+
+        trim_cache(&dir, 20, &dir.join("old")).unwrap();
+        assert!(dir.join("old").exists());
+        assert!(!dir.join("middle").exists());
+        assert!(dir.join("new").exists());
+        assert!(dir.join("download.part").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A bucket on a MinIO or other S3-compatible server, from
+    /// `MARKFS_TEST_S3_ENDPOINT`, `MARKFS_TEST_S3_BUCKET`,
+    /// `MARKFS_TEST_S3_ACCESS_KEY` and `MARKFS_TEST_S3_SECRET_KEY`
+    fn test_remote(cache_dir: &PathBuf) -> S3Remote {
+        let var = |name: &str| env::var(name).expect(&format!("{} must be set to run the S3 tests", name));
+
+        let mut config = S3Config::new(var("MARKFS_TEST_S3_BUCKET"), &cache_dir.clone().into_os_string());
+        config.endpoint = Some(var("MARKFS_TEST_S3_ENDPOINT"));
+        config.access_key = Some(var("MARKFS_TEST_S3_ACCESS_KEY"));
+        config.secret_key = Some(var("MARKFS_TEST_S3_SECRET_KEY"));
+        config.prefix = format!("test-{}/", Uuid::new_v4());
+        config.cache_dir = cache_dir.clone();
+        config.retries = 1;
+        S3Remote::new(config).unwrap()
+    }
+
+    #[test]
+    #[ignore]
+    fn blobs_round_trip_through_minio() {
+        let dir = temp_dir();
+        let remote = test_remote(&dir);
+        assert!(remote.is_reachable());
+
+        let small = b"small blob".to_vec();
+        let large: Vec<u8> = (0..MIN_PART_SIZE * 2 + 100).map(|i| i as u8).collect();
+        for data in [small, large].iter() {
+            let hash = sha1_of(data);
+            assert!(!remote.has_blob(&hash));
+            remote.put_blob(&hash, data.len() as u64, &mut Cursor::new(data.clone())).unwrap();
+            assert!(remote.has_blob(&hash));
+
+            let mut fetched = Vec::new();
+            remote.fetch(&hash).unwrap().read_to_end(&mut fetched).unwrap();
+            assert_eq!(&fetched, data);
+
+            remote.delete_blob(&hash).unwrap();
+            assert!(!remote.has_blob(&hash));
+            assert!(!dir.join(&hash).exists());
+        }
+
+        // Content that doesn't match its hash is not stored
+        let hash = sha1_of(b"expected");
+        assert!(remote.put_blob(&hash, 5, &mut Cursor::new(b"other".to_vec())).is_err());
+        assert!(!remote.has_blob(&hash));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn actions_round_trip_through_minio() {
+        let dir = temp_dir();
+        let remote = test_remote(&dir);
+
+        for seq in 1..4 {
+            remote.put_action(&ActionEnvelope {
+                seq,
+                device: "device".to_string(),
+                uid: 1000,
+                name: "create_dir".to_string(),
+                data: vec![seq as u8]
+            }).unwrap();
+        }
+
+        assert_eq!(remote.get_action_devices().unwrap(), vec!["device".to_string()]);
+        let seqs: Vec<i64> = remote.get_actions("device", 1).unwrap().iter().map(|envelope| envelope.seq).collect();
+        assert_eq!(seqs, vec![2, 3]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
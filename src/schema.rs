@@ -0,0 +1,303 @@
+use rusqlite::Connection;
+use time;
+use uuid::Uuid;
+use libc;
+use error::Error;
+use metadata::INodeKind;
+
+type Migration = fn(&Connection) -> Result<(), Error>;
+
+/// Every change to the schema, in order, `PRAGMA user_version` counts how many
+/// a database has had
+///
+/// Only ever append to this list. Volumes created before the version was kept
+/// start at 0 with part of the schema, so each step checks what is there.
+const MIGRATIONS: &'static [Migration] = &[
+    create_tables,
+    add_scrub_state,
+    add_symlinks,
+    split_dentries,
+    add_xattrs,
+    add_ownership,
+    add_quotas,
+    add_placeholders,
+    add_sync_state,
+    add_pins,
+    add_sync_rules,
+    add_local_only_actions,
+    add_version_times,
+    add_audit_log,
+    add_action_origin,
+    add_pending_actions,
+];
+
+/// Bring the schema up to date, the caller holds the write lock
+pub fn migrate(conn: &Connection) -> Result<(), Error> {
+    let version = conn.query_row("PRAGMA user_version", &[], |row| row.get::<_, i64>(0))? as usize;
+    if version > MIGRATIONS.len() {
+        error!("Metadata schema version {} is newer than this markfs knows ({})", version, MIGRATIONS.len());
+        return Err(Error::NotSupported);
+    }
+
+    for (step, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        debug!("Migrating the metadata schema to version {}", step + 1);
+        migration(conn)?;
+    }
+
+    if version < MIGRATIONS.len() {
+        conn.execute_batch(&format!("PRAGMA user_version = {}", MIGRATIONS.len()))?;
+    }
+    Ok(())
+}
+
+fn has_table(conn: &Connection, table: &str) -> Result<bool, Error> {
+    let count = conn.query_row("SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+                               &[&table], |row| row.get::<_, i64>(0))?;
+    Ok(count > 0)
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool, Error> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let names = stmt.query_map(&[], |row| row.get::<_, String>(1))?;
+    for name in names {
+        if name? == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Add a column unless an earlier build already did, returns whether it was added
+fn add_column(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<bool, Error> {
+    if has_column(conn, table, column)? {
+        return Ok(false);
+    }
+    conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))?;
+    Ok(true)
+}
+
+/// The first schema, with an empty root directory
+fn create_tables(conn: &Connection) -> Result<(), Error> {
+    if has_table(conn, "inode")? {
+        return Ok(());
+    }
+
+    conn.execute_batch("
+        CREATE TABLE inode (
+            ino             INTEGER PRIMARY KEY,
+            id              TEXT NOT NULL,
+            parent          TEXT NOT NULL,
+            name            TEXT NOT NULL,
+            kind            INTEGER NOT NULL,
+            atime           TEXT NOT NULL,
+            mtime           TEXT NOT NULL,
+            ctime           TEXT NOT NULL,
+            crtime          TEXT NOT NULL,
+            nlink           INTEGER NOT NULL,
+            current_version TEXT
+        );
+        CREATE TABLE file_version (
+            id              TEXT NOT NULL,
+            version         TEXT NOT NULL,
+            source_version  TEXT NOT NULL,
+            size            INTEGER NOT NULL,
+            hash            TEXT NOT NULL
+        );")?;
+
+    let root_guid = Uuid::new_v4().to_string();
+    let root_name = "";
+    let create_time = time::get_time();
+
+    conn.execute("INSERT INTO inode (ino, id, parent, name, kind, atime, mtime, ctime, crtime, nlink)
+                  VALUES (?1, ?2, ?2, ?3, ?4, ?5, ?5, ?5, ?5, ?6)",
+                 &[&1, &root_guid, &root_name, &(INodeKind::Directory as i32), &create_time, &2])?;
+
+    Ok(())
+}
+
+fn add_scrub_state(conn: &Connection) -> Result<(), Error> {
+    add_column(conn, "file_version", "last_scrub", "TEXT")?;
+    add_column(conn, "file_version", "corrupt", "INTEGER NOT NULL DEFAULT 0")?;
+    Ok(())
+}
+
+fn add_symlinks(conn: &Connection) -> Result<(), Error> {
+    add_column(conn, "inode", "target", "TEXT")?;
+    conn.execute_batch("
+        CREATE TABLE IF NOT EXISTS action_log (
+            seq             INTEGER PRIMARY KEY,
+            name            TEXT NOT NULL,
+            data            BLOB NOT NULL,
+            time            TEXT NOT NULL,
+            finished        INTEGER NOT NULL DEFAULT 0,
+            success         INTEGER NOT NULL DEFAULT 0
+        );")?;
+    Ok(())
+}
+
+/// Move the names of inodes to their own table, so an inode can have several
+fn split_dentries(conn: &Connection) -> Result<(), Error> {
+    if has_column(conn, "inode", "parent")? {
+        conn.execute_batch("
+            CREATE TABLE dentry (
+                parent          TEXT NOT NULL,
+                name            TEXT NOT NULL,
+                id              TEXT NOT NULL,
+                PRIMARY KEY (parent, name)
+            );
+            INSERT INTO dentry (parent, name, id)
+                 SELECT parent, name, id
+                   FROM inode
+               ORDER BY ino;
+            CREATE TABLE inode_split (
+                ino             INTEGER PRIMARY KEY,
+                id              TEXT NOT NULL UNIQUE,
+                kind            INTEGER NOT NULL,
+                atime           TEXT NOT NULL,
+                mtime           TEXT NOT NULL,
+                ctime           TEXT NOT NULL,
+                crtime          TEXT NOT NULL,
+                nlink           INTEGER NOT NULL,
+                current_version TEXT,
+                target          TEXT
+            );
+            INSERT INTO inode_split (ino, id, kind, atime, mtime, ctime, crtime, nlink, current_version, target)
+                 SELECT ino, id, kind, atime, mtime, ctime, crtime, nlink, current_version, target
+                   FROM inode;
+            DROP TABLE inode;
+            ALTER TABLE inode_split RENAME TO inode;
+
+            -- A directory is linked from its parent, from itself and from each subdirectory
+            UPDATE inode
+               SET nlink = 2 + (SELECT count(*)
+                                  FROM dentry
+                                  JOIN inode AS child ON child.id = dentry.id
+                                 WHERE dentry.parent = inode.id
+                                   AND dentry.id <> inode.id
+                                   AND child.kind = 0)
+             WHERE kind = 0;")?;
+    }
+
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS dentry_id ON dentry (id)")?;
+    Ok(())
+}
+
+fn add_xattrs(conn: &Connection) -> Result<(), Error> {
+    conn.execute_batch("
+        CREATE TABLE IF NOT EXISTS xattr (
+            id              TEXT NOT NULL,
+            name            TEXT NOT NULL,
+            value           BLOB NOT NULL,
+            PRIMARY KEY (id, name)
+        );")?;
+    Ok(())
+}
+
+/// Permission bits and owners, the existing tree goes to whoever opens it first
+fn add_ownership(conn: &Connection) -> Result<(), Error> {
+    if !add_column(conn, "inode", "mode", "INTEGER NOT NULL DEFAULT 0")? {
+        return Ok(());
+    }
+    add_column(conn, "inode", "uid", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column(conn, "inode", "gid", "INTEGER NOT NULL DEFAULT 0")?;
+
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+    conn.execute("
+        UPDATE inode
+           SET mode = CASE kind WHEN ?1 THEN ?4 WHEN ?2 THEN ?5 WHEN ?3 THEN ?6 ELSE 0 END,
+               uid = ?7,
+               gid = ?8",
+        &[&(INodeKind::Directory as i32), &(INodeKind::RegularFile as i32), &(INodeKind::Symlink as i32),
+          &0o755, &0o644, &0o777, &uid, &gid])?;
+    Ok(())
+}
+
+fn add_quotas(conn: &Connection) -> Result<(), Error> {
+    conn.execute_batch("
+        CREATE TABLE IF NOT EXISTS quota (
+            kind            INTEGER NOT NULL,
+            target          TEXT NOT NULL,
+            max_bytes       INTEGER,
+            max_inodes      INTEGER,
+            used_bytes      INTEGER NOT NULL DEFAULT 0,
+            used_inodes     INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (kind, target)
+        );")?;
+    Ok(())
+}
+
+fn add_placeholders(conn: &Connection) -> Result<(), Error> {
+    add_column(conn, "file_version", "hydrated", "INTEGER NOT NULL DEFAULT 1")?;
+    Ok(())
+}
+
+fn add_sync_state(conn: &Connection) -> Result<(), Error> {
+    add_column(conn, "file_version", "synced", "INTEGER NOT NULL DEFAULT 0")?;
+    Ok(())
+}
+
+fn add_pins(conn: &Connection) -> Result<(), Error> {
+    add_column(conn, "inode", "pinned", "INTEGER NOT NULL DEFAULT 0")?;
+    Ok(())
+}
+
+fn add_sync_rules(conn: &Connection) -> Result<(), Error> {
+    conn.execute_batch("
+        CREATE TABLE IF NOT EXISTS sync_rule (
+            path            TEXT PRIMARY KEY,
+            include         INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS setting (
+            name            TEXT PRIMARY KEY,
+            value           TEXT NOT NULL
+        );")?;
+    Ok(())
+}
+
+fn add_local_only_actions(conn: &Connection) -> Result<(), Error> {
+    add_column(conn, "action_log", "local_only", "INTEGER NOT NULL DEFAULT 0")?;
+    Ok(())
+}
+
+fn add_version_times(conn: &Connection) -> Result<(), Error> {
+    add_column(conn, "file_version", "created", "TEXT")?;
+    Ok(())
+}
+
+fn add_audit_log(conn: &Connection) -> Result<(), Error> {
+    conn.execute_batch("
+        CREATE TABLE IF NOT EXISTS audit_log (
+            seq             INTEGER PRIMARY KEY,
+            time            TEXT NOT NULL,
+            device          TEXT NOT NULL,
+            uid             INTEGER NOT NULL,
+            name            TEXT NOT NULL,
+            inodes          TEXT NOT NULL,
+            path            TEXT NOT NULL,
+            new_path        TEXT
+        );
+        CREATE INDEX IF NOT EXISTS audit_log_time ON audit_log (time);")?;
+    Ok(())
+}
+
+/// Actions replayed from peers are logged with the device and user they came from
+fn add_action_origin(conn: &Connection) -> Result<(), Error> {
+    add_column(conn, "action_log", "device", "TEXT NOT NULL DEFAULT ''")?;
+    add_column(conn, "action_log", "uid", "INTEGER NOT NULL DEFAULT 0")?;
+    Ok(())
+}
+
+/// Actions of peers on paths the sync rules hide, replayed once the rules change
+fn add_pending_actions(conn: &Connection) -> Result<(), Error> {
+    conn.execute_batch("
+        CREATE TABLE IF NOT EXISTS pending_action (
+            id              INTEGER PRIMARY KEY AUTOINCREMENT,
+            seq             INTEGER NOT NULL,
+            device          TEXT NOT NULL,
+            uid             INTEGER NOT NULL,
+            name            TEXT NOT NULL,
+            data            BLOB NOT NULL
+        );")?;
+    Ok(())
+}
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+use libc::{O_RDONLY, O_WRONLY, O_TRUNC};
+use error::optional;
+use volume::OpenFiles;
+use metadata::{Metadata, INode, FileVersion};
+use peer::Peer;
+use storage::{StorageBackend, FileStream};
+use hash::copy_hashed;
+
+/// Number of versions fetched from the metadata per batch
+const BATCH_SIZE: u32 = 100;
+
+/// Background integrity check of stored versions
+///
+/// Re-hashes the stored blob of every current file version, least recently
+/// scrubbed first, and compares it with `file_version.hash`. Corrupted versions
+/// are flagged in the metadata and re-fetched from the first peer holding a good copy.
+/// Flagged versions are checked first, until a repair succeeds.
+///
+/// Files open in the mount are left for a later batch, and so is any file
+/// whose version changed while it was being hashed.
+pub struct Scrubber<S: StorageBackend> {
+    state_dir: OsString,
+    storage: S,
+    peers: Vec<Box<Peer + Send>>,
+    open_files: OpenFiles,
+    bytes_per_second: u64,
+    interval: Duration,
+    stopped: Arc<AtomicBool>
+}
+
+impl<S: StorageBackend + Send + 'static> Scrubber<S> {
+    pub fn new(state_dir: &OsString, storage: S, bytes_per_second: u64, interval: Duration) -> Scrubber<S> {
+        Scrubber {
+            state_dir: state_dir.clone(),
+            storage,
+            peers: Vec::new(),
+            open_files: Arc::new(Mutex::new(HashMap::new())),
+            bytes_per_second,
+            interval,
+            stopped: Arc::new(AtomicBool::new(false))
+        }
+    }
+
+    pub fn add_peer(&mut self, peer: Box<Peer + Send>) {
+        self.peers.push(peer);
+    }
+
+    /// Skip the files with a handle in here, see `Volume::open_files`
+    pub fn set_open_files(&mut self, open_files: OpenFiles) {
+        self.open_files = open_files;
+    }
+
+    /// Stop the spawned scrubber after its current batch once the flag is set
+    pub fn set_stop_flag(&mut self, stopped: Arc<AtomicBool>) {
+        self.stopped = stopped;
+    }
+
+    /// Run the scrubber on its own thread, with its own metadata connection
+    pub fn spawn(self) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let metadata = match Metadata::new(&self.state_dir) {
+                Ok(metadata) => metadata,
+                Err(e)       => {
+                    error!("Scrubber stopped, unable to open the metadata: {}", e);
+                    return;
+                }
+            };
+
+            while !self.stopped.load(Ordering::SeqCst) {
+                self.scrub(&metadata);
+                thread::sleep(self.interval);
+            }
+        })
+    }
+
+    /// Scrub one batch of versions
+    pub fn scrub(&self, metadata: &Metadata) {
+        let versions = match metadata.get_versions_to_scrub(BATCH_SIZE) {
+            Ok(versions) => versions,
+            Err(e)       => {
+                warn!("Unable to find versions to scrub: {}", e);
+                return;
+            }
+        };
+
+        for (inode, file_version) in versions {
+            if self.is_open(&inode) {
+                continue;
+            }
+
+            // Nothing to verify against
+            if file_version.hash.is_empty() {
+                let _ = metadata.set_scrubbed(&file_version, false);
+                continue;
+            }
+
+            let mut path_buf = PathBuf::new();
+            if let Err(e) = metadata.get_path(&inode, &mut path_buf) {
+                warn!("Unable to find inode {}: {}", inode.id, e);
+                continue;
+            }
+
+            let intact = match self.hash_file(path_buf.as_path()) {
+                Some(hash) => hash == file_version.hash,
+                None       => false
+            };
+
+            // Written while it was hashed, the new content is checked next time
+            if !self.is_unchanged(metadata, &file_version) {
+                continue;
+            }
+
+            if intact {
+                let _ = metadata.set_scrubbed(&file_version, false);
+                continue;
+            }
+
+            warn!("Version {} of {:?} is corrupt", file_version.version, path_buf);
+            let _ = metadata.set_scrubbed(&file_version, true);
+
+            if self.repair(metadata, &inode, path_buf.as_path(), &file_version) {
+                info!("Version {} of {:?} repaired", file_version.version, path_buf);
+                let _ = metadata.set_scrubbed(&file_version, false);
+            }
+        }
+    }
+
+    /// Re-fetch a corrupted version from the first peer holding a good copy
+    ///
+    /// The copy is fetched next to the file under a name of its own, and only
+    /// swapped in while the file is still closed and at the same version.
+    fn repair(&self, metadata: &Metadata, inode: &INode, path: &Path, file_version: &FileVersion) -> bool {
+        let file_name = path.file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned());
+        let temp_path = path.with_file_name(format!(".{}.markfs-repair-{}", file_name, Uuid::new_v4()));
+
+        for peer in self.peers.iter() {
+            let mut reader = match peer.fetch(&file_version.hash) {
+                Some(reader) => reader,
+                None         => continue
+            };
+
+            let _ = self.storage.create(&temp_path);
+            let copied = match self.storage.open(&temp_path, O_WRONLY | O_TRUNC) {
+                Ok(handle) => copy_hashed(&mut reader, &mut FileStream::new(handle), self.bytes_per_second),
+                Err(_)     => None
+            };
+
+            match copied {
+                Some(ref hash) if *hash == file_version.hash => {
+                    if self.is_open(inode) || !self.is_unchanged(metadata, file_version) {
+                        info!("{:?} changed during its repair, leaving it", path);
+                        let _ = self.storage.remove(&temp_path);
+                        return false;
+                    }
+                    if self.swap(inode, &temp_path, path) {
+                        return true;
+                    }
+                },
+                _ => {
+                    warn!("Peer {} has no good copy of version {}", peer.name(), file_version.version);
+                }
+            }
+            let _ = self.storage.remove(&temp_path);
+        }
+        false
+    }
+
+    /// Replace the content of a file with the repaired copy
+    ///
+    /// Other names of a hard linked file share its content, so that is
+    /// overwritten in place rather than renamed over.
+    fn swap(&self, inode: &INode, temp_path: &Path, path: &Path) -> bool {
+        if inode.nlink <= 1 {
+            return self.storage.rename(temp_path, path).is_ok();
+        }
+
+        let copied = self.storage.open(temp_path, O_RDONLY).and_then(|reader| {
+            let writer = self.storage.open(path, O_WRONLY | O_TRUNC)?;
+            io::copy(&mut FileStream::new(reader), &mut FileStream::new(writer))?;
+            Ok(())
+        });
+        let _ = self.storage.remove(temp_path);
+        copied.is_ok()
+    }
+
+    fn is_open(&self, inode: &INode) -> bool {
+        self.open_files.lock().unwrap().values().any(|ino| *ino == inode.ino)
+    }
+
+    /// Whether the version is still current, with the hash it was checked against
+    fn is_unchanged(&self, metadata: &Metadata, file_version: &FileVersion) -> bool {
+        match optional(metadata.get_by_id(&file_version.id)) {
+            Ok(Some(current)) => current.current_version == file_version.version && current.hash == file_version.hash,
+            Ok(None)          => false,
+            Err(e)            => {
+                warn!("Unable to read the current version of {}: {}", file_version.id, e);
+                false
+            }
+        }
+    }
+
+    fn hash_file(&self, path: &Path) -> Option<String> {
+        match self.storage.open(path, 0) {
+            Ok(handle) => copy_hashed(&mut FileStream::new(handle), &mut io::sink(), self.bytes_per_second),
+            Err(_)     => None
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::ffi::OsString;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use time::Timespec;
+use error::Error;
+
+pub trait FileHandle {
+    fn read(&mut self, offset: u64, size: u32) -> Result<Vec<u8>, Error>;
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<u32, Error>;
+    /// Truncate, or extend with zeros
+    fn set_len(&mut self, size: u64) -> Result<(), Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatKind {
+    Directory,
+    RegularFile,
+    Other
+}
+
+#[derive(Debug, Clone)]
+pub struct Stat {
+    pub kind: StatKind,
+    pub size: u64,
+    pub mtime: Timespec
+}
+
+/// Space of the backing store, in the terms of statfs
+#[derive(Debug, Clone, Default)]
+pub struct Capacity {
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub bsize: u32,
+    pub frsize: u32
+}
+
+/// Where the content of a volume is stored
+///
+/// All paths are relative to the root of the volume. Directories and symlinks
+/// are defined by the metadata, the backend only needs to mirror the tree.
+pub trait StorageBackend {
+
+    /// Open an existing file, with the `O_*` flags of open(2)
+    fn open(&self, path: &Path, flags: i32) -> Result<Box<FileHandle + Send>, Error>;
+
+    /// Create a new, empty file
+    fn create(&self, path: &Path) -> Result<(), Error>;
+
+    fn read(&self, path: &Path, offset: u64, size: u32) -> Result<Vec<u8>, Error> {
+        self.open(path, 0)?.read(offset, size)
+    }
+
+    fn write(&self, path: &Path, offset: u64, data: &[u8]) -> Result<u32, Error> {
+        self.open(path, ::libc::O_WRONLY)?.write(offset, data)
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<(), Error>;
+
+    /// Rename, replacing the file or empty directory at the new path
+    fn rename(&self, old_path: &Path, new_path: &Path) -> Result<(), Error>;
+
+    /// Remove a file or an empty directory
+    fn remove(&self, path: &Path) -> Result<(), Error>;
+
+    /// Give the content of an existing file a second name
+    fn link(&self, path: &Path, new_path: &Path) -> Result<(), Error>;
+
+    fn stat(&self, path: &Path) -> Result<Stat, Error>;
+
+    fn list(&self, path: &Path) -> Result<Vec<OsString>, Error>;
+
+    fn capacity(&self) -> Result<Capacity, Error>;
+}
+
+/// Sequential `Read` and `Write` on a file handle
+pub struct FileStream {
+    handle: Box<FileHandle + Send>,
+    offset: u64
+}
+
+impl FileStream {
+    pub fn new(handle: Box<FileHandle + Send>) -> FileStream {
+        FileStream {
+            handle,
+            offset: 0
+        }
+    }
+}
+
+impl Read for FileStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.handle.read(self.offset, buf.len() as u32) {
+            Ok(data) => {
+                buf[..data.len()].copy_from_slice(&data);
+                self.offset += data.len() as u64;
+                Ok(data.len())
+            },
+            Err(e) => Err(e.into())
+        }
+    }
+}
+
+impl Write for FileStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.handle.write(self.offset, buf) {
+            Ok(n) => {
+                self.offset += n as u64;
+                Ok(n as usize)
+            },
+            Err(e) => Err(e.into())
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
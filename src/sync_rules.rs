@@ -0,0 +1,124 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use metadata::Metadata;
+use error::Error;
+
+/// Name of the setting holding the `ExcludedMode`
+pub const EXCLUDED_MODE_SETTING: &'static str = "sync.excluded_mode";
+
+/// How excluded paths show up in the mount
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExcludedMode {
+    /// Leave them out of lookups and directory listings
+    Hide,
+    /// Show them, their content is only fetched when opened
+    Placeholder
+}
+
+impl ExcludedMode {
+    pub fn from_str(mode: &str) -> Option<ExcludedMode> {
+        match mode {
+            "hide"        => Some(ExcludedMode::Hide),
+            "placeholder" => Some(ExcludedMode::Placeholder),
+            _             => None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ExcludedMode::Hide        => "hide",
+            ExcludedMode::Placeholder => "placeholder"
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncRule {
+    /// Volume path, like `/artifacts`
+    pub path: PathBuf,
+    pub include: bool
+}
+
+/// Rules from the configuration file, `markfs sync` overrides them per path
+#[derive(Debug, Clone, Default)]
+pub struct SyncConfig {
+    pub rules: Vec<SyncRule>,
+    pub excluded_mode: Option<ExcludedMode>
+}
+
+/// The rules of a mount, shared with its replicator and control socket so
+/// changes apply without remounting
+pub type SharedSyncRules = Arc<Mutex<SyncRules>>;
+
+/// Which paths of the volume replicate to this device
+///
+/// The rule with the longest matching path decides. Without a matching rule a
+/// path is synced, unless there are include rules, then only what they cover is.
+#[derive(Debug, Clone)]
+pub struct SyncRules {
+    rules: Vec<SyncRule>,
+    pub excluded_mode: ExcludedMode
+}
+
+impl SyncRules {
+    /// Everything syncs
+    pub fn all() -> SyncRules {
+        SyncRules {
+            rules: Vec::new(),
+            excluded_mode: ExcludedMode::Placeholder
+        }
+    }
+
+    pub fn load(metadata: &Metadata, config: &SyncConfig) -> Result<SyncRules, Error> {
+        let mut rules: Vec<SyncRule> = metadata.get_sync_rules()?.into_iter().map(|(path, include)| SyncRule {
+            path: PathBuf::from(path),
+            include
+        }).collect();
+        for rule in config.rules.iter() {
+            if !rules.iter().any(|other| other.path == rule.path) {
+                rules.push(rule.clone());
+            }
+        }
+        rules.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let excluded_mode = metadata.get_setting(EXCLUDED_MODE_SETTING)?
+            .and_then(|mode| ExcludedMode::from_str(&mode))
+            .or(config.excluded_mode)
+            .unwrap_or(ExcludedMode::Placeholder);
+
+        Ok(SyncRules {
+            rules,
+            excluded_mode
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// By path
+    pub fn rules(&self) -> &[SyncRule] {
+        &self.rules
+    }
+
+    /// Whether the content at the volume path replicates to this device
+    pub fn is_synced(&self, path: &Path) -> bool {
+        let matching = self.rules.iter()
+            .filter(|rule| path.starts_with(&rule.path))
+            .max_by_key(|rule| rule.path.components().count());
+
+        match matching {
+            Some(rule) => rule.include,
+            None       => !self.rules.iter().any(|rule| rule.include)
+        }
+    }
+
+    /// Whether the volume path shows up in the mount
+    ///
+    /// Directories leading to an included path stay visible, so it can be reached.
+    pub fn is_visible(&self, path: &Path) -> bool {
+        self.excluded_mode == ExcludedMode::Placeholder
+            || self.is_synced(path)
+            || self.rules.iter().any(|rule| rule.include && rule.path.starts_with(path))
+    }
+}
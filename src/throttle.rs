@@ -0,0 +1,59 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Limit transfers to an average number of bytes per second
+///
+/// One throttle is shared by consecutive transfers. Time spent idle doesn't
+/// build up credit for a burst afterwards.
+pub struct Throttle {
+    bytes_per_second: u64,
+    start: Instant,
+    total: u64
+}
+
+impl Throttle {
+    /// No limit when `bytes_per_second` is 0
+    pub fn new(bytes_per_second: u64) -> Throttle {
+        Throttle {
+            bytes_per_second,
+            start: Instant::now(),
+            total: 0
+        }
+    }
+
+    /// Account for transferred bytes, sleeping when ahead of the limit
+    pub fn consume(&mut self, bytes: usize) {
+        self.total += bytes as u64;
+        if self.bytes_per_second == 0 {
+            return;
+        }
+
+        let expected = Duration::from_millis(self.total * 1000 / self.bytes_per_second);
+        let elapsed = self.start.elapsed();
+        if expected > elapsed {
+            thread::sleep(expected - elapsed);
+        } else {
+            self.start = Instant::now();
+            self.total = 0;
+        }
+    }
+}
+
+/// Throttles shared by every remote of a volume, so its limits hold for all
+/// transfers together
+#[derive(Clone)]
+pub struct Bandwidth {
+    pub upload: Arc<Mutex<Throttle>>,
+    pub download: Arc<Mutex<Throttle>>
+}
+
+impl Bandwidth {
+    /// No limit in a direction when it's 0
+    pub fn new(upload_bytes_per_second: u64, download_bytes_per_second: u64) -> Bandwidth {
+        Bandwidth {
+            upload: Arc::new(Mutex::new(Throttle::new(upload_bytes_per_second))),
+            download: Arc::new(Mutex::new(Throttle::new(download_bytes_per_second)))
+        }
+    }
+}
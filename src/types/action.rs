@@ -1,38 +1,50 @@
-use libc;
-
-pub enum ActionError {
-	Conflict(String),
-	NoEntry,
-	FileExists,
-	NotADirectory,
-	IsADirectory,
-	NoSpaceLeftOnDevice,
-	NotImplemented
-}
+use metadata::Metadata;
+use storage::StorageBackend;
+use error::Error;
 
-/// Convert to libc error
-impl Into<libc::c_int> for ActionError {
-	fn into(self) -> libc::c_int {
-		match self {
-			ActionError::NoEntry             => libc::ENOENT,
-			ActionError::FileExists          => libc::EEXIST,
-			ActionError::NotADirectory       => libc::ENOTDIR,
-			ActionError::IsADirectory        => libc::EISDIR,
-			ActionError::NoSpaceLeftOnDevice => libc::ENOSPC,
-			ActionError::NotImplemented      => libc::ENOSYS,
-			_                                => 0
-		}
-	}
+/// Everything an action may modify
+pub struct ActionContext<'a> {
+	pub metadata: &'a Metadata,
+	/// Local content, mirroring the changes to the metadata
+	pub storage: &'a StorageBackend,
+	/// Device the action was made on, a peer's for replayed actions
+	pub device: &'a str,
+	/// User that made the action
+	pub uid: u32
 }
 
 /// Modifications are run as actions
 /// Actions are serializable and atomic
+///
+/// The runner wraps `run` in a metadata transaction, so an action changes
+/// the metadata first and the storage last. A failing storage step then
+/// leaves both as they were.
 pub trait Action {
 
 	/// Return the name
 	fn get_name(&self) -> &str;
 
+	/// Return the id of the inode acted on, the parent for new inodes
+	fn get_target(&self) -> &str;
+
+	/// Return the ids of the affected inodes, for the audit log
+	fn get_inodes(&self) -> Vec<String> {
+		vec![self.get_target().to_string()]
+	}
+
+	/// Return the parent id and name acted on, for the audit log
+	///
+	/// Without one the path of the first affected inode is logged.
+	fn get_dentry(&self) -> Option<(&str, &str)> {
+		None
+	}
+
+	/// Return the parent id and name the entry moved to, for the audit log
+	fn get_new_dentry(&self) -> Option<(&str, &str)> {
+		None
+	}
+
 	/// Run the action
-	fn run(&mut self, _replay: bool) -> Result<(), ActionError>;
+	fn run(&mut self, _context: &ActionContext, _replay: bool) -> Result<(), Error>;
 }
 
@@ -1 +1,3 @@
-mod action;
\ No newline at end of file
+mod action;
+
+pub use self::action::{Action, ActionContext};
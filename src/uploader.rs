@@ -0,0 +1,133 @@
+use std::cmp;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use error::{Error, optional};
+use metadata::Metadata;
+use peer::Remote;
+use storage::{StorageBackend, FileStream};
+
+/// How often a paused uploader checks whether it may continue
+const PAUSE_POLL_MS: u64 = 500;
+
+/// How long the uploader waits for work without snapshots
+const IDLE_SECS: u64 = 60;
+
+/// A failed upload is tried again after this long, doubling up to `RETRY_MAX_SECS`
+const RETRY_SECS: u64 = 5;
+const RETRY_MAX_SECS: u64 = 600;
+
+/// A written version to upload
+#[derive(Debug, Clone)]
+pub struct Upload {
+    /// Inode id and version, marked synced once uploaded
+    pub id: String,
+    pub version: String,
+    pub hash: String
+}
+
+/// An upload that failed, waiting for its next attempt
+struct Retry {
+    upload: Upload,
+    attempts: u32,
+    due: Instant
+}
+
+/// Upload blobs queued by the mount to the remote on a background thread
+///
+/// Every queued version is read from `storage` under the current path of
+/// its inode, stored and marked synced. Failed uploads are tried again with
+/// a growing delay. A version that's no longer current by then is skipped,
+/// its content is gone and the one replacing it is queued itself.
+///
+/// With a `snapshot_interval` the metadata is snapshotted on the side.
+/// While `paused` is set uploads and snapshots wait.
+pub fn spawn<R, S>(remote: R, state_dir: &OsString, storage: S, snapshot_interval: Option<Duration>, paused: Arc<AtomicBool>) -> Sender<Upload>
+    where R: Remote + Send + 'static, S: StorageBackend + Send + 'static {
+    let (sender, receiver) = channel::<Upload>();
+    let state_dir = state_dir.clone();
+
+    thread::spawn(move || {
+        let metadata = match Metadata::new(&state_dir) {
+            Ok(metadata) => metadata,
+            Err(e)       => {
+                error!("Uploader stopped, unable to open the metadata: {}", e);
+                return;
+            }
+        };
+        let interval = snapshot_interval.unwrap_or(Duration::from_secs(IDLE_SECS));
+        let mut last_snapshot = Instant::now();
+        let mut retries: Vec<Retry> = Vec::new();
+
+        loop {
+            if paused.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(PAUSE_POLL_MS));
+                continue;
+            }
+
+            let now = Instant::now();
+            let (due, waiting): (Vec<Retry>, Vec<Retry>) = retries.into_iter().partition(|retry| retry.due <= now);
+            retries = waiting;
+            let timeout = retries.iter()
+                .map(|retry| retry.due - now)
+                .fold(interval, cmp::min);
+
+            let mut attempts: Vec<(Upload, u32)> = due.into_iter().map(|retry| (retry.upload, retry.attempts)).collect();
+            if attempts.is_empty() {
+                match receiver.recv_timeout(timeout) {
+                    Ok(upload)                          => attempts.push((upload, 0)),
+                    Err(RecvTimeoutError::Timeout)      => {},
+                    Err(RecvTimeoutError::Disconnected) => break
+                }
+            }
+
+            for (upload, attempt) in attempts {
+                match upload_version(&remote, &metadata, &storage, &upload) {
+                    Ok(true)  => { let _ = metadata.set_synced(&upload.id, &upload.version); },
+                    Ok(false) => debug!("Skipped the upload of {} version {}, it's no longer current", upload.id, upload.version),
+                    Err(e)    => {
+                        let delay = cmp::min(RETRY_SECS << cmp::min(attempt, 16), RETRY_MAX_SECS);
+                        warn!("Upload of {} version {} failed, trying again in {}s: {:?}", upload.id, upload.version, delay, e);
+                        retries.push(Retry {
+                            upload,
+                            attempts: attempt + 1,
+                            due: Instant::now() + Duration::from_secs(delay)
+                        });
+                    }
+                }
+            }
+
+            if snapshot_interval.is_some() && last_snapshot.elapsed() >= interval {
+                if let Err(e) = remote.put_snapshot(&metadata) {
+                    warn!("Metadata snapshot failed: {:?}", e);
+                }
+                last_snapshot = Instant::now();
+            }
+        }
+    });
+
+    sender
+}
+
+/// Upload the content of the version, false when it's no longer the current
+/// content of its inode
+fn upload_version<R: Remote, S: StorageBackend>(remote: &R, metadata: &Metadata, storage: &S, upload: &Upload) -> Result<bool, Error> {
+    let inode = match optional(metadata.get_by_id(&upload.id))? {
+        Some(inode) => inode,
+        None        => return Ok(false)
+    };
+    if inode.current_version != upload.version || inode.hash != upload.hash || !inode.hydrated {
+        return Ok(false);
+    }
+
+    let mut path = PathBuf::new();
+    metadata.get_path(&inode, &mut path)?;
+    let stat = storage.stat(&path)?;
+    let handle = storage.open(&path, 0)?;
+    remote.put_blob(&upload.hash, stat.size, &mut FileStream::new(handle))?;
+    Ok(true)
+}
@@ -0,0 +1,738 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+use libc::{self, O_ACCMODE, O_RDONLY, O_WRONLY, O_TRUNC};
+use time::Timespec;
+use uuid::Uuid;
+use rustc_serialize::Encodable;
+use metadata::{Metadata, INode, FileVersion, Ownership, QuotaKind};
+use action_runner::ActionRunner;
+use actions::{CreateDir, CreateFile, Link, Unlink, RemoveDir, SetAttr, WriteVersion, Rename};
+use types::{Action, ActionContext};
+use local::LocalFileOperations;
+use storage::{StorageBackend, FileHandle};
+use hydrate::{Hydrator, Hydrations};
+use ignore::Ignores;
+use hash::hash_file;
+use s3::S3Remote;
+use uploader::Upload;
+use error::{Error, optional};
+use config::VolumeConfig;
+use metrics::Metrics;
+
+/// Mode of files created through `Volume::write`
+const DEFAULT_FILE_MODE: u32 = 0o644;
+
+/// Inode numbers by open file handle, shared with the scrubber so it leaves open files alone
+pub type OpenFiles = Arc<Mutex<HashMap<u64, u64>>>;
+
+/// A file opened through `Volume::open_handle`
+struct OpenFile {
+    handle: Box<FileHandle + Send>,
+    ino: u64,
+    /// User that opened the file, who makes the version written through it
+    uid: u32,
+    /// Written through this handle, its version is finished on release
+    dirty: bool
+}
+
+/// A volume, read and written without mounting it
+///
+/// Paths are relative to the root of the volume, like `photos/2018/beach.jpg`.
+/// Unlike a mount, the API doesn't check permissions, new files are owned by
+/// the user running the process. `MarkFS` serves a volume over FUSE on top of it.
+pub struct Volume<S: StorageBackend> {
+    metadata: Metadata,
+    storage: S,
+    action_runner: ActionRunner,
+    hydrator: Hydrator,
+    ignores: Ignores,
+    upload_queue: Option<Sender<Upload>>,
+    hydrate_queue: Option<Sender<String>>,
+    open_files: HashMap<u64, OpenFile>,
+    open_inodes: OpenFiles,
+    last_fh: u64,
+    read_only: bool,
+    /// Id of this device in the audit log
+    device: String
+}
+
+impl Volume<LocalFileOperations> {
+    /// Open the volume stored at the local path, with its configured settings and peers
+    pub fn open(local_path: &OsString) -> Result<Volume<LocalFileOperations>, Error> {
+        let config = match VolumeConfig::load(Some(local_path)) {
+            Ok(config) => config,
+            Err(e)     => return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, e.to_string())))
+        };
+        if !Path::new(&config.state_dir).join("metadata.sqlite").is_file() {
+            return Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, format!("No volume at {}", local_path.to_string_lossy()))));
+        }
+
+        let mut volume = Volume::new(Metadata::new(&config.state_dir)?, LocalFileOperations::new(local_path));
+        volume.configure(&config);
+        for peer in config.peers.iter() {
+            match S3Remote::new(peer.clone()) {
+                Ok(remote) => volume.hydrator.add_peer(Box::new(remote)),
+                Err(e)     => return Err(Error::Remote(format!("Unable to connect to the S3 bucket {}: {:?}", peer.bucket, e)))
+            }
+        }
+        Ok(volume)
+    }
+}
+
+impl<S: StorageBackend> Volume<S> {
+    pub fn new(metadata: Metadata, storage: S) -> Volume<S> {
+        let device = match metadata.device_id() {
+            Ok(device) => device,
+            Err(e)     => {
+                warn!("Unable to store the id of this device, changes are audited as unknown: {}", e);
+                "unknown".to_string()
+            }
+        };
+
+        Volume {
+            metadata,
+            storage,
+            action_runner: ActionRunner::new(),
+            hydrator: Hydrator::new(),
+            ignores: Ignores::new(),
+            upload_queue: None,
+            hydrate_queue: None,
+            open_files: HashMap::new(),
+            open_inodes: Arc::new(Mutex::new(HashMap::new())),
+            last_fh: 0,
+            read_only: false,
+            device
+        }
+    }
+
+    /// Apply the settings of the configuration file that concern the content
+    pub fn configure(&mut self, config: &VolumeConfig) {
+        self.ignores.set_global(&config.ignore);
+        self.read_only = config.read_only;
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Id of this device, see `Metadata::device_id`
+    pub fn device(&self) -> &str {
+        &self.device
+    }
+
+    /// Record the latency of every action in these metrics, shared with the mount
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.action_runner.set_metrics(metrics);
+    }
+
+    /// Runs the actions of this volume, clones share its lock
+    pub fn action_runner(&self) -> ActionRunner {
+        self.action_runner.clone()
+    }
+
+    /// Run an action by `uid`, one on an ignored path is logged but never replicated
+    pub fn run_action<A: Action + Encodable>(&self, uid: u32, action: &mut A, local_only: bool) -> Result<(), Error> {
+        let context = ActionContext {
+            metadata: &self.metadata,
+            storage: &self.storage,
+            device: &self.device,
+            uid
+        };
+        if local_only {
+            self.action_runner.run_local_only(&context, action)
+        } else {
+            self.action_runner.run(&context, action)
+        }
+    }
+
+    /// Fetch the content of placeholders with this hydrator
+    pub fn set_hydrator(&mut self, hydrator: Hydrator) {
+        self.hydrator = hydrator;
+    }
+
+    /// Running hydrations, to report progress or cancel them from another thread
+    pub fn hydrations(&self) -> Hydrations {
+        self.hydrator.hydrations()
+    }
+
+    /// Download pinned subtrees in the background, see `Hydrator::spawn_queue`
+    pub fn set_hydrate_queue(&mut self, hydrate_queue: Sender<String>) {
+        self.hydrate_queue = Some(hydrate_queue);
+    }
+
+    /// Queue every newly written version for upload, starting with the ones
+    /// an earlier mount didn't get to
+    pub fn set_upload_queue(&mut self, upload_queue: Sender<Upload>) {
+        match self.metadata.get_unsynced() {
+            Ok(inodes) => for inode in inodes {
+                // Ignored files stay on this device
+                if let Ok(false) = self.is_ignored_inode(&inode) {
+                    let _ = upload_queue.send(Upload {
+                        id: inode.id.clone(),
+                        version: inode.current_version.clone(),
+                        hash: inode.hash.clone()
+                    });
+                }
+            },
+            Err(e) => warn!("Unable to find the versions still to upload: {}", e)
+        }
+        self.upload_queue = Some(upload_queue);
+    }
+
+    /// The inode at the path
+    pub fn stat(&self, path: &Path) -> Result<INode, Error> {
+        self.metadata.resolve(path)
+    }
+
+    /// The entries of the directory at the path
+    pub fn list(&self, path: &Path) -> Result<Vec<INode>, Error> {
+        let inode = self.stat(path)?;
+        if !inode.kind.is_directory() {
+            return Err(Error::NotADirectory);
+        }
+        self.metadata.get_children(&inode)
+    }
+
+    /// Read up to `size` bytes at `offset`, a placeholder is hydrated first
+    pub fn read(&self, path: &Path, offset: u64, size: u32) -> Result<Vec<u8>, Error> {
+        let inode = self.regular_file(path)?;
+        let storage_path = self.storage_path(&inode)?;
+        self.ensure_hydrated(&inode, &storage_path, false)?;
+
+        let data = self.storage.open(&storage_path, O_RDONLY)?.read(offset, size)?;
+        let _ = self.metadata.touch_atime(&inode);
+        Ok(data)
+    }
+
+    /// Write at `offset` into the file, which is created when missing
+    ///
+    /// Each call opens and releases the file, so it makes a new version.
+    pub fn write(&mut self, path: &Path, offset: u64, data: &[u8]) -> Result<u32, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let uid = unsafe { libc::getuid() };
+        let inode = match optional(self.metadata.resolve(path))? {
+            Some(inode) => inode,
+            None        => self.create_file_at(path)?
+        };
+        if !inode.kind.is_regular_file() {
+            return Err(Error::IsADirectory);
+        }
+
+        let fh = self.open_handle(uid, &inode, O_WRONLY)?;
+        let written = self.write_handle(fh, offset, data);
+        let released = self.release_handle(fh);
+        let written = written?;
+        released?;
+        Ok(written)
+    }
+
+    /// Move the entry at `from` to `to`, replacing a file or an empty directory there
+    pub fn rename(&mut self, from: &Path, to: &Path) -> Result<(), Error> {
+        let inode = self.stat(from)?;
+        let parent = self.metadata.get_by_id(&inode.parent)?;
+        let (new_parent, new_name) = self.parent_and_name(to)?;
+        self.move_inode(unsafe { libc::getuid() }, &parent, &inode, &new_parent, &new_name)
+    }
+
+    /// All versions of the file, oldest first
+    pub fn history(&self, path: &Path) -> Result<Vec<FileVersion>, Error> {
+        let inode = self.regular_file(path)?;
+        self.metadata.get_versions(&inode)
+    }
+
+    /// Make an earlier version of the file current again
+    ///
+    /// Content that isn't stored locally is fetched from the peers first, the
+    /// file is left alone when none of them holds it.
+    pub fn restore(&mut self, path: &Path, version: &str) -> Result<INode, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let inode = self.regular_file(path)?;
+        let restored = self.hydrator.restore(&self.storage, &self.metadata, &inode, &version.to_string())?;
+        let local_only = self.is_ignored_inode(&inode)?;
+        self.run_action(unsafe { libc::getuid() }, &mut WriteVersion::restoring(&restored), local_only)?;
+        self.metadata.get_by_id(&inode.id)
+    }
+
+    /// Create a directory, owned by `ownership.uid`
+    pub fn create_dir(&mut self, parent: &INode, name: &String, ownership: &Ownership) -> Result<INode, Error> {
+        self.check_new_entry(parent, name, ownership.uid)?;
+
+        let mut action = CreateDir {
+            id: Uuid::new_v4().to_string(),
+            parent: parent.id.clone(),
+            name: name.clone(),
+            mode: ownership.mode,
+            uid: ownership.uid,
+            gid: ownership.gid
+        };
+        let local_only = self.is_ignored(parent, name, true)?;
+        self.run_action(ownership.uid, &mut action, local_only)?;
+        self.metadata.lookup(parent, name)
+    }
+
+    /// Create an empty regular file, owned by `ownership.uid`
+    pub fn create_file(&mut self, parent: &INode, name: &String, ownership: &Ownership) -> Result<INode, Error> {
+        self.check_new_entry(parent, name, ownership.uid)?;
+
+        let mut action = CreateFile {
+            id: Uuid::new_v4().to_string(),
+            version: Uuid::new_v4().to_string(),
+            parent: parent.id.clone(),
+            name: name.clone(),
+            mode: ownership.mode,
+            uid: ownership.uid,
+            gid: ownership.gid
+        };
+        let local_only = self.is_ignored(parent, name, false)?;
+        self.run_action(ownership.uid, &mut action, local_only)?;
+        self.metadata.lookup(parent, name)
+    }
+
+    /// Give a file or symlink another name, returns the inode under that name
+    pub fn link(&mut self, uid: u32, inode: &INode, new_parent: &INode, new_name: &String) -> Result<INode, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if inode.kind.is_directory() {
+            return Err(Error::PermissionDenied);
+        }
+        if !new_parent.kind.is_directory() {
+            return Err(Error::NotADirectory);
+        }
+        if optional(self.metadata.lookup(new_parent, new_name))?.is_some() {
+            return Err(Error::Exists);
+        }
+
+        let mut action = Link {
+            id: inode.id.clone(),
+            parent: new_parent.id.clone(),
+            name: new_name.clone()
+        };
+        let local_only = self.is_ignored_inode(inode)? || self.is_ignored(new_parent, new_name, false)?;
+        self.run_action(uid, &mut action, local_only)?;
+        self.metadata.lookup(new_parent, new_name)
+    }
+
+    /// Remove the name of a file or symlink from its parent
+    ///
+    /// Other names of a hard linked file keep its content.
+    pub fn unlink(&mut self, uid: u32, parent: &INode, inode: &INode) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if inode.kind.is_directory() {
+            return Err(Error::IsADirectory);
+        }
+
+        let mut action = Unlink {
+            id: inode.id.clone(),
+            parent: parent.id.clone(),
+            name: inode.name.clone()
+        };
+        let local_only = self.is_ignored_inode(inode)?;
+        self.run_action(uid, &mut action, local_only)
+    }
+
+    /// Remove an empty directory from its parent
+    pub fn remove_dir(&mut self, uid: u32, parent: &INode, inode: &INode) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if !inode.kind.is_directory() {
+            return Err(Error::NotADirectory);
+        }
+
+        let mut action = RemoveDir {
+            id: inode.id.clone(),
+            parent: parent.id.clone(),
+            name: inode.name.clone()
+        };
+        let local_only = self.is_ignored_inode(inode)?;
+        self.run_action(uid, &mut action, local_only)
+    }
+
+    /// Move the name of an inode in `parent` to a new parent and name, replacing
+    /// a compatible entry there
+    pub fn move_inode(&mut self, uid: u32, parent: &INode, inode: &INode, new_parent: &INode, new_name: &String) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        // Subtree quotas it moves into count it from now on
+        if inode.parent != new_parent.id {
+            quota_result(self.metadata.check_move_quota(inode, new_parent)?)?;
+        }
+
+        let mut action = Rename {
+            id: inode.id.clone(),
+            parent: parent.id.clone(),
+            name: inode.name.clone(),
+            new_parent: new_parent.id.clone(),
+            new_name: new_name.clone()
+        };
+
+        // Moving into or out of an ignored directory changes what peers see,
+        // the move itself only replicates between replicated paths
+        let local_only = self.is_ignored_inode(inode)? || self.is_ignored(new_parent, new_name, inode.kind.is_directory())?;
+        self.run_action(uid, &mut action, local_only)
+    }
+
+    /// Change the mode, owner, group or times of an inode, as `uid`
+    ///
+    /// The caller checks whether `uid` may make the change.
+    pub fn set_attr(&mut self, uid: u32, inode: &INode, mode: Option<u32>, owner: Option<u32>, group: Option<u32>, atime: Option<Timespec>, mtime: Option<Timespec>) -> Result<INode, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let seconds = |time: Option<Timespec>| time.map(|time| (time.sec, time.nsec));
+        let mut action = SetAttr {
+            id: inode.id.clone(),
+            mode,
+            uid: owner,
+            gid: group,
+            atime: seconds(atime),
+            mtime: seconds(mtime)
+        };
+
+        let local_only = self.is_ignored_inode(inode)?;
+        self.run_action(uid, &mut action, local_only)?;
+        self.metadata.get_by_id(&inode.id)
+    }
+
+    /// Truncate or extend a regular file, through its open handle when given
+    ///
+    /// Like a write it makes a new version, finished when the handle is
+    /// released. Without a handle the file is opened and released right away.
+    pub fn truncate(&mut self, uid: u32, inode: &INode, fh: Option<u64>, size: u64) -> Result<INode, Error> {
+        if let Some(fh) = fh {
+            return self.truncate_handle(fh, size);
+        }
+
+        // A placeholder truncated to nothing is never fetched
+        let flags = if size == 0 { O_WRONLY | O_TRUNC } else { O_WRONLY };
+        let fh = self.open_handle(uid, inode, flags)?;
+        let truncated = self.truncate_handle(fh, size);
+        let released = self.release_handle(fh);
+        truncated?;
+        released?;
+        self.metadata.get_by_id(&inode.id)
+    }
+
+    /// Open a regular file with the `O_*` flags of open(2), returns the file handle
+    ///
+    /// A placeholder is hydrated first, unless it's truncated. The first write
+    /// through the handle starts a new version, which is finished on release.
+    pub fn open_handle(&mut self, uid: u32, inode: &INode, flags: i32) -> Result<u64, Error> {
+        if !inode.kind.is_regular_file() {
+            return Err(Error::IsADirectory);
+        }
+        let write = flags & O_ACCMODE != O_RDONLY;
+        if self.read_only && write {
+            return Err(Error::ReadOnly);
+        }
+
+        let storage_path = self.storage_path(inode)?;
+        let truncate = write && flags & O_TRUNC == O_TRUNC;
+        self.ensure_hydrated(inode, &storage_path, truncate)?;
+        let handle = self.storage.open(&storage_path, flags)?;
+
+        self.last_fh += 1;
+        let fh = self.last_fh;
+        self.open_files.insert(fh, OpenFile {
+            handle,
+            ino: inode.ino,
+            uid,
+            dirty: false
+        });
+        self.open_inodes.lock().unwrap().insert(fh, inode.ino);
+
+        // Record the access on open rather than on every read, for the eviction order
+        let _ = self.metadata.touch_atime(inode);
+
+        if truncate {
+            let inode = self.begin_write(fh, inode)?;
+            self.metadata.set_size(&inode, 0)?;
+        }
+        Ok(fh)
+    }
+
+    /// Read up to `size` bytes at `offset` through a file handle
+    pub fn read_handle(&mut self, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, Error> {
+        match self.open_files.get_mut(&fh) {
+            Some(open_file) => open_file.handle.read(offset, size),
+            None            => Err(Error::BadFileHandle)
+        }
+    }
+
+    /// Write at `offset` through a file handle, within the quotas of the owner
+    pub fn write_handle(&mut self, fh: u64, offset: u64, data: &[u8]) -> Result<u32, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let ino = match self.open_files.get(&fh) {
+            Some(open_file) => open_file.ino,
+            None            => return Err(Error::BadFileHandle)
+        };
+        let inode = self.metadata.get_by_ino(ino)?;
+
+        let end = offset + data.len() as u64;
+        let new_size = if end > inode.size { end } else { inode.size };
+        self.check_growth(&inode, new_size)?;
+
+        let inode = self.begin_write(fh, &inode)?;
+        let written = match self.open_files.get_mut(&fh) {
+            Some(open_file) => open_file.handle.write(offset, data)?,
+            None            => return Err(Error::BadFileHandle)
+        };
+        self.metadata.set_size(&inode, new_size)?;
+        Ok(written)
+    }
+
+    /// Truncate or extend through a file handle, within the quotas of the owner
+    pub fn truncate_handle(&mut self, fh: u64, size: u64) -> Result<INode, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let ino = match self.open_files.get(&fh) {
+            Some(open_file) => open_file.ino,
+            None            => return Err(Error::BadFileHandle)
+        };
+        let inode = self.metadata.get_by_ino(ino)?;
+        self.check_growth(&inode, size)?;
+
+        let inode = self.begin_write(fh, &inode)?;
+        match self.open_files.get_mut(&fh) {
+            Some(open_file) => open_file.handle.set_len(size)?,
+            None            => return Err(Error::BadFileHandle)
+        }
+        self.metadata.set_size(&inode, size)
+    }
+
+    /// Close a file handle, finishing the version written through it
+    ///
+    /// Returns whether a version was finished.
+    pub fn release_handle(&mut self, fh: u64) -> Result<bool, Error> {
+        let open_file = match self.open_files.remove(&fh) {
+            Some(open_file) => open_file,
+            None            => return Err(Error::BadFileHandle)
+        };
+
+        // Finish the version before the scrubber sees the file closed
+        let committed = if open_file.dirty {
+            match self.metadata.get_by_ino(open_file.ino) {
+                Ok(inode) => self.commit(open_file.uid, &inode).map(|_| true),
+                Err(e)    => Err(e)
+            }
+        } else {
+            Ok(false)
+        };
+        self.open_inodes.lock().unwrap().remove(&fh);
+        committed
+    }
+
+    /// Inode numbers by open file handle, to leave open files alone
+    pub fn open_files(&self) -> OpenFiles {
+        self.open_inodes.clone()
+    }
+
+    /// Start a new version at the first write through a handle, unless
+    /// another handle is writing the file already
+    fn begin_write(&mut self, fh: u64, inode: &INode) -> Result<INode, Error> {
+        let dirty = match self.open_files.get(&fh) {
+            Some(open_file) => open_file.dirty,
+            None            => return Err(Error::BadFileHandle)
+        };
+        if dirty {
+            return Ok(inode.clone());
+        }
+
+        let shared = self.open_files.values().any(|open_file| open_file.ino == inode.ino && open_file.dirty);
+        let inode = if shared { inode.clone() } else { self.metadata.begin_version(inode)? };
+        if let Some(open_file) = self.open_files.get_mut(&fh) {
+            open_file.dirty = true;
+        }
+        Ok(inode)
+    }
+
+    /// Finish the version `uid` wrote, recording its hash for the scrubber and peers
+    fn commit(&mut self, uid: u32, inode: &INode) -> Result<(), Error> {
+        let storage_path = self.storage_path(inode)?;
+        let hash = match hash_file(&self.storage, &storage_path) {
+            Some(hash) => hash,
+            None       => return Err(Error::Io(io::Error::new(io::ErrorKind::Other, format!("Unable to hash {:?}", storage_path))))
+        };
+        let mut inode = inode.clone();
+        let mut file_version = self.metadata.get_version(&inode, &inode.current_version)?;
+        // Another handle finished the version first, a finished one never changes
+        if !file_version.hash.is_empty() {
+            inode = self.metadata.begin_version(&inode)?;
+            file_version = self.metadata.get_version(&inode, &inode.current_version)?;
+        }
+
+        let mut action = WriteVersion {
+            id: inode.id.clone(),
+            version: file_version.version.clone(),
+            source_version: file_version.source_version,
+            size: inode.size,
+            hash: hash.clone()
+        };
+
+        // Ignored files stay on this device
+        let local_only = self.is_ignored_inode(&inode)?;
+        self.run_action(uid, &mut action, local_only)?;
+        if !local_only {
+            if let Some(ref upload_queue) = self.upload_queue {
+                let _ = upload_queue.send(Upload {
+                    id: inode.id.clone(),
+                    version: file_version.version,
+                    hash
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Make sure the content of a placeholder is stored locally before it's opened
+    ///
+    /// This blocks the mount while downloading, see `Hydrator::hydrate`.
+    pub fn ensure_hydrated(&self, inode: &INode, path: &Path, truncate: bool) -> Result<(), Error> {
+        if inode.hydrated {
+            return Ok(());
+        }
+
+        // Nothing to fetch when the content is empty or about to be dropped
+        if !truncate && inode.size > 0 {
+            self.hydrator.hydrate(&self.storage, inode, path)?;
+        }
+
+        self.metadata.set_hydrated(inode, true)?;
+        Ok(())
+    }
+
+    /// Pin or unpin an inode, pinning downloads the whole subtree
+    ///
+    /// With a hydrate queue the download runs in the background, otherwise
+    /// it's done before returning.
+    pub fn set_pinned(&self, inode: &INode, pinned: bool) -> Result<(), Error> {
+        let inode = self.metadata.set_pinned(inode, pinned)?;
+
+        if pinned {
+            if let Some(ref hydrate_queue) = self.hydrate_queue {
+                let _ = hydrate_queue.send(inode.id.clone());
+                return Ok(());
+            }
+            let failed = self.hydrator.hydrate_subtree(&self.storage, &self.metadata, &inode)?;
+            if failed > 0 {
+                warn!("{} pinned files could not be downloaded", failed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a `.markfsignore` keeps the entry on this device
+    pub fn is_ignored(&mut self, parent: &INode, name: &str, is_directory: bool) -> Result<bool, Error> {
+        self.ignores.is_ignored(&self.metadata, &self.storage, parent, name, is_directory)
+    }
+
+    pub fn is_ignored_inode(&mut self, inode: &INode) -> Result<bool, Error> {
+        if inode.parent == inode.id {
+            return Ok(false);
+        }
+        match optional(self.metadata.get_by_id(&inode.parent))? {
+            Some(parent) => self.is_ignored(&parent, &inode.name, inode.kind.is_directory()),
+            None         => Ok(false)
+        }
+    }
+
+    /// Check the quotas for new content below the parent
+    pub fn check_quota(&self, parent: &INode, uid: u32, bytes: i64, inodes: i64) -> Result<(), Error> {
+        quota_result(self.metadata.check_quota(parent, uid, bytes, inodes)?)
+    }
+
+    /// Path in the storage backend
+    pub fn storage_path(&self, inode: &INode) -> Result<PathBuf, Error> {
+        let mut path_buf = PathBuf::new();
+        self.metadata.get_path(inode, &mut path_buf)?;
+        Ok(path_buf)
+    }
+
+    fn regular_file(&self, path: &Path) -> Result<INode, Error> {
+        let inode = self.stat(path)?;
+        if !inode.kind.is_regular_file() {
+            return Err(Error::IsADirectory);
+        }
+        Ok(inode)
+    }
+
+    /// The existing parent directory of a path, and the name in it
+    fn parent_and_name(&self, path: &Path) -> Result<(INode, String), Error> {
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_string(),
+            None       => return Err(Error::InvalidArgument)
+        };
+        let parent = self.stat(path.parent().unwrap_or(Path::new("")))?;
+        if !parent.kind.is_directory() {
+            return Err(Error::NotADirectory);
+        }
+        Ok((parent, name))
+    }
+
+    /// Check the quotas before a file grows to `size`
+    fn check_growth(&self, inode: &INode, size: u64) -> Result<(), Error> {
+        if size <= inode.size {
+            return Ok(());
+        }
+        match optional(self.metadata.get_by_id(&inode.parent))? {
+            Some(parent) => self.check_quota(&parent, inode.uid, size as i64 - inode.size as i64, 0),
+            None         => Ok(())
+        }
+    }
+
+    /// A new name in the parent, within the quotas of the owner
+    fn check_new_entry(&self, parent: &INode, name: &String, uid: u32) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if !parent.kind.is_directory() {
+            return Err(Error::NotADirectory);
+        }
+        if optional(self.metadata.lookup(parent, name))?.is_some() {
+            return Err(Error::Exists);
+        }
+        self.check_quota(parent, uid, 0, 1)
+    }
+
+    fn create_file_at(&mut self, path: &Path) -> Result<INode, Error> {
+        let (parent, name) = self.parent_and_name(path)?;
+        let ownership = Ownership {
+            mode: DEFAULT_FILE_MODE,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() }
+        };
+        self.create_file(&parent, &name, &ownership)
+    }
+}
+
+/// A subtree quota fails like a full disk, a user quota with EDQUOT
+fn quota_result(exceeded: Option<QuotaKind>) -> Result<(), Error> {
+    match exceeded {
+        None                     => Ok(()),
+        Some(QuotaKind::Subtree) => Err(Error::NoSpace),
+        Some(QuotaKind::User)    => Err(Error::QuotaExceeded)
+    }
+}